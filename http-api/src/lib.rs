@@ -0,0 +1,691 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Json, Router,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use paho_mqtt::AsyncClient;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use smart_homes::{
+    cli::parse_duration,
+    error::Error,
+    fault::Faults,
+    history::{aggregate, Event, HistoryStore, Record},
+    layout::{TopicScheme, KINDS},
+    provision::{AdminCommand, DeviceInfo},
+    rules::RuleSet,
+    scene::{check_scene_name, scene_topic, Scene, SceneReport, SceneRequest, SceneTopic},
+    schedule::{NextRun, ScheduleConfig, Scheduler},
+    transport::Message,
+    DeviceStatus,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// How long to wait for the simulator to report on the activation of a
+/// scene.
+const SCENE_REPORT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for the message retained on a topic, e.g. the devices
+/// of the simulator.
+const RETAINED_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most buckets a history query can be cut into.
+const MAX_BUCKETS: i64 = 10_000;
+
+/// The messages received by the client of the API. A paho client has a single
+/// stream, so it is read once and its messages handed to every request
+/// subscribed.
+#[derive(Clone)]
+struct Incoming {
+    sender: broadcast::Sender<paho_mqtt::Message>,
+    topics: Arc<Mutex<HashMap<String, Subscribed>>>,
+    /// Held while subscribing or unsubscribing, so that the unsubscribe of a
+    /// request done with a topic cannot overtake the subscribe of the next
+    /// one. The topics cannot be held instead: the client holds its own lock
+    /// while handing over messages.
+    subscribing: Arc<Mutex<()>>,
+}
+
+/// A topic requests are subscribed to.
+#[derive(Default)]
+struct Subscribed {
+    /// How many requests are subscribed.
+    requests: usize,
+    /// The last message received on the topic, for the requests that fall
+    /// behind.
+    latest: Option<paho_mqtt::Message>,
+}
+
+impl Incoming {
+    fn new(client: &AsyncClient) -> Self {
+        let (sender, _) = broadcast::channel(256);
+        let incoming = Self {
+            sender,
+            topics: Arc::default(),
+            subscribing: Arc::default(),
+        };
+        let receiver = incoming.clone();
+        // the callback, unlike the stream of the client, does not lose
+        // messages when the requests are slow to take them
+        client.set_message_callback(move |_, msg| {
+            let Some(msg) = msg else {
+                return;
+            };
+            if let Some(subscribed) = receiver.topics.lock().get_mut(msg.topic()) {
+                subscribed.latest = Some(msg.clone());
+            }
+            // nobody may be listening
+            let _ = receiver.sender.send(msg);
+        });
+        incoming
+    }
+}
+
+/// The subscription of a request to a topic. The broker subscription ends
+/// with the last request subscribed to the topic, so that the messages of
+/// topics nobody waits for do not crowd out the others.
+struct Subscription {
+    topic: String,
+    client: AsyncClient,
+    listener: broadcast::Receiver<paho_mqtt::Message>,
+    incoming: Incoming,
+}
+
+impl Subscription {
+    /// Subscribe to `topic`, which has the broker send the message retained
+    /// on it again.
+    async fn new(state: &SharedState, topic: String) -> Result<Self, (StatusCode, String)> {
+        let incoming = state.incoming.clone();
+        let listener = incoming.sender.subscribe();
+        let token = {
+            let _subscribing = incoming.subscribing.lock();
+            let mut topics = incoming.topics.lock();
+            topics.entry(topic.clone()).or_default().requests += 1;
+            drop(topics);
+            state.client.subscribe(&topic, 1)
+        };
+        let subscription = Self {
+            topic,
+            client: state.client.clone(),
+            listener,
+            incoming,
+        };
+        token.await.map_err(gateway_error)?;
+        Ok(subscription)
+    }
+
+    /// The next message published on the topic, or the last one if the
+    /// request fell behind. `None` if the client is gone.
+    async fn next(&mut self) -> Option<paho_mqtt::Message> {
+        loop {
+            match self.listener.recv().await {
+                Ok(msg) if msg.topic() == self.topic => return Some(msg),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(_)) => {
+                    let latest = self.incoming.topics.lock()[&self.topic].latest.clone();
+                    if latest.is_some() {
+                        return latest;
+                    }
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let _subscribing = self.incoming.subscribing.lock();
+        let mut topics = self.incoming.topics.lock();
+        let Some(subscribed) = topics.get_mut(&self.topic) else {
+            return;
+        };
+        subscribed.requests -= 1;
+        if subscribed.requests == 0 {
+            topics.remove(&self.topic);
+            drop(topics);
+            // the request is sent already, nobody waits for its outcome
+            drop(self.client.unsubscribe(&self.topic));
+        }
+    }
+}
+
+fn gateway_error(err: paho_mqtt::Error) -> (StatusCode, String) {
+    (StatusCode::BAD_GATEWAY, err.to_string())
+}
+
+/// The message retained on `topic`, of which `what` tells when there is
+/// none.
+async fn retained(
+    state: &SharedState,
+    topic: &str,
+    what: &str,
+) -> Result<paho_mqtt::Message, (StatusCode, String)> {
+    let mut subscription = Subscription::new(state, topic.to_string()).await?;
+    match tokio::time::timeout(RETAINED_TIMEOUT, subscription.next()).await {
+        Ok(Some(msg)) => Ok(msg),
+        Ok(None) => Err((
+            StatusCode::BAD_GATEWAY,
+            "Lost connection to the broker".into(),
+        )),
+        Err(_) => Err((StatusCode::NOT_FOUND, what.into())),
+    }
+}
+
+/// Decode a status by its content type, or by the codec of `topics` if it
+/// has none. A status of a newer schema than the API knows is a conflict,
+/// any other undecodable status a bad gateway.
+fn decode_status(
+    topics: &TopicScheme,
+    msg: paho_mqtt::Message,
+) -> Result<DeviceStatus, (StatusCode, String)> {
+    let msg = Message::from(msg);
+    let codec = topics.codec().or_content_type(msg.content_type());
+    codec.decode_status(msg.payload()).map_err(|err| match err {
+        Error::UnsupportedSchemaVersion(_) => (StatusCode::CONFLICT, err.to_string()),
+        _ => (StatusCode::BAD_GATEWAY, err.to_string()),
+    })
+}
+
+async fn get_bulb_info(
+    Path(home_id): Path<u32>,
+    State(state): State<SharedState>,
+) -> Result<Json<DeviceStatus>, (StatusCode, String)> {
+    get_status(&state, "bulb", home_id).await
+}
+
+async fn get_fan_info(
+    Path(house_id): Path<u32>,
+    State(state): State<SharedState>,
+) -> Result<Json<DeviceStatus>, (StatusCode, String)> {
+    get_status(&state, "fan", house_id).await
+}
+
+async fn get_tv_info(
+    Path(house_id): Path<u32>,
+    State(state): State<SharedState>,
+) -> Result<Json<DeviceStatus>, (StatusCode, String)> {
+    get_status(&state, "tv", house_id).await
+}
+
+/// The last status of the device of `kind` in the home.
+async fn get_status(
+    state: &SharedState,
+    kind: &str,
+    house_id: u32,
+) -> Result<Json<DeviceStatus>, (StatusCode, String)> {
+    let topic = state.topics.status_topic(kind, &format!("home/{house_id}"));
+    let msg = retained(state, &topic, "The device did not publish its status").await?;
+    decode_status(&state.topics, msg).map(Json)
+}
+
+/// Replace the automation rules of the simulator, which takes them from its
+/// admin topic.
+async fn put_rules(
+    State(state): State<SharedState>,
+    Json(rules): Json<RuleSet>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    rules
+        .validate()
+        .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))?;
+    let payload = serde_json::to_vec(&rules)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let msg = Message::new(state.topics.topic("sim/admin/rules"), payload);
+    state
+        .client
+        .publish(msg.into())
+        .await
+        .map_err(|err| (StatusCode::BAD_GATEWAY, err.to_string()))?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Save the definition of a scene, as a retained message every client of the
+/// broker sees.
+async fn put_scene(
+    Path(name): Path<String>,
+    State(state): State<SharedState>,
+    Json(scene): Json<Scene>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    check_scene_name(&name).map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    let payload = serde_json::to_vec(&scene)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let topic = scene_topic(&state.topics, &name, SceneTopic::Definition);
+    state
+        .client
+        .publish(Message::new_retained(topic, payload).into())
+        .await
+        .map_err(|err| (StatusCode::BAD_GATEWAY, err.to_string()))?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Ask the simulator to save the current state of the home as a scene.
+async fn capture_scene(
+    Path((house_id, name)): Path<(u32, String)>,
+    State(state): State<SharedState>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    check_scene_name(&name).map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    let request = SceneRequest {
+        home: format!("home/{house_id}"),
+        request_id: None,
+    };
+    let payload = serde_json::to_vec(&request)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let topic = scene_topic(&state.topics, &name, SceneTopic::Capture);
+    state
+        .client
+        .publish(Message::new(topic, payload).into())
+        .await
+        .map_err(|err| (StatusCode::BAD_GATEWAY, err.to_string()))?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Ask the simulator to activate a scene in the home, and wait for how it
+/// went for every device.
+async fn activate_scene(
+    Path((house_id, name)): Path<(u32, String)>,
+    State(state): State<SharedState>,
+) -> Result<Json<SceneReport>, (StatusCode, String)> {
+    static REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+    check_scene_name(&name).map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    let request_id = format!(
+        "http-api-{}-{}",
+        std::process::id(),
+        REQUESTS.fetch_add(1, Ordering::Relaxed)
+    );
+    let request = SceneRequest {
+        home: format!("home/{house_id}"),
+        request_id: Some(request_id.clone()),
+    };
+    let payload = serde_json::to_vec(&request)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    let report_topic = scene_topic(&state.topics, &name, SceneTopic::Report);
+    let mut reports = Subscription::new(&state, report_topic).await?;
+    let topic = scene_topic(&state.topics, &name, SceneTopic::Activate);
+    state
+        .client
+        .publish(Message::new(topic, payload).into())
+        .await
+        .map_err(gateway_error)?;
+
+    let report = tokio::time::timeout(SCENE_REPORT_TIMEOUT, async {
+        while let Some(msg) = reports.next().await {
+            let Ok(report) = serde_json::from_slice::<SceneReport>(msg.payload()) else {
+                continue;
+            };
+            if report.request_id.as_deref() == Some(request_id.as_str()) {
+                return Some(report);
+            }
+        }
+        None
+    })
+    .await;
+    match report {
+        Ok(Some(report)) => Ok(Json(report)),
+        Ok(None) => Err((
+            StatusCode::BAD_GATEWAY,
+            "Lost connection to the broker".into(),
+        )),
+        Err(_) => Err((
+            StatusCode::GATEWAY_TIMEOUT,
+            "The simulator did not report on the scene".into(),
+        )),
+    }
+}
+
+/// Replace the schedules of the simulator, which takes them from its admin
+/// topic.
+async fn put_schedules(
+    State(state): State<SharedState>,
+    Json(config): Json<ScheduleConfig>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let payload = serde_json::to_vec(&config)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Scheduler::new(state.topics.clone(), config)
+        .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))?;
+    let msg = Message::new(state.topics.topic("sim/admin/schedules"), payload);
+    state
+        .client
+        .publish(msg.into())
+        .await
+        .map_err(|err| (StatusCode::BAD_GATEWAY, err.to_string()))?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// The next run of every schedule, as last published by the simulator.
+async fn get_next_runs(
+    State(state): State<SharedState>,
+) -> Result<Json<Vec<NextRun>>, (StatusCode, String)> {
+    let topic = state.topics.topic("sim/schedules/next");
+    let msg = retained(
+        &state,
+        &topic,
+        "The simulator did not publish its schedules",
+    )
+    .await?;
+    serde_json::from_slice(msg.payload())
+        .map(Json)
+        .map_err(|err| (StatusCode::BAD_GATEWAY, err.to_string()))
+}
+
+/// The target of an admin command: the home, or a device of it. Unknown
+/// kinds are not found.
+fn admin_target(house_id: u32, kind: Option<&str>) -> Result<String, (StatusCode, String)> {
+    match kind {
+        None => Ok(format!("home/{house_id}")),
+        Some(kind) if KINDS.contains(&kind) => Ok(format!("{kind}/home/{house_id}")),
+        Some(kind) => Err((StatusCode::NOT_FOUND, format!("No device of kind {kind}"))),
+    }
+}
+
+/// Have the simulator add, remove, configure, pause or resume devices, which
+/// it does from its admin topic.
+async fn send_admin_command(
+    state: &SharedState,
+    command: AdminCommand,
+) -> Result<StatusCode, (StatusCode, String)> {
+    command
+        .devices()
+        .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))?;
+    let payload = serde_json::to_vec(&command)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let msg = Message::new(state.topics.topic("sim/admin/command"), payload);
+    state
+        .client
+        .publish(msg.into())
+        .await
+        .map_err(|err| (StatusCode::BAD_GATEWAY, err.to_string()))?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn add_home(
+    Path(house_id): Path<u32>,
+    State(state): State<SharedState>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let target = admin_target(house_id, None)?;
+    send_admin_command(&state, AdminCommand::Add { target }).await
+}
+
+async fn remove_home(
+    Path(house_id): Path<u32>,
+    State(state): State<SharedState>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let target = admin_target(house_id, None)?;
+    send_admin_command(&state, AdminCommand::Remove { target }).await
+}
+
+async fn add_device(
+    Path((house_id, kind)): Path<(u32, String)>,
+    State(state): State<SharedState>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let target = admin_target(house_id, Some(&kind))?;
+    send_admin_command(&state, AdminCommand::Add { target }).await
+}
+
+async fn remove_device(
+    Path((house_id, kind)): Path<(u32, String)>,
+    State(state): State<SharedState>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let target = admin_target(house_id, Some(&kind))?;
+    send_admin_command(&state, AdminCommand::Remove { target }).await
+}
+
+/// Set the faults of a device, in place of those of its kind.
+async fn put_faults(
+    Path((house_id, kind)): Path<(u32, String)>,
+    State(state): State<SharedState>,
+    Json(faults): Json<Faults>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let target = admin_target(house_id, Some(&kind))?;
+    let faults = Some(faults);
+    send_admin_command(&state, AdminCommand::Configure { target, faults }).await
+}
+
+/// Let a device have the faults of its kind again.
+async fn delete_faults(
+    Path((house_id, kind)): Path<(u32, String)>,
+    State(state): State<SharedState>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let target = admin_target(house_id, Some(&kind))?;
+    let faults = None;
+    send_admin_command(&state, AdminCommand::Configure { target, faults }).await
+}
+
+async fn pause_home(
+    Path(house_id): Path<u32>,
+    State(state): State<SharedState>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let target = admin_target(house_id, None)?;
+    send_admin_command(&state, AdminCommand::Pause { target }).await
+}
+
+async fn resume_home(
+    Path(house_id): Path<u32>,
+    State(state): State<SharedState>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let target = admin_target(house_id, None)?;
+    send_admin_command(&state, AdminCommand::Resume { target }).await
+}
+
+async fn pause_device(
+    Path((house_id, kind)): Path<(u32, String)>,
+    State(state): State<SharedState>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let target = admin_target(house_id, Some(&kind))?;
+    send_admin_command(&state, AdminCommand::Pause { target }).await
+}
+
+async fn resume_device(
+    Path((house_id, kind)): Path<(u32, String)>,
+    State(state): State<SharedState>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let target = admin_target(house_id, Some(&kind))?;
+    send_admin_command(&state, AdminCommand::Resume { target }).await
+}
+
+/// The devices running in the simulator, as last published by it.
+async fn get_devices(
+    State(state): State<SharedState>,
+) -> Result<Json<Vec<DeviceInfo>>, (StatusCode, String)> {
+    let topic = state.topics.topic("sim/devices");
+    let msg = retained(&state, &topic, "The simulator did not publish its devices").await?;
+    serde_json::from_slice(msg.payload())
+        .map(Json)
+        .map_err(|err| (StatusCode::BAD_GATEWAY, err.to_string()))
+}
+
+#[derive(Clone)]
+struct SharedState {
+    client: AsyncClient,
+    incoming: Incoming,
+    topics: TopicScheme,
+}
+
+/// Build the routes of the API on top of a client connected to the broker,
+/// reading the statuses the simulator publishes with the same `topics`.
+///
+/// The API takes over the messages of the client, which must not be
+/// streamed or handled by anything else.
+pub fn router(client: AsyncClient, topics: TopicScheme) -> Router {
+    let incoming = Incoming::new(&client);
+    Router::new()
+        .route("/house/:house_id/bulb/status", get(get_bulb_info))
+        .route("/house/:house_id/fan/status", get(get_fan_info))
+        .route("/house/:house_id/tv/status", get(get_tv_info))
+        .route("/rules", put(put_rules))
+        .route("/schedules", put(put_schedules))
+        .route("/schedules/next", get(get_next_runs))
+        .route("/scenes/:name", put(put_scene))
+        .route("/house/:house_id/scenes/:name/capture", post(capture_scene))
+        .route(
+            "/house/:house_id/scenes/:name/activate",
+            post(activate_scene),
+        )
+        .route("/devices", get(get_devices))
+        .route("/house/:house_id", put(add_home).delete(remove_home))
+        .route("/house/:house_id/pause", post(pause_home))
+        .route("/house/:house_id/resume", post(resume_home))
+        .route(
+            "/house/:house_id/:kind",
+            put(add_device).delete(remove_device),
+        )
+        .route(
+            "/house/:house_id/:kind/faults",
+            put(put_faults).delete(delete_faults),
+        )
+        .route("/house/:house_id/:kind/pause", post(pause_device))
+        .route("/house/:house_id/:kind/resume", post(resume_device))
+        .with_state(SharedState {
+            client,
+            incoming,
+            topics,
+        })
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    /// Unix time in seconds, a day before `to` by default.
+    from: Option<i64>,
+    /// Unix time in seconds, now by default.
+    to: Option<i64>,
+    /// The length of the buckets to aggregate the records in, e.g. `15m`.
+    /// The raw records are returned without it.
+    step: Option<String>,
+    #[serde(default)]
+    format: Format,
+}
+
+/// A record as a CSV row, the fields a device does not have left empty.
+#[derive(Debug, Default, Serialize)]
+struct CsvRecord<'a> {
+    timestamp: i64,
+    kind: &'a str,
+    id: &'a str,
+    is_available: Option<bool>,
+    is_on: Option<bool>,
+    speed: Option<u8>,
+    voltage: Option<f32>,
+    color: Option<String>,
+    channel: Option<u16>,
+    volume: Option<u8>,
+    is_muted: Option<bool>,
+}
+
+impl<'a> From<&'a Record> for CsvRecord<'a> {
+    fn from(record: &'a Record) -> Self {
+        let mut row = CsvRecord {
+            timestamp: record.timestamp.timestamp(),
+            kind: &record.kind,
+            id: &record.id,
+            ..Default::default()
+        };
+        match &record.event {
+            Event::Availability(is_available) => row.is_available = Some(*is_available),
+            Event::Status(status) => {
+                row.is_on = Some(status.is_on());
+                row.voltage = status.voltage();
+                match status {
+                    DeviceStatus::Bulb(s) => {
+                        let (r, g, b) = s.color;
+                        row.speed = Some(s.speed);
+                        row.color = Some(format!("{r},{g},{b}"));
+                    }
+                    DeviceStatus::Fan(s) => row.speed = Some(s.speed),
+                    DeviceStatus::TV(s) => {
+                        row.channel = Some(s.channel);
+                        row.volume = Some(s.volume);
+                        row.is_muted = Some(s.is_muted);
+                    }
+                }
+            }
+        }
+        row
+    }
+}
+
+fn csv_response<T: Serialize>(rows: impl IntoIterator<Item = T>) -> Result<Response, StatusCode> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for row in rows {
+        writer
+            .serialize(row)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    let body = writer
+        .into_inner()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(([(header::CONTENT_TYPE, "text/csv")], body).into_response())
+}
+
+async fn get_history(
+    Path((house_id, kind)): Path<(u32, String)>,
+    Query(query): Query<HistoryQuery>,
+    State(history): State<HistoryStore>,
+) -> Result<Response, StatusCode> {
+    if !KINDS.contains(&kind.as_str()) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let timestamp = |secs| DateTime::from_timestamp(secs, 0).ok_or(StatusCode::BAD_REQUEST);
+    let to = query
+        .to
+        .map(timestamp)
+        .transpose()?
+        .unwrap_or_else(Utc::now);
+    let from = match query.from {
+        Some(secs) => timestamp(secs)?,
+        None => to
+            .checked_sub_signed(TimeDelta::days(1))
+            .ok_or(StatusCode::BAD_REQUEST)?,
+    };
+    let step = query
+        .step
+        .as_deref()
+        .map(parse_duration)
+        .transpose()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    if from > to
+        || step.is_some_and(|step| (to - from).num_seconds() / step.num_seconds() >= MAX_BUCKETS)
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // reading the history files would hold up the executor
+    let id = format!("home/{house_id}");
+    let records = tokio::task::spawn_blocking(move || history.query(&kind, &id, from, to))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    match (step, query.format) {
+        (None, Format::Json) => Ok(Json(records).into_response()),
+        (None, Format::Csv) => csv_response(records.iter().map(CsvRecord::from)),
+        (Some(step), Format::Json) => Ok(Json(aggregate(&records, from, to, step)).into_response()),
+        (Some(step), Format::Csv) => csv_response(aggregate(&records, from, to, step)),
+    }
+}
+
+/// Build the routes serving the history of the devices recorded in
+/// `history`, to merge with the [`router`].
+pub fn history_router(history: HistoryStore) -> Router {
+    Router::new()
+        .route("/house/:house_id/:kind/history", get(get_history))
+        .with_state(history)
+}
@@ -1,85 +1,140 @@
-use axum::{
-    extract::{Path, State},
-    routing::get,
-    Json, Router,
+use chrono::Utc;
+use clap::Parser;
+use http_api::{history_router, router};
+use paho_mqtt::{AsyncClient, QOS_1};
+use smart_homes::{
+    broker::BrokerConfig,
+    cli::{validate_topic_prefix, BrokerArgs, HistoryArgs},
+    codec::Codec,
+    history::HistoryStore,
+    layout::TopicScheme,
+    transport::Message,
 };
-use paho_mqtt::{AsyncClient, ConnectOptionsBuilder};
-use smart_homes::DeviceStatus;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{error, warn};
 
-async fn get_bulb_info(
-    Path(home_id): Path<u32>,
-    State(state): State<SharedState>,
-) -> Json<DeviceStatus> {
-    let topic = format!("bulb/home/{}/status", home_id);
-    let mut client = state.client;
-    let _ = client.subscribe(topic, 1).await.unwrap();
+#[derive(Debug, Parser)]
+struct Cli {
+    #[clap(flatten)]
+    broker: BrokerArgs,
 
-    let buf = client.get_stream(16);
-    let Ok(Some(msg)) = buf.recv().await else {
-        // TODO: add retry/error handling
-        panic!()
-    };
-    return Json(serde_json::from_slice(msg.payload()).unwrap());
-}
+    /// Where the history of the devices is, to serve it.
+    #[clap(flatten)]
+    history: HistoryArgs,
 
-async fn get_fan_info(
-    Path(house_id): Path<u32>,
-    State(state): State<SharedState>,
-) -> Json<DeviceStatus> {
-    let topic = format!("fan/home/{}/status", house_id);
-    let mut client = state.client;
+    /// Record the history in `--history-dir` as well, for when the
+    /// simulator does not.
+    #[clap(long, requires = "history_dir")]
+    record_history: bool,
 
-    let _ = client.subscribe(topic, 1).await.unwrap();
-    let buf = client.get_stream(16);
-    let Ok(Some(msg)) = buf.recv().await else {
-        // TODO: add retry/error handling
-        panic!()
-    };
-    return Json(serde_json::from_slice(msg.payload()).unwrap());
+    /// The topic prefix the simulator was started with.
+    #[clap(long, value_parser = validate_topic_prefix)]
+    topic_prefix: Option<String>,
+
+    /// The codec the simulator was started with, for the statuses that do
+    /// not tell their content type.
+    #[clap(long, value_enum, default_value_t = Codec::Json)]
+    codec: Codec,
 }
 
-async fn get_tv_info(
-    Path(house_id): Path<u32>,
-    State(state): State<SharedState>,
-) -> Json<DeviceStatus> {
-    let topic = format!("tv/home/{}/status", house_id);
-    let mut client = state.client;
+/// How long to wait before recording the history again after a failure.
+const RECORD_RETRY_DELAY: Duration = Duration::from_secs(5);
 
-    let _ = client
-        .subscribe_with_options(topic, 1, None, None)
-        .await
-        .unwrap();
-    let buf = client.get_stream(16);
-    let Ok(Some(msg)) = buf.recv().await else {
-        // TODO: add retry/error handling
-        panic!()
+/// Record the statuses and availability of the devices in `history`, until
+/// the connection to the broker is lost.
+async fn record_history(
+    broker: &BrokerConfig,
+    topics: &TopicScheme,
+    history: &HistoryStore,
+) -> Result<(), paho_mqtt::Error> {
+    let client_id = topics.client_id("http-api/history");
+    let mut client = AsyncClient::new(broker.create_options(&client_id))?;
+    let stream = client.get_stream(64);
+    let connect_opts = broker.connect_options(&client_id)?.finalize();
+    client.connect(connect_opts).await?;
+    client
+        .subscribe_many_same_qos(&topics.device_filters(), QOS_1)
+        .await?;
+
+    // writing the history files would hold up the executor
+    let (records_tx, records_rx) = mpsc::channel(256);
+    let writer = {
+        let topics = topics.clone();
+        let history = history.clone();
+        tokio::task::spawn_blocking(move || write_history(records_rx, &topics, &history))
     };
-    return Json(serde_json::from_slice(msg.payload()).unwrap());
+    // `None` tells of a lost connection
+    while let Ok(Some(msg)) = stream.recv().await {
+        if records_tx.send(Message::from(msg)).await.is_err() {
+            break;
+        }
+    }
+    drop(records_tx);
+    if let Err(err) = writer.await {
+        error!(?err, "Failed to write the history");
+    }
+    Ok(())
+}
+
+/// Write the messages received on `records` to `history`, applying its
+/// policy every minute, until the receiver of the messages stops.
+fn write_history(
+    mut records: mpsc::Receiver<Message>,
+    topics: &TopicScheme,
+    history: &HistoryStore,
+) {
+    let mut last_policy_run = Instant::now();
+    while let Some(msg) = records.blocking_recv() {
+        if let Err(err) = history.record(topics, &msg, Utc::now()) {
+            error!(?err, "Failed to record history");
+        }
+        if last_policy_run.elapsed() > Duration::from_secs(60) {
+            if let Err(err) = history.apply_policy(Utc::now()) {
+                error!(?err, "Failed to apply the history policy");
+            }
+            last_policy_run = Instant::now();
+        }
+    }
 }
 
-#[derive(Clone)]
-struct SharedState {
-    client: AsyncClient,
+/// Record the history for as long as the server runs, starting again
+/// whenever recording stops.
+async fn keep_recording_history(broker: BrokerConfig, topics: TopicScheme, history: HistoryStore) {
+    loop {
+        match record_history(&broker, &topics, &history).await {
+            Ok(()) => warn!("Lost the connection recording the history"),
+            Err(err) => error!(?err, "Failed to record the history"),
+        }
+        tokio::time::sleep(RECORD_RETRY_DELAY).await;
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    let client = AsyncClient::new("tcp://localhost:1883").unwrap();
-    client
-        .connect(
-            ConnectOptionsBuilder::new_v5()
-                .connect_timeout(Duration::from_secs(5))
-                .finalize(),
-        )
-        .await
-        .unwrap();
+    let cli = Cli::parse();
+    tracing_subscriber::fmt().init();
+    let broker = cli.broker.config();
+    let mut topics = TopicScheme::default().with_codec(cli.codec);
+    if let Some(prefix) = cli.topic_prefix {
+        topics = topics.with_prefix(prefix);
+    }
 
-    let app = Router::new()
-        .route("/house/:house_id/bulb/status", get(get_bulb_info))
-        .route("/house/:house_id/fan/status", get(get_fan_info))
-        .route("/house/:house_id/tv/status", get(get_tv_info))
-        .with_state(SharedState { client });
+    let client_id = topics.client_id("http-api");
+    let client = AsyncClient::new(broker.create_options(&client_id)).unwrap();
+    let connect_opts = broker
+        .connect_options(&client_id)
+        .unwrap()
+        .connect_timeout(Duration::from_secs(5))
+        .finalize();
+    client.connect(connect_opts).await.unwrap();
+    let mut app = router(client, topics.clone());
+    if let Some(history) = cli.history.open().unwrap() {
+        if cli.record_history {
+            tokio::spawn(keep_recording_history(broker, topics, history.clone()));
+        }
+        app = app.merge(history_router(history));
+    }
 
     let listener = tokio::net::TcpListener::bind("localhost:3000")
         .await
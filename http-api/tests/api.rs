@@ -0,0 +1,474 @@
+use axum::{
+    body::{to_bytes, Body},
+    http::{Request, StatusCode},
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use paho_mqtt::AsyncClient;
+use serde_json::{json, Value};
+use smart_homes::{
+    bulb::Bulb,
+    clock::Clock,
+    codec::Codec,
+    fan::{Fan, FanStatus},
+    history::{Event, HistoryPolicy, HistoryStore, Record},
+    layout::TopicScheme,
+    scene::{scene_filters, SceneManager},
+    DeviceStatus,
+};
+use std::time::{Duration, Instant};
+use test_broker::Broker;
+use tokio::{task::JoinSet, time::sleep};
+use tower::ServiceExt;
+
+async fn get(client: AsyncClient, uri: &str) -> Value {
+    let response = http_api::router(client, TopicScheme::default())
+        .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+async fn connected_client(broker: &Broker) -> AsyncClient {
+    let client = AsyncClient::new(broker.url()).unwrap();
+    client.connect(None).await.unwrap();
+    client
+}
+
+/// Wait until the device has published its first status.
+async fn wait_for_status(broker: &Broker, topic: &str) {
+    for _ in 0..100 {
+        if broker.retained(topic).is_some() {
+            return;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    panic!("no status published on {topic}");
+}
+
+#[tokio::test]
+async fn bulb_status_is_served() {
+    let broker = Broker::start().await.unwrap();
+    let mut bulb = Bulb::try_new("home/3", broker.url()).unwrap();
+    tokio::spawn(async move { bulb.handle_incoming().await });
+    wait_for_status(&broker, "bulb/home/3/status").await;
+
+    let status = get(connected_client(&broker).await, "/house/3/bulb/status").await;
+    assert_eq!(status["type"], "bulb");
+    assert_eq!(status["status"]["id"], "home/3");
+}
+
+#[tokio::test]
+async fn fan_status_is_served() {
+    let broker = Broker::start().await.unwrap();
+    let mut fan = Fan::try_new("home/7", broker.url()).unwrap();
+    tokio::spawn(async move { fan.handle_incoming().await });
+    wait_for_status(&broker, "fan/home/7/status").await;
+
+    let status = get(connected_client(&broker).await, "/house/7/fan/status").await;
+    assert_eq!(status["type"], "fan");
+    assert_eq!(status["status"]["id"], "home/7");
+    assert_eq!(status["status"]["is_on"], false);
+}
+
+#[tokio::test]
+async fn undecodable_statuses_are_reported() {
+    let broker = Broker::start().await.unwrap();
+    let simulator = connected_client(&broker).await;
+    let newer = json!({
+        "schema_version": 99,
+        "type": "fan",
+        "status": {"id": "home/9", "is_on": false, "speed": 0, "voltage": 230.0, "timestamp": 0}
+    });
+    for (topic, payload) in [
+        ("fan/home/8/status", "not a status".to_string()),
+        ("fan/home/9/status", newer.to_string()),
+    ] {
+        let msg = paho_mqtt::Message::new_retained(topic, payload, 1);
+        simulator.publish(msg).await.unwrap();
+    }
+
+    let app = http_api::router(connected_client(&broker).await, TopicScheme::default());
+    for (uri, expected) in [
+        ("/house/8/fan/status", StatusCode::BAD_GATEWAY),
+        ("/house/9/fan/status", StatusCode::CONFLICT),
+    ] {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), expected);
+    }
+}
+
+#[tokio::test]
+async fn history_is_served() {
+    let dir = std::env::temp_dir().join(format!("http-api-history-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let history = HistoryStore::open(&dir, HistoryPolicy::default()).unwrap();
+    let timestamp = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    for (id, is_available) in [("home/1", true), ("home/1", false), ("home/2", true)] {
+        history
+            .append(&Record {
+                timestamp,
+                kind: "tv".into(),
+                id: id.into(),
+                event: Event::Availability(is_available),
+            })
+            .unwrap();
+    }
+
+    let app = http_api::history_router(history);
+    let uri = "/house/1/tv/history?from=1699999000&to=1700000000";
+    let response = app
+        .clone()
+        .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let records: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        records,
+        json!([
+            {"timestamp": 1_700_000_000, "kind": "tv", "id": "home/1", "availability": true},
+            {"timestamp": 1_700_000_000, "kind": "tv", "id": "home/1", "availability": false},
+        ])
+    );
+
+    let uri = "/house/1/oven/history";
+    let response = app
+        .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn history_is_aggregated_as_csv() {
+    let dir = std::env::temp_dir().join(format!("http-api-aggregate-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let history = HistoryStore::open(&dir, HistoryPolicy::default()).unwrap();
+    let from = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    for (minutes, is_on, voltage) in [(0, true, 218.0), (20, true, 222.0), (30, false, 220.0)] {
+        let timestamp = from + TimeDelta::minutes(minutes);
+        let status = DeviceStatus::Fan(FanStatus {
+            id: "home/4".into(),
+            is_on,
+            speed: 2,
+            voltage,
+            timestamp,
+        });
+        history
+            .append(&Record {
+                timestamp,
+                kind: "fan".into(),
+                id: "home/4".into(),
+                event: Event::Status(status),
+            })
+            .unwrap();
+    }
+
+    let app = http_api::history_router(history);
+    let get = |uri: &str| {
+        app.clone()
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+    };
+    let uri = "/house/4/fan/history?from=1700000000&to=1700003600&step=30m&format=csv";
+    let response = get(uri).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/csv");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let csv = String::from_utf8(body.to_vec()).unwrap();
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "start,end,statuses,min_voltage,max_voltage,avg_voltage,on_percent,channel_changes",
+            "1700000000,1700001800,2,218.0,222.0,220.0,100.0,",
+            "1700001800,1700003600,1,220.0,220.0,220.0,0.0,",
+        ]
+    );
+
+    // the raw records, with the columns of every kind of device
+    let uri = "/house/4/fan/history?from=1700000000&to=1700003600&format=csv";
+    let body = to_bytes(get(uri).await.unwrap().into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let csv = String::from_utf8(body.to_vec()).unwrap();
+    assert_eq!(
+        csv.lines().nth(1),
+        Some("1700000000,fan,home/4,,true,2,218.0,,,,")
+    );
+
+    // a day before the start of time
+    let too_early = format!(
+        "/house/4/fan/history?to={}",
+        DateTime::<Utc>::MIN_UTC.timestamp()
+    );
+    for uri in [
+        "/house/4/fan/history?step=1s",
+        "/house/4/fan/history?step=often",
+        "/house/4/fan/history?step=5%C2%B5",
+        "/house/4/fan/history?from=1700003600&to=1700000000",
+        &too_early,
+    ] {
+        assert_eq!(get(uri).await.unwrap().status(), StatusCode::BAD_REQUEST);
+    }
+}
+
+#[tokio::test]
+async fn rules_are_sent_to_the_simulator() {
+    let broker = Broker::start().await.unwrap();
+    let mut simulator = connected_client(&broker).await;
+    let stream = simulator.get_stream(4);
+    simulator.subscribe("sim/admin/rules", 1).await.unwrap();
+
+    let app = http_api::router(connected_client(&broker).await, TopicScheme::default());
+    let put = |rules: Value| {
+        let request = Request::put("/rules")
+            .header("content-type", "application/json")
+            .body(Body::from(rules.to_string()))
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+    let rules = json!({"rules": [{
+        "name": "night",
+        "trigger": {"on": "time", "at": "22:00"},
+        "actions": [{"do": "command", "device": "tv/home/0", "command": {"cmd": "off"}}]
+    }]});
+    let response = put(rules).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let msg = stream.recv().await.unwrap().unwrap();
+    let received: Value = serde_json::from_slice(msg.payload()).unwrap();
+    assert_eq!(received["rules"][0]["name"], "night");
+
+    let broken = json!({"rules": [{
+        "name": "broken",
+        "trigger": {"on": "time", "at": "22:00"},
+        "actions": [{"do": "command", "device": "tv/home/0", "command": {"cmd": "spin"}}]
+    }]});
+    let response = put(broken).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn schedules_are_sent_and_listed() {
+    let broker = Broker::start().await.unwrap();
+    let mut simulator = connected_client(&broker).await;
+    let stream = simulator.get_stream(4);
+    simulator.subscribe("sim/admin/schedules", 1).await.unwrap();
+
+    let app = http_api::router(connected_client(&broker).await, TopicScheme::default());
+    let put = |schedules: Value| {
+        let request = Request::put("/schedules")
+            .header("content-type", "application/json")
+            .body(Body::from(schedules.to_string()))
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+    let schedule = |when: Value| {
+        json!({"schedules": [{
+            "name": "night",
+            "home": "home/0",
+            "when": when,
+            "actions": [{"do": "command", "device": "tv", "command": {"cmd": "off"}}]
+        }]})
+    };
+    // no location to tell the sunset by
+    let response = put(schedule(json!({"sun": "sunset"}))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let response = put(schedule(json!({"cron": "0 22 * * *"}))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let msg = stream.recv().await.unwrap().unwrap();
+    let received: Value = serde_json::from_slice(msg.payload()).unwrap();
+    assert_eq!(received["schedules"][0]["name"], "night");
+
+    let next = json!([{
+        "schedule": "night",
+        "home": "home/0",
+        "at": 1_700_000_000,
+        "local": "2023-11-14T22:13:20+00:00"
+    }]);
+    let msg = paho_mqtt::Message::new_retained("sim/schedules/next", next.to_string(), 1);
+    simulator.publish(msg).await.unwrap();
+    let client = connected_client(&broker).await;
+    assert_eq!(get(client, "/schedules/next").await, next);
+}
+
+#[tokio::test]
+async fn devices_are_provisioned_and_listed() {
+    let broker = Broker::start().await.unwrap();
+    let mut simulator = connected_client(&broker).await;
+    let stream = simulator.get_stream(16);
+    simulator.subscribe("sim/admin/command", 1).await.unwrap();
+
+    let app = http_api::router(connected_client(&broker).await, TopicScheme::default());
+    let send = |request: Request<Body>| app.clone().oneshot(request);
+    let requests = [
+        Request::put("/house/4").body(Body::empty()),
+        Request::delete("/house/4/fan").body(Body::empty()),
+        Request::post("/house/4/pause").body(Body::empty()),
+        Request::post("/house/4/tv/resume").body(Body::empty()),
+        Request::put("/house/4/bulb/faults")
+            .header("content-type", "application/json")
+            .body(Body::from(json!({"drop_status": 0.5}).to_string())),
+        Request::delete("/house/4/bulb/faults").body(Body::empty()),
+    ];
+    for request in requests {
+        let response = send(request.unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+    }
+    let mut received = vec![];
+    for _ in 0..6 {
+        let msg = stream.recv().await.unwrap().unwrap();
+        received.push(serde_json::from_slice::<Value>(msg.payload()).unwrap());
+    }
+    assert_eq!(
+        received,
+        [
+            json!({"cmd": "add", "target": "home/4"}),
+            json!({"cmd": "remove", "target": "fan/home/4"}),
+            json!({"cmd": "pause", "target": "home/4"}),
+            json!({"cmd": "resume", "target": "tv/home/4"}),
+            json!({"cmd": "configure", "target": "bulb/home/4", "faults": received[4]["faults"]}),
+            json!({"cmd": "configure", "target": "bulb/home/4", "faults": null}),
+        ]
+    );
+    assert_eq!(received[4]["faults"]["drop_status"], 0.5);
+    let request = Request::post("/house/4/toaster/pause").body(Body::empty());
+    let response = send(request.unwrap()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let devices = json!([{"device": "bulb/home/4", "paused": true}]);
+    let msg = paho_mqtt::Message::new_retained("sim/devices", devices.to_string(), 1);
+    simulator.publish(msg).await.unwrap();
+    let client = connected_client(&broker).await;
+    assert_eq!(get(client, "/devices").await, devices);
+}
+
+#[tokio::test]
+async fn concurrent_requests_get_their_own_messages() {
+    let broker = Broker::start().await.unwrap();
+    let simulator = connected_client(&broker).await;
+    let devices = json!([{"device": "bulb/home/4", "paused": false}]);
+    let next = json!([]);
+    for (topic, payload) in [("sim/devices", &devices), ("sim/schedules/next", &next)] {
+        let msg = paho_mqtt::Message::new_retained(topic, payload.to_string(), 1);
+        simulator.publish(msg).await.unwrap();
+    }
+
+    let app = http_api::router(connected_client(&broker).await, TopicScheme::default());
+    let get = |uri: &str| {
+        let app = app.clone();
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<Value>(&body).unwrap()
+        }
+    };
+    for _ in 0..5 {
+        let (received_devices, received_next) =
+            tokio::join!(get("/devices"), get("/schedules/next"));
+        assert_eq!(received_devices, devices);
+        assert_eq!(received_next, next);
+    }
+}
+
+#[tokio::test]
+async fn many_concurrent_status_requests_are_served() {
+    let broker = Broker::start().await.unwrap();
+    let simulator = connected_client(&broker).await;
+    // more homes than messages the API holds for its requests at once
+    let homes = 600;
+    for home in 0..homes {
+        let status = DeviceStatus::Fan(FanStatus {
+            id: format!("home/{home}"),
+            is_on: false,
+            speed: 0,
+            voltage: 230.0,
+            timestamp: Utc::now(),
+        });
+        let payload = Codec::Json.encode_status(&status).unwrap();
+        let topic = format!("fan/home/{home}/status");
+        let msg = paho_mqtt::Message::new_retained(topic, payload, 1);
+        simulator.publish(msg).await.unwrap();
+    }
+
+    let app = http_api::router(connected_client(&broker).await, TopicScheme::default());
+    let mut requests = JoinSet::new();
+    for home in 0..homes {
+        let request = Request::get(format!("/house/{home}/fan/status"))
+            .body(Body::empty())
+            .unwrap();
+        requests.spawn(app.clone().oneshot(request));
+    }
+    while let Some(response) = requests.join_next().await {
+        assert_eq!(response.unwrap().unwrap().status(), StatusCode::OK);
+    }
+}
+
+/// Serve the scene requests the way the simulator does.
+async fn serve_scenes(broker: &Broker) {
+    let topics = TopicScheme::default();
+    let mut client = connected_client(broker).await;
+    let stream = client.get_stream(64);
+    let mut filters = topics.device_filters();
+    filters.extend(scene_filters(&topics));
+    client.subscribe_many_same_qos(&filters, 1).await.unwrap();
+    let mut manager = SceneManager::new(topics).with_timeout(Duration::from_secs(5));
+    tokio::spawn(async move {
+        while let Ok(Some(msg)) = stream.recv().await {
+            for msg in manager.handle(&msg.into(), Instant::now()) {
+                client.publish(msg.into()).await.unwrap();
+            }
+        }
+    });
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn scenes_are_activated() {
+    let broker = Broker::start().await.unwrap();
+    let clock = Clock::manual(Utc::now());
+    let mut bulb = Bulb::try_new("home/5", broker.url())
+        .unwrap()
+        .with_clock(clock.clone());
+    tokio::spawn(async move { bulb.handle_incoming().await });
+    wait_for_status(&broker, "bulb/home/5/status").await;
+    serve_scenes(&broker).await;
+    // keep the bulb reporting its status
+    tokio::spawn(async move {
+        loop {
+            clock.advance(Duration::from_secs(5));
+            sleep(Duration::from_millis(50)).await;
+        }
+    });
+
+    let app = http_api::router(connected_client(&broker).await, TopicScheme::default());
+    let scene = json!({"bulb": {"is_on": true, "color": [255, 191, 0]}});
+    let request = Request::put("/scenes/movie%20night")
+        .header("content-type", "application/json")
+        .body(Body::from(scene.to_string()))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let definition = broker.retained("sim/scenes/movie night").unwrap();
+    assert_eq!(
+        serde_json::from_slice::<Value>(&definition.payload).unwrap(),
+        json!({"bulb": {"is_on": true, "color": [255, 191, 0]}, "fan": null, "tv": null})
+    );
+
+    let request = Request::post("/house/5/scenes/movie%20night/activate")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let report: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(report["success"], true, "{report}");
+    assert_eq!(
+        report["devices"],
+        json!([{"device": "bulb/home/5", "commands": 2, "outcome": "applied"}])
+    );
+}
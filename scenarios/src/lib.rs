@@ -0,0 +1,463 @@
+//! Scenarios: scripted end-to-end runs of the simulator and the HTTP API, to
+//! check the behaviours of the devices.
+//!
+//! Every scenario runs against a broker, homes and an HTTP API of its own,
+//! inside the process, so that scenarios cannot disturb each other.
+
+use anyhow::{bail, Context};
+use axum::{
+    body::{to_bytes, Body},
+    http::{Method, Request},
+    Router,
+};
+use paho_mqtt::AsyncClient;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use smart_homes::{
+    broker::BrokerConfig,
+    bulb::{Bulb, BulbCommand},
+    clock::{Clock, MAX_TIME_SCALE, MIN_TIME_SCALE},
+    codec::Codec,
+    error::Error,
+    fan::{Fan, FanCommand},
+    home::Home,
+    layout::{TopicScheme, KINDS},
+    schema::Availability,
+    transport::Message,
+    tv::{TVCommand, TV},
+};
+use std::{
+    fmt,
+    path::Path,
+    time::{Duration, Instant},
+};
+use test_broker::Broker;
+use tokio::{task::JoinSet, time::sleep};
+use tower::ServiceExt;
+
+/// How often the retained messages are checked while waiting.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long the devices of the homes started get to become available, in
+/// seconds.
+const STARTUP_TIMEOUT: f64 = 10.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scenario {
+    pub name: String,
+    /// Run the simulated time this many times faster than real time, so
+    /// that the devices report more often than every 5 seconds.
+    #[serde(default = "one")]
+    pub time_scale: f64,
+    /// Seed the random numbers of the devices.
+    pub seed: Option<u64>,
+    pub steps: Vec<Step>,
+}
+
+fn one() -> f64 {
+    1.0
+}
+
+fn ten() -> f64 {
+    10.0
+}
+
+fn get() -> String {
+    "GET".into()
+}
+
+fn ok() -> u16 {
+    200
+}
+
+/// A step of a scenario. The steps that wait fail when their time, in real
+/// seconds, is up.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "do", rename_all = "snake_case")]
+pub enum Step {
+    /// Start `count` more homes, from `home/0` on, every device with a
+    /// connection of its own, and wait until their devices are available.
+    StartHomes { count: u32 },
+    /// Send `command` to `device`, e.g. `bulb/home/0`, like any client.
+    Send { device: String, command: Value },
+    /// Wait until the status of `device` has the fields of `matches`, e.g.
+    /// `{"is_on": true}`.
+    WaitForStatus {
+        device: String,
+        matches: Value,
+        #[serde(default = "ten")]
+        within_secs: f64,
+    },
+    /// Wait until `device` is available, or not.
+    WaitForAvailability {
+        device: String,
+        is_available: bool,
+        #[serde(default = "ten")]
+        within_secs: f64,
+    },
+    /// Drop the connections of the devices of `home/{home}` to the broker,
+    /// as if its network failed.
+    Disconnect { home: u32 },
+    /// Call the HTTP API, and check the status code and the fields of the
+    /// JSON answer.
+    Http {
+        #[serde(default = "get")]
+        method: String,
+        path: String,
+        body: Option<Value>,
+        #[serde(default = "ok")]
+        status: u16,
+        matches: Option<Value>,
+    },
+    /// Do nothing for a while.
+    Sleep { secs: f64 },
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StartHomes { count: 1 } => write!(f, "start 1 home"),
+            Self::StartHomes { count } => write!(f, "start {count} homes"),
+            Self::Send { device, command } => write!(f, "send {command} to {device}"),
+            Self::WaitForStatus {
+                device,
+                matches,
+                within_secs,
+            } => write!(
+                f,
+                "status of {device} matches {matches} within {within_secs}s"
+            ),
+            Self::WaitForAvailability {
+                device,
+                is_available,
+                within_secs,
+            } => {
+                let available = if *is_available {
+                    "available"
+                } else {
+                    "unavailable"
+                };
+                write!(f, "{device} is {available} within {within_secs}s")
+            }
+            Self::Disconnect { home } => write!(f, "disconnect home/{home}"),
+            Self::Http {
+                method,
+                path,
+                status,
+                ..
+            } => write!(f, "{method} {path} answers {status}"),
+            Self::Sleep { secs } => write!(f, "sleep {secs}s"),
+        }
+    }
+}
+
+impl Scenario {
+    /// Read the scenario of a JSON file.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path).with_context(|| format!("cannot read {path:?}"))?;
+        serde_json::from_slice(&data).with_context(|| format!("{path:?} is not a scenario"))
+    }
+
+    /// Run the steps in order, up to the first one that fails.
+    pub async fn run(&self) -> ScenarioReport {
+        let start = Instant::now();
+        let mut steps = vec![];
+        let mut skipped = 0;
+        match Runner::start(self).await {
+            Ok(mut runner) => {
+                for step in &self.steps {
+                    if steps.last().is_some_and(|step: &StepReport| !step.passed) {
+                        skipped += 1;
+                        continue;
+                    }
+                    let step_start = Instant::now();
+                    let res = runner.run(step).await;
+                    steps.push(StepReport {
+                        step: step.to_string(),
+                        passed: res.is_ok(),
+                        error: res.err().map(|err| format!("{err:#}")),
+                        secs: step_start.elapsed().as_secs_f64(),
+                    });
+                }
+            }
+            Err(err) => {
+                skipped = self.steps.len();
+                steps.push(StepReport {
+                    step: "start the broker and the HTTP API".into(),
+                    passed: false,
+                    error: Some(format!("{err:#}")),
+                    secs: start.elapsed().as_secs_f64(),
+                });
+            }
+        }
+        ScenarioReport {
+            name: self.name.clone(),
+            passed: steps.iter().all(|step| step.passed),
+            secs: start.elapsed().as_secs_f64(),
+            steps,
+            skipped,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StepReport {
+    pub step: String,
+    pub passed: bool,
+    pub error: Option<String>,
+    /// How long the step took, in real seconds.
+    pub secs: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScenarioReport {
+    pub name: String,
+    pub passed: bool,
+    pub secs: f64,
+    /// The steps that ran, the failed one last.
+    pub steps: Vec<StepReport>,
+    /// How many steps did not run because one failed.
+    pub skipped: usize,
+}
+
+/// The broker, homes and HTTP API of a scenario, dropped with it.
+struct Runner {
+    broker: Broker,
+    clock: Clock,
+    seed: Option<u64>,
+    topics: TopicScheme,
+    client: AsyncClient,
+    api: Router,
+    homes: JoinSet<Result<(), Error>>,
+    /// How many homes were started.
+    started: u32,
+}
+
+impl Runner {
+    async fn start(scenario: &Scenario) -> anyhow::Result<Self> {
+        if !(MIN_TIME_SCALE..=MAX_TIME_SCALE).contains(&scenario.time_scale) {
+            bail!("time_scale must be between {MIN_TIME_SCALE} and {MAX_TIME_SCALE}");
+        }
+        let broker = Broker::start().await?;
+        let topics = TopicScheme::default();
+        let config = BrokerConfig::new(broker.url());
+        let client = connect(&config, "scenario").await?;
+        let api_client = connect(&config, "scenario/http-api").await?;
+        Ok(Self {
+            broker,
+            clock: Clock::scaled(scenario.time_scale),
+            seed: scenario.seed,
+            api: http_api::router(api_client, topics.clone()),
+            topics,
+            client,
+            homes: JoinSet::new(),
+            started: 0,
+        })
+    }
+
+    async fn run(&mut self, step: &Step) -> anyhow::Result<()> {
+        match step {
+            Step::StartHomes { count } => {
+                let first = self.started;
+                self.started += count;
+                for i in first..self.started {
+                    let id = format!("home/{i}");
+                    let url = self.broker.url();
+                    let mut bulb = Bulb::try_new(&id, &url)?.with_clock(self.clock.clone());
+                    let mut fan = Fan::try_new(&id, &url)?.with_clock(self.clock.clone());
+                    let mut tv = TV::try_new(&id, &url)?.with_clock(self.clock.clone());
+                    if let Some(seed) = self.seed {
+                        bulb = bulb.with_seed(seed);
+                        fan = fan.with_seed(seed);
+                        tv = tv.with_seed(seed);
+                    }
+                    let home = Home::new(format!("home-{i}"), bulb, fan, tv);
+                    self.homes.spawn(home.handle_incoming());
+                }
+                // the commands sent before a device subscribed would be lost
+                for i in first..self.started {
+                    for kind in KINDS {
+                        let topic = self.topics.available_topic(kind, &format!("home/{i}"));
+                        let available = self
+                            .wait_for(&topic, STARTUP_TIMEOUT, availability, |a| a.is_available)
+                            .await;
+                        if available.is_err() {
+                            bail!("{kind}/home/{i} did not become available");
+                        }
+                    }
+                }
+            }
+            Step::Send { device, command } => {
+                let (kind, id) = parse_device(device)?;
+                let checked = match kind {
+                    "bulb" => serde_json::from_value::<BulbCommand>(command.clone()).map(drop),
+                    "fan" => serde_json::from_value::<FanCommand>(command.clone()).map(drop),
+                    _ => serde_json::from_value::<TVCommand>(command.clone()).map(drop),
+                };
+                checked.with_context(|| format!("{command} is not a {kind} command"))?;
+                let codec = self.topics.codec();
+                let msg = Message::new(
+                    self.topics.command_topic(kind, id),
+                    codec.encode_command(command)?,
+                )
+                .with_content_type(codec.content_type());
+                self.client.publish(msg.into()).await?;
+            }
+            Step::WaitForStatus {
+                device,
+                matches,
+                within_secs,
+            } => {
+                let (kind, id) = parse_device(device)?;
+                let topic = self.topics.status_topic(kind, id);
+                let status = self
+                    .wait_for(
+                        &topic,
+                        *within_secs,
+                        |payload| {
+                            let status = Codec::Json.decode_status(payload).ok()?;
+                            let status = serde_json::to_value(status).ok()?;
+                            Some(status["status"].clone())
+                        },
+                        |status| contains(status, matches),
+                    )
+                    .await;
+                if let Err(last) = status {
+                    bail!("the last status was {}", describe(last));
+                }
+            }
+            Step::WaitForAvailability {
+                device,
+                is_available,
+                within_secs,
+            } => {
+                let (kind, id) = parse_device(device)?;
+                let topic = self.topics.available_topic(kind, id);
+                let availability = self
+                    .wait_for(&topic, *within_secs, availability, |availability| {
+                        availability.is_available == *is_available
+                    })
+                    .await;
+                if let Err(last) = availability {
+                    let last = last.map(|a| Value::Bool(a.is_available));
+                    bail!("the last availability was {}", describe(last));
+                }
+            }
+            Step::Disconnect { home } => {
+                let mut disconnected = false;
+                for kind in KINDS {
+                    disconnected |= self.broker.disconnect(&format!("{kind}/home/{home}"));
+                }
+                if !disconnected {
+                    bail!("no device of home/{home} is connected");
+                }
+            }
+            Step::Http {
+                method,
+                path,
+                body,
+                status,
+                matches,
+            } => {
+                let mut request = Request::builder()
+                    .method(Method::from_bytes(method.as_bytes())?)
+                    .uri(path);
+                if body.is_some() {
+                    request = request.header("content-type", "application/json");
+                }
+                let body = match body {
+                    Some(body) => Body::from(body.to_string()),
+                    None => Body::empty(),
+                };
+                let response = self.api.clone().oneshot(request.body(body)?).await?;
+                let code = response.status();
+                let body = to_bytes(response.into_body(), usize::MAX).await?;
+                if code.as_u16() != *status {
+                    bail!("the answer was {code}: {}", String::from_utf8_lossy(&body));
+                }
+                if let Some(matches) = matches {
+                    let answer: Value = serde_json::from_slice(&body).with_context(|| {
+                        format!("{} is not JSON", String::from_utf8_lossy(&body))
+                    })?;
+                    if !contains(&answer, matches) {
+                        bail!("the answer was {answer}");
+                    }
+                }
+            }
+            Step::Sleep { secs } => sleep(Duration::try_from_secs_f64(*secs)?).await,
+        }
+        Ok(())
+    }
+
+    /// Wait for the message retained on `topic`, decoded by `decode`, to
+    /// satisfy `pred`, or get the last one decoded.
+    async fn wait_for<T>(
+        &self,
+        topic: &str,
+        within_secs: f64,
+        decode: impl Fn(&[u8]) -> Option<T>,
+        pred: impl Fn(&T) -> bool,
+    ) -> Result<T, Option<T>> {
+        let deadline = Instant::now() + Duration::from_secs_f64(within_secs.max(0.0));
+        let mut last = None;
+        loop {
+            if let Some(value) = self
+                .broker
+                .retained(topic)
+                .and_then(|msg| decode(&msg.payload))
+            {
+                if pred(&value) {
+                    return Ok(value);
+                }
+                last = Some(value);
+            }
+            if Instant::now() >= deadline {
+                return Err(last);
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Connect a client to the broker of the scenario, with MQTT 5 for the
+/// content types.
+async fn connect(config: &BrokerConfig, client_id: &str) -> anyhow::Result<AsyncClient> {
+    let client = AsyncClient::new(config.create_options(client_id))?;
+    let connect_opts = config.connect_options(client_id)?.finalize();
+    client.connect(connect_opts).await?;
+    Ok(client)
+}
+
+fn availability(payload: &[u8]) -> Option<Availability> {
+    serde_json::from_slice(payload).ok()
+}
+
+/// Split a device name, e.g. `bulb/home/0`, into its kind and id.
+fn parse_device(device: &str) -> anyhow::Result<(&'static str, &str)> {
+    device
+        .split_once('/')
+        .and_then(|(kind, id)| Some((*KINDS.iter().find(|k| **k == kind)?, id)))
+        .with_context(|| format!("{device} is not a device, e.g. bulb/home/0"))
+}
+
+fn describe(value: Option<impl Serialize>) -> String {
+    match value.and_then(|value| serde_json::to_string(&value).ok()) {
+        Some(value) => value,
+        None => "none".into(),
+    }
+}
+
+/// Whether `value` has the fields of `expected`, with the same values.
+/// Numbers are compared by value, so that `220` matches `220.0`.
+pub fn contains(value: &Value, expected: &Value) -> bool {
+    match (value, expected) {
+        (Value::Object(value), Value::Object(expected)) => expected
+            .iter()
+            .all(|(key, expected)| value.get(key).is_some_and(|v| contains(v, expected))),
+        (Value::Array(value), Value::Array(expected)) => {
+            value.len() == expected.len() && value.iter().zip(expected).all(|(v, e)| contains(v, e))
+        }
+        (Value::Number(value), Value::Number(expected)) => value.as_f64() == expected.as_f64(),
+        _ => value == expected,
+    }
+}
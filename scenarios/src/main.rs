@@ -0,0 +1,67 @@
+//! Run scenario files against the simulator and the HTTP API, and report
+//! which steps passed.
+
+use clap::{Parser, ValueEnum};
+use scenarios::{Scenario, ScenarioReport};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[clap(name = "smart-homes-scenarios")]
+struct Cli {
+    /// The scenario files to run, one after the other.
+    #[clap(required = true)]
+    files: Vec<PathBuf>,
+
+    /// How to print the reports.
+    #[clap(short, long, value_enum, default_value_t = Output::Table)]
+    output: Output,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Output {
+    Table,
+    Json,
+}
+
+fn print_report(report: &ScenarioReport) {
+    let result = if report.passed { "PASS" } else { "FAIL" };
+    println!("{result} {} ({:.2}s)", report.name, report.secs);
+    for step in &report.steps {
+        let result = if step.passed { "ok" } else { "FAIL" };
+        println!("  {result:<4} {:>7.2}s  {}", step.secs, step.step);
+        if let Some(error) = &step.error {
+            println!("                  {error}");
+        }
+    }
+    if report.skipped > 0 {
+        println!("  {} steps skipped", report.skipped);
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let scenarios = cli
+        .files
+        .iter()
+        .map(Scenario::load)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut reports = vec![];
+    for scenario in &scenarios {
+        let report = scenario.run().await;
+        if cli.output == Output::Table {
+            print_report(&report);
+        }
+        reports.push(report);
+    }
+    if cli.output == Output::Json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    }
+
+    let failed = reports.iter().filter(|report| !report.passed).count();
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} scenarios failed", reports.len());
+    }
+    Ok(())
+}
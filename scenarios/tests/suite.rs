@@ -0,0 +1,59 @@
+use scenarios::{Scenario, Step};
+use serde_json::json;
+
+/// Run every scenario of the regression suite.
+#[tokio::test(flavor = "multi_thread")]
+async fn suite_passes() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/suite");
+    let mut paths: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+    for path in paths {
+        let report = Scenario::load(&path).unwrap().run().await;
+        assert!(report.passed, "{path:?} failed: {report:#?}");
+        assert_eq!(report.skipped, 0);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failures_are_reported() {
+    let scenario: Scenario = serde_json::from_value(json!({
+        "name": "wrong",
+        "time_scale": 10,
+        "steps": [
+            {"do": "start_homes", "count": 1},
+            {"do": "wait_for_status", "device": "bulb/home/0", "matches": {"is_on": false}},
+            {"do": "wait_for_status", "device": "bulb/home/0", "matches": {"is_on": true}, "within_secs": 1},
+            {"do": "send", "device": "bulb/home/0", "command": {"cmd": "on"}}
+        ]
+    }))
+    .unwrap();
+    assert_eq!(
+        scenario.steps[3],
+        Step::Send {
+            device: "bulb/home/0".into(),
+            command: json!({"cmd": "on"})
+        }
+    );
+    let report = scenario.run().await;
+    assert!(!report.passed);
+    assert_eq!(report.steps.len(), 3);
+    assert_eq!(report.skipped, 1);
+    let failed = &report.steps[2];
+    assert!(failed.secs >= 1.0);
+    let error = failed.error.as_deref().unwrap();
+    assert!(error.contains(r#""is_on":false"#), "{error}");
+
+    // commands are checked before they are sent
+    let scenario: Scenario = serde_json::from_value(json!({
+        "name": "bad command",
+        "steps": [{"do": "send", "device": "fan/home/0", "command": {"cmd": "color"}}]
+    }))
+    .unwrap();
+    let report = scenario.run().await;
+    let error = report.steps[0].error.as_deref().unwrap();
+    assert!(error.contains("is not a fan command"), "{error}");
+}
@@ -0,0 +1,35 @@
+//! Run a few homes in a single process, without any broker, and print what
+//! the devices publish.
+
+use smart_homes::{
+    bulb::Bulb,
+    connection::Connection,
+    fan::Fan,
+    home::Home,
+    transport::{memory::MemoryBroker, Message, Transport},
+    tv::TV,
+};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let broker = MemoryBroker::new();
+    for i in 0..3 {
+        let id = format!("home/{i}");
+        let conn = Connection::new_shared(&id, broker.transport(&id));
+        let home = Home::new(
+            format!("home-{i}"),
+            Bulb::new(&id, conn.clone()),
+            Fan::new(&id, conn.clone()),
+            TV::new(&id, conn),
+        );
+        tokio::spawn(home.handle_incoming());
+    }
+
+    let watcher = broker.transport("watcher");
+    let mut stream = watcher.connect(Message::new("watcher/gone", "")).await?;
+    watcher.subscribe("+/home/+/status").await?;
+    while let Some(msg) = stream.recv().await {
+        println!("{}: {}", msg.topic(), msg.payload_str());
+    }
+    Ok(())
+}
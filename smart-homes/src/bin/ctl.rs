@@ -0,0 +1,299 @@
+//! Look at and control the simulated devices from the command line.
+
+use anyhow::{bail, Context};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use paho_mqtt::{AsyncClient, AsyncReceiver, QOS_1};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+use smart_homes::{
+    bulb::BulbCommand,
+    cli::{validate_topic_prefix, BrokerArgs},
+    codec::Codec,
+    fan::FanCommand,
+    layout::TopicScheme,
+    transport::Message,
+    tv::TVCommand,
+    DeviceStatus,
+};
+use std::{collections::BTreeMap, time::Duration};
+use tokio::time::{timeout, timeout_at, Instant};
+
+#[derive(Debug, Parser)]
+#[clap(name = "smart-homes-ctl")]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+
+    #[clap(flatten)]
+    broker: BrokerArgs,
+
+    /// The topic prefix the simulator was started with.
+    #[clap(long, global = true, value_parser = validate_topic_prefix)]
+    topic_prefix: Option<String>,
+
+    /// The codec the simulator was started with, which the commands are sent
+    /// with.
+    #[clap(long, global = true, value_enum, default_value_t = Codec::Json)]
+    codec: Codec,
+
+    /// How to print the statuses.
+    #[clap(short, long, global = true, value_enum, default_value_t = Output::Table)]
+    output: Output,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List the devices that have published a status.
+    List {
+        /// How long to wait for the statuses, in seconds.
+        #[clap(long, default_value = "1", value_parser = validate_wait)]
+        wait: Duration,
+    },
+    /// Show the status of a device.
+    Status { kind: Kind, id: String },
+    /// Print the statuses as they are published, until interrupted.
+    Watch {
+        /// Only watch the devices of this kind.
+        kind: Option<Kind>,
+        /// Only watch this device.
+        #[clap(requires = "kind")]
+        id: Option<String>,
+    },
+    /// Send a command to a bulb, e.g. `bulb home/3 color 255 0 0`.
+    Bulb(CommandArgs),
+    /// Send a command to a fan, e.g. `fan home/3 speed 2`.
+    Fan(CommandArgs),
+    /// Send a command to a tv, e.g. `tv home/3 channel 5`.
+    Tv(CommandArgs),
+}
+
+#[derive(Debug, Args)]
+struct CommandArgs {
+    /// The id of the device, e.g. `home/3`.
+    id: String,
+    /// The command, e.g. `on`, `off` or `color`.
+    cmd: String,
+    /// The arguments of the command.
+    args: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Kind {
+    Bulb,
+    Fan,
+    Tv,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Bulb => "bulb",
+            Self::Fan => "fan",
+            Self::Tv => "tv",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Output {
+    Table,
+    Json,
+}
+
+/// How long to wait for the status of a device.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest `list` waits for the statuses, which the broker sends right
+/// away anyway.
+const MAX_WAIT_SECS: f64 = 3600.0;
+
+fn validate_wait(v: &str) -> Result<Duration, String> {
+    match v.parse::<f64>() {
+        Ok(secs) if (0.0..=MAX_WAIT_SECS).contains(&secs) => Ok(Duration::from_secs_f64(secs)),
+        Ok(_) => Err(format!("wait must be between 0 and {MAX_WAIT_SECS}")),
+        Err(_) => Err("wait must be a number".to_string()),
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let mut topics = TopicScheme::default().with_codec(cli.codec);
+    if let Some(prefix) = &cli.topic_prefix {
+        topics = topics.with_prefix(prefix);
+    }
+
+    let broker = cli.broker.config();
+    // several of them may run at once
+    let client_id = topics.client_id(&format!("ctl/{}", std::process::id()));
+    let mut client = AsyncClient::new(broker.create_options(&client_id))?;
+    let stream = client.get_stream(64);
+    let connect_opts = broker
+        .connect_options(&client_id)?
+        .connect_timeout(Duration::from_secs(5))
+        .finalize();
+    client.connect(connect_opts).await?;
+
+    match cli.command {
+        Command::List { wait } => {
+            client
+                .subscribe_many_same_qos(&status_filters(&topics, None), QOS_1)
+                .await?;
+            // the statuses are retained, so the broker sends them right away
+            let mut devices = BTreeMap::new();
+            let deadline = Instant::now() + wait;
+            while let Ok(Ok(Some(msg))) = timeout_at(deadline, stream.recv()).await {
+                if let Some(status) = decode_status(&topics, msg) {
+                    devices.insert((status.kind(), status.id().to_string()), status);
+                }
+            }
+            let statuses: Vec<_> = devices.into_values().collect();
+            match cli.output {
+                Output::Table => print_table(&statuses),
+                Output::Json => println!("{}", serde_json::to_string_pretty(&statuses)?),
+            }
+        }
+        Command::Status { kind, id } => {
+            let topic = topics.status_topic(kind.as_str(), &id);
+            client.subscribe(&topic, QOS_1).await?;
+            let Ok(Some(status)) = timeout(STATUS_TIMEOUT, next_status(&topics, &stream)).await
+            else {
+                bail!("{} {id} published no status", kind.as_str());
+            };
+            match cli.output {
+                Output::Table => print_table(&[status]),
+                Output::Json => println!("{}", serde_json::to_string_pretty(&status)?),
+            }
+        }
+        Command::Watch { kind, id } => {
+            let filters = match (kind, &id) {
+                (Some(kind), Some(id)) => vec![topics.status_topic(kind.as_str(), id)],
+                (kind, _) => status_filters(&topics, kind),
+            };
+            client.subscribe_many_same_qos(&filters, QOS_1).await?;
+            if cli.output == Output::Table {
+                print_row(HEADER);
+            }
+            while let Some(status) = next_status(&topics, &stream).await {
+                match cli.output {
+                    Output::Table => print_row(row(&status).each_ref().map(String::as_str)),
+                    Output::Json => println!("{}", serde_json::to_string(&status)?),
+                }
+            }
+        }
+        Command::Bulb(args) => send::<BulbCommand>(&client, &topics, Kind::Bulb, args).await?,
+        Command::Fan(args) => send::<FanCommand>(&client, &topics, Kind::Fan, args).await?,
+        Command::Tv(args) => send::<TVCommand>(&client, &topics, Kind::Tv, args).await?,
+    }
+
+    client.disconnect(None).await?;
+    Ok(())
+}
+
+/// The filters matching the statuses of all the devices of `kind`, or of all
+/// the devices.
+fn status_filters(topics: &TopicScheme, kind: Option<Kind>) -> Vec<String> {
+    let kinds = match kind {
+        Some(kind) => vec![kind],
+        None => vec![Kind::Bulb, Kind::Fan, Kind::Tv],
+    };
+    kinds
+        .into_iter()
+        .map(|kind| topics.topic(&format!("{}/#", kind.as_str())))
+        .collect()
+}
+
+/// The status carried by `msg`, if it carries one.
+fn decode_status(topics: &TopicScheme, msg: paho_mqtt::Message) -> Option<DeviceStatus> {
+    if !msg.topic().ends_with("/status") {
+        return None;
+    }
+    let msg = Message::from(msg);
+    let codec = topics.codec().or_content_type(msg.content_type());
+    match codec.decode_status(msg.payload()) {
+        Ok(status) => Some(status),
+        Err(err) => {
+            eprintln!("Ignoring the status on {}: {err}", msg.topic());
+            None
+        }
+    }
+}
+
+/// Wait for the next status, or `None` if the connection is lost.
+async fn next_status(
+    topics: &TopicScheme,
+    stream: &AsyncReceiver<Option<paho_mqtt::Message>>,
+) -> Option<DeviceStatus> {
+    while let Ok(Some(msg)) = stream.recv().await {
+        if let Some(status) = decode_status(topics, msg) {
+            return Some(status);
+        }
+    }
+    None
+}
+
+/// Build a command of the device from its name and arguments, the way it
+/// would be written in JSON, and send it.
+async fn send<C: Serialize + DeserializeOwned>(
+    client: &AsyncClient,
+    topics: &TopicScheme,
+    kind: Kind,
+    args: CommandArgs,
+) -> anyhow::Result<()> {
+    let mut command = json!({ "cmd": args.cmd });
+    match args.args.as_slice() {
+        [] => {}
+        [arg] => command["args"] = json!(arg),
+        values => command["args"] = json!(values),
+    }
+    let command: C = serde_json::from_value(command).with_context(|| {
+        format!(
+            "{} {:?} is not a {} command",
+            args.cmd,
+            args.args,
+            kind.as_str()
+        )
+    })?;
+
+    let codec = topics.codec();
+    let topic = topics.command_topic(kind.as_str(), &args.id);
+    let msg = Message::new(topic, codec.encode_command(&command)?)
+        .with_content_type(codec.content_type());
+    client.publish(msg.into()).await?;
+    Ok(())
+}
+
+const HEADER: [&str; 5] = ["KIND", "ID", "POWER", "STATE", "UPDATED"];
+
+fn print_row(cols: [&str; 5]) {
+    let [kind, id, power, state, updated] = cols;
+    println!("{kind:<5} {id:<12} {power:<5} {state:<36} {updated}");
+}
+
+fn print_table(statuses: &[DeviceStatus]) {
+    print_row(HEADER);
+    for status in statuses {
+        print_row(row(status).each_ref().map(String::as_str));
+    }
+}
+
+fn row(status: &DeviceStatus) -> [String; 5] {
+    let state = match status {
+        DeviceStatus::Bulb(s) => {
+            let (r, g, b) = s.color;
+            format!("color={r},{g},{b} voltage={:.1}", s.voltage)
+        }
+        DeviceStatus::Fan(s) => format!("speed={} voltage={:.1}", s.speed, s.voltage),
+        DeviceStatus::TV(s) => {
+            let muted = if s.is_muted { " muted" } else { "" };
+            format!("channel={} volume={}{muted}", s.channel, s.volume)
+        }
+    };
+    [
+        status.kind().into(),
+        status.id().into(),
+        if status.is_on() { "on" } else { "off" }.into(),
+        state,
+        status.timestamp().format("%Y-%m-%d %H:%M:%S").to_string(),
+    ]
+}
@@ -0,0 +1,130 @@
+//! Where the broker is and how to log into it.
+
+use educe::Educe;
+use std::path::PathBuf;
+
+/// TLS settings of the connections to the broker.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// PEM bundle of the certificate authorities to trust, instead of the
+    /// default ones.
+    pub ca_file: Option<PathBuf>,
+    /// PEM certificate of the client, for brokers that want one.
+    pub cert_file: Option<PathBuf>,
+    /// PEM private key of the client certificate, if not in `cert_file`.
+    pub key_file: Option<PathBuf>,
+    /// Protocols to negotiate with ALPN, e.g. `mqtt`.
+    pub alpn: Vec<String>,
+}
+
+/// The broker to connect to, and the credentials to connect with.
+///
+/// `{client_id}` in the username or password is replaced by the client id
+/// of each connection, so that every device can have credentials of its own.
+#[derive(Educe, Clone, Default, PartialEq, Eq)]
+#[educe(Debug)]
+pub struct BrokerConfig {
+    pub url: String,
+    pub tls: Option<TlsConfig>,
+    pub username: Option<String>,
+    #[educe(Debug(ignore))]
+    pub password: Option<String>,
+}
+
+impl BrokerConfig {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            ..Default::default()
+        }
+    }
+
+    /// The username of the connection of `client_id`, if any.
+    pub fn username_for(&self, client_id: &str) -> Option<String> {
+        self.username
+            .as_ref()
+            .map(|v| v.replace("{client_id}", client_id))
+    }
+
+    /// The password of the connection of `client_id`, if any.
+    pub fn password_for(&self, client_id: &str) -> Option<String> {
+        self.password
+            .as_ref()
+            .map(|v| v.replace("{client_id}", client_id))
+    }
+}
+
+impl From<String> for BrokerConfig {
+    fn from(url: String) -> Self {
+        Self::new(url)
+    }
+}
+
+impl From<&str> for BrokerConfig {
+    fn from(url: &str) -> Self {
+        Self::new(url)
+    }
+}
+
+impl From<&String> for BrokerConfig {
+    fn from(url: &String) -> Self {
+        Self::new(url)
+    }
+}
+
+impl From<&BrokerConfig> for BrokerConfig {
+    fn from(config: &BrokerConfig) -> Self {
+        config.clone()
+    }
+}
+
+#[cfg(feature = "paho")]
+mod paho {
+    use super::BrokerConfig;
+    use paho_mqtt::{
+        ConnectOptionsBuilder, CreateOptions, CreateOptionsBuilder, SslOptionsBuilder,
+    };
+
+    impl BrokerConfig {
+        /// The options to create a paho client as `client_id`.
+        pub fn create_options(&self, client_id: &str) -> CreateOptions {
+            CreateOptionsBuilder::new()
+                .client_id(client_id)
+                .server_uri(&self.url)
+                .finalize()
+        }
+
+        /// The options to connect a paho client as `client_id`, with TLS
+        /// and credentials set.
+        pub fn connect_options(
+            &self,
+            client_id: &str,
+        ) -> Result<ConnectOptionsBuilder, paho_mqtt::Error> {
+            let mut builder = ConnectOptionsBuilder::new_v5();
+            if let Some(tls) = &self.tls {
+                let mut ssl = SslOptionsBuilder::new();
+                if let Some(ca_file) = &tls.ca_file {
+                    ssl.trust_store(ca_file)?;
+                }
+                if let Some(cert_file) = &tls.cert_file {
+                    ssl.key_store(cert_file)?;
+                }
+                if let Some(key_file) = &tls.key_file {
+                    ssl.private_key(key_file)?;
+                }
+                if !tls.alpn.is_empty() {
+                    let protos: Vec<_> = tls.alpn.iter().map(String::as_str).collect();
+                    ssl.alpn_protos(&protos);
+                }
+                builder.ssl_options(ssl.finalize());
+            }
+            if let Some(username) = self.username_for(client_id) {
+                builder.user_name(username);
+            }
+            if let Some(password) = self.password_for(client_id) {
+                builder.password(password);
+            }
+            Ok(builder)
+        }
+    }
+}
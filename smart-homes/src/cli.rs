@@ -1,19 +1,303 @@
-use clap::Parser;
+use crate::{
+    broker::{BrokerConfig, TlsConfig},
+    clock::{MAX_TIME_SCALE, MIN_TIME_SCALE},
+    codec::Codec,
+    discovery::DEFAULT_PREFIX,
+    error::Error,
+    history::{Downsampling, HistoryPolicy, HistoryStore},
+    layout::TopicLayout,
+};
+use chrono::TimeDelta;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use clap_verbosity_flag::{InfoLevel, Verbosity};
+use std::path::PathBuf;
 
 #[derive(Debug, Parser)]
 pub struct Cli {
-    #[clap(short, long, default_value = "tcp://localhost:1883")]
-    pub broker_url: String,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    #[clap(flatten)]
+    pub broker: BrokerArgs,
+
+    #[clap(flatten)]
+    pub history: HistoryArgs,
 
     /// Number of houses to simulate.
     #[clap(short, long, default_value_t = 10, value_parser = validate_num_houses)]
     pub num_houses: u32,
 
+    /// Save device states to this file and restore them on startup.
+    #[clap(long)]
+    pub state_file: Option<PathBuf>,
+
+    /// How many devices share one connection to the broker.
+    #[clap(long, value_enum, default_value_t = ConnectionSharing::Device)]
+    pub share_connection: ConnectionSharing,
+
+    /// Seed the random numbers of the devices and start the homes one after
+    /// the other, so that a run can be reproduced.
+    #[clap(long)]
+    pub seed: Option<u64>,
+
+    /// Run the simulated time this many times faster than real time.
+    #[clap(long, default_value_t = 1.0, value_parser = validate_time_scale)]
+    pub time_scale: f64,
+
+    /// Make devices misbehave, e.g. `bulb:drop_status=0.1`,
+    /// `fan/home/1:stuck=1` or `all:delay=0.5`. Can be repeated.
+    #[clap(long = "fault", value_name = "TARGET:NAME=VALUE")]
+    pub faults: Vec<String>,
+
+    /// Read the faults to simulate from this JSON file. Faults given with
+    /// `--fault` are applied on top.
+    #[clap(long)]
+    pub fault_file: Option<PathBuf>,
+
+    /// Run the automation rules of this JSON file, reloaded whenever it
+    /// changes. Needs the default topic layout.
+    #[clap(long)]
+    pub rules_file: Option<PathBuf>,
+
+    /// Keep the schedules in this JSON file, where the schedules received on
+    /// the admin topic are saved too.
+    #[clap(long)]
+    pub schedule_file: Option<PathBuf>,
+
+    /// Simulate the people living in the homes, who use the devices through
+    /// the day. Their days are drawn from `--seed`, and their time zones and
+    /// daylight are those of the schedule file. Needs the default topic
+    /// layout.
+    #[clap(long)]
+    pub residents: bool,
+
+    /// The topics and payloads the devices use.
+    #[clap(long, value_enum, default_value_t = TopicLayout::Default)]
+    pub topic_layout: TopicLayout,
+
+    /// How the statuses and commands are encoded. Homie payloads are always
+    /// plain strings.
+    #[clap(long, value_enum, default_value_t = Codec::Json)]
+    pub codec: Codec,
+
+    /// Put every topic and client id under this prefix, e.g.
+    /// `tenant/acme/site/paris`, so that several simulators can share one
+    /// broker.
+    #[clap(long, value_parser = validate_topic_prefix)]
+    pub topic_prefix: Option<String>,
+
+    /// Show a dashboard of the homes in the terminal instead of logging the
+    /// statuses. Needs the default topic layout.
+    #[clap(long)]
+    pub dashboard: bool,
+
+    /// Record every message seen on the broker to this file, to replay it
+    /// later with `replay`. Of the MQTT 5 properties of the messages, only
+    /// the content type is recorded.
+    #[clap(long, value_name = "FILE", conflicts_with = "dashboard")]
+    pub record: Option<PathBuf>,
+
+    /// Announce the devices to Home Assistant through MQTT discovery, under
+    /// this discovery prefix. Needs the default topic layout.
+    #[clap(
+        long,
+        value_name = "PREFIX",
+        num_args = 0..=1,
+        default_missing_value = DEFAULT_PREFIX
+    )]
+    pub ha_discovery: Option<String>,
+
     #[clap(flatten)]
     pub verbosity: Verbosity<InfoLevel>,
 }
 
+/// What to do instead of simulating the homes.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Print the JSON Schemas of the status, command and availability
+    /// payloads.
+    Schema {
+        /// Write every schema to `{name}.schema.json` in this directory
+        /// instead.
+        #[clap(long)]
+        out_dir: Option<PathBuf>,
+    },
+    /// Publish the messages of a recording again, with their original
+    /// timing, instead of simulating the homes.
+    Replay {
+        /// The recording made with `--record`.
+        file: PathBuf,
+
+        /// Replay this many times faster than the messages were recorded, at
+        /// least 0.001.
+        #[clap(long, default_value_t = 1.0, value_parser = validate_speed)]
+        speed: f64,
+    },
+    /// Start the homes at a given rate and send commands to their bulbs,
+    /// then report the throughput and the round trip of the commands.
+    /// Needs the default topic layout.
+    Load(LoadArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct LoadArgs {
+    /// How many homes to start per second, up to 1000.
+    #[clap(long, default_value_t = 100.0, value_parser = validate_rate)]
+    pub ramp_rate: f64,
+
+    /// How many commands to send per second, up to 1000.
+    #[clap(long, default_value_t = 10.0, value_parser = validate_rate)]
+    pub command_rate: f64,
+
+    /// How long to start homes and send commands for, e.g. `5m`.
+    #[clap(long, default_value = "1m", value_parser = parse_duration)]
+    pub duration: TimeDelta,
+
+    /// How long a command may wait for its status before it counts as
+    /// timed out.
+    #[clap(long, default_value = "30s", value_parser = parse_duration)]
+    pub timeout: TimeDelta,
+
+    /// How to print the report.
+    #[clap(short, long, value_enum, default_value_t = ReportFormat::Text)]
+    pub output: ReportFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    Text,
+    Json,
+}
+
+/// How to reach the broker, shared by all the binaries.
+#[derive(Debug, Clone, Args)]
+pub struct BrokerArgs {
+    /// URL of the broker, `ssl://` to connect with TLS.
+    #[clap(short, long, default_value = "tcp://localhost:1883")]
+    pub broker_url: String,
+
+    /// PEM bundle of the certificate authorities to trust.
+    #[clap(long)]
+    pub ca_file: Option<PathBuf>,
+
+    /// PEM client certificate, for brokers that authenticate clients with
+    /// certificates.
+    #[clap(long, requires = "key_file")]
+    pub cert_file: Option<PathBuf>,
+
+    /// PEM private key of the client certificate.
+    #[clap(long, requires = "cert_file")]
+    pub key_file: Option<PathBuf>,
+
+    /// Protocol to negotiate with ALPN. Can be repeated.
+    #[clap(long)]
+    pub alpn: Vec<String>,
+
+    /// Username to log in with. `{client_id}` is replaced by the client id of
+    /// each connection, e.g. `device-{client_id}`.
+    #[clap(long, env = "MQTT_USERNAME")]
+    pub username: Option<String>,
+
+    /// Password to log in with, which may contain `{client_id}` as well.
+    #[clap(long, env = "MQTT_PASSWORD", hide_env_values = true)]
+    pub password: Option<String>,
+}
+
+impl BrokerArgs {
+    pub fn config(&self) -> BrokerConfig {
+        let tls = self.broker_url.starts_with("ssl://")
+            || self.ca_file.is_some()
+            || self.cert_file.is_some()
+            || !self.alpn.is_empty();
+        BrokerConfig {
+            url: self.broker_url.clone(),
+            tls: tls.then(|| TlsConfig {
+                ca_file: self.ca_file.clone(),
+                cert_file: self.cert_file.clone(),
+                key_file: self.key_file.clone(),
+                alpn: self.alpn.clone(),
+            }),
+            username: self.username.clone(),
+            password: self.password.clone(),
+        }
+    }
+}
+
+/// Where to keep the history of the devices, shared by all the binaries.
+#[derive(Debug, Clone, Args)]
+pub struct HistoryArgs {
+    /// Record the statuses and availability of the devices in this
+    /// directory.
+    #[clap(long)]
+    pub history_dir: Option<PathBuf>,
+
+    /// Drop the history older than this, e.g. `30d`.
+    #[clap(long, requires = "history_dir", value_parser = parse_duration)]
+    pub history_retention: Option<TimeDelta>,
+
+    /// Keep a single status per device and `--history-interval` in the
+    /// history older than this, e.g. `1d`.
+    #[clap(
+        long,
+        requires_all = ["history_dir", "history_interval"],
+        value_parser = parse_duration,
+    )]
+    pub history_downsample_after: Option<TimeDelta>,
+
+    /// The interval of the downsampled history, e.g. `15m`.
+    #[clap(long, requires = "history_downsample_after", value_parser = parse_duration)]
+    pub history_interval: Option<TimeDelta>,
+}
+
+impl HistoryArgs {
+    /// Open the history store, if one is wanted.
+    pub fn open(&self) -> Result<Option<HistoryStore>, Error> {
+        let Some(dir) = &self.history_dir else {
+            return Ok(None);
+        };
+        let downsampling = self
+            .history_downsample_after
+            .zip(self.history_interval)
+            .map(|(after, interval)| Downsampling { after, interval });
+        let policy = HistoryPolicy {
+            retention: self.history_retention,
+            downsampling,
+        };
+        HistoryStore::open(dir, policy).map(Some)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConnectionSharing {
+    /// Every device has its own connection.
+    Device,
+    /// The devices of a home share one connection.
+    Home,
+    /// All the devices of the simulator share one connection.
+    Process,
+}
+
+/// Parse a duration like `90s`, `15m`, `12h` or `30d`.
+pub fn parse_duration(v: &str) -> Result<TimeDelta, String> {
+    // the unit may be any character, which may take several bytes
+    let split = v.char_indices().next_back().map_or(0, |(i, _)| i);
+    let (n, unit) = v.split_at(split);
+    let n = n
+        .parse::<i64>()
+        .map_err(|_| format!("{v} must look like 90s, 15m, 12h or 30d"))?;
+    let duration = match unit {
+        "s" => TimeDelta::try_seconds(n),
+        "m" => TimeDelta::try_minutes(n),
+        "h" => TimeDelta::try_hours(n),
+        "d" => TimeDelta::try_days(n),
+        _ => None,
+    };
+    match duration {
+        Some(duration) if duration > TimeDelta::zero() => Ok(duration),
+        _ => Err(format!("{v} must look like 90s, 15m, 12h or 30d")),
+    }
+}
+
 // not using value_parser!(u32).range(1..) because the error message is weird.
 fn validate_num_houses(v: &str) -> Result<u32, String> {
     match v.parse::<u32>() {
@@ -27,3 +311,58 @@ fn validate_num_houses(v: &str) -> Result<u32, String> {
         Err(_) => Err("num_houses must be a number".to_string()),
     }
 }
+
+fn validate_positive(v: &str, name: &str) -> Result<f64, String> {
+    match v.parse::<f64>() {
+        Ok(v) if v.is_finite() && v > 0.0 => Ok(v),
+        Ok(_) => Err(format!("{name} must be greater than 0")),
+        Err(_) => Err(format!("{name} must be a number")),
+    }
+}
+
+fn validate_time_scale(v: &str) -> Result<f64, String> {
+    match validate_positive(v, "time_scale")? {
+        scale if scale < MIN_TIME_SCALE => {
+            Err(format!("time_scale must be at least {MIN_TIME_SCALE}"))
+        }
+        scale if scale > MAX_TIME_SCALE => {
+            Err(format!("time_scale must be at most {MAX_TIME_SCALE}"))
+        }
+        scale => Ok(scale),
+    }
+}
+
+/// The slowest a recording can be replayed, which keeps the delays of its
+/// messages, at most a year recorded, within the range of a replay.
+const MIN_SPEED: f64 = 0.001;
+
+fn validate_speed(v: &str) -> Result<f64, String> {
+    match validate_positive(v, "speed")? {
+        speed if speed < MIN_SPEED => Err(format!("speed must be at least {MIN_SPEED}")),
+        speed => Ok(speed),
+    }
+}
+
+/// The most homes or commands per second of a load test, since their
+/// timers tick once a millisecond at most.
+const MAX_RATE: f64 = 1000.0;
+
+fn validate_rate(v: &str) -> Result<f64, String> {
+    match validate_positive(v, "rate")? {
+        rate if rate > MAX_RATE => Err(format!("rate must be at most {MAX_RATE}")),
+        rate => Ok(rate),
+    }
+}
+
+/// Check that a topic prefix can be put in front of topics, and in front of
+/// the topic filters of the subscribers.
+pub fn validate_topic_prefix(v: &str) -> Result<String, String> {
+    let v = v.trim_matches('/');
+    if v.contains(['+', '#']) {
+        Err("topic_prefix must not contain wildcards".to_string())
+    } else if v.split('/').any(str::is_empty) {
+        Err("topic_prefix must not contain empty levels".to_string())
+    } else {
+        Ok(v.to_string())
+    }
+}
@@ -0,0 +1,128 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::watch, time::Instant};
+
+/// The slowest a scaled clock can run. Slower clocks stretch the sleeps of
+/// the devices past any practical run.
+pub const MIN_TIME_SCALE: f64 = 0.001;
+
+/// The fastest a scaled clock can run, a year of simulated time in about
+/// five minutes. Faster clocks leave the range of the timestamps within
+/// hours.
+pub const MAX_TIME_SCALE: f64 = 100_000.0;
+
+/// The clock the devices use to timestamp their statuses and to pace
+/// themselves.
+///
+/// Besides following the real time, the clock can run faster than real time
+/// to simulate long periods quickly, or be advanced by hand in tests.
+#[derive(Debug, Clone, Default)]
+pub struct Clock {
+    kind: ClockKind,
+}
+
+#[derive(Debug, Clone, Default)]
+enum ClockKind {
+    #[default]
+    System,
+    Scaled {
+        real_start: Instant,
+        virtual_start: DateTime<Utc>,
+        scale: f64,
+    },
+    Manual(Arc<watch::Sender<DateTime<Utc>>>),
+}
+
+impl Clock {
+    /// A clock that follows the real time.
+    pub fn system() -> Self {
+        Self::default()
+    }
+
+    /// A clock that starts at the current time and runs `scale` times faster
+    /// than real time.
+    pub fn scaled(scale: f64) -> Self {
+        assert!(scale > 0.0, "time scale must be positive");
+        if scale == 1.0 {
+            return Self::system();
+        }
+        Self {
+            kind: ClockKind::Scaled {
+                real_start: Instant::now(),
+                virtual_start: Utc::now(),
+                scale,
+            },
+        }
+    }
+
+    /// A clock that stands still at `start` until it is moved with
+    /// [`Clock::advance`].
+    pub fn manual(start: DateTime<Utc>) -> Self {
+        Self {
+            kind: ClockKind::Manual(Arc::new(watch::Sender::new(start))),
+        }
+    }
+
+    /// The current time according to the clock.
+    pub fn now(&self) -> DateTime<Utc> {
+        match &self.kind {
+            ClockKind::System => Utc::now(),
+            ClockKind::Scaled {
+                real_start,
+                virtual_start,
+                scale,
+            } => {
+                let elapsed = real_start.elapsed().as_secs_f64() * scale;
+                Duration::try_from_secs_f64(elapsed)
+                    .ok()
+                    .and_then(|elapsed| TimeDelta::from_std(elapsed).ok())
+                    .and_then(|elapsed| virtual_start.checked_add_signed(elapsed))
+                    .unwrap_or(DateTime::<Utc>::MAX_UTC)
+            }
+            ClockKind::Manual(now) => *now.borrow(),
+        }
+    }
+
+    /// How many times faster than real time the clock runs. A manual clock
+    /// does not run at all, hence 0.
+    pub fn scale(&self) -> f64 {
+        match &self.kind {
+            ClockKind::System => 1.0,
+            ClockKind::Scaled { scale, .. } => *scale,
+            ClockKind::Manual(_) => 0.0,
+        }
+    }
+
+    /// Wait until `duration` has passed according to the clock.
+    pub async fn sleep(&self, duration: Duration) {
+        match &self.kind {
+            ClockKind::System => tokio::time::sleep(duration).await,
+            ClockKind::Scaled { scale, .. } => {
+                let real = Duration::try_from_secs_f64(duration.as_secs_f64() / scale);
+                tokio::time::sleep(real.unwrap_or(Duration::MAX)).await;
+            }
+            ClockKind::Manual(now) => {
+                let deadline = later(*now.borrow(), duration);
+                let mut rx = now.subscribe();
+                // the sender lives as long as this clock, so this cannot fail.
+                let _ = rx.wait_for(|now| *now >= deadline).await;
+            }
+        }
+    }
+
+    /// Move a manual clock forward, waking up everyone whose sleep is over.
+    /// Clocks that follow the real time are not affected.
+    pub fn advance(&self, duration: Duration) {
+        if let ClockKind::Manual(now) = &self.kind {
+            now.send_modify(|now| *now = later(*now, duration));
+        }
+    }
+}
+
+/// `duration` after `time`, or the end of time if that is out of range.
+fn later(time: DateTime<Utc>, duration: Duration) -> DateTime<Utc> {
+    TimeDelta::from_std(duration)
+        .ok()
+        .and_then(|duration| time.checked_add_signed(duration))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
@@ -0,0 +1,352 @@
+//! How the statuses and commands are encoded on the wire.
+//!
+//! The encoding of a payload is advertised with the MQTT 5 content type
+//! property, so that consumers can decode a fleet that mixes encodings. When
+//! a payload has no content type, the codec of the deployment is assumed.
+//!
+//! Whatever the codec, payloads carry their [schema version].
+//!
+//! [schema version]: crate::schema
+
+use crate::{
+    error::Error,
+    schema::{check_version, Versioned},
+    DeviceStatus,
+};
+use clap::ValueEnum;
+use serde::{de::DeserializeOwned, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Codec {
+    #[default]
+    Json,
+    Cbor,
+    #[value(name = "msgpack")]
+    MessagePack,
+    Protobuf,
+}
+
+impl Codec {
+    /// The MQTT content type of the payloads encoded with the codec.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Cbor => "application/cbor",
+            Self::MessagePack => "application/msgpack",
+            Self::Protobuf => "application/x-protobuf",
+        }
+    }
+
+    /// The codec of a payload with the given content type, if it is known.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        Self::value_variants()
+            .iter()
+            .find(|codec| codec.content_type() == content_type)
+            .copied()
+    }
+
+    /// The codec of a payload, from its content type, or `self` if it has
+    /// none.
+    pub fn or_content_type(self, content_type: Option<&str>) -> Self {
+        content_type
+            .and_then(Self::from_content_type)
+            .unwrap_or(self)
+    }
+
+    pub fn encode_status(&self, status: &DeviceStatus) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::Protobuf => Ok(prost::Message::encode_to_vec(&proto::Status::from(status))),
+            _ => self.encode(&Versioned::new(status)),
+        }
+    }
+
+    pub fn decode_status(&self, payload: &[u8]) -> Result<DeviceStatus, Error> {
+        match self {
+            Self::Protobuf => {
+                let status = <proto::Status as prost::Message>::decode(payload)
+                    .map_err(|err| Error::CodecError(err.to_string()))?;
+                check_version(status.schema_version)?;
+                status.try_into()
+            }
+            _ => self.decode::<Versioned<_>>(payload)?.into_payload(),
+        }
+    }
+
+    /// Encode a command, given as one of the command enums of the devices.
+    pub fn encode_command(&self, command: &impl Serialize) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::Protobuf => {
+                let command = proto::Command::try_from(serde_json::to_value(command)?)?;
+                Ok(prost::Message::encode_to_vec(&command))
+            }
+            _ => self.encode(&Versioned::new(command)),
+        }
+    }
+
+    /// Decode a command into one of the command enums of the devices.
+    pub fn decode_command<C: DeserializeOwned>(&self, payload: &[u8]) -> Result<C, Error> {
+        match self {
+            Self::Protobuf => {
+                let command = <proto::Command as prost::Message>::decode(payload)
+                    .map_err(|err| Error::CodecError(err.to_string()))?;
+                check_version(command.schema_version)?;
+                Ok(serde_json::from_value(command.into())?)
+            }
+            _ => self.decode::<Versioned<_>>(payload)?.into_payload(),
+        }
+    }
+
+    fn encode(&self, value: &impl Serialize) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::Json => Ok(serde_json::to_vec(value)?),
+            Self::Cbor => {
+                let mut buf = vec![];
+                ciborium::into_writer(value, &mut buf)
+                    .map_err(|err| Error::CodecError(err.to_string()))?;
+                Ok(buf)
+            }
+            // the field names must be kept, or the tagged enums can't be
+            // decoded.
+            Self::MessagePack => {
+                rmp_serde::to_vec_named(value).map_err(|err| Error::CodecError(err.to_string()))
+            }
+            Self::Protobuf => unreachable!("protobuf messages are encoded by type"),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T, Error> {
+        match self {
+            Self::Json => Ok(serde_json::from_slice(payload)?),
+            Self::Cbor => {
+                ciborium::from_reader(payload).map_err(|err| Error::CodecError(err.to_string()))
+            }
+            Self::MessagePack => {
+                rmp_serde::from_slice(payload).map_err(|err| Error::CodecError(err.to_string()))
+            }
+            Self::Protobuf => unreachable!("protobuf messages are decoded by type"),
+        }
+    }
+}
+
+/// The protobuf messages, written by hand since there are so few of them.
+///
+/// ```proto
+/// message Status {
+///   oneof status {
+///     BulbStatus bulb = 1;
+///     FanStatus fan = 2;
+///     TvStatus tv = 3;
+///   }
+///   uint32 schema_version = 4;
+/// }
+/// message BulbStatus {
+///   string id = 1; bool is_on = 2; uint32 speed = 3; float voltage = 4;
+///   uint32 color = 5; /* 0xRRGGBB */ int64 timestamp = 6;
+/// }
+/// message FanStatus {
+///   string id = 1; bool is_on = 2; uint32 speed = 3; float voltage = 4;
+///   int64 timestamp = 5;
+/// }
+/// message TvStatus {
+///   string id = 1; bool is_on = 2; uint32 channel = 3; uint32 volume = 4;
+///   bool is_muted = 5; int64 timestamp = 6;
+/// }
+/// message Command {
+///   string cmd = 1; repeated uint32 args = 2; uint32 schema_version = 3;
+/// }
+/// ```
+mod proto {
+    use crate::{
+        bulb::BulbStatus, error::Error, fan::FanStatus, schema::SCHEMA_VERSION, tv::TVStatus,
+        DeviceStatus,
+    };
+    use chrono::DateTime;
+    use serde_json::{json, Value};
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Status {
+        #[prost(oneof = "Kind", tags = "1, 2, 3")]
+        pub kind: Option<Kind>,
+        #[prost(uint32, tag = "4")]
+        pub schema_version: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Kind {
+        #[prost(message, tag = "1")]
+        Bulb(Bulb),
+        #[prost(message, tag = "2")]
+        Fan(Fan),
+        #[prost(message, tag = "3")]
+        Tv(Tv),
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Bulb {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(bool, tag = "2")]
+        pub is_on: bool,
+        #[prost(uint32, tag = "3")]
+        pub speed: u32,
+        #[prost(float, tag = "4")]
+        pub voltage: f32,
+        #[prost(uint32, tag = "5")]
+        pub color: u32,
+        #[prost(int64, tag = "6")]
+        pub timestamp: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Fan {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(bool, tag = "2")]
+        pub is_on: bool,
+        #[prost(uint32, tag = "3")]
+        pub speed: u32,
+        #[prost(float, tag = "4")]
+        pub voltage: f32,
+        #[prost(int64, tag = "5")]
+        pub timestamp: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Tv {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(bool, tag = "2")]
+        pub is_on: bool,
+        #[prost(uint32, tag = "3")]
+        pub channel: u32,
+        #[prost(uint32, tag = "4")]
+        pub volume: u32,
+        #[prost(bool, tag = "5")]
+        pub is_muted: bool,
+        #[prost(int64, tag = "6")]
+        pub timestamp: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Command {
+        #[prost(string, tag = "1")]
+        pub cmd: String,
+        #[prost(uint32, repeated, tag = "2")]
+        pub args: Vec<u32>,
+        #[prost(uint32, tag = "3")]
+        pub schema_version: u32,
+    }
+
+    impl From<&DeviceStatus> for Status {
+        fn from(status: &DeviceStatus) -> Self {
+            let kind = match status {
+                DeviceStatus::Bulb(s) => {
+                    let (r, g, b) = s.color;
+                    Kind::Bulb(Bulb {
+                        id: s.id.clone(),
+                        is_on: s.is_on,
+                        speed: s.speed.into(),
+                        voltage: s.voltage,
+                        color: u32::from_be_bytes([0, r, g, b]),
+                        timestamp: s.timestamp.timestamp(),
+                    })
+                }
+                DeviceStatus::Fan(s) => Kind::Fan(Fan {
+                    id: s.id.clone(),
+                    is_on: s.is_on,
+                    speed: s.speed.into(),
+                    voltage: s.voltage,
+                    timestamp: s.timestamp.timestamp(),
+                }),
+                DeviceStatus::TV(s) => Kind::Tv(Tv {
+                    id: s.id.clone(),
+                    is_on: s.is_on,
+                    channel: s.channel.into(),
+                    volume: s.volume.into(),
+                    is_muted: s.is_muted,
+                    timestamp: s.timestamp.timestamp(),
+                }),
+            };
+            Self {
+                kind: Some(kind),
+                schema_version: SCHEMA_VERSION,
+            }
+        }
+    }
+
+    fn out_of_range(field: &str) -> Error {
+        Error::CodecError(format!("{field} is out of range"))
+    }
+
+    impl TryFrom<Status> for DeviceStatus {
+        type Error = Error;
+
+        fn try_from(status: Status) -> Result<Self, Error> {
+            let timestamp =
+                |secs| DateTime::from_timestamp(secs, 0).ok_or_else(|| out_of_range("timestamp"));
+            Ok(match status.kind.ok_or_else(|| out_of_range("status"))? {
+                Kind::Bulb(s) => {
+                    let [_, r, g, b] = s.color.to_be_bytes();
+                    DeviceStatus::Bulb(BulbStatus {
+                        id: s.id,
+                        is_on: s.is_on,
+                        speed: s.speed.try_into().map_err(|_| out_of_range("speed"))?,
+                        voltage: s.voltage,
+                        color: (r, g, b),
+                        timestamp: timestamp(s.timestamp)?,
+                    })
+                }
+                Kind::Fan(s) => DeviceStatus::Fan(FanStatus {
+                    id: s.id,
+                    is_on: s.is_on,
+                    speed: s.speed.try_into().map_err(|_| out_of_range("speed"))?,
+                    voltage: s.voltage,
+                    timestamp: timestamp(s.timestamp)?,
+                }),
+                Kind::Tv(s) => DeviceStatus::TV(TVStatus {
+                    id: s.id,
+                    is_on: s.is_on,
+                    channel: s.channel.try_into().map_err(|_| out_of_range("channel"))?,
+                    volume: s.volume.try_into().map_err(|_| out_of_range("volume"))?,
+                    is_muted: s.is_muted,
+                    timestamp: timestamp(s.timestamp)?,
+                }),
+            })
+        }
+    }
+
+    /// Commands are `{"cmd": ..., "args": ...}` in JSON, where the arguments
+    /// are a number, a list of numbers or missing.
+    impl TryFrom<Value> for Command {
+        type Error = Error;
+
+        fn try_from(value: Value) -> Result<Self, Error> {
+            let number = |v: &Value| {
+                v.as_u64()
+                    .and_then(|v| v.try_into().ok())
+                    .ok_or_else(|| out_of_range("args"))
+            };
+            let args = match &value["args"] {
+                Value::Null => vec![],
+                Value::Array(args) => args.iter().map(number).collect::<Result<_, _>>()?,
+                arg => vec![number(arg)?],
+            };
+            let cmd = value["cmd"].as_str().ok_or_else(|| out_of_range("cmd"))?;
+            Ok(Self {
+                cmd: cmd.into(),
+                args,
+                schema_version: SCHEMA_VERSION,
+            })
+        }
+    }
+
+    impl From<Command> for Value {
+        fn from(command: Command) -> Self {
+            match command.args.as_slice() {
+                [] => json!({"cmd": command.cmd}),
+                [arg] => json!({"cmd": command.cmd, "args": arg}),
+                args => json!({"cmd": command.cmd, "args": args}),
+            }
+        }
+    }
+}
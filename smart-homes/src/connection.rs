@@ -0,0 +1,303 @@
+#[cfg(feature = "paho")]
+use crate::{broker::BrokerConfig, transport::paho::PahoTransport};
+use crate::{
+    error::Error,
+    schema::Availability,
+    transport::{topic_matches, Message, Transport},
+};
+use educe::Educe;
+use parking_lot::Mutex;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::Arc,
+};
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    OnceCell,
+};
+use tracing::{debug, info, warn};
+
+/// Where the messages received on a connection go, by the filter they were
+/// subscribed with.
+#[derive(Debug, Default)]
+struct Routes {
+    /// The filters without wildcards, which are the topics themselves.
+    exact: HashMap<String, mpsc::Sender<Message>>,
+    /// The filters with wildcards, in the order they were subscribed.
+    wildcards: Vec<(String, mpsc::Sender<Message>)>,
+}
+
+impl Routes {
+    /// Route the messages matching `filter` to `route`. Every filter can only
+    /// have one route.
+    fn insert(&mut self, filter: &str, route: mpsc::Sender<Message>) -> Result<(), Error> {
+        let duplicate = || Error::DuplicateSubscription(filter.into());
+        if !filter.contains(['+', '#']) {
+            return match self.exact.entry(filter.into()) {
+                Entry::Occupied(_) => Err(duplicate()),
+                Entry::Vacant(entry) => {
+                    entry.insert(route);
+                    Ok(())
+                }
+            };
+        }
+        if self.wildcards.iter().any(|(f, _)| f == filter) {
+            return Err(duplicate());
+        }
+        self.wildcards.push((filter.into(), route));
+        Ok(())
+    }
+
+    /// The route of a message published on `topic`: that of the topic
+    /// itself, or else that of the first wildcard filter matching it.
+    fn route(&self, topic: &str) -> Option<&mpsc::Sender<Message>> {
+        self.exact.get(topic).or_else(|| {
+            self.wildcards
+                .iter()
+                .find(|(filter, _)| topic_matches(filter, topic))
+                .map(|(_, route)| route)
+        })
+    }
+
+    fn remove(&mut self, filter: &str) {
+        self.exact.remove(filter);
+        self.wildcards.retain(|(f, _)| f != filter);
+    }
+
+    fn clear(&mut self) {
+        self.exact.clear();
+        self.wildcards.clear();
+    }
+}
+
+/// A connection to the broker that can be shared by several devices.
+///
+/// The connection is established by the first device that calls
+/// [`Connection::connect`]. Incoming messages are routed to the devices by
+/// their topic, so that every device only sees its own commands.
+///
+/// Since MQTT allows only one last will per connection, the will of a shared
+/// connection is published on `{client_id}/available`. Devices that share a
+/// connection announce their own availability when they start and stop, and
+/// leave their last will with the connection. If the connection drops, the
+/// broker only updates the connection's availability topic, so the wills of
+/// the devices are published through the [supervisor] of the connection.
+///
+/// [supervisor]: Connection::with_supervisor
+#[derive(Educe, Clone)]
+#[educe(Debug)]
+pub struct Connection {
+    #[educe(Debug(ignore))]
+    transport: Arc<dyn Transport>,
+    pub client_id: String,
+    will: Message,
+    shared: bool,
+    #[educe(Debug(ignore))]
+    routes: Arc<Mutex<Routes>>,
+    #[educe(Debug(ignore))]
+    connected: Arc<OnceCell<()>>,
+    /// The last wills of the devices sharing the connection, by topic.
+    #[educe(Debug(ignore))]
+    device_wills: Arc<Mutex<HashMap<String, Message>>>,
+    #[educe(Debug(ignore))]
+    supervisor: Option<Arc<Connection>>,
+}
+
+impl Connection {
+    /// Create a connection for a single device over `transport`, whose last
+    /// will is published on `will_topic`. The device announces that it is
+    /// available by itself, once it is ready for commands.
+    pub fn new(
+        client_id: impl Into<String>,
+        will_topic: impl Into<String>,
+        transport: impl Transport,
+    ) -> Self {
+        Self {
+            transport: Arc::new(transport),
+            client_id: client_id.into(),
+            will: availability_message(will_topic, false),
+            shared: false,
+            routes: Default::default(),
+            connected: Default::default(),
+            device_wills: Default::default(),
+            supervisor: None,
+        }
+    }
+
+    /// Create a connection over `transport` meant to be shared by several
+    /// devices.
+    pub fn new_shared(client_id: impl Into<String>, transport: impl Transport) -> Self {
+        let client_id = client_id.into();
+        let will_topic = format!("{}/available", client_id);
+        Self {
+            shared: true,
+            ..Self::new(client_id, will_topic, transport)
+        }
+    }
+
+    /// Create a connection for a single device to `broker`, whose last will
+    /// is published on `will_topic`.
+    #[cfg(feature = "paho")]
+    pub fn try_new(
+        client_id: impl Into<String>,
+        will_topic: impl Into<String>,
+        broker: impl Into<BrokerConfig>,
+    ) -> Result<Self, Error> {
+        let client_id = client_id.into();
+        let transport = PahoTransport::try_new(&client_id, broker)?;
+        Ok(Self::new(client_id, will_topic, transport))
+    }
+
+    /// Create a connection to `broker` meant to be shared by several
+    /// devices.
+    #[cfg(feature = "paho")]
+    pub fn try_new_shared(
+        client_id: impl Into<String>,
+        broker: impl Into<BrokerConfig>,
+    ) -> Result<Self, Error> {
+        let client_id = client_id.into();
+        let transport = PahoTransport::try_new(&client_id, broker)?;
+        Ok(Self::new_shared(client_id, transport))
+    }
+
+    /// The topic of the last will of the connection.
+    pub fn will_topic(&self) -> &str {
+        self.will.topic()
+    }
+
+    /// Whether the connection is shared by several devices.
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    /// Use `will` as the last will of the connection instead of the
+    /// availability message. Only has an effect before connecting.
+    pub fn with_will(self, will: Message) -> Self {
+        Self { will, ..self }
+    }
+
+    /// Publish the last wills of the devices through `supervisor`, a
+    /// connection of its own, if the shared connection is lost. Only has an
+    /// effect before connecting.
+    pub fn with_supervisor(self, supervisor: Connection) -> Self {
+        Self {
+            supervisor: Some(Arc::new(supervisor)),
+            ..self
+        }
+    }
+
+    /// Leave the last will of a device with a shared connection, to be
+    /// published if the connection is lost. A will on the same topic
+    /// replaces the previous one.
+    pub fn add_device_will(&self, will: Message) {
+        if self.shared {
+            self.device_wills.lock().insert(will.topic().into(), will);
+        }
+    }
+
+    /// Take back the last will of a device, e.g. because it stopped.
+    pub fn remove_device_will(&self, topic: &str) {
+        self.device_wills.lock().remove(topic);
+    }
+
+    /// Connect to the broker, unless some other device already did.
+    pub async fn connect(&self) -> Result<(), Error> {
+        self.connected
+            .get_or_try_init(|| async {
+                if let Some(supervisor) = &self.supervisor {
+                    // a supervisor could have a supervisor of its own
+                    Box::pin(supervisor.connect()).await?;
+                }
+                // if I am turned off, let others know that I am not available
+                let mut stream = self.transport.connect(self.will.clone()).await?;
+                info!(self.client_id, "connected");
+
+                let routes = self.routes.clone();
+                let client_id = self.client_id.clone();
+                let device_wills = self.device_wills.clone();
+                let supervisor = self.supervisor.clone();
+                tokio::spawn(async move {
+                    while let Some(msg) = stream.recv().await {
+                        let route = routes.lock().route(msg.topic()).cloned();
+                        let Some(route) = route else {
+                            debug!(client_id, topic = msg.topic(), "Unrouted message");
+                            continue;
+                        };
+                        // a slow or paused device must not hold up the
+                        // others, so its messages are dropped instead
+                        if let Err(TrySendError::Full(msg)) = route.try_send(msg) {
+                            warn!(
+                                client_id,
+                                topic = msg.topic(),
+                                "Dropping message, its receiver is behind"
+                            );
+                        }
+                    }
+                    warn!(client_id, "Connection lost");
+                    // taken before the devices stop and take their wills back
+                    let wills: Vec<_> = device_wills.lock().drain().map(|(_, will)| will).collect();
+                    // let the devices know that they will not get any more
+                    // messages.
+                    routes.lock().clear();
+
+                    let Some(supervisor) = supervisor else {
+                        return;
+                    };
+                    for will in wills {
+                        let topic = will.topic().to_string();
+                        if let Err(err) = supervisor.publish(will).await {
+                            warn!(client_id, topic, ?err, "Failed to publish a device will");
+                        }
+                    }
+                });
+
+                if self.shared {
+                    self.publish(availability_message(self.will_topic(), true))
+                        .await?;
+                }
+                Ok::<_, Error>(())
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Subscribe to the topic `filter`, wildcards included, and get a stream
+    /// of the messages published on the matching topics. A message matching
+    /// several filters only goes to the stream of its topic, or else to that
+    /// of the first filter subscribed. Subscribing twice to the same filter
+    /// is an error.
+    pub async fn subscribe(
+        &self,
+        filter: impl Into<String>,
+    ) -> Result<mpsc::Receiver<Message>, Error> {
+        let filter = filter.into();
+        let (tx, rx) = mpsc::channel(16);
+        // routed first, so that the retained messages sent on subscribing
+        // find their way
+        self.routes.lock().insert(&filter, tx)?;
+        if let Err(err) = self.transport.subscribe(&filter).await {
+            self.routes.lock().remove(&filter);
+            return Err(err);
+        }
+        Ok(rx)
+    }
+
+    /// Stop routing the messages of `filter`, which ends the stream of its
+    /// subscription, and unsubscribe from it at the broker.
+    pub async fn unsubscribe(&self, filter: &str) -> Result<(), Error> {
+        self.routes.lock().remove(filter);
+        self.transport.unsubscribe(filter).await
+    }
+
+    /// Publish a message on the connection.
+    pub async fn publish(&self, msg: Message) -> Result<(), Error> {
+        self.transport.publish(msg).await
+    }
+}
+
+/// Build the retained availability message published on `topic`.
+pub fn availability_message(topic: impl Into<String>, is_available: bool) -> Message {
+    let payload = serde_json::to_vec(&Availability { is_available })
+        .expect("availability is always serializable");
+    Message::new_retained(topic.into(), payload)
+}
@@ -0,0 +1,345 @@
+//! A terminal dashboard of the homes, to use instead of the watcher logs.
+//!
+//! Every home is a row with a cell per device, telling whether it is
+//! available, whether it is on, its voltage or channel, and when it was last
+//! heard of. Commands are sent to the selected device with the keyboard.
+
+use crate::{
+    bulb::BulbCommand,
+    error::Error,
+    fan::FanCommand,
+    layout::{DeviceTopic, TopicScheme, KINDS},
+    schema::Availability,
+    transport::Message,
+    tv::TVCommand,
+    DeviceStatus,
+};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier},
+    text::{Line, Span},
+    widgets::{Block, Paragraph, Row, Table, TableState},
+    Frame,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+/// The colors the bulbs cycle through.
+const COLORS: [(u8, u8, u8); 4] = [(255, 255, 255), (255, 0, 0), (0, 255, 0), (0, 0, 255)];
+
+/// What the dashboard knows about a device.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceView {
+    pub status: Option<DeviceStatus>,
+    pub available: Option<bool>,
+    /// When the last status was received.
+    pub last_seen: Option<Instant>,
+}
+
+/// What a key press asks for.
+#[derive(Debug)]
+pub enum Action {
+    /// Send a command to the selected device.
+    Send(Message),
+    Quit,
+}
+
+pub struct Dashboard {
+    topics: TopicScheme,
+    /// The homes, in order, e.g. `home/2` before `home/10`.
+    homes: Vec<String>,
+    /// The devices by kind and id.
+    devices: HashMap<(&'static str, String), DeviceView>,
+    table: TableState,
+    /// The selected kind of device, as an index of [`KINDS`].
+    column: usize,
+    /// The last command sent, shown at the bottom.
+    last_command: Option<String>,
+}
+
+impl Dashboard {
+    pub fn new(topics: TopicScheme) -> Self {
+        Self {
+            topics,
+            homes: vec![],
+            devices: HashMap::new(),
+            table: TableState::default().with_selected(0),
+            column: 0,
+            last_command: None,
+        }
+    }
+
+    pub fn homes(&self) -> &[String] {
+        &self.homes
+    }
+
+    pub fn device(&self, kind: &str, id: &str) -> Option<&DeviceView> {
+        let kind = KINDS.iter().find(|k| **k == kind)?;
+        self.devices.get(&(*kind, id.to_string()))
+    }
+
+    /// The kind and id of the selected device.
+    pub fn selected(&self) -> Option<(&'static str, &str)> {
+        let home = self.homes.get(self.table.selected()?)?;
+        Some((KINDS[self.column], home))
+    }
+
+    /// Take into account a message received at `now`.
+    pub fn update(&mut self, msg: &Message, now: Instant) {
+        let Some((kind, id, what)) = self.topics.parse_topic(msg.topic()) else {
+            return;
+        };
+        match what {
+            DeviceTopic::Status => {
+                let codec = self.topics.codec().or_content_type(msg.content_type());
+                // malformed statuses still tell that the device is alive
+                let status = codec.decode_status(msg.payload()).ok();
+                let device = self.device_mut(kind, id);
+                device.status = status.or(device.status.take());
+                device.last_seen = Some(now);
+            }
+            DeviceTopic::Available => {
+                if let Ok(availability) = serde_json::from_slice::<Availability>(msg.payload()) {
+                    self.device_mut(kind, id).available = Some(availability.is_available);
+                }
+            }
+            DeviceTopic::Command => {}
+        }
+    }
+
+    fn device_mut(&mut self, kind: &'static str, id: &str) -> &mut DeviceView {
+        if let Err(i) = self
+            .homes
+            .binary_search_by_key(&home_order(id), |home| home_order(home))
+        {
+            self.homes.insert(i, id.into());
+        }
+        self.devices.entry((kind, id.into())).or_default()
+    }
+
+    /// Move the selection or build a command for the selected device.
+    pub fn handle_key(&mut self, key: KeyCode) -> Option<Action> {
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => return Some(Action::Quit),
+            KeyCode::Up | KeyCode::Char('k') => self.table.select_previous(),
+            KeyCode::Down | KeyCode::Char('j')
+                if self.table.selected() < Some(self.homes.len().saturating_sub(1)) =>
+            {
+                self.table.select_next()
+            }
+            KeyCode::Left | KeyCode::Char('h') => self.column = self.column.saturating_sub(1),
+            KeyCode::Right | KeyCode::Char('l') => self.column = (self.column + 1).min(2),
+            KeyCode::Char(key) => return self.command(key).map(Action::Send),
+            _ => {}
+        }
+        None
+    }
+
+    /// The command `key` stands for on the selected device, given its last
+    /// status.
+    fn command(&mut self, key: char) -> Option<Message> {
+        let (kind, id) = self.selected()?;
+        let id = id.to_string();
+        let status = self.device(kind, &id)?.status.clone()?;
+        let msg = match (&status, key) {
+            (DeviceStatus::Bulb(s), 'o') => {
+                let command = if s.is_on {
+                    BulbCommand::Off
+                } else {
+                    BulbCommand::On
+                };
+                self.command_message(kind, &id, command)?
+            }
+            (DeviceStatus::Fan(s), 'o') => {
+                let command = if s.is_on {
+                    FanCommand::Off
+                } else {
+                    FanCommand::On
+                };
+                self.command_message(kind, &id, command)?
+            }
+            (DeviceStatus::TV(s), 'o') => {
+                let command = if s.is_on {
+                    TVCommand::Off
+                } else {
+                    TVCommand::On
+                };
+                self.command_message(kind, &id, command)?
+            }
+            (DeviceStatus::Bulb(s), 'c') => {
+                let next = COLORS
+                    .iter()
+                    .position(|color| *color == s.color)
+                    .map_or(0, |i| (i + 1) % COLORS.len());
+                self.command_message(kind, &id, BulbCommand::Color(COLORS[next]))?
+            }
+            (DeviceStatus::Fan(s), '+') => {
+                self.command_message(kind, &id, FanCommand::Speed(s.speed.saturating_add(1)))?
+            }
+            (DeviceStatus::Fan(s), '-') => {
+                self.command_message(kind, &id, FanCommand::Speed(s.speed.saturating_sub(1)))?
+            }
+            (DeviceStatus::TV(s), '+') => {
+                self.command_message(kind, &id, TVCommand::Channel(s.channel.saturating_add(1)))?
+            }
+            (DeviceStatus::TV(s), '-') => {
+                self.command_message(kind, &id, TVCommand::Channel(s.channel.saturating_sub(1)))?
+            }
+            (DeviceStatus::TV(_), 'm') => self.command_message(kind, &id, TVCommand::Mute)?,
+            _ => return None,
+        };
+        Some(msg)
+    }
+
+    fn command_message(
+        &mut self,
+        kind: &str,
+        id: &str,
+        command: impl Serialize + std::fmt::Debug,
+    ) -> Option<Message> {
+        let codec = self.topics.codec();
+        let payload = codec.encode_command(&command).ok()?;
+        self.last_command = Some(format!("{kind} {id}: {command:?}"));
+        let msg = Message::new(self.topics.command_topic(kind, id), payload)
+            .with_content_type(codec.content_type());
+        Some(msg)
+    }
+
+    pub fn draw(&mut self, frame: &mut Frame, now: Instant) {
+        let [title, table, help] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let available = self
+            .devices
+            .values()
+            .filter(|device| device.available == Some(true))
+            .count();
+        frame.render_widget(
+            Line::from(format!(
+                "{} homes, {available}/{} devices available",
+                self.homes.len(),
+                self.devices.len()
+            ))
+            .style(Modifier::BOLD),
+            title,
+        );
+
+        self.table.select_column(Some(self.column + 1));
+        let rows = self.homes.iter().map(|home| {
+            let cells = KINDS.map(|kind| cell(self.device(kind, home), now));
+            Row::new([Line::from(home.as_str())].into_iter().chain(cells))
+        });
+        let widths = [
+            Constraint::Length(10),
+            Constraint::Fill(1),
+            Constraint::Fill(1),
+            Constraint::Fill(1),
+        ];
+        let header = Row::new(["HOME", "BULB", "FAN", "TV"]).style(Modifier::BOLD);
+        frame.render_stateful_widget(
+            Table::new(rows, widths)
+                .header(header)
+                .block(Block::bordered())
+                .cell_highlight_style(Modifier::REVERSED),
+            table,
+            &mut self.table,
+        );
+
+        let mut help_line =
+            "arrows move, o power, c color, +/- speed or channel, m mute, q quit".to_string();
+        if let Some(command) = &self.last_command {
+            help_line = format!("{help_line} | sent {command}");
+        }
+        frame.render_widget(Paragraph::new(help_line), help);
+    }
+}
+
+/// Sort `home/2` before `home/10`.
+fn home_order(id: &str) -> (&str, Option<u64>, &str) {
+    match id.rsplit_once('/') {
+        Some((base, n)) => (base, n.parse().ok(), n),
+        None => (id, None, ""),
+    }
+}
+
+fn cell(device: Option<&DeviceView>, now: Instant) -> Line<'static> {
+    let Some(device) = device else {
+        return Line::from("-");
+    };
+    let availability = match device.available {
+        Some(true) => Span::styled("● ", Color::Green),
+        Some(false) => Span::styled("○ ", Color::Red),
+        None => Span::styled("? ", Color::DarkGray),
+    };
+    let Some(status) = &device.status else {
+        return Line::from(vec![availability, "no status".into()]);
+    };
+    let power = if status.is_on() {
+        Span::styled("on ", Color::Green)
+    } else {
+        Span::styled("off", Color::DarkGray)
+    };
+    let details = match status {
+        DeviceStatus::Bulb(s) => format!(" {:.0}V", s.voltage),
+        DeviceStatus::Fan(s) => format!(" {:.0}V speed {}", s.voltage, s.speed),
+        DeviceStatus::TV(s) => format!(" ch {} vol {}", s.channel, s.volume),
+    };
+    let age = device
+        .last_seen
+        .map(|seen| format!(" {}s ago", now.duration_since(seen).as_secs()))
+        .unwrap_or_default();
+    Line::from(vec![
+        availability,
+        power,
+        details.into(),
+        Span::styled(age, Color::DarkGray),
+    ])
+}
+
+/// Show the dashboard in the terminal until it is quit, updating it with the
+/// `incoming` messages and sending the commands to `outgoing`.
+///
+/// This blocks, so it must run on a thread of its own.
+pub fn run(
+    mut dashboard: Dashboard,
+    mut incoming: mpsc::Receiver<Message>,
+    outgoing: mpsc::Sender<Message>,
+) -> Result<(), Error> {
+    let mut terminal = ratatui::init();
+    let res = (|| loop {
+        while let Ok(msg) = incoming.try_recv() {
+            dashboard.update(&msg, Instant::now());
+        }
+        terminal.draw(|frame| dashboard.draw(frame, Instant::now()))?;
+
+        if !event::poll(Duration::from_millis(250))? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match dashboard.handle_key(key.code) {
+            Some(Action::Quit) => return Ok(()),
+            Some(Action::Send(msg)) => {
+                // a full queue means the broker is not keeping up, so the
+                // command may as well be dropped.
+                let _ = outgoing.try_send(msg);
+            }
+            None => {}
+        }
+    })();
+    ratatui::restore();
+    res.map_err(Error::Terminal)
+}
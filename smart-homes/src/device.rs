@@ -0,0 +1,348 @@
+//! The simulated device, whatever its kind: how it talks to the broker, keeps
+//! its state and misbehaves. What sets a bulb, a fan and a tv apart is their
+//! [`DeviceState`].
+
+#[cfg(feature = "paho")]
+use crate::broker::BrokerConfig;
+use crate::{
+    clock::Clock,
+    connection::Connection,
+    error::Error,
+    fault::{command_delay, roll, status_payloads, DeviceFaults, FaultInjector},
+    layout::TopicScheme,
+    rng::{device_rng, DeviceRng},
+    store::StateStore,
+    transport::Message,
+    DeviceStatus,
+};
+use chrono::{DateTime, Utc};
+use educe::Educe;
+use parking_lot::{Mutex, MutexGuard};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fmt::Debug, sync::Arc, time::Duration};
+use tokio::{select, task::JoinSet};
+use tracing::{error, info, warn};
+
+/// The state of a kind of device, which tells what the device reports and
+/// which commands it understands.
+pub trait DeviceState:
+    Debug + Default + Copy + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    /// The kind of the device, as in its topics, e.g. `bulb`.
+    const KIND: &'static str;
+
+    /// The commands received by the device.
+    type Command: Debug + DeserializeOwned + Send;
+
+    fn set_on(&mut self, is_on: bool);
+
+    /// The status of the device `id` at `timestamp`.
+    fn status(&self, id: &str, rng: &DeviceRng, timestamp: DateTime<Utc>) -> DeviceStatus;
+
+    /// Carry out a command received by `device`.
+    fn apply(device: &mut Device<Self>, command: Self::Command);
+
+    /// The Home Assistant discovery configs of the device `id`.
+    fn discovery_configs(
+        topics: &TopicScheme,
+        prefix: &str,
+        id: &str,
+        will_topic: &str,
+    ) -> Vec<Message>;
+}
+
+/// A device that publishes its status every 5 seconds and carries out the
+/// commands it receives.
+#[derive(Educe, Clone)]
+#[educe(Debug)]
+pub struct Device<S: DeviceState> {
+    conn: Connection,
+    pub id: String,
+    state: Arc<Mutex<S>>,
+    #[educe(Debug(ignore))]
+    store: Option<StateStore>,
+    clock: Clock,
+    #[educe(Debug(ignore))]
+    rng: DeviceRng,
+    #[educe(Debug(ignore))]
+    faults: DeviceFaults,
+    discovery_prefix: Option<String>,
+    topics: TopicScheme,
+}
+
+impl<S: DeviceState> Device<S> {
+    #[cfg(feature = "paho")]
+    pub fn try_new(id: impl AsRef<str>, broker: impl Into<BrokerConfig>) -> Result<Self, Error> {
+        Self::try_new_with_topics(id, broker, TopicScheme::default())
+    }
+
+    /// Create a device with a connection of its own to `broker`, whose
+    /// client id is under the prefix of `topics`.
+    #[cfg(feature = "paho")]
+    pub fn try_new_with_topics(
+        id: impl AsRef<str>,
+        broker: impl Into<BrokerConfig>,
+        topics: TopicScheme,
+    ) -> Result<Self, Error> {
+        let conn = Connection::try_new(
+            topics.client_id(&format!("{}/{}", S::KIND, id.as_ref())),
+            topics.available_topic(S::KIND, id.as_ref()),
+            broker,
+        )?;
+        Ok(Self::new(id, conn).with_topics(topics))
+    }
+
+    /// Create a device that talks to the broker over `conn`, which may be
+    /// shared with other devices.
+    pub fn new(id: impl AsRef<str>, conn: Connection) -> Self {
+        let key = format!("{}/{}", S::KIND, id.as_ref());
+        Self {
+            id: id.as_ref().into(),
+            conn,
+            state: Default::default(),
+            store: None,
+            clock: Clock::system(),
+            rng: device_rng(None, &key),
+            faults: FaultInjector::default().device(key),
+            discovery_prefix: None,
+            topics: TopicScheme::default(),
+        }
+    }
+
+    /// Restore the last state of the device saved in `store`, and keep
+    /// saving it there whenever it changes.
+    pub fn with_store(mut self, store: StateStore) -> Self {
+        if let Some(state) = store.load::<S>(&self.store_key()) {
+            info!(self.id, ?state, "Restored saved state");
+            *self.state.lock() = state;
+        }
+        self.store = Some(store);
+        self
+    }
+
+    /// Draw the random numbers of the device from a generator derived from
+    /// the simulation `seed`, so that runs are reproducible.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = device_rng(Some(seed), &self.store_key());
+        self
+    }
+
+    /// Timestamp and pace the device with `clock` instead of the system
+    /// clock.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Simulate the faults configured in `injector` for the device.
+    pub fn with_faults(mut self, injector: FaultInjector) -> Self {
+        self.faults = self.faults.with_injector(injector);
+        self
+    }
+
+    /// Announce the device to Home Assistant through MQTT discovery, under
+    /// the discovery `prefix`.
+    pub fn with_discovery(mut self, prefix: impl Into<String>) -> Self {
+        self.discovery_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Use the topics and payloads of `topics` to talk to the outside world.
+    pub fn with_topics(mut self, topics: TopicScheme) -> Self {
+        if !self.conn.is_shared() {
+            self.conn = self.conn.with_will(topics.will(S::KIND, &self.id));
+        }
+        self.topics = topics;
+        self
+    }
+
+    /// The connection of the device, which may be shared with other devices.
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// The topic filter the device gets its commands on.
+    pub fn command_filter(&self) -> String {
+        self.topics.command_filter(S::KIND, &self.id)
+    }
+
+    fn store_key(&self) -> String {
+        format!("{}/{}", S::KIND, self.id)
+    }
+
+    /// Save the state of the device. A store that cannot be written is no
+    /// reason to stop the device, so failures are only logged.
+    fn save_state(&self) {
+        if let Some(store) = &self.store {
+            let state = *self.state.lock();
+            if let Err(err) = store.save(&self.store_key(), &state) {
+                error!(self.id, ?err, "Failed to save the state");
+            }
+        }
+    }
+
+    /// The current state of the device.
+    pub(crate) fn state(&self) -> MutexGuard<'_, S> {
+        self.state.lock()
+    }
+
+    pub fn turn_on(&mut self) {
+        self.state.lock().set_on(true);
+        info!(self.id, "Turning on {}", S::KIND);
+    }
+
+    pub fn turn_off(&mut self) {
+        self.state.lock().set_on(false);
+        info!(self.id, "Turning off {}", S::KIND);
+    }
+
+    /// Publish the status of the device.
+    pub async fn publish_status(&self) -> Result<(), Error> {
+        if self.faults.is_offline() {
+            return Ok(());
+        }
+
+        let status = self
+            .state
+            .lock()
+            .status(&self.id, &self.rng, self.clock.now());
+        for payload in status_payloads(
+            status,
+            self.topics.codec(),
+            &self.faults.current(),
+            &self.rng,
+        )? {
+            for msg in self.topics.status_messages(S::KIND, &self.id, payload) {
+                self.conn.publish(msg).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn process_payload(&mut self, msg: Message) -> Result<(), Error> {
+        let payload = msg.payload();
+        let Some(command) = self.topics.parse_command::<S::Command>(S::KIND, &msg) else {
+            let payload_str = &*msg.payload_str();
+            warn!(?payload, payload_str, "Invalid command received");
+            // invalid payload is not the end of the world, hence no error.
+            return Ok(());
+        };
+
+        let faults = self.faults.current();
+        if self.faults.is_offline() {
+            info!(self.id, "Dropping command while simulating a disconnect");
+            return Ok(());
+        }
+        if let Some(delay) = command_delay(&faults, &self.rng) {
+            self.clock.sleep(delay).await;
+        }
+        if roll(&self.rng, faults.stuck) {
+            warn!(
+                self.id,
+                ?command,
+                "Ignoring command, simulating a stuck device"
+            );
+            return Ok(());
+        }
+
+        S::apply(self, command);
+        self.save_state();
+        Ok(())
+    }
+
+    pub async fn handle_incoming(&mut self) -> Result<(), Error> {
+        self.connect().await?;
+        self.run().await
+    }
+
+    /// Connect to the broker.
+    pub async fn connect(&mut self) -> Result<(), Error> {
+        info!(?self.id, "Starting {}", S::KIND);
+        self.conn.connect().await
+    }
+
+    /// Publish my status and process commands until something fails. The
+    /// device must have been connected with [`Device::connect`] before.
+    pub async fn run(&mut self) -> Result<(), Error> {
+        let res = self.process_commands().await;
+        // the connection may be shared with devices that keep running
+        let _ = self.conn.unsubscribe(&self.command_filter()).await;
+
+        // the last will is only published when the connection drops, which
+        // does not happen if the connection is shared with other devices. If
+        // it did drop, the will is left for the supervisor to publish.
+        if self.conn.publish(self.availability(false)).await.is_ok() {
+            self.conn
+                .remove_device_will(self.topics.will(S::KIND, &self.id).topic());
+        }
+        res
+    }
+
+    /// Pretend to have lost the connection for a while.
+    async fn go_offline(&self) -> Result<(), Error> {
+        let offline_for = Duration::from_secs(self.faults.current().offline_secs);
+        warn!(self.id, ?offline_for, "Simulating a disconnect");
+        self.faults.set_offline(true);
+        self.conn.publish(self.availability(false)).await?;
+        self.clock.sleep(offline_for).await;
+        self.faults.set_offline(false);
+        if self.faults.is_offline() {
+            // paused in the meantime
+            return Ok(());
+        }
+        self.conn.publish(self.availability(true)).await
+    }
+
+    fn availability(&self, is_available: bool) -> Message {
+        self.topics.availability(S::KIND, &self.id, is_available)
+    }
+
+    async fn process_commands(&mut self) -> Result<(), Error> {
+        // listen for commands
+        let mut commands = self.conn.subscribe(self.command_filter()).await?;
+        for msg in self.topics.announce(S::KIND, &self.id) {
+            self.conn.publish(msg).await?;
+        }
+
+        // let others know that I am available now, and that I am not if a
+        // shared connection drops
+        self.conn.publish(self.availability(true)).await?;
+        self.conn
+            .add_device_will(self.topics.will(S::KIND, &self.id));
+        if let Some(prefix) = &self.discovery_prefix {
+            for config in
+                S::discovery_configs(&self.topics, prefix, &self.id, self.conn.will_topic())
+            {
+                self.conn.publish(config).await?;
+            }
+        }
+
+        // start a task to publish my status at regular intervals, which is
+        // aborted with me
+        let self_clone = self.clone();
+        let mut status_task = JoinSet::<Result<(), Error>>::new();
+        status_task.spawn(async move {
+            loop {
+                if roll(&self_clone.rng, self_clone.faults.current().disconnect) {
+                    self_clone.go_offline().await?;
+                }
+                self_clone.publish_status().await?;
+                self_clone.clock.sleep(Duration::from_secs(5)).await;
+            }
+        });
+
+        loop {
+            select! {
+                msg = commands.recv() => {
+                    let Some(msg) = msg else {
+                        return Err(Error::Disconnected);
+                    };
+                    self.process_payload(msg).await?;
+                }
+                Some(res) = status_task.join_next() => {
+                    res??
+                }
+            }
+        }
+    }
+}
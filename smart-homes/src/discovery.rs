@@ -0,0 +1,219 @@
+//! Home Assistant MQTT discovery.
+//!
+//! Every device publishes retained configs on
+//! `{prefix}/{component}/{object_id}/config`, so that Home Assistant creates
+//! its entities by itself. The configs point at the usual topics of the
+//! device and extract the values from the [`DeviceStatus`] JSON.
+//!
+//! [`DeviceStatus`]: crate::DeviceStatus
+
+use crate::{layout::TopicScheme, transport::Message};
+use serde_json::{json, Value};
+
+/// The discovery prefix Home Assistant listens on by default.
+pub const DEFAULT_PREFIX: &str = "homeassistant";
+
+const STATE_TEMPLATE: &str = "{{ 'ON' if value_json.status.is_on else 'OFF' }}";
+
+/// Turn `kind/id` into something Home Assistant accepts as an object id.
+fn object_id(kind: &str, id: &str) -> String {
+    format!("{kind}_{id}")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Describes one simulated device to Home Assistant.
+struct Entities<'a> {
+    topics: &'a TopicScheme,
+    prefix: &'a str,
+    kind: &'a str,
+    id: &'a str,
+    model: &'a str,
+    will_topic: &'a str,
+}
+
+impl Entities<'_> {
+    /// Build the config of an entity of the device. `name` is `None` for the
+    /// main entity, which takes the name of the device.
+    fn config(&self, component: &str, name: Option<&str>, mut config: Value) -> Message {
+        let device_id = object_id(self.kind, self.id);
+        let object_id = match name {
+            Some(name) => format!("{device_id}_{}", name.to_lowercase()),
+            None => device_id.clone(),
+        };
+
+        // a device sharing its connection is unavailable when either it says
+        // so or the connection is lost.
+        let mut availability = vec![json!({
+            "topic": self.topics.available_topic(self.kind, self.id),
+            "value_template": "{{ 'online' if value_json.is_available else 'offline' }}",
+        })];
+        if self.will_topic != self.topics.available_topic(self.kind, self.id) {
+            availability.push(json!({
+                "topic": self.will_topic,
+                "value_template": "{{ 'online' if value_json.is_available else 'offline' }}",
+            }));
+        }
+
+        let common = json!({
+            "name": name,
+            "unique_id": format!("smart_homes_{object_id}"),
+            "object_id": object_id,
+            "availability": availability,
+            "availability_mode": "all",
+            "state_topic": self.topics.status_topic(self.kind, self.id),
+            "device": {
+                "identifiers": [format!("smart_homes_{device_id}")],
+                "name": format!("{} {}", self.id, self.kind),
+                "manufacturer": "smart-homes",
+                "model": self.model,
+            },
+        });
+        let (Value::Object(config_map), Value::Object(common)) = (&mut config, common) else {
+            unreachable!("configs are JSON objects");
+        };
+        for (key, value) in common {
+            config_map.entry(key).or_insert(value);
+        }
+        // sensors cannot be commanded
+        if component != "sensor" {
+            config_map.insert(
+                "command_topic".into(),
+                self.topics.command_topic(self.kind, self.id).into(),
+            );
+        }
+
+        let topic = format!("{}/{component}/{object_id}/config", self.prefix);
+        Message::new_retained(topic, config.to_string())
+    }
+}
+
+/// The discovery configs of the bulb `id`: a light with its colour.
+pub fn bulb_configs(
+    topics: &TopicScheme,
+    prefix: &str,
+    id: &str,
+    will_topic: &str,
+) -> Vec<Message> {
+    let entities = Entities {
+        topics,
+        prefix,
+        kind: "bulb",
+        id,
+        model: "Simulated bulb",
+        will_topic,
+    };
+    vec![
+        entities.config(
+            "light",
+            None,
+            json!({
+                "schema": "template",
+                "command_on_template": concat!(
+                    "{% if red is defined %}",
+                    r#"{"cmd": "color", "args": [{{ red }}, {{ green }}, {{ blue }}]}"#,
+                    "{% else %}",
+                    r#"{"cmd": "on"}"#,
+                    "{% endif %}",
+                ),
+                "command_off_template": r#"{"cmd": "off"}"#,
+                "state_template": "{{ 'on' if value_json.status.is_on else 'off' }}",
+                "red_template": "{{ value_json.status.color[0] }}",
+                "green_template": "{{ value_json.status.color[1] }}",
+                "blue_template": "{{ value_json.status.color[2] }}",
+            }),
+        ),
+        voltage_sensor(&entities),
+    ]
+}
+
+/// The discovery configs of the fan `id`: a fan whose speed is its
+/// percentage.
+pub fn fan_configs(topics: &TopicScheme, prefix: &str, id: &str, will_topic: &str) -> Vec<Message> {
+    let entities = Entities {
+        topics,
+        prefix,
+        kind: "fan",
+        id,
+        model: "Simulated fan",
+        will_topic,
+    };
+    vec![
+        entities.config(
+            "fan",
+            None,
+            json!({
+                "command_template": r#"{"cmd": "{{ value | lower }}"}"#,
+                "state_value_template": STATE_TEMPLATE,
+                "percentage_state_topic": topics.status_topic("fan", id),
+                "percentage_command_topic": topics.command_topic("fan", id),
+                "percentage_command_template": r#"{"cmd": "speed", "args": {{ value }}}"#,
+                "percentage_value_template": "{{ value_json.status.speed }}",
+                "speed_range_min": 1,
+                "speed_range_max": 100,
+            }),
+        ),
+        voltage_sensor(&entities),
+    ]
+}
+
+/// The discovery configs of the tv `id`. Home Assistant has no MQTT media
+/// player, so the tv is a switch with numbers for its channel and volume.
+pub fn tv_configs(topics: &TopicScheme, prefix: &str, id: &str, will_topic: &str) -> Vec<Message> {
+    let entities = Entities {
+        topics,
+        prefix,
+        kind: "tv",
+        id,
+        model: "Simulated tv",
+        will_topic,
+    };
+    vec![
+        entities.config(
+            "switch",
+            None,
+            json!({
+                "payload_on": r#"{"cmd": "on"}"#,
+                "payload_off": r#"{"cmd": "off"}"#,
+                "state_on": "ON",
+                "state_off": "OFF",
+                "value_template": STATE_TEMPLATE,
+            }),
+        ),
+        entities.config(
+            "number",
+            Some("Channel"),
+            json!({
+                "command_template": r#"{"cmd": "channel", "args": {{ value | int }}}"#,
+                "value_template": "{{ value_json.status.channel }}",
+                "min": 1,
+                "max": u16::MAX,
+                "mode": "box",
+            }),
+        ),
+        entities.config(
+            "number",
+            Some("Volume"),
+            json!({
+                "command_template": r#"{"cmd": "volume", "args": {{ value | int }}}"#,
+                "value_template": "{{ value_json.status.volume }}",
+                "min": 0,
+                "max": u8::MAX,
+            }),
+        ),
+    ]
+}
+
+fn voltage_sensor(entities: &Entities) -> Message {
+    entities.config(
+        "sensor",
+        Some("Voltage"),
+        json!({
+            "device_class": "voltage",
+            "unit_of_measurement": "V",
+            "state_class": "measurement",
+            "value_template": "{{ value_json.status.voltage | round(1) }}",
+        }),
+    )
+}
@@ -1,11 +1,57 @@
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[cfg(feature = "paho")]
     #[error("Failed to create MQTT client: {0}")]
     ClientCreation(#[from] paho_mqtt::Error),
 
+    #[error("MQTT transport failed: {0}")]
+    Transport(String),
+
     #[error("Failed to join tokio task: {0}")]
     JoinError(#[from] tokio::task::JoinError),
 
     #[error("Failed to serialize message: {0}")]
     SerializeError(#[from] serde_json::Error),
+
+    #[error("Failed to encode or decode payload: {0}")]
+    CodecError(String),
+
+    #[error(
+        "Unsupported schema version {0}, expected at most {}",
+        crate::schema::SCHEMA_VERSION
+    )]
+    UnsupportedSchemaVersion(u32),
+
+    #[error("Invalid rule: {0}")]
+    InvalidRule(String),
+
+    #[error("Invalid scene: {0}")]
+    InvalidScene(String),
+
+    #[error("Invalid schedule: {0}")]
+    InvalidSchedule(String),
+
+    #[error("Invalid admin command: {0}")]
+    InvalidAdminCommand(String),
+
+    #[error("Failed to access state store: {0}")]
+    StoreError(std::io::Error),
+
+    #[error("Failed to access the history: {0}")]
+    HistoryError(std::io::Error),
+
+    #[error("Failed to access the recording: {0}")]
+    RecordingError(std::io::Error),
+
+    #[error("Failed to access the schedule file: {0}")]
+    ScheduleFileError(std::io::Error),
+
+    #[error("Failed to drive the terminal: {0}")]
+    Terminal(std::io::Error),
+
+    #[error("Already subscribed to {0} on this connection")]
+    DuplicateSubscription(String),
+
+    #[error("Lost connection to the broker")]
+    Disconnected,
 }
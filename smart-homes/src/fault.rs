@@ -0,0 +1,300 @@
+use crate::{codec::Codec, error::Error, rng::DeviceRng, DeviceStatus};
+use chrono::TimeDelta;
+use parking_lot::RwLock;
+use rand::Rng;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// The longest a command can be delayed by, in milliseconds: an hour.
+const MAX_DELAY_MS: u64 = 60 * 60 * 1000;
+/// The longest a device can go offline for, in seconds: a day.
+const MAX_OFFLINE_SECS: u64 = 24 * 60 * 60;
+/// The most a timestamp can be skewed by, in seconds: a year.
+const MAX_SKEW_SECS: u64 = 365 * 24 * 60 * 60;
+
+/// Misbehaviours a device can simulate. Every fault has the probability of it
+/// happening each time the device publishes a status or handles a command.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Faults {
+    /// Silently skip publishing a status.
+    #[serde(deserialize_with = "probability")]
+    pub drop_status: f64,
+    /// Wait `delay_ms` before handling a command.
+    #[serde(deserialize_with = "probability")]
+    pub delay: f64,
+    #[serde(deserialize_with = "at_most::<MAX_DELAY_MS, _>")]
+    pub delay_ms: u64,
+    /// Go offline for `offline_secs`, ignoring commands and publishing
+    /// nothing.
+    #[serde(deserialize_with = "probability")]
+    pub disconnect: f64,
+    #[serde(deserialize_with = "at_most::<MAX_OFFLINE_SECS, _>")]
+    pub offline_secs: u64,
+    /// Ignore a command, so the state gets stuck.
+    #[serde(deserialize_with = "probability")]
+    pub stuck: f64,
+    /// Publish a status with values the real device could never report.
+    #[serde(deserialize_with = "probability")]
+    pub out_of_range: f64,
+    /// Publish a status that cannot be decoded.
+    #[serde(deserialize_with = "probability")]
+    pub malformed: f64,
+    /// Shift the timestamp of a status by up to `skew_secs` either way.
+    #[serde(deserialize_with = "probability")]
+    pub clock_skew: f64,
+    #[serde(deserialize_with = "at_most::<MAX_SKEW_SECS, _>")]
+    pub skew_secs: u64,
+    /// Publish a status twice.
+    #[serde(deserialize_with = "probability")]
+    pub duplicate: f64,
+}
+
+impl Faults {
+    /// Set the fault or parameter called `name`, as named in the JSON form.
+    pub fn set(&mut self, name: &str, value: f64) -> Result<(), String> {
+        match name {
+            "delay_ms" => self.delay_ms = bounded(name, value, MAX_DELAY_MS)?,
+            "offline_secs" => self.offline_secs = bounded(name, value, MAX_OFFLINE_SECS)?,
+            "skew_secs" => self.skew_secs = bounded(name, value, MAX_SKEW_SECS)?,
+            _ => {
+                let probability = match name {
+                    "drop_status" => &mut self.drop_status,
+                    "delay" => &mut self.delay,
+                    "disconnect" => &mut self.disconnect,
+                    "stuck" => &mut self.stuck,
+                    "out_of_range" => &mut self.out_of_range,
+                    "malformed" => &mut self.malformed,
+                    "clock_skew" => &mut self.clock_skew,
+                    "duplicate" => &mut self.duplicate,
+                    _ => return Err(format!("unknown fault {name}")),
+                };
+                if !(0.0..=1.0).contains(&value) {
+                    return Err(format!("probability of {name} must be between 0 and 1"));
+                }
+                *probability = value;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `value` as a whole number between 0 and `max`.
+fn bounded(name: &str, value: f64, max: u64) -> Result<u64, String> {
+    if !(0.0..=max as f64).contains(&value) {
+        return Err(format!("{name} must be between 0 and {max}"));
+    }
+    Ok(value as u64)
+}
+
+fn probability<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let value = f64::deserialize(deserializer)?;
+    if !(0.0..=1.0).contains(&value) {
+        return Err(de::Error::custom("a probability must be between 0 and 1"));
+    }
+    Ok(value)
+}
+
+fn at_most<'de, const MAX: u64, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let value = u64::deserialize(deserializer)?;
+    if value > MAX {
+        return Err(de::Error::custom(format!("must be at most {MAX}")));
+    }
+    Ok(value)
+}
+
+/// Which faults apply to which devices.
+///
+/// Faults of a device override those of its kind, which override those of
+/// all the devices.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FaultConfig {
+    /// Faults of every device.
+    pub all: Option<Faults>,
+    /// Faults by kind of device, e.g. `bulb`.
+    pub kinds: HashMap<String, Faults>,
+    /// Faults by device, e.g. `bulb/home/1`.
+    pub devices: HashMap<String, Faults>,
+}
+
+impl FaultConfig {
+    /// Add a fault given as `target:name=value`, where `target` is `all`, a
+    /// kind of device or a device.
+    pub fn add(&mut self, spec: &str) -> Result<(), String> {
+        let (target, setting) = spec
+            .split_once(':')
+            .ok_or("fault must look like target:name=value")?;
+        let (name, value) = setting
+            .split_once('=')
+            .ok_or("fault must look like target:name=value")?;
+        let value = value
+            .parse::<f64>()
+            .map_err(|_| format!("value of {name} must be a number"))?;
+
+        let faults = match target {
+            "all" => self.all.get_or_insert_with(Default::default),
+            kind if !kind.contains('/') => self.kinds.entry(kind.into()).or_default(),
+            device => self.devices.entry(device.into()).or_default(),
+        };
+        faults.set(name, value)
+    }
+
+    fn faults_of(&self, key: &str) -> Faults {
+        let kind = key.split('/').next().unwrap_or_default();
+        self.devices
+            .get(key)
+            .or_else(|| self.kinds.get(kind))
+            .or(self.all.as_ref())
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// The fault configuration of the simulator, which can be changed while the
+/// devices are running.
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector {
+    config: Arc<RwLock<FaultConfig>>,
+    /// The devices paused by the admin, which behave as if offline until
+    /// they are resumed.
+    paused: Arc<RwLock<HashSet<String>>>,
+}
+
+impl FaultInjector {
+    pub fn new(config: FaultConfig) -> Self {
+        Self {
+            config: Arc::new(RwLock::new(config)),
+            paused: Default::default(),
+        }
+    }
+
+    /// Replace the fault configuration of all the devices.
+    pub fn set_config(&self, config: FaultConfig) {
+        *self.config.write() = config;
+    }
+
+    /// Set the faults of the device identified by `key`, or let it have
+    /// those of its kind again.
+    pub fn set_device_faults(&self, key: &str, faults: Option<Faults>) {
+        let mut config = self.config.write();
+        match faults {
+            Some(faults) => config.devices.insert(key.into(), faults),
+            None => config.devices.remove(key),
+        };
+    }
+
+    pub fn set_paused(&self, key: &str, paused: bool) {
+        let mut set = self.paused.write();
+        match paused {
+            true => set.insert(key.into()),
+            false => set.remove(key),
+        };
+    }
+
+    pub fn is_paused(&self, key: &str) -> bool {
+        self.paused.read().contains(key)
+    }
+
+    /// The faults of the device identified by `key`, e.g. `bulb/home/1`.
+    pub fn device(&self, key: impl Into<String>) -> DeviceFaults {
+        DeviceFaults {
+            injector: self.clone(),
+            key: key.into(),
+            offline: Default::default(),
+        }
+    }
+}
+
+/// The faults of a single device.
+#[derive(Debug, Clone)]
+pub struct DeviceFaults {
+    injector: FaultInjector,
+    key: String,
+    offline: Arc<AtomicBool>,
+}
+
+impl DeviceFaults {
+    /// Use the faults configured in `injector` from now on.
+    pub fn with_injector(self, injector: FaultInjector) -> Self {
+        Self { injector, ..self }
+    }
+
+    /// The faults currently configured for the device.
+    pub fn current(&self) -> Faults {
+        self.injector.config.read().faults_of(&self.key)
+    }
+
+    /// Whether the device is pretending to be disconnected, or is paused.
+    pub fn is_offline(&self) -> bool {
+        self.offline.load(Ordering::Relaxed) || self.injector.is_paused(&self.key)
+    }
+
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.store(offline, Ordering::Relaxed);
+    }
+}
+
+/// Decide whether a fault with the given `probability` happens this time.
+pub fn roll(rng: &DeviceRng, probability: f64) -> bool {
+    probability > 0.0 && rng.lock().gen_bool(probability.min(1.0))
+}
+
+/// Apply the faults that affect a status about to be published, and get the
+/// payloads to publish for it: none if it is dropped, two if it is duplicated.
+pub fn status_payloads(
+    mut status: DeviceStatus,
+    codec: Codec,
+    faults: &Faults,
+    rng: &DeviceRng,
+) -> Result<Vec<Vec<u8>>, Error> {
+    if roll(rng, faults.drop_status) {
+        return Ok(vec![]);
+    }
+
+    if roll(rng, faults.out_of_range) {
+        match &mut status {
+            DeviceStatus::Bulb(status) => status.voltage = -status.voltage * 100.0,
+            DeviceStatus::Fan(status) => status.speed = u8::MAX,
+            DeviceStatus::TV(status) => status.channel = u16::MAX,
+        }
+    }
+
+    if roll(rng, faults.clock_skew) {
+        let max = faults.skew_secs.min(MAX_SKEW_SECS) as i64;
+        let skew = TimeDelta::try_seconds(rng.lock().gen_range(-max..=max));
+        let timestamp = match &mut status {
+            DeviceStatus::Bulb(status) => &mut status.timestamp,
+            DeviceStatus::Fan(status) => &mut status.timestamp,
+            DeviceStatus::TV(status) => &mut status.timestamp,
+        };
+        // a timestamp skewed out of range is left alone
+        if let Some(skewed) = skew.and_then(|skew| timestamp.checked_add_signed(skew)) {
+            *timestamp = skewed;
+        }
+    }
+
+    let mut payload = codec.encode_status(&status)?;
+    if roll(rng, faults.malformed) {
+        // chop the payload in half, which is what a flaky link would do
+        payload.truncate(payload.len() / 2);
+    }
+
+    if roll(rng, faults.duplicate) {
+        Ok(vec![payload.clone(), payload])
+    } else {
+        Ok(vec![payload])
+    }
+}
+
+/// How long to wait before handling a command, if it is delayed at all.
+pub fn command_delay(faults: &Faults, rng: &DeviceRng) -> Option<Duration> {
+    roll(rng, faults.delay).then(|| Duration::from_millis(faults.delay_ms))
+}
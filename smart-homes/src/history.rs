@@ -0,0 +1,373 @@
+//! The history of the statuses and availability of the devices.
+//!
+//! Records are appended as JSON lines to one file per day, e.g.
+//! `2024-10-21.jsonl`, so that old days can be downsampled or dropped
+//! without touching the current one.
+
+use crate::{
+    error::Error,
+    layout::{DeviceTopic, TopicScheme},
+    schema::Availability,
+    transport::Message,
+    DeviceStatus,
+};
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::{debug, warn};
+
+/// What happened to a device at some point.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    /// The timestamp of the status, or when the availability was received.
+    #[serde_as(as = "serde_with::TimestampSeconds<i64, serde_with::formats::Flexible>")]
+    pub timestamp: DateTime<Utc>,
+    pub kind: String,
+    pub id: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Status(DeviceStatus),
+    Availability(bool),
+}
+
+/// A summary of the records of a device between `start` and `end`.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Bucket {
+    #[serde_as(as = "serde_with::TimestampSeconds<i64>")]
+    pub start: DateTime<Utc>,
+    #[serde_as(as = "serde_with::TimestampSeconds<i64>")]
+    pub end: DateTime<Utc>,
+    /// How many statuses were received.
+    pub statuses: usize,
+    /// The voltages of the statuses, for the bulbs and fans.
+    pub min_voltage: Option<f32>,
+    pub max_voltage: Option<f32>,
+    pub avg_voltage: Option<f32>,
+    /// The share of the time the device was on, in percent, out of the time
+    /// its state is known, i.e. from its first status on and while it is
+    /// available.
+    pub on_percent: Option<f64>,
+    /// How many times the channel of a tv changed.
+    pub channel_changes: Option<u32>,
+}
+
+/// Summarize the `records` of a single device in buckets of `step` from
+/// `from` to `to`.
+///
+/// A status holds until the next one, across buckets, or until the device
+/// becomes unavailable.
+pub fn aggregate(
+    records: &[Record],
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    step: TimeDelta,
+) -> Vec<Bucket> {
+    // skewed clocks get statuses out of order
+    let mut records: Vec<_> = records.iter().collect();
+    records.sort_by_key(|record| record.timestamp);
+    let mut records = records.into_iter().peekable();
+
+    let mut buckets = vec![];
+    // whether the device is on, since when
+    let mut on = None;
+    let mut since = from;
+    let mut channel = None;
+    let mut start = from;
+    while start < to {
+        // the last bucket ends at `to`, even at the end of time
+        let end = start.checked_add_signed(step).map_or(to, |end| end.min(to));
+        let mut voltages = vec![];
+        let mut statuses = 0;
+        let mut channel_changes = None;
+        let mut on_time = TimeDelta::zero();
+        let mut known_time = TimeDelta::zero();
+        let mut hold = |on: Option<bool>, since: DateTime<Utc>, until: DateTime<Utc>| {
+            if let Some(is_on) = on {
+                known_time += until - since;
+                if is_on {
+                    on_time += until - since;
+                }
+            }
+        };
+
+        // the last bucket includes `to`
+        while let Some(record) =
+            records.next_if(|record| record.timestamp < end || record.timestamp == to)
+        {
+            let timestamp = record.timestamp.max(start);
+            hold(on, since, timestamp);
+            since = timestamp;
+            match &record.event {
+                Event::Availability(false) => on = None,
+                Event::Availability(true) => {}
+                Event::Status(status) => {
+                    statuses += 1;
+                    on = Some(status.is_on());
+                    voltages.extend(status.voltage());
+                    if let DeviceStatus::TV(status) = status {
+                        let changes = channel_changes.get_or_insert(0);
+                        if channel.is_some_and(|channel| channel != status.channel) {
+                            *changes += 1;
+                        }
+                        channel = Some(status.channel);
+                    }
+                }
+            }
+        }
+        hold(on, since, end);
+        since = end;
+
+        let seconds = |delta: TimeDelta| delta.num_milliseconds() as f64 / 1000.0;
+        buckets.push(Bucket {
+            start,
+            end,
+            statuses,
+            min_voltage: voltages.iter().copied().reduce(f32::min),
+            max_voltage: voltages.iter().copied().reduce(f32::max),
+            avg_voltage: (!voltages.is_empty())
+                .then(|| voltages.iter().sum::<f32>() / voltages.len() as f32),
+            on_percent: (known_time > TimeDelta::zero())
+                .then(|| 100.0 * seconds(on_time) / seconds(known_time)),
+            channel_changes: channel_changes.or(channel.map(|_| 0)),
+        });
+        start = end;
+    }
+    buckets
+}
+
+/// Make older records sparser: keep a single status per device and
+/// `interval` for the records older than `after`. Availability changes are
+/// always kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Downsampling {
+    pub after: TimeDelta,
+    pub interval: TimeDelta,
+}
+
+/// How long the records are kept, and how densely.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HistoryPolicy {
+    /// Drop the records older than this.
+    pub retention: Option<TimeDelta>,
+    pub downsampling: Option<Downsampling>,
+}
+
+/// An append-only store of [`Record`]s in a directory.
+#[derive(Debug, Clone)]
+pub struct HistoryStore {
+    dir: Arc<PathBuf>,
+    policy: HistoryPolicy,
+    /// The file of the day records are appended to.
+    writer: Arc<Mutex<Option<(NaiveDate, File)>>>,
+}
+
+impl HistoryStore {
+    /// Open the store in `dir`, creating it if needed.
+    pub fn open(dir: impl AsRef<Path>, policy: HistoryPolicy) -> Result<Self, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(Error::HistoryError)?;
+        Ok(Self {
+            dir: Arc::new(dir),
+            policy,
+            writer: Default::default(),
+        })
+    }
+
+    fn segment_path(&self, day: NaiveDate) -> PathBuf {
+        self.dir.join(format!("{day}.jsonl"))
+    }
+
+    /// The days that have records, in order.
+    fn segments(&self) -> Result<Vec<NaiveDate>, Error> {
+        let mut days: Vec<_> = fs::read_dir(&*self.dir)
+            .map_err(Error::HistoryError)?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name();
+                let day = name.to_str()?.strip_suffix(".jsonl")?;
+                day.parse().ok()
+            })
+            .collect();
+        days.sort();
+        Ok(days)
+    }
+
+    pub fn append(&self, record: &Record) -> Result<(), Error> {
+        let day = record.timestamp.date_naive();
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let mut writer = self.writer.lock();
+        let file = match &mut *writer {
+            Some((current, file)) if *current == day => file,
+            _ => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.segment_path(day))
+                    .map_err(Error::HistoryError)?;
+                &mut writer.insert((day, file)).1
+            }
+        };
+        file.write_all(&line).map_err(Error::HistoryError)?;
+        Ok(())
+    }
+
+    /// Record what `msg` tells about a device, if anything. `now` is the
+    /// time availability changes are recorded at.
+    pub fn record(
+        &self,
+        topics: &TopicScheme,
+        msg: &Message,
+        now: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let Some((kind, id, what)) = topics.parse_topic(msg.topic()) else {
+            return Ok(());
+        };
+        let (timestamp, event) = match what {
+            DeviceTopic::Status => {
+                let codec = topics.codec().or_content_type(msg.content_type());
+                let Ok(status) = codec.decode_status(msg.payload()) else {
+                    // malformed statuses are a simulated fault, not history
+                    return Ok(());
+                };
+                (status.timestamp(), Event::Status(status))
+            }
+            DeviceTopic::Available => {
+                let Ok(availability) = serde_json::from_slice::<Availability>(msg.payload()) else {
+                    return Ok(());
+                };
+                (now, Event::Availability(availability.is_available))
+            }
+            DeviceTopic::Command => return Ok(()),
+        };
+        self.append(&Record {
+            timestamp,
+            kind: kind.into(),
+            id: id.into(),
+            event,
+        })
+    }
+
+    fn read_segment(&self, day: NaiveDate) -> Result<Vec<Record>, Error> {
+        let file = match File::open(self.segment_path(day)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(Error::HistoryError(err)),
+        };
+        let mut records = vec![];
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(Error::HistoryError)?;
+            match serde_json::from_str(&line) {
+                Ok(record) => records.push(record),
+                // most likely a line cut short by a crash
+                Err(err) => warn!(%day, ?err, "Skipping unreadable history record"),
+            }
+        }
+        Ok(records)
+    }
+
+    /// The records of the device `id` of `kind` between `from` and `to`,
+    /// both included, in the order they were recorded.
+    pub fn query(
+        &self,
+        kind: &str,
+        id: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<Record>, Error> {
+        let mut records = vec![];
+        for day in self.segments()? {
+            if day < from.date_naive() || day > to.date_naive() {
+                continue;
+            }
+            records.extend(self.read_segment(day)?.into_iter().filter(|record| {
+                record.kind == kind && record.id == id && (from..=to).contains(&record.timestamp)
+            }));
+        }
+        Ok(records)
+    }
+
+    /// Drop and downsample the records as the policy says, as of `now`.
+    pub fn apply_policy(&self, now: DateTime<Utc>) -> Result<(), Error> {
+        let HistoryPolicy {
+            retention,
+            downsampling,
+        } = self.policy;
+        // periods reaching past the start of time leave nothing behind them
+        let expired = retention.and_then(|retention| now.checked_sub_signed(retention));
+        let sparse =
+            downsampling.and_then(|d| Some((now.checked_sub_signed(d.after)?, d.interval)));
+        // only the days holding records old enough need rewriting
+        let Some(cutoff) = [expired, sparse.map(|(before, _)| before)]
+            .into_iter()
+            .flatten()
+            .max()
+        else {
+            return Ok(());
+        };
+
+        // hold the writer, so that nothing is appended to a file being
+        // rewritten.
+        let mut writer = self.writer.lock();
+        *writer = None;
+        for day in self.segments()? {
+            if day > cutoff.date_naive() {
+                break;
+            }
+            let records = self.read_segment(day)?;
+            let before = records.len();
+            let mut buckets = HashSet::new();
+            let kept: Vec<_> = records
+                .into_iter()
+                .filter(|record| {
+                    if expired.is_some_and(|expired| record.timestamp < expired) {
+                        return false;
+                    }
+                    let Some((before, interval)) = sparse else {
+                        return true;
+                    };
+                    if record.timestamp >= before || matches!(record.event, Event::Availability(_))
+                    {
+                        return true;
+                    }
+                    let bucket = record.timestamp.timestamp() / interval.num_seconds().max(1);
+                    buckets.insert((record.kind.clone(), record.id.clone(), bucket))
+                })
+                .collect();
+            if kept.len() == before {
+                continue;
+            }
+
+            let path = self.segment_path(day);
+            if kept.is_empty() {
+                fs::remove_file(&path).map_err(Error::HistoryError)?;
+            } else {
+                let mut data = vec![];
+                for record in &kept {
+                    serde_json::to_writer(&mut data, record)?;
+                    data.push(b'\n');
+                }
+                // same as the state store: never leave a truncated file
+                let tmp_path = path.with_extension("tmp");
+                fs::write(&tmp_path, data).map_err(Error::HistoryError)?;
+                fs::rename(&tmp_path, &path).map_err(Error::HistoryError)?;
+            }
+            debug!(%day, before, after = kept.len(), "Compacted history");
+        }
+        Ok(())
+    }
+}
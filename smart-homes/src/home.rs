@@ -22,14 +22,45 @@ impl Home {
         }
     }
 
+    /// Connect the devices of the home one after the other, in a fixed order.
+    pub async fn connect(&mut self) -> Result<(), Error> {
+        self.bulb.connect().await?;
+        self.fan.connect().await?;
+        self.tv.connect().await
+    }
+
     pub async fn handle_incoming(self) -> Result<(), Error> {
+        self.spawn_devices(true).await
+    }
+
+    /// Run the devices of a home that was connected with [`Home::connect`].
+    pub async fn run(self) -> Result<(), Error> {
+        self.spawn_devices(false).await
+    }
+
+    async fn spawn_devices(self, connect: bool) -> Result<(), Error> {
         let mut bulb = self.bulb;
         let mut fan = self.fan;
         let mut tv = self.tv;
 
-        let mut bulb_handle = tokio::spawn(async move { bulb.handle_incoming().await });
-        let mut fan_handle = spawn(async move { fan.handle_incoming().await });
-        let mut tv_handle = spawn(async move { tv.handle_incoming().await });
+        let mut bulb_handle = tokio::spawn(async move {
+            if connect {
+                bulb.connect().await?;
+            }
+            bulb.run().await
+        });
+        let mut fan_handle = spawn(async move {
+            if connect {
+                fan.connect().await?;
+            }
+            fan.run().await
+        });
+        let mut tv_handle = spawn(async move {
+            if connect {
+                tv.connect().await?;
+            }
+            tv.run().await
+        });
 
         loop {
             select! {
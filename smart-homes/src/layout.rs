@@ -0,0 +1,395 @@
+//! The topics and payloads the devices use to talk to the outside world.
+
+use crate::{codec::Codec, connection::availability_message, transport::Message};
+use clap::ValueEnum;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+/// The version of the Homie convention the devices follow.
+const HOMIE_VERSION: &str = "4.0";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum TopicLayout {
+    /// `{kind}/{id}/status`, `{kind}/{id}/command` and
+    /// `{kind}/{id}/available`, with payloads encoded by the codec of the
+    /// scheme.
+    #[default]
+    Default,
+    /// The Homie 4 convention: `homie/{kind}-{id}/...`, one topic per
+    /// property and a `/set` topic for every settable property.
+    Homie,
+}
+
+/// The kinds of devices, as in their topics.
+pub const KINDS: [&str; 3] = ["bulb", "fan", "tv"];
+
+/// What a topic of a device in the default layout carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceTopic {
+    Status,
+    Command,
+    Available,
+}
+
+/// A property of a Homie node, read from a field of the status of the
+/// device.
+struct Property {
+    name: &'static str,
+    /// The field of the status holding the value.
+    field: &'static str,
+    datatype: &'static str,
+    format: Option<&'static str>,
+    unit: Option<&'static str>,
+    /// The command that sets the property, if any.
+    command: Option<&'static str>,
+}
+
+const fn property(name: &'static str, field: &'static str, datatype: &'static str) -> Property {
+    Property {
+        name,
+        field,
+        datatype,
+        format: None,
+        unit: None,
+        command: None,
+    }
+}
+
+const POWER: Property = Property {
+    command: Some("power"),
+    ..property("power", "is_on", "boolean")
+};
+
+const VOLTAGE: Property = Property {
+    unit: Some("V"),
+    ..property("voltage", "voltage", "float")
+};
+
+const BULB: &[Property] = &[
+    POWER,
+    Property {
+        format: Some("rgb"),
+        command: Some("color"),
+        ..property("color", "color", "color")
+    },
+    VOLTAGE,
+];
+
+const FAN: &[Property] = &[
+    POWER,
+    Property {
+        format: Some("0:255"),
+        command: Some("speed"),
+        ..property("speed", "speed", "integer")
+    },
+    VOLTAGE,
+];
+
+const TV: &[Property] = &[
+    POWER,
+    Property {
+        format: Some("0:65535"),
+        command: Some("channel"),
+        ..property("channel", "channel", "integer")
+    },
+    Property {
+        format: Some("0:255"),
+        command: Some("volume"),
+        ..property("volume", "volume", "integer")
+    },
+    // muted follows the volume, which is also what unmutes the tv
+    property("muted", "is_muted", "boolean"),
+];
+
+fn properties(kind: &str) -> &'static [Property] {
+    match kind {
+        "bulb" => BULB,
+        "fan" => FAN,
+        "tv" => TV,
+        _ => &[],
+    }
+}
+
+/// The Homie id of a device, which may only contain lowercase letters,
+/// digits and hyphens.
+fn homie_id(kind: &str, id: &str) -> String {
+    format!("{kind}-{id}")
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '-',
+        })
+        .collect()
+}
+
+/// Where the devices publish and what they publish there.
+///
+/// Every topic lives under an optional prefix, e.g. `tenant/acme/site/paris`,
+/// so that several simulators can share one broker. The simulator, its
+/// watcher and the HTTP API must all use the same scheme.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TopicScheme {
+    prefix: Option<String>,
+    layout: TopicLayout,
+    codec: Codec,
+}
+
+impl TopicScheme {
+    pub fn new(layout: TopicLayout) -> Self {
+        Self {
+            prefix: None,
+            layout,
+            codec: Codec::default(),
+        }
+    }
+
+    /// Put every topic under `prefix`.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        let prefix = prefix.into();
+        let prefix = prefix.trim_matches('/');
+        self.prefix = (!prefix.is_empty()).then(|| prefix.into());
+        self
+    }
+
+    /// Encode the statuses and commands with `codec`. Availability stays
+    /// JSON, so that any client can tell whether a device is there.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn layout(&self) -> TopicLayout {
+        self.layout
+    }
+
+    /// The codec of the statuses. Homie payloads are plain strings, built
+    /// from the JSON form of the statuses.
+    pub fn codec(&self) -> Codec {
+        match self.layout {
+            TopicLayout::Default => self.codec,
+            TopicLayout::Homie => Codec::Json,
+        }
+    }
+
+    /// The topic `name` under the prefix of the scheme.
+    pub fn topic(&self, name: &str) -> String {
+        match &self.prefix {
+            Some(prefix) => format!("{prefix}/{name}"),
+            None => name.into(),
+        }
+    }
+
+    /// The MQTT client id of the connection `name`, e.g. `sim/rules`, under
+    /// the prefix of the scheme. Simulators sharing a broker would otherwise
+    /// take over each other's sessions.
+    pub fn client_id(&self, name: &str) -> String {
+        self.topic(name)
+    }
+
+    /// The name of `topic` under the prefix of the scheme, if it is under it.
+    pub fn strip_prefix<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        match &self.prefix {
+            Some(prefix) => topic.strip_prefix(prefix.as_str())?.strip_prefix('/'),
+            None => Some(topic),
+        }
+    }
+
+    /// The topic of the status of a device in the default layout. `id`
+    /// may contain wildcards.
+    pub fn status_topic(&self, kind: &str, id: &str) -> String {
+        self.topic(&format!("{kind}/{id}/status"))
+    }
+
+    /// The topic of the commands of a device in the default layout.
+    pub fn command_topic(&self, kind: &str, id: &str) -> String {
+        self.topic(&format!("{kind}/{id}/command"))
+    }
+
+    /// The topic of the availability of a device in the default layout.
+    pub fn available_topic(&self, kind: &str, id: &str) -> String {
+        self.topic(&format!("{kind}/{id}/available"))
+    }
+
+    /// The filters matching every topic of every device in the default
+    /// layout.
+    pub fn device_filters(&self) -> Vec<String> {
+        KINDS
+            .iter()
+            .map(|kind| self.topic(&format!("{kind}/#")))
+            .collect()
+    }
+
+    /// Tell which device a topic of the default layout belongs to, and what
+    /// it carries: the kind, the id and the [`DeviceTopic`].
+    pub fn parse_topic<'a>(&self, topic: &'a str) -> Option<(&'static str, &'a str, DeviceTopic)> {
+        let (kind, rest) = self.strip_prefix(topic)?.split_once('/')?;
+        let kind = KINDS.into_iter().find(|k| *k == kind)?;
+        let (id, name) = rest.rsplit_once('/')?;
+        let what = match name {
+            "status" => DeviceTopic::Status,
+            "command" => DeviceTopic::Command,
+            "available" => DeviceTopic::Available,
+            _ => return None,
+        };
+        Some((kind, id, what))
+    }
+
+    /// The last will of the connection `name` shared by several devices,
+    /// published under its [client id](Self::client_id).
+    pub fn connection_will(&self, name: &str) -> Message {
+        availability_message(format!("{}/available", self.client_id(name)), false)
+    }
+
+    fn homie_base(&self, kind: &str, id: &str) -> String {
+        self.topic(&format!("homie/{}", homie_id(kind, id)))
+    }
+
+    fn homie_state(&self, kind: &str, id: &str, state: &str) -> Message {
+        let base = self.homie_base(kind, id);
+        Message::new_retained(format!("{base}/$state"), state)
+    }
+
+    /// The topic filter the device listens on for commands.
+    pub fn command_filter(&self, kind: &str, id: &str) -> String {
+        match self.layout {
+            TopicLayout::Default => self.command_topic(kind, id),
+            TopicLayout::Homie => format!("{}/{kind}/+/set", self.homie_base(kind, id)),
+        }
+    }
+
+    /// The message telling whether the device is available.
+    pub fn availability(&self, kind: &str, id: &str, is_available: bool) -> Message {
+        match self.layout {
+            TopicLayout::Default => {
+                availability_message(self.available_topic(kind, id), is_available)
+            }
+            TopicLayout::Homie => {
+                let state = if is_available {
+                    "ready"
+                } else {
+                    "disconnected"
+                };
+                self.homie_state(kind, id, state)
+            }
+        }
+    }
+
+    /// The last will of a device that has a connection of its own.
+    pub fn will(&self, kind: &str, id: &str) -> Message {
+        match self.layout {
+            TopicLayout::Default => self.availability(kind, id, false),
+            TopicLayout::Homie => self.homie_state(kind, id, "lost"),
+        }
+    }
+
+    /// The messages describing the device, published before it becomes
+    /// available.
+    pub fn announce(&self, kind: &str, id: &str) -> Vec<Message> {
+        let TopicLayout::Homie = self.layout else {
+            return vec![];
+        };
+
+        let base = self.homie_base(kind, id);
+        let props = properties(kind);
+        let names: Vec<_> = props.iter().map(|p| p.name).collect();
+        let mut msgs = vec![
+            self.homie_state(kind, id, "init"),
+            Message::new_retained(format!("{base}/$homie"), HOMIE_VERSION),
+            Message::new_retained(format!("{base}/$name"), format!("{kind} {id}")),
+            Message::new_retained(format!("{base}/$nodes"), kind),
+            Message::new_retained(format!("{base}/$extensions"), ""),
+            Message::new_retained(format!("{base}/{kind}/$name"), kind),
+            Message::new_retained(format!("{base}/{kind}/$type"), kind),
+            Message::new_retained(format!("{base}/{kind}/$properties"), names.join(",")),
+        ];
+        for prop in props {
+            let topic = format!("{base}/{kind}/{}", prop.name);
+            msgs.push(Message::new_retained(format!("{topic}/$name"), prop.name));
+            msgs.push(Message::new_retained(
+                format!("{topic}/$datatype"),
+                prop.datatype,
+            ));
+            msgs.push(Message::new_retained(
+                format!("{topic}/$settable"),
+                prop.command.is_some().to_string(),
+            ));
+            if let Some(format) = prop.format {
+                msgs.push(Message::new_retained(format!("{topic}/$format"), format));
+            }
+            if let Some(unit) = prop.unit {
+                msgs.push(Message::new_retained(format!("{topic}/$unit"), unit));
+            }
+        }
+        msgs
+    }
+
+    /// The messages carrying the status of the device, given as a
+    /// [`DeviceStatus`] encoded with the [codec] of the scheme.
+    ///
+    /// [`DeviceStatus`]: crate::DeviceStatus
+    /// [codec]: TopicScheme::codec
+    pub fn status_messages(&self, kind: &str, id: &str, payload: Vec<u8>) -> Vec<Message> {
+        let TopicLayout::Homie = self.layout else {
+            let msg = Message::new_retained(self.status_topic(kind, id), payload)
+                .with_content_type(self.codec.content_type());
+            return vec![msg];
+        };
+
+        let base = format!("{}/{kind}", self.homie_base(kind, id));
+        let Ok(status) = serde_json::from_slice::<Value>(&payload) else {
+            // a malformed status still has to reach the subscribers, or the
+            // fault would be invisible.
+            return vec![Message::new_retained(format!("{base}/power"), payload)];
+        };
+        properties(kind)
+            .iter()
+            .filter_map(|prop| {
+                let value = match &status["status"][prop.field] {
+                    Value::Array(rgb) => rgb
+                        .iter()
+                        .map(Value::to_string)
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    Value::String(s) => s.clone(),
+                    Value::Null => return None,
+                    value => value.to_string(),
+                };
+                Some(Message::new_retained(
+                    format!("{base}/{}", prop.name),
+                    value,
+                ))
+            })
+            .collect()
+    }
+
+    /// Parse a command received on the [command filter] of the device.
+    ///
+    /// In the default layout the command is decoded by its content type, or
+    /// by the codec of the scheme if it has none.
+    ///
+    /// [command filter]: TopicScheme::command_filter
+    pub fn parse_command<C: DeserializeOwned>(&self, kind: &str, msg: &Message) -> Option<C> {
+        let TopicLayout::Homie = self.layout else {
+            let codec = self.codec.or_content_type(msg.content_type());
+            return codec.decode_command(msg.payload()).ok();
+        };
+
+        let name = msg.topic().strip_suffix("/set")?.rsplit('/').next()?;
+        let prop = properties(kind).iter().find(|p| p.name == name)?;
+        let payload = msg.payload_str();
+        let command = match (prop.command?, &*payload) {
+            ("power", "true") => json!({"cmd": "on"}),
+            ("power", "false") => json!({"cmd": "off"}),
+            ("color", rgb) => {
+                let rgb = rgb
+                    .split(',')
+                    .map(|c| c.trim().parse::<u8>().ok())
+                    .collect::<Option<Vec<_>>>()?;
+                json!({"cmd": "color", "args": rgb})
+            }
+            (cmd, value) => json!({"cmd": cmd, "args": value.parse::<u64>().ok()?}),
+        };
+        serde_json::from_value(command).ok()
+    }
+}
@@ -1,18 +1,41 @@
+pub mod broker;
 pub mod bulb;
 pub mod cli;
+pub mod clock;
+pub mod codec;
+pub mod connection;
+pub mod dashboard;
+pub mod device;
+pub mod discovery;
 pub mod error;
 pub mod fan;
+pub mod fault;
+pub mod history;
 pub mod home;
+pub mod layout;
+pub mod load;
+pub mod provision;
+pub mod recording;
+pub mod residents;
+pub mod rng;
+pub mod rules;
+pub mod scene;
+pub mod schedule;
+pub mod schema;
+pub mod store;
+pub mod transport;
 pub mod tv;
 
 use bulb::{Bulb, BulbStatus};
+use chrono::{DateTime, Utc};
 use fan::{Fan, FanStatus};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use tv::{TVStatus, TV};
 
 // NOTE: using tagged enum so that it can be consumed in a more meaningful way
 // by other clients outside the rust world.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "type", content = "status", rename_all = "snake_case")]
 pub enum DeviceStatus {
     Bulb(BulbStatus),
@@ -20,3 +43,47 @@ pub enum DeviceStatus {
     #[serde(rename = "tv")]
     TV(TVStatus),
 }
+
+impl DeviceStatus {
+    /// The kind of the device, as in its topics: `bulb`, `fan` or `tv`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Bulb(_) => "bulb",
+            Self::Fan(_) => "fan",
+            Self::TV(_) => "tv",
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        match self {
+            Self::Bulb(status) => &status.id,
+            Self::Fan(status) => &status.id,
+            Self::TV(status) => &status.id,
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        match self {
+            Self::Bulb(status) => status.is_on,
+            Self::Fan(status) => status.is_on,
+            Self::TV(status) => status.is_on,
+        }
+    }
+
+    /// The voltage of the bulbs and fans; tvs do not report theirs.
+    pub fn voltage(&self) -> Option<f32> {
+        match self {
+            Self::Bulb(status) => Some(status.voltage),
+            Self::Fan(status) => Some(status.voltage),
+            Self::TV(_) => None,
+        }
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            Self::Bulb(status) => status.timestamp,
+            Self::Fan(status) => status.timestamp,
+            Self::TV(status) => status.timestamp,
+        }
+    }
+}
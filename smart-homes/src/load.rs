@@ -0,0 +1,285 @@
+//! Load tests: how the broker and its clients keep up with many devices.
+//!
+//! Commands change the color of the bulbs, to a color of its own for every
+//! command, so that the status carrying the effect of a command can be told
+//! apart from the ones published before it. The round trip of a command is
+//! the time from sending it to receiving that status, which includes the wait
+//! for the next status of the bulb, every 5 simulated seconds.
+
+use crate::bulb::BulbCommand;
+use rand::Rng;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// A command waiting for its status.
+#[derive(Debug)]
+struct Pending {
+    color: (u8, u8, u8),
+    sent: Instant,
+}
+
+/// Keeps track of the devices and of the commands sent to their bulbs during
+/// a load test.
+#[derive(Debug)]
+pub struct LoadTracker {
+    started: Instant,
+    timeout: Duration,
+    /// How many devices are expected to become available.
+    devices: usize,
+    /// The devices that are available, e.g. `bulb/home/0`.
+    available: HashSet<String>,
+    all_available_at: Option<Instant>,
+    /// The ids of the bulbs that are available and have no command under
+    /// way.
+    idle: Vec<String>,
+    pending: HashMap<String, Pending>,
+    next_color: u32,
+    latencies: Vec<Duration>,
+    sent: usize,
+    statuses: usize,
+    errors: Errors,
+}
+
+/// What went wrong during a load test.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Errors {
+    /// Commands that could not be published.
+    pub send: usize,
+    /// Commands whose status did not come in time.
+    pub timeouts: usize,
+    /// Commands under way when their bulb became unavailable.
+    pub lost: usize,
+    /// Commands not sent because every available bulb had one under way.
+    pub skipped: usize,
+    /// Homes that could not be started, and devices that failed.
+    pub device_failures: usize,
+}
+
+impl LoadTracker {
+    /// Track a load test started at `now`, expecting `devices` devices.
+    pub fn new(devices: usize, timeout: Duration, now: Instant) -> Self {
+        Self {
+            started: now,
+            timeout,
+            devices,
+            available: HashSet::new(),
+            all_available_at: None,
+            idle: vec![],
+            pending: HashMap::new(),
+            next_color: 0,
+            latencies: vec![],
+            sent: 0,
+            statuses: 0,
+            errors: Errors::default(),
+        }
+    }
+
+    /// The device `id` of `kind` became available or unavailable.
+    pub fn availability(&mut self, kind: &str, id: &str, is_available: bool, now: Instant) {
+        let device = format!("{kind}/{id}");
+        if is_available {
+            if !self.available.insert(device) {
+                return;
+            }
+            if kind == "bulb" {
+                self.idle.push(id.into());
+            }
+            if self.all_available_at.is_none() && self.available.len() >= self.devices {
+                self.all_available_at = Some(now);
+            }
+        } else if self.available.remove(&device) && kind == "bulb" {
+            self.idle.retain(|idle| idle != id);
+            if self.pending.remove(id).is_some() {
+                self.errors.lost += 1;
+            }
+        }
+    }
+
+    /// Pick an idle bulb and the command to send it, sent at `now`.
+    pub fn send(&mut self, rng: &mut impl Rng, now: Instant) -> Option<(String, BulbCommand)> {
+        if self.idle.is_empty() {
+            self.errors.skipped += 1;
+            return None;
+        }
+        let id = self.idle.swap_remove(rng.gen_range(0..self.idle.len()));
+        // the bulbs start white, which comes last
+        let [_, r, g, b] = self.next_color.to_be_bytes();
+        self.next_color = (self.next_color + 1) % 0xff_ffff;
+        let color = (r, g, b);
+        self.pending
+            .insert(id.clone(), Pending { color, sent: now });
+        self.sent += 1;
+        Some((id, BulbCommand::Color(color)))
+    }
+
+    /// The command sent to bulb `id` could not be published.
+    pub fn send_failed(&mut self, id: &str) {
+        if self.pending.remove(id).is_some() {
+            self.errors.send += 1;
+            self.idle.push(id.into());
+        }
+    }
+
+    /// Bulb `id` reported `color` at `now`. Returns the round trip of the
+    /// command it answers, if any.
+    pub fn status(&mut self, id: &str, color: (u8, u8, u8), now: Instant) -> Option<Duration> {
+        self.statuses += 1;
+        if self.pending.get(id)?.color != color {
+            return None;
+        }
+        let pending = self.pending.remove(id)?;
+        self.idle.push(id.into());
+        let latency = now.saturating_duration_since(pending.sent);
+        if latency > self.timeout {
+            self.errors.timeouts += 1;
+            return None;
+        }
+        self.latencies.push(latency);
+        Some(latency)
+    }
+
+    /// Give up on the commands sent more than the timeout before `now`.
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now.saturating_duration_since(pending.sent) > timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            self.pending.remove(&id);
+            self.errors.timeouts += 1;
+            self.idle.push(id);
+        }
+    }
+
+    /// How many commands wait for their status.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn device_failed(&mut self) {
+        self.errors.device_failures += 1;
+    }
+
+    /// The report of the test, up to `now`. Commands still under way are
+    /// counted as timed out.
+    pub fn report(&self, now: Instant) -> LoadReport {
+        let secs = now.saturating_duration_since(self.started).as_secs_f64();
+        let per_sec = |n: usize| if secs > 0.0 { n as f64 / secs } else { 0.0 };
+        let mut errors = self.errors.clone();
+        errors.timeouts += self.pending.len();
+        LoadReport {
+            devices: self.devices,
+            available: self.available.len(),
+            ramp_up_secs: self
+                .all_available_at
+                .map(|at| at.saturating_duration_since(self.started).as_secs_f64()),
+            secs,
+            commands_sent: self.sent,
+            commands_per_sec: per_sec(self.sent),
+            answered: self.latencies.len(),
+            statuses: self.statuses,
+            statuses_per_sec: per_sec(self.statuses),
+            latency_ms: Percentiles::of(&self.latencies),
+            errors,
+        }
+    }
+}
+
+/// The round trips of the commands, in milliseconds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Percentiles {
+    pub min: f64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Percentiles {
+    /// The percentiles of `latencies`, by nearest rank. None without any.
+    pub fn of(latencies: &[Duration]) -> Option<Self> {
+        let mut sorted = latencies.to_vec();
+        sorted.sort();
+        let ms = |d: &Duration| d.as_secs_f64() * 1000.0;
+        let rank = |p: f64| {
+            let n = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+            ms(&sorted[n.clamp(1, sorted.len()) - 1])
+        };
+        Some(Self {
+            min: ms(sorted.first()?),
+            mean: sorted.iter().map(ms).sum::<f64>() / sorted.len() as f64,
+            p50: rank(50.0),
+            p90: rank(90.0),
+            p99: rank(99.0),
+            max: ms(sorted.last()?),
+        })
+    }
+}
+
+/// What a load test measured.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LoadReport {
+    /// How many devices were simulated.
+    pub devices: usize,
+    /// How many of them were available at the end.
+    pub available: usize,
+    /// How long it took for every device to be available, if they all were.
+    pub ramp_up_secs: Option<f64>,
+    pub secs: f64,
+    pub commands_sent: usize,
+    pub commands_per_sec: f64,
+    /// How many commands got their status in time.
+    pub answered: usize,
+    /// How many statuses of the bulbs were received.
+    pub statuses: usize,
+    pub statuses_per_sec: f64,
+    pub latency_ms: Option<Percentiles>,
+    pub errors: Errors,
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "devices   {} ({} available",
+            self.devices, self.available
+        )?;
+        match self.ramp_up_secs {
+            Some(secs) => writeln!(f, ", all after {secs:.1}s)")?,
+            None => writeln!(f, ")")?,
+        }
+        writeln!(f, "duration  {:.1}s", self.secs)?;
+        writeln!(
+            f,
+            "commands  {} sent ({:.1}/s), {} answered",
+            self.commands_sent, self.commands_per_sec, self.answered
+        )?;
+        writeln!(
+            f,
+            "statuses  {} received ({:.1}/s)",
+            self.statuses, self.statuses_per_sec
+        )?;
+        match &self.latency_ms {
+            Some(l) => writeln!(
+                f,
+                "latency   min {:.1}ms, mean {:.1}ms, p50 {:.1}ms, p90 {:.1}ms, p99 {:.1}ms, max {:.1}ms",
+                l.min, l.mean, l.p50, l.p90, l.p99, l.max
+            )?,
+            None => writeln!(f, "latency   no command answered")?,
+        }
+        let e = &self.errors;
+        writeln!(
+            f,
+            "errors    {} send, {} timeouts, {} lost, {} skipped, {} device failures",
+            e.send, e.timeouts, e.lost, e.skipped, e.device_failures
+        )
+    }
+}
@@ -1,29 +1,111 @@
 use clap::Parser;
-use paho_mqtt::{AsyncClient, QOS_0};
-use smart_homes::{bulb::Bulb, cli::Cli, error::Error, fan::Fan, home::Home, tv::TV, DeviceStatus};
-use tokio::{pin, select, task::JoinSet};
+use paho_mqtt::{AsyncClient, SubscribeOptions, QOS_0, QOS_1};
+use smart_homes::{
+    broker::BrokerConfig,
+    cli::{Cli, Command, ConnectionSharing, LoadArgs, ReportFormat},
+    clock::Clock,
+    codec::Codec,
+    connection::Connection,
+    dashboard::{self, Dashboard},
+    error::Error,
+    fault::{FaultConfig, FaultInjector},
+    history::HistoryStore,
+    layout::{DeviceTopic, TopicLayout, TopicScheme, KINDS},
+    load::{LoadReport, LoadTracker},
+    provision::{AdminCommand, Device, Provisioner},
+    recording::{self, Recorder},
+    residents::Residents,
+    rng::device_rng,
+    rules::{RuleEngine, RuleSet},
+    scene::{scene_filters, SceneManager, SCENE_TIMEOUT},
+    schedule::{ScheduleConfig, Scheduler},
+    schema::{self, Availability},
+    store::StateStore,
+    transport::{topic_matches, Message},
+    DeviceStatus,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    pin::pin,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{select, sync::mpsc, task::JoinSet};
 use tracing::{error, info, warn};
 use tracing_log::AsTrace;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 
-async fn watcher(broker_url: impl AsRef<str>) -> Result<(), Error> {
+async fn watcher(
+    broker: BrokerConfig,
+    topics: TopicScheme,
+    history: Option<HistoryStore>,
+    mut recorder: Option<Recorder>,
+    clock: Clock,
+) -> Result<(), Error> {
     info!("Starting watcher");
-    let mut client = AsyncClient::new(broker_url.as_ref()).unwrap();
-    client.connect(None).await?;
+    let client_id = topics.client_id("sim/watcher");
+    let mut client = AsyncClient::new(broker.create_options(&client_id))?;
+    // room for the retained messages of every topic when recording
+    let stream = client.get_stream(1024);
+    let connect_opts = broker.connect_options(&client_id)?.finalize();
+    client.connect(connect_opts).await?;
 
-    let _ = client
-        .subscribe_many_same_qos(
-            &[
-                "fan/home/+/status",
-                "tv/home/+/status",
-                "bulb/home/+/status",
-            ],
-            QOS_0,
-        )
-        .await?;
+    let layout = topics.layout();
+    let mut filters = match layout {
+        TopicLayout::Default => vec![
+            topics.status_topic("fan", "home/+"),
+            topics.status_topic("tv", "home/+"),
+            topics.status_topic("bulb", "home/+"),
+        ],
+        TopicLayout::Homie => vec![topics.topic("homie/+/+/+")],
+    };
+    if history.is_some() {
+        for kind in KINDS {
+            filters.push(topics.available_topic(kind, "home/+"));
+        }
+    }
+    if recorder.is_some() {
+        // everything goes into the recording, with the retained flag as
+        // it was published
+        let opts = SubscribeOptions::with_retain_as_published();
+        let _ = client
+            .subscribe_with_options(topics.topic("#"), QOS_0, opts, None)
+            .await?;
+    } else {
+        let _ = client.subscribe_many_same_qos(&filters, QOS_0).await?;
+    }
 
-    let stream = client.get_stream(16);
     while let Ok(Some(msg)) = stream.recv().await {
-        let Ok(status) = serde_json::from_slice(msg.payload()) else {
+        let msg = Message::from(msg);
+        if let Some(recorder) = &mut recorder {
+            if let Err(err) = recorder.record(&msg) {
+                error!(?err, "Failed to record a message");
+            }
+            if !filters
+                .iter()
+                .any(|filter| topic_matches(filter, msg.topic()))
+            {
+                continue;
+            }
+        }
+        if layout == TopicLayout::Homie {
+            // attributes of the nodes match the filter as well
+            if !msg.topic().contains('$') {
+                info!(topic = msg.topic(), value = &*msg.payload_str(), "property");
+            }
+            continue;
+        }
+        if let Some(history) = &history {
+            if let Err(err) = history.record(&topics, &msg, clock.now()) {
+                error!(?err, "Failed to record history");
+            }
+        }
+        if !msg.topic().ends_with("/status") {
+            continue;
+        }
+        let codec = topics.codec().or_content_type(msg.content_type());
+        let Ok(status) = codec.decode_status(msg.payload()) else {
             warn!("Failed to parse message: {:?}", msg);
             continue;
         };
@@ -43,54 +125,782 @@ async fn watcher(broker_url: impl AsRef<str>) -> Result<(), Error> {
     Ok(())
 }
 
+/// Drop and downsample the old history every minute.
+async fn apply_history_policy(history: HistoryStore, clock: Clock) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        let history = history.clone();
+        let now = clock.now();
+        // rewriting the day files blocks, and holds up recording meanwhile
+        match tokio::task::spawn_blocking(move || history.apply_policy(now)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => error!(?err, "Failed to apply the history policy"),
+            Err(err) => error!(?err, "Applying the history policy failed"),
+        }
+    }
+}
+
+/// Show the dashboard of the homes until it is quit.
+async fn dashboard(
+    broker: BrokerConfig,
+    topics: TopicScheme,
+    history: Option<HistoryStore>,
+    clock: Clock,
+) -> Result<(), Error> {
+    let client_id = topics.client_id("sim/dashboard");
+    let mut client = AsyncClient::new(broker.create_options(&client_id))?;
+    let stream = client.get_stream(64);
+    let connect_opts = broker.connect_options(&client_id)?.finalize();
+    client.connect(connect_opts).await?;
+
+    let _ = client
+        .subscribe_many_same_qos(&topics.device_filters(), QOS_1)
+        .await?;
+
+    let (incoming_tx, incoming_rx) = mpsc::channel(1024);
+    let recorded_topics = topics.clone();
+    tokio::spawn(async move {
+        while let Ok(Some(msg)) = stream.recv().await {
+            let msg = Message::from(msg);
+            if let Some(history) = &history {
+                // the logs are off, so a failure can only be skipped
+                let _ = history.record(&recorded_topics, &msg, clock.now());
+            }
+            if incoming_tx.send(msg).await.is_err() {
+                break;
+            }
+        }
+    });
+    let dashboard = Dashboard::new(topics);
+    let (outgoing_tx, mut outgoing_rx) = mpsc::channel::<Message>(16);
+    let publisher = client.clone();
+    tokio::spawn(async move {
+        while let Some(msg) = outgoing_rx.recv().await {
+            // there is nowhere to log to, and the dashboard shows whether
+            // the command had an effect anyway.
+            let _ = publisher.publish(msg.into()).await;
+        }
+    });
+
+    tokio::task::spawn_blocking(move || dashboard::run(dashboard, incoming_rx, outgoing_tx)).await?
+}
+
+/// Listen for fault configurations on the admin topic and apply them to all
+/// the devices.
+async fn fault_admin(
+    broker: BrokerConfig,
+    topics: TopicScheme,
+    injector: FaultInjector,
+) -> Result<(), Error> {
+    let conn = Connection::try_new_shared(topics.client_id("sim/admin"), broker)?
+        .with_will(topics.connection_will("sim/admin"));
+    conn.connect().await?;
+    let mut configs = conn.subscribe(topics.topic("sim/admin/faults")).await?;
+
+    while let Some(msg) = configs.recv().await {
+        match serde_json::from_slice::<FaultConfig>(msg.payload()) {
+            Ok(config) => {
+                info!(?config, "Changing faults");
+                injector.set_config(config);
+            }
+            Err(err) => warn!(?err, "Invalid fault configuration received"),
+        }
+    }
+    Err(Error::Disconnected)
+}
+
+/// Read the rules of `path`, if it changed since `modified`.
+fn reload_rules(path: &Path, modified: &mut Option<SystemTime>) -> anyhow::Result<Option<RuleSet>> {
+    let mtime = std::fs::metadata(path)?.modified()?;
+    if *modified == Some(mtime) {
+        return Ok(None);
+    }
+    *modified = Some(mtime);
+    Ok(Some(serde_json::from_slice(&std::fs::read(path)?)?))
+}
+
+/// Run the automation rules against the devices, taking new rules from the
+/// rules file and the admin topic.
+async fn rules(
+    broker: BrokerConfig,
+    topics: TopicScheme,
+    mut engine: RuleEngine,
+    rules_file: Option<PathBuf>,
+    mut modified: Option<SystemTime>,
+    clock: Clock,
+) -> Result<(), Error> {
+    let client_id = topics.client_id("sim/rules");
+    let mut client = AsyncClient::new(broker.create_options(&client_id))?;
+    let stream = client.get_stream(256);
+    let connect_opts = broker.connect_options(&client_id)?.finalize();
+    client.connect(connect_opts).await?;
+    let admin_topic = topics.topic("sim/admin/rules");
+    let mut filters = topics.device_filters();
+    filters.push(admin_topic.clone());
+    let _ = client.subscribe_many_same_qos(&filters, QOS_1).await?;
+
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        let firings = select! {
+            msg = stream.recv() => {
+                let Ok(Some(msg)) = msg else {
+                    return Err(Error::Disconnected);
+                };
+                let msg = Message::from(msg);
+                if msg.topic() == admin_topic {
+                    match serde_json::from_slice(msg.payload()).map_err(Error::from) {
+                        Ok(rules) => match engine.set_rules(rules) {
+                            Ok(()) => info!(count = engine.rules().len(), "Changing rules"),
+                            Err(err) => warn!(?err, "Invalid rules received"),
+                        },
+                        Err(err) => warn!(?err, "Invalid rules received"),
+                    }
+                    continue;
+                }
+                engine.handle(&msg, clock.now())
+            }
+            _ = interval.tick() => {
+                if let Some(path) = &rules_file {
+                    match reload_rules(path, &mut modified) {
+                        Ok(Some(rules)) => match engine.set_rules(rules) {
+                            Ok(()) => info!(?path, count = engine.rules().len(), "Loaded rules"),
+                            Err(err) => warn!(?path, ?err, "Invalid rules file"),
+                        },
+                        Ok(None) => {}
+                        Err(err) => warn!(?path, ?err, "Failed to read the rules file"),
+                    }
+                }
+                engine.tick(clock.now())
+            }
+        };
+        for firing in firings {
+            info!(
+                rule = firing.rule,
+                actions = firing.messages.len(),
+                "Rule fired"
+            );
+            for msg in firing.messages {
+                if let Err(err) = client.publish(msg.into()).await {
+                    error!(rule = firing.rule, ?err, "Failed to run a rule action");
+                }
+            }
+        }
+    }
+}
+
+/// Save and activate the scenes requested on their topics.
+async fn scenes(
+    broker: BrokerConfig,
+    topics: TopicScheme,
+    mut manager: SceneManager,
+) -> Result<(), Error> {
+    let client_id = topics.client_id("sim/scenes");
+    let mut client = AsyncClient::new(broker.create_options(&client_id))?;
+    let stream = client.get_stream(256);
+    let connect_opts = broker.connect_options(&client_id)?.finalize();
+    client.connect(connect_opts).await?;
+    let mut filters = topics.device_filters();
+    filters.extend(scene_filters(&topics));
+    let _ = client.subscribe_many_same_qos(&filters, QOS_1).await?;
+
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        let messages = select! {
+            msg = stream.recv() => {
+                let Ok(Some(msg)) = msg else {
+                    return Err(Error::Disconnected);
+                };
+                manager.handle(&Message::from(msg), Instant::now())
+            }
+            _ = interval.tick() => manager.tick(Instant::now()),
+        };
+        for msg in messages {
+            if let Err(err) = client.publish(msg.into()).await {
+                error!(?err, "Failed to publish for a scene");
+            }
+        }
+    }
+}
+
+/// Publish the next run of every schedule, retained for the HTTP API.
+async fn publish_next_runs(
+    client: &AsyncClient,
+    topics: &TopicScheme,
+    scheduler: &Scheduler,
+    clock: &Clock,
+) -> Result<(), Error> {
+    let payload = serde_json::to_vec(&scheduler.next_runs(clock.now()))?;
+    let msg = Message::new_retained(topics.topic("sim/schedules/next"), payload);
+    client.publish(msg.into()).await?;
+    Ok(())
+}
+
+/// Run the schedules on the simulated clock, taking new schedules from the
+/// admin topic and saving them to the schedule file.
+async fn schedules(
+    broker: BrokerConfig,
+    topics: TopicScheme,
+    mut scheduler: Scheduler,
+    schedule_file: Option<PathBuf>,
+    clock: Clock,
+) -> Result<(), Error> {
+    let client_id = topics.client_id("sim/schedules");
+    let mut client = AsyncClient::new(broker.create_options(&client_id))?;
+    let stream = client.get_stream(16);
+    let connect_opts = broker.connect_options(&client_id)?.finalize();
+    client.connect(connect_opts).await?;
+    let _ = client
+        .subscribe(topics.topic("sim/admin/schedules"), QOS_1)
+        .await?;
+    publish_next_runs(&client, &topics, &scheduler, &clock).await?;
+
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        select! {
+            msg = stream.recv() => {
+                let Ok(Some(msg)) = msg else {
+                    return Err(Error::Disconnected);
+                };
+                let config = serde_json::from_slice::<ScheduleConfig>(msg.payload())
+                    .map_err(Error::from)
+                    .and_then(|config| scheduler.set_config(config));
+                if let Err(err) = config {
+                    warn!(?err, "Invalid schedules received");
+                    continue;
+                }
+                info!(count = scheduler.config().schedules.len(), "Changing schedules");
+                if let Some(path) = &schedule_file {
+                    if let Err(err) = scheduler.config().save(path) {
+                        error!(?path, ?err, "Failed to save the schedules");
+                    }
+                }
+            }
+            _ = interval.tick() => {
+                let runs = scheduler.tick(clock.now());
+                if runs.is_empty() {
+                    continue;
+                }
+                for run in runs {
+                    info!(schedule = run.schedule, skipped = run.skipped, "Running schedule");
+                    for msg in run.messages {
+                        if let Err(err) = client.publish(msg.into()).await {
+                            error!(schedule = run.schedule, ?err, "Failed to run a schedule");
+                        }
+                    }
+                }
+            }
+        }
+        publish_next_runs(&client, &topics, &scheduler, &clock).await?;
+    }
+}
+
+/// Run the devices, adding, removing, configuring and pausing them as asked on
+/// the admin topic.
+async fn devices(
+    broker: BrokerConfig,
+    topics: TopicScheme,
+    mut provisioner: Provisioner,
+) -> Result<(), Error> {
+    let client_id = topics.client_id("sim/devices");
+    let mut client = AsyncClient::new(broker.create_options(&client_id))?;
+    let stream = client.get_stream(64);
+    let connect_opts = broker.connect_options(&client_id)?.finalize();
+    client.connect(connect_opts).await?;
+    let _ = client
+        .subscribe(topics.topic("sim/admin/command"), QOS_1)
+        .await?;
+    client.publish(provisioner.inventory().into()).await?;
+
+    loop {
+        let messages = select! {
+            msg = stream.recv() => {
+                let Ok(Some(msg)) = msg else {
+                    return Err(Error::Disconnected);
+                };
+                let command = match serde_json::from_slice::<AdminCommand>(msg.payload()) {
+                    Ok(command) => command,
+                    Err(err) => {
+                        warn!(?err, "Invalid admin command received");
+                        continue;
+                    }
+                };
+                match provisioner.handle(&command).await {
+                    Ok(messages) => {
+                        info!(?command, "Ran admin command");
+                        messages
+                    }
+                    Err(err) => {
+                        warn!(?command, ?err, "Failed to run admin command");
+                        continue;
+                    }
+                }
+            }
+            Some((device, res)) = provisioner.join_next() => {
+                if let Err(err) = res {
+                    error!(?err, device, "device failed");
+                }
+                vec![provisioner.inventory()]
+            }
+        };
+        for msg in messages {
+            if let Err(err) = client.publish(msg.into()).await {
+                error!(?err, "Failed to publish for an admin command");
+            }
+        }
+    }
+}
+
+/// Send the commands of the residents of the homes as the simulated time goes
+/// by.
+async fn residents(
+    broker: BrokerConfig,
+    topics: TopicScheme,
+    residents: Option<Residents>,
+    clock: Clock,
+) -> Result<(), Error> {
+    let Some(mut residents) = residents else {
+        // nobody lives in the homes, but the task must not end
+        return std::future::pending().await;
+    };
+    let client_id = topics.client_id("sim/residents");
+    let client = AsyncClient::new(broker.create_options(&client_id))?;
+    let connect_opts = broker.connect_options(&client_id)?.finalize();
+    client.connect(connect_opts).await?;
+
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        for msg in residents.tick(clock.now()) {
+            if let Err(err) = client.publish(msg.into()).await {
+                error!(?err, "Failed to send a command of the residents");
+            }
+        }
+    }
+}
+
+/// Start `num_houses` homes at the ramp rate and send commands to their bulbs
+/// at the command rate, measuring how long their statuses take to reflect
+/// them.
+async fn load_test(
+    broker: BrokerConfig,
+    topics: TopicScheme,
+    num_houses: u32,
+    mut provisioner: Provisioner,
+    args: &LoadArgs,
+    seed: Option<u64>,
+) -> anyhow::Result<LoadReport> {
+    let client_id = topics.client_id("sim/load");
+    let mut client = AsyncClient::new(broker.create_options(&client_id))?;
+    // every bulb reports its status every 5 simulated seconds
+    let stream = client.get_stream(65536);
+    let connect_opts = broker.connect_options(&client_id)?.finalize();
+    client.connect(connect_opts).await?;
+    let mut filters = vec![topics.status_topic("bulb", "home/+")];
+    for kind in KINDS {
+        filters.push(topics.available_topic(kind, "home/+"));
+    }
+    let _ = client.subscribe_many_same_qos(&filters, QOS_0).await?;
+
+    let timeout = args.timeout.to_std()?;
+    let started = tokio::time::Instant::now();
+    let sending_until = started + args.duration.to_std()?;
+    let mut tracker = LoadTracker::new(
+        num_houses as usize * KINDS.len(),
+        timeout,
+        started.into_std(),
+    );
+    let rng = device_rng(seed, "load");
+    let codec = topics.codec();
+    let mut ramp = tokio::time::interval(Duration::from_secs_f64(1.0 / args.ramp_rate));
+    let mut commands = tokio::time::interval(Duration::from_secs_f64(1.0 / args.command_rate));
+    let mut publishes = JoinSet::new();
+    let mut next_home = 0;
+    let mut sending = true;
+    info!(num_houses, "Starting the load test");
+
+    loop {
+        if !sending && tracker.pending() == 0 {
+            break;
+        }
+        select! {
+            _ = ramp.tick(), if sending && next_home < num_houses => {
+                let home = format!("home/{next_home}");
+                if let Err(err) = provisioner.add_home(&home, false).await {
+                    error!(?err, home, "Failed to start a home");
+                    tracker.device_failed();
+                }
+                next_home += 1;
+            }
+            _ = commands.tick(), if sending => {
+                let Some((id, command)) = tracker.send(&mut *rng.lock(), Instant::now()) else {
+                    continue;
+                };
+                let payload = codec.encode_command(&serde_json::to_value(command)?)?;
+                let msg = Message::new(topics.command_topic("bulb", &id), payload)
+                    .with_content_type(codec.content_type());
+                let token = client.publish(msg.into());
+                publishes.spawn(async move { token.await.map_err(|err| (id, err)) });
+            }
+            Some(res) = publishes.join_next() => {
+                if let Ok(Err((id, err))) = res {
+                    warn!(?err, id, "Failed to send a command");
+                    tracker.send_failed(&id);
+                }
+            }
+            Some((device, res)) = provisioner.join_next() => {
+                if let Err(err) = res {
+                    error!(?err, device, "device failed");
+                }
+                tracker.device_failed();
+            }
+            msg = stream.recv() => {
+                let Ok(Some(msg)) = msg else {
+                    return Err(Error::Disconnected.into());
+                };
+                let msg = Message::from(msg);
+                let now = Instant::now();
+                match topics.parse_topic(msg.topic()) {
+                    Some((kind, id, DeviceTopic::Available)) => {
+                        if let Ok(availability) = serde_json::from_slice::<Availability>(msg.payload()) {
+                            tracker.availability(kind, id, availability.is_available, now);
+                        }
+                    }
+                    Some((_, id, DeviceTopic::Status)) => {
+                        let codec = codec.or_content_type(msg.content_type());
+                        if let Ok(DeviceStatus::Bulb(status)) = codec.decode_status(msg.payload()) {
+                            tracker.status(id, status.color, now);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ = tokio::time::sleep_until(sending_until), if sending => {
+                // wait for the statuses of the last commands
+                sending = false;
+            }
+            _ = tokio::time::sleep_until(sending_until + timeout) => break,
+        }
+    }
+    tracker.expire(Instant::now());
+    Ok(tracker.report(Instant::now()))
+}
+
+/// Publish the messages of the recording in `path` again, `speed` times
+/// faster than they were recorded.
+async fn replay(
+    broker: BrokerConfig,
+    topics: TopicScheme,
+    path: &Path,
+    speed: f64,
+) -> anyhow::Result<()> {
+    let messages = recording::load(path)?;
+    info!(
+        messages = messages.len(),
+        speed,
+        "Replaying {}",
+        path.display()
+    );
+    let client_id = topics.client_id("sim/replay");
+    let client = AsyncClient::new(broker.create_options(&client_id))?;
+    let connect_opts = broker.connect_options(&client_id)?.finalize();
+    client.connect(connect_opts).await?;
+
+    let started = tokio::time::Instant::now();
+    for recorded in &messages {
+        tokio::time::sleep_until(started + recorded.due(speed)).await;
+        client.publish(recorded.message().into()).await?;
+    }
+    client.disconnect(None).await?;
+    info!("Replay done");
+    Ok(())
+}
+
+/// Print the JSON Schemas of the payloads, or write them to `out_dir`.
+fn write_schemas(out_dir: Option<&Path>) -> anyhow::Result<()> {
+    let schemas = schema::schemas();
+    let Some(out_dir) = out_dir else {
+        println!("{}", serde_json::to_string_pretty(&schemas)?);
+        return Ok(());
+    };
+    std::fs::create_dir_all(out_dir)?;
+    for (name, schema) in schemas {
+        let path = out_dir.join(format!("{name}.schema.json"));
+        std::fs::write(&path, serde_json::to_string_pretty(&schema)?)?;
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
-    tracing_subscriber::fmt()
-        .with_level(true)
-        .with_max_level(cli.verbosity.log_level_filter().as_trace())
-        .pretty()
-        .init();
+    if let Some(Command::Schema { out_dir }) = &cli.command {
+        return write_schemas(out_dir.as_deref());
+    }
+    // the logs would be drawn over the dashboard
+    if !cli.dashboard {
+        // the report of a load test is printed alone on stdout
+        let writer = match cli.command {
+            Some(Command::Load(_)) => BoxMakeWriter::new(std::io::stderr),
+            _ => BoxMakeWriter::new(std::io::stdout),
+        };
+        tracing_subscriber::fmt()
+            .with_writer(writer)
+            .with_level(true)
+            .with_max_level(cli.verbosity.log_level_filter().as_trace())
+            .pretty()
+            .init();
+    }
+    let mut topics = TopicScheme::new(cli.topic_layout).with_codec(cli.codec);
+    if let Some(prefix) = &cli.topic_prefix {
+        topics = topics.with_prefix(prefix);
+    }
+    if let Some(Command::Replay { file, speed }) = &cli.command {
+        return replay(cli.broker.config(), topics, file, *speed).await;
+    }
+
+    if cli.ha_discovery.is_some() && cli.topic_layout != TopicLayout::Default {
+        anyhow::bail!("--ha-discovery needs the default topic layout");
+    }
+    if cli.dashboard && cli.topic_layout != TopicLayout::Default {
+        anyhow::bail!("--dashboard needs the default topic layout");
+    }
+    if cli.history.history_dir.is_some() && cli.topic_layout != TopicLayout::Default {
+        anyhow::bail!("--history-dir needs the default topic layout");
+    }
+    if cli.rules_file.is_some() && cli.topic_layout != TopicLayout::Default {
+        anyhow::bail!("--rules-file needs the default topic layout");
+    }
+    if cli.residents && cli.topic_layout != TopicLayout::Default {
+        anyhow::bail!("--residents needs the default topic layout");
+    }
+    if matches!(cli.command, Some(Command::Load(_))) && cli.topic_layout != TopicLayout::Default {
+        anyhow::bail!("load needs the default topic layout");
+    }
+    if cli.ha_discovery.is_some() && cli.codec != Codec::Json {
+        anyhow::bail!("--ha-discovery needs the json codec");
+    }
+
+    let broker = cli.broker.config();
+    let store = cli.state_file.map(StateStore::open).transpose()?;
+    let history = cli.history.open()?;
+    let recorder = cli.record.as_ref().map(Recorder::create).transpose()?;
+    let clock = Clock::scaled(cli.time_scale);
+
+    let mut fault_config = match &cli.fault_file {
+        Some(path) => serde_json::from_slice(&std::fs::read(path)?)?,
+        None => FaultConfig::default(),
+    };
+    for spec in &cli.faults {
+        fault_config.add(spec).map_err(anyhow::Error::msg)?;
+    }
+    let injector = FaultInjector::new(fault_config);
+    let mut rules_modified = None;
+    let rule_set = match &cli.rules_file {
+        Some(path) => reload_rules(path, &mut rules_modified)?.unwrap_or_default(),
+        None => RuleSet::default(),
+    };
+    let engine = RuleEngine::new(topics.clone(), rule_set)?;
+    let schedule_config = match &cli.schedule_file {
+        Some(path) => ScheduleConfig::load(path)?,
+        None => ScheduleConfig::default(),
+    };
+    let households = cli.residents.then(|| {
+        (0..cli.num_houses).fold(Residents::new(topics.clone(), cli.seed), |residents, i| {
+            let home = format!("home/{i}");
+            let location = schedule_config.location(&home).clone();
+            residents.with_home(home, location)
+        })
+    });
+    let scheduler = Scheduler::new(topics.clone(), schedule_config)?;
+    // tells that the devices of a shared connection are unavailable when it
+    // drops
+    let supervisor = Connection::try_new_shared(topics.client_id("sim/supervisor"), &broker)?
+        .with_will(topics.connection_will("sim/supervisor"));
+    let process_conn = match cli.share_connection {
+        ConnectionSharing::Process => Some(
+            Connection::try_new_shared(topics.client_id("simulator"), &broker)?
+                .with_will(topics.connection_will("simulator"))
+                .with_supervisor(supervisor.clone()),
+        ),
+        _ => None,
+    };
+
+    // the connections of the homes, shared by their devices
+    let home_conns = Mutex::new(HashMap::<String, Connection>::new());
+    let build_device = {
+        let (broker, topics, store) = (broker.clone(), topics.clone(), store.clone());
+        let (clock, injector) = (clock.clone(), injector.clone());
+        let (sharing, discovery, seed) = (cli.share_connection, cli.ha_discovery.clone(), cli.seed);
+        move |kind: &str, id: &str| -> Result<Device, Error> {
+            let conn = match sharing {
+                ConnectionSharing::Device => None,
+                ConnectionSharing::Home => {
+                    let mut conns = home_conns.lock().unwrap();
+                    if !conns.contains_key(id) {
+                        let conn = Connection::try_new_shared(topics.client_id(id), &broker)?
+                            .with_will(topics.connection_will(id))
+                            .with_supervisor(supervisor.clone());
+                        conns.insert(id.into(), conn);
+                    }
+                    conns.get(id).cloned()
+                }
+                ConnectionSharing::Process => process_conn.clone(),
+            };
+            let mut device = match conn {
+                Some(conn) => Device::new(kind, id, conn),
+                None => Device::try_new(kind, id, &broker, &topics)?,
+            };
+            if let Some(store) = &store {
+                device = device.with_store(store.clone());
+            }
+            device = device
+                .with_clock(clock.clone())
+                .with_faults(injector.clone());
+            device = device.with_topics(topics.clone());
+            if let Some(prefix) = &discovery {
+                device = device.with_discovery(prefix);
+            }
+            if let Some(seed) = seed {
+                device = device.with_seed(seed);
+            }
+            Ok(device)
+        }
+    };
+    let mut provisioner = Provisioner::new(topics.clone(), injector.clone(), build_device);
 
-    let broker_url = cli.broker_url;
+    if let Some(Command::Load(args)) = &cli.command {
+        let report = load_test(
+            broker.clone(),
+            topics.clone(),
+            cli.num_houses,
+            provisioner,
+            args,
+            cli.seed,
+        )
+        .await?;
+        match args.output {
+            ReportFormat::Text => print!("{report}"),
+            ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+        }
+        return Ok(());
+    }
 
     // simulate 10 houses
-    let mut join_set = JoinSet::new();
     for i in 0..cli.num_houses {
-        let broker_url = broker_url.clone();
-        join_set.spawn(async move {
-            Home::new(
-                format!("home-{i}"),
-                Bulb::try_new(format!("home/{}", i), &broker_url).unwrap(),
-                Fan::try_new(format!("home/{}", i), &broker_url).unwrap(),
-                TV::try_new(format!("home/{}", i), &broker_url).unwrap(),
-            )
-            .handle_incoming()
-            .await
-        });
-    }
-    pin!(
-        let join_fut = join_set.join_all();
-    );
+        // with a seed, connect the homes one after the other, so that they
+        // reach the broker in the same order in every run.
+        provisioner
+            .add_home(&format!("home/{i}"), cli.seed.is_some())
+            .await?;
+    }
+    let mut devices_handle = tokio::spawn(devices(broker.clone(), topics.clone(), provisioner));
 
-    let mut watcher_handle = tokio::spawn(watcher(broker_url));
+    let mut admin_handle = tokio::spawn(fault_admin(broker.clone(), topics.clone(), injector));
+    let mut rules_handle = tokio::spawn(rules(
+        broker.clone(),
+        topics.clone(),
+        engine,
+        cli.rules_file,
+        rules_modified,
+        clock.clone(),
+    ));
+    if let Some(history) = history.clone() {
+        tokio::spawn(apply_history_policy(history, clock.clone()));
+    }
+    // the devices report their state every 5 simulated seconds
+    let scene_timeout = SCENE_TIMEOUT.div_f64(cli.time_scale);
+    let manager = SceneManager::new(topics.clone()).with_timeout(scene_timeout);
+    let mut scenes_handle = tokio::spawn(scenes(broker.clone(), topics.clone(), manager));
+    let mut schedules_handle = tokio::spawn(schedules(
+        broker.clone(),
+        topics.clone(),
+        scheduler,
+        cli.schedule_file,
+        clock.clone(),
+    ));
+    let mut residents_handle = tokio::spawn(residents(
+        broker.clone(),
+        topics.clone(),
+        households,
+        clock.clone(),
+    ));
+    let mut watcher_handle = if cli.dashboard {
+        tokio::spawn(dashboard(broker, topics, history, clock))
+    } else {
+        tokio::spawn(watcher(broker, topics, history, recorder, clock))
+    };
 
-    loop {
-        select! {
-            res = &mut watcher_handle => {
-                let res = res?;
-                if let Err(ref err) = res {
-                    error!(?err, "watcher failed");
-                    res?
-                }
-            },
-            results = &mut join_fut => {
-                for res in results {
-                    if let Err(err) = res {
-                        error!(?err, "device failed");
+    // stopped by Ctrl-C, rather than killed, so that the state store is
+    // saved one last time
+    let mut interrupted = pin!(tokio::signal::ctrl_c());
+    let res: anyhow::Result<()> = async {
+        loop {
+            select! {
+                res = &mut interrupted => {
+                    res?;
+                    info!("Interrupted, stopping");
+                    return Ok(());
+                },
+                res = &mut watcher_handle => {
+                    let res = res?;
+                    if let Err(ref err) = res {
+                        error!(?err, "watcher failed");
+                        res?
                     }
-                }
+                    // the dashboard was quit, or the watcher lost its connection
+                    return Ok(());
+                },
+                res = &mut admin_handle => {
+                    let res = res?;
+                    if let Err(ref err) = res {
+                        error!(?err, "fault admin failed");
+                        res?
+                    }
+                },
+                res = &mut rules_handle => {
+                    let res = res?;
+                    if let Err(ref err) = res {
+                        error!(?err, "rules failed");
+                        res?
+                    }
+                },
+                res = &mut scenes_handle => {
+                    let res = res?;
+                    if let Err(ref err) = res {
+                        error!(?err, "scenes failed");
+                        res?
+                    }
+                },
+                res = &mut schedules_handle => {
+                    let res = res?;
+                    if let Err(ref err) = res {
+                        error!(?err, "schedules failed");
+                        res?
+                    }
+                },
+                res = &mut residents_handle => {
+                    let res = res?;
+                    if let Err(ref err) = res {
+                        error!(?err, "residents failed");
+                        res?
+                    }
+                },
+                res = &mut devices_handle => {
+                    let res = res?;
+                    if let Err(ref err) = res {
+                        error!(?err, "devices failed");
+                        res?
+                    }
+                },
             }
         }
     }
+    .await;
+    if let Some(store) = &store {
+        if let Err(err) = store.flush().await {
+            error!(?err, "Failed to save the device states");
+        }
+    }
+    res
 }
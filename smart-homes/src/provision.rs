@@ -0,0 +1,415 @@
+//! Runtime provisioning: homes and devices are added, removed, configured,
+//! paused and resumed while the simulator runs, by commands received on
+//! `sim/admin/command`.
+//!
+//! Every device runs in a task of the [`Provisioner`], which removes a device
+//! by aborting its task and unsubscribing from its commands.
+
+use crate::{
+    bulb::Bulb,
+    clock::Clock,
+    connection::Connection,
+    error::Error,
+    fan::Fan,
+    fault::{FaultInjector, Faults},
+    layout::{TopicScheme, KINDS},
+    store::StateStore,
+    transport::Message,
+    tv::TV,
+};
+use educe::Educe;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tokio::task::{AbortHandle, JoinSet};
+use tracing::warn;
+
+/// A command to change the devices of the simulator, e.g.
+/// `{"cmd": "pause", "target": "home/3"}`.
+///
+/// The target is a device, e.g. `fan/home/3`, or a home, e.g. `home/3`, for
+/// its bulb, fan and tv.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum AdminCommand {
+    /// Start the devices that are not running yet.
+    Add {
+        target: String,
+    },
+    /// Stop the devices, which are then told unavailable.
+    Remove {
+        target: String,
+    },
+    /// Set the faults of the devices, or let them have those of their kind
+    /// again without any.
+    Configure {
+        target: String,
+        faults: Option<Faults>,
+    },
+    /// Have the devices ignore commands and publish nothing, as if they were
+    /// offline, until they are resumed.
+    Pause {
+        target: String,
+    },
+    Resume {
+        target: String,
+    },
+}
+
+impl AdminCommand {
+    pub fn target(&self) -> &str {
+        match self {
+            Self::Add { target }
+            | Self::Remove { target }
+            | Self::Configure { target, .. }
+            | Self::Pause { target }
+            | Self::Resume { target } => target,
+        }
+    }
+
+    /// The devices the command is about, by kind and id.
+    pub fn devices(&self) -> Result<Vec<(&'static str, String)>, Error> {
+        let target = self.target();
+        let invalid = || {
+            Error::InvalidAdminCommand(format!(
+                "{target} is not a device or a home, e.g. bulb/home/0 or home/0"
+            ))
+        };
+        if target.contains(['+', '#']) || target.split('/').any(str::is_empty) {
+            return Err(invalid());
+        }
+        let kind = target
+            .split_once('/')
+            .and_then(|(kind, id)| Some((*KINDS.iter().find(|k| **k == kind)?, id)));
+        match kind {
+            Some((kind, id)) => Ok(vec![(kind, id.into())]),
+            None if KINDS.contains(&target) => Err(invalid()),
+            None => Ok(KINDS.iter().map(|kind| (*kind, target.into())).collect()),
+        }
+    }
+}
+
+/// A running device, as published on `sim/devices`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    /// The kind and id of the device, e.g. `bulb/home/0`.
+    pub device: String,
+    pub paused: bool,
+}
+
+/// A device of any kind.
+#[derive(Debug)]
+pub enum Device {
+    Bulb(Bulb),
+    Fan(Fan),
+    TV(TV),
+}
+
+/// Apply the same expression to the device, whatever its kind.
+macro_rules! each {
+    ($device:expr, $d:ident => $e:expr) => {
+        match $device {
+            Device::Bulb($d) => Device::Bulb($e),
+            Device::Fan($d) => Device::Fan($e),
+            Device::TV($d) => Device::TV($e),
+        }
+    };
+}
+
+impl Device {
+    /// Create a device of `kind` that talks to the broker over `conn`.
+    pub fn new(kind: &str, id: &str, conn: Connection) -> Self {
+        match kind {
+            "bulb" => Self::Bulb(Bulb::new(id, conn)),
+            "fan" => Self::Fan(Fan::new(id, conn)),
+            _ => Self::TV(TV::new(id, conn)),
+        }
+    }
+
+    /// Create a device of `kind` with a connection of its own to `broker`,
+    /// talking through `topics`.
+    #[cfg(feature = "paho")]
+    pub fn try_new(
+        kind: &str,
+        id: &str,
+        broker: &crate::broker::BrokerConfig,
+        topics: &TopicScheme,
+    ) -> Result<Self, Error> {
+        let topics = topics.clone();
+        Ok(match kind {
+            "bulb" => Self::Bulb(Bulb::try_new_with_topics(id, broker, topics)?),
+            "fan" => Self::Fan(Fan::try_new_with_topics(id, broker, topics)?),
+            _ => Self::TV(TV::try_new_with_topics(id, broker, topics)?),
+        })
+    }
+
+    pub fn with_store(self, store: StateStore) -> Self {
+        each!(self, d => d.with_store(store))
+    }
+
+    pub fn with_seed(self, seed: u64) -> Self {
+        each!(self, d => d.with_seed(seed))
+    }
+
+    pub fn with_clock(self, clock: Clock) -> Self {
+        each!(self, d => d.with_clock(clock))
+    }
+
+    pub fn with_faults(self, injector: FaultInjector) -> Self {
+        each!(self, d => d.with_faults(injector))
+    }
+
+    pub fn with_discovery(self, prefix: impl Into<String>) -> Self {
+        each!(self, d => d.with_discovery(prefix))
+    }
+
+    pub fn with_topics(self, topics: TopicScheme) -> Self {
+        each!(self, d => d.with_topics(topics))
+    }
+
+    /// The connection of the device, which may be shared with other devices.
+    pub fn connection(&self) -> &Connection {
+        match self {
+            Self::Bulb(bulb) => bulb.connection(),
+            Self::Fan(fan) => fan.connection(),
+            Self::TV(tv) => tv.connection(),
+        }
+    }
+
+    /// The topic filter the device gets its commands on.
+    pub fn command_filter(&self) -> String {
+        match self {
+            Self::Bulb(bulb) => bulb.command_filter(),
+            Self::Fan(fan) => fan.command_filter(),
+            Self::TV(tv) => tv.command_filter(),
+        }
+    }
+
+    pub async fn connect(&mut self) -> Result<(), Error> {
+        match self {
+            Self::Bulb(bulb) => bulb.connect().await,
+            Self::Fan(fan) => fan.connect().await,
+            Self::TV(tv) => tv.connect().await,
+        }
+    }
+
+    /// Publish the status and process the commands of a connected device
+    /// until something fails.
+    pub async fn run(self) -> Result<(), Error> {
+        match self {
+            Self::Bulb(mut bulb) => bulb.run().await,
+            Self::Fan(mut fan) => fan.run().await,
+            Self::TV(mut tv) => tv.run().await,
+        }
+    }
+}
+
+/// Builds the device of a kind and id, e.g. `bulb` and `home/0`.
+pub type DeviceBuilder = Box<dyn Fn(&str, &str) -> Result<Device, Error> + Send>;
+
+/// A device running in a task of the [`Provisioner`].
+#[derive(Debug)]
+struct Running {
+    task: AbortHandle,
+    /// What the device subscribed to, which is left behind when its task is
+    /// aborted.
+    conn: Connection,
+    command_filter: String,
+}
+
+/// Runs the devices of the simulator, and adds and removes them on demand.
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct Provisioner {
+    topics: TopicScheme,
+    injector: FaultInjector,
+    #[educe(Debug(ignore))]
+    build: DeviceBuilder,
+    #[educe(Debug(ignore))]
+    tasks: JoinSet<Result<(), Error>>,
+    /// The running devices, e.g. `bulb/home/0`.
+    devices: BTreeMap<String, Running>,
+}
+
+impl Provisioner {
+    /// Manage devices made by `build`, whose faults and pauses are those of
+    /// `injector`.
+    pub fn new(
+        topics: TopicScheme,
+        injector: FaultInjector,
+        build: impl Fn(&str, &str) -> Result<Device, Error> + Send + 'static,
+    ) -> Self {
+        Self {
+            topics,
+            injector,
+            build: Box::new(build),
+            tasks: JoinSet::new(),
+            devices: BTreeMap::new(),
+        }
+    }
+
+    /// Start the device `id` of `kind`, unless it is running already. With
+    /// `in_order`, it is connected before this returns, so that devices
+    /// reach the broker in the order they are added.
+    pub async fn add(&mut self, kind: &str, id: &str, in_order: bool) -> Result<bool, Error> {
+        let name = format!("{kind}/{id}");
+        if self.devices.contains_key(&name) {
+            return Ok(false);
+        }
+        let mut device = (self.build)(kind, id)?;
+        if in_order {
+            device.connect().await?;
+        }
+        let (conn, command_filter) = (device.connection().clone(), device.command_filter());
+        let task = self.tasks.spawn(async move {
+            if !in_order {
+                device.connect().await?;
+            }
+            device.run().await
+        });
+        self.devices.insert(
+            name,
+            Running {
+                task,
+                conn,
+                command_filter,
+            },
+        );
+        Ok(true)
+    }
+
+    /// Start the bulb, fan and tv of the home `id`.
+    pub async fn add_home(&mut self, id: &str, in_order: bool) -> Result<(), Error> {
+        let devices = KINDS.iter().map(|kind| (*kind, id.to_string()));
+        self.add_all(devices, in_order).await
+    }
+
+    /// Start the `devices`, or none of them if one fails to start: those
+    /// started already are stopped again.
+    async fn add_all(
+        &mut self,
+        devices: impl IntoIterator<Item = (&'static str, String)>,
+        in_order: bool,
+    ) -> Result<(), Error> {
+        let mut added = vec![];
+        for (kind, id) in devices {
+            match self.add(kind, &id, in_order).await {
+                Ok(true) => added.push((kind, id)),
+                Ok(false) => {}
+                Err(err) => {
+                    for (kind, id) in added {
+                        self.remove(kind, &id).await;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop the device `id` of `kind`. Returns whether it was running.
+    pub async fn remove(&mut self, kind: &str, id: &str) -> bool {
+        let name = format!("{kind}/{id}");
+        let Some(running) = self.devices.remove(&name) else {
+            return false;
+        };
+        running.task.abort();
+        // the connection may be shared with devices that keep running, and
+        // the device cannot clean up after itself once aborted
+        let conn = &running.conn;
+        if let Err(err) = conn.unsubscribe(&running.command_filter).await {
+            warn!(name, ?err, "Failed to unsubscribe a removed device");
+        }
+        conn.remove_device_will(self.topics.will(kind, id).topic());
+        self.injector.set_paused(&name, false);
+        true
+    }
+
+    pub fn contains(&self, kind: &str, id: &str) -> bool {
+        self.devices.contains_key(&format!("{kind}/{id}"))
+    }
+
+    /// The running devices, in order.
+    pub fn devices(&self) -> Vec<DeviceInfo> {
+        self.devices
+            .keys()
+            .map(|device| DeviceInfo {
+                device: device.clone(),
+                paused: self.injector.is_paused(device),
+            })
+            .collect()
+    }
+
+    /// The running devices, retained for the HTTP API.
+    pub fn inventory(&self) -> Message {
+        let payload = serde_json::to_vec(&self.devices()).expect("devices are serializable");
+        Message::new_retained(self.topics.topic("sim/devices"), payload)
+    }
+
+    /// Run an admin command. Returns the messages to publish: the
+    /// availability of the devices removed, paused or resumed, and the new
+    /// inventory.
+    pub async fn handle(&mut self, command: &AdminCommand) -> Result<Vec<Message>, Error> {
+        let devices = command.devices()?;
+        if let AdminCommand::Add { .. } = command {
+            self.add_all(devices, false).await?;
+            return Ok(vec![self.inventory()]);
+        }
+        if !devices.iter().any(|(kind, id)| self.contains(kind, id)) {
+            return Err(Error::InvalidAdminCommand(format!(
+                "no device is running at {}",
+                command.target()
+            )));
+        }
+
+        let mut messages = vec![];
+        for (kind, id) in devices {
+            let name = format!("{kind}/{id}");
+            match command {
+                // added above
+                AdminCommand::Add { .. } => {}
+                // the devices of a home that are not running
+                _ if !self.contains(kind, &id) => {}
+                AdminCommand::Remove { .. } => {
+                    self.remove(kind, &id).await;
+                    messages.push(self.topics.availability(kind, &id, false));
+                }
+                AdminCommand::Configure { faults, .. } => {
+                    self.injector.set_device_faults(&name, faults.clone());
+                }
+                AdminCommand::Pause { .. } => {
+                    self.injector.set_paused(&name, true);
+                    messages.push(self.topics.availability(kind, &id, false));
+                }
+                AdminCommand::Resume { .. } => {
+                    self.injector.set_paused(&name, false);
+                    messages.push(self.topics.availability(kind, &id, true));
+                }
+            }
+        }
+        messages.push(self.inventory());
+        Ok(messages)
+    }
+
+    /// Wait for a device to stop by itself, e.g. because it lost its
+    /// connection, and tell which. Resolves to None at once if no device
+    /// runs.
+    pub async fn join_next(&mut self) -> Option<(String, Result<(), Error>)> {
+        loop {
+            let (task, res) = match self.tasks.join_next_with_id().await? {
+                Ok((task, res)) => (task, res),
+                Err(err) => (err.id(), Err(err.into())),
+            };
+            // the removed devices are not running any more
+            let Some(name) = self
+                .devices
+                .iter()
+                .find(|(_, running)| running.task.id() == task)
+                .map(|(name, _)| name.clone())
+            else {
+                continue;
+            };
+            self.devices.remove(&name);
+            return Some((name, res));
+        }
+    }
+}
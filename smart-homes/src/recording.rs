@@ -0,0 +1,141 @@
+//! Recordings of the traffic on the broker, to replay an incident locally.
+//!
+//! Messages are written as JSON lines with the time elapsed since the
+//! recording started, e.g.
+//! `{"offset_ms":5012,"topic":"bulb/home/0/status","retained":true,"content_type":"application/json","payload":"{…}"}`.
+//! Payloads that are not UTF-8, e.g. CBOR statuses, are kept in
+//! `payload_base64` instead. The other MQTT 5 properties of the messages,
+//! e.g. their response topic or user properties, are not recorded.
+
+use crate::{error::Error, transport::Message};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// The latest a message can be seen after a recording started, a year. Later
+/// offsets, which only an edited recording has, would be out of the range of
+/// a replay.
+const MAX_OFFSET_MS: u64 = 366 * 24 * 60 * 60 * 1000;
+
+/// A message as it was seen on the broker.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    /// When the message was seen, in milliseconds since the recording
+    /// started.
+    pub offset_ms: u64,
+    pub topic: String,
+    /// Whether the message was published retained, or was the retained
+    /// message of the topic when the recording started.
+    #[serde(default)]
+    pub retained: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(flatten)]
+    pub payload: Payload,
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Payload {
+    #[serde(rename = "payload")]
+    Text(String),
+    #[serde(rename = "payload_base64")]
+    Binary(#[serde_as(as = "serde_with::base64::Base64")] Vec<u8>),
+}
+
+impl RecordedMessage {
+    pub fn new(offset: Duration, msg: &Message) -> Self {
+        let payload = match std::str::from_utf8(msg.payload()) {
+            Ok(text) => Payload::Text(text.into()),
+            Err(_) => Payload::Binary(msg.payload().to_vec()),
+        };
+        Self {
+            offset_ms: offset.as_millis() as u64,
+            topic: msg.topic().into(),
+            retained: msg.retained(),
+            content_type: msg.content_type().map(Into::into),
+            payload,
+        }
+    }
+
+    /// How long after the start of a replay at `speed` times the original
+    /// pace the message is due.
+    pub fn due(&self, speed: f64) -> Duration {
+        Duration::from_millis(self.offset_ms).div_f64(speed)
+    }
+
+    /// The message to publish again.
+    pub fn message(&self) -> Message {
+        let payload = match &self.payload {
+            Payload::Text(text) => text.as_bytes().to_vec(),
+            Payload::Binary(bytes) => bytes.clone(),
+        };
+        let msg = match self.retained {
+            true => Message::new_retained(&self.topic, payload),
+            false => Message::new(&self.topic, payload),
+        };
+        match &self.content_type {
+            Some(content_type) => msg.with_content_type(content_type),
+            None => msg,
+        }
+    }
+}
+
+/// Writes the messages seen to a recording, as they come.
+#[derive(Debug)]
+pub struct Recorder {
+    file: File,
+    started: Instant,
+}
+
+impl Recorder {
+    /// Start a recording in `path`, replacing the file if it exists.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(Self {
+            file: File::create(path).map_err(Error::RecordingError)?,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, msg: &Message) -> Result<(), Error> {
+        let recorded = RecordedMessage::new(self.started.elapsed(), msg);
+        let mut line = serde_json::to_vec(&recorded)?;
+        line.push(b'\n');
+        // a line at a time, so that a recording cut short can still be read
+        self.file.write_all(&line).map_err(Error::RecordingError)?;
+        Ok(())
+    }
+}
+
+/// Read the messages of a recording, in the order they were seen.
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<RecordedMessage>, Error> {
+    let mut messages: Vec<RecordedMessage> = vec![];
+    for (n, line) in BufReader::new(File::open(path).map_err(Error::RecordingError)?)
+        .lines()
+        .enumerate()
+    {
+        let line = line.map_err(Error::RecordingError)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<RecordedMessage>(&line) {
+            Ok(msg) if msg.offset_ms > MAX_OFFSET_MS => {
+                warn!(
+                    line = n + 1,
+                    msg.offset_ms, "Skipping message recorded too late"
+                );
+            }
+            Ok(msg) => messages.push(msg),
+            // most likely the last line of a recording that was killed
+            Err(err) => warn!(line = n + 1, ?err, "Skipping unreadable recorded message"),
+        }
+    }
+    // edited recordings may not be in order any more
+    messages.sort_by_key(|msg| msg.offset_ms);
+    Ok(messages)
+}
@@ -0,0 +1,254 @@
+//! Residents: the people living in the homes, who wake up, go to work, watch
+//! the tv in the evening and turn the fan on when it is warm, sending their
+//! commands to the devices like any other client.
+//!
+//! The day of a home is drawn from the seed, the home and the date, so that a
+//! seeded run replays the same days however fast the clock goes.
+
+use crate::{
+    bulb::BulbCommand,
+    fan::FanCommand,
+    layout::TopicScheme,
+    rng::device_rng,
+    schedule::{sun_time, Location, SunEvent},
+    transport::Message,
+    tv::TVCommand,
+};
+use chrono::{
+    DateTime, Datelike, NaiveDate, NaiveTime, TimeDelta, TimeZone, Timelike, Utc, Weekday,
+};
+use rand::Rng;
+use serde::Serialize;
+use serde_json::Value;
+use std::f64::consts::PI;
+
+/// The hour of the warmest temperature of the day.
+const PEAK_HOUR: f64 = 15.0;
+/// How much cooler the night is than the afternoon, in °C.
+const DAILY_SWING: f64 = 10.0;
+/// The temperature the residents turn the fan on at, in °C. They turn it up
+/// every 2°C above.
+const WARM: f64 = 26.0;
+
+/// What the residents of a home do on a given day, in local time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Day {
+    pub wake_up: NaiveTime,
+    /// When nobody is home, e.g. at work or out for the afternoon.
+    pub away: Option<(NaiveTime, NaiveTime)>,
+    /// When the tv is on, and on which channel.
+    pub tv: (NaiveTime, NaiveTime),
+    pub channel: u16,
+    pub bedtime: NaiveTime,
+    /// The warmest temperature of the day, in °C.
+    pub peak_temperature: f64,
+}
+
+/// Draw a time between `from` and `to` minutes past midnight.
+fn time_between(rng: &mut impl Rng, from: u32, to: u32) -> NaiveTime {
+    let minutes = rng.gen_range(from..=to);
+    NaiveTime::from_hms_opt(minutes / 60, minutes % 60, 0).unwrap_or(NaiveTime::MIN)
+}
+
+impl Day {
+    /// Draw the day of `home` on `date`. Without a seed, every run has days
+    /// of its own.
+    pub fn draw(seed: Option<u64>, home: &str, date: NaiveDate) -> Self {
+        let rng = device_rng(seed, &format!("residents/{home}/{date}"));
+        let rng = &mut *rng.lock();
+        let (wake_up, away) = match date.weekday() {
+            Weekday::Sat | Weekday::Sun => {
+                let wake_up = time_between(rng, 7 * 60 + 30, 9 * 60 + 30);
+                // out for the afternoon, one weekend day out of two
+                let away = rng.gen_bool(0.5).then(|| {
+                    let leave = time_between(rng, 13 * 60, 15 * 60);
+                    (leave, leave + TimeDelta::minutes(rng.gen_range(90..=240)))
+                });
+                (wake_up, away)
+            }
+            _ => {
+                let wake_up = time_between(rng, 6 * 60, 7 * 60 + 30);
+                // working from home one day out of five
+                let away = rng.gen_bool(0.8).then(|| {
+                    let leave = wake_up + TimeDelta::minutes(rng.gen_range(45..=75));
+                    (leave, time_between(rng, 17 * 60, 19 * 60))
+                });
+                (wake_up, away)
+            }
+        };
+        let bedtime = time_between(rng, 22 * 60 + 30, 23 * 60 + 55);
+        let tv_on = time_between(rng, 19 * 60 + 30, 21 * 60);
+        let tv_off = bedtime - TimeDelta::minutes(rng.gen_range(5..=20));
+        Self {
+            wake_up,
+            away,
+            tv: (tv_on, tv_off),
+            channel: rng.gen_range(1..=50),
+            bedtime,
+            peak_temperature: rng.gen_range(18.0..34.0),
+        }
+    }
+
+    /// The outdoor temperature at `time`, coolest at 3:00.
+    pub fn temperature(&self, time: NaiveTime) -> f64 {
+        let hours = time.num_seconds_from_midnight() as f64 / 3600.0;
+        let coolness = (1.0 - (2.0 * PI * (hours - PEAK_HOUR) / 24.0).cos()) / 2.0;
+        self.peak_temperature - DAILY_SWING * coolness
+    }
+
+    /// Whether someone is home and awake at `time`.
+    pub fn is_up(&self, time: NaiveTime) -> bool {
+        (self.wake_up..self.bedtime).contains(&time)
+            && self
+                .away
+                .is_none_or(|(leave, back)| !(leave..back).contains(&time))
+    }
+}
+
+/// The state the residents want their devices in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Wanted {
+    pub light: bool,
+    /// The speed of the fan, if on.
+    pub fan: Option<u8>,
+    /// The channel of the tv, if on.
+    pub tv: Option<u16>,
+}
+
+impl Wanted {
+    /// The commands that take the devices from the `previous` state to
+    /// this one, by kind of device. Without a previous state, every device
+    /// gets a command.
+    pub fn commands(&self, previous: Option<&Wanted>) -> Vec<(&'static str, Value)> {
+        fn to_value(command: impl Serialize) -> Value {
+            serde_json::to_value(command).expect("commands are serializable")
+        }
+
+        let mut commands = vec![];
+        if previous.is_none_or(|previous| previous.light != self.light) {
+            let command = match self.light {
+                true => BulbCommand::On,
+                false => BulbCommand::Off,
+            };
+            commands.push(("bulb", to_value(command)));
+        }
+        match (previous.map(|previous| previous.fan), self.fan) {
+            (Some(before), now) if before == now => {}
+            (_, None) => commands.push(("fan", to_value(FanCommand::Off))),
+            (before, Some(speed)) => {
+                // the fan must be on for the speed to stick
+                if !matches!(before, Some(Some(_))) {
+                    commands.push(("fan", to_value(FanCommand::On)));
+                }
+                commands.push(("fan", to_value(FanCommand::Speed(speed))));
+            }
+        }
+        match (previous.map(|previous| previous.tv), self.tv) {
+            (Some(before), now) if before == now => {}
+            (_, None) => commands.push(("tv", to_value(TVCommand::Off))),
+            (before, Some(channel)) => {
+                if !matches!(before, Some(Some(_))) {
+                    commands.push(("tv", to_value(TVCommand::On)));
+                }
+                commands.push(("tv", to_value(TVCommand::Channel(channel))));
+            }
+        }
+        commands
+    }
+}
+
+#[derive(Debug)]
+struct Household {
+    home: String,
+    location: Location,
+    /// The day being lived, drawn when it starts.
+    day: Option<(NaiveDate, Day)>,
+    wanted: Option<Wanted>,
+}
+
+impl Household {
+    fn wanted_at(&mut self, seed: Option<u64>, now: DateTime<Utc>) -> Wanted {
+        let local = self.location.timezone.from_utc_datetime(&now.naive_utc());
+        let (date, time) = (local.date_naive(), local.time());
+        if self.day.as_ref().is_some_and(|(day, _)| *day != date) {
+            self.day = None;
+        }
+        let (_, day) = self
+            .day
+            .get_or_insert_with(|| (date, Day::draw(seed, &self.home, date)));
+        if !day.is_up(time) {
+            return Wanted::default();
+        }
+
+        let sun = |event| {
+            let (latitude, longitude) = (self.location.latitude?, self.location.longitude?);
+            sun_time(date, latitude, longitude, event)
+        };
+        let light = match (sun(SunEvent::Sunrise), sun(SunEvent::Sunset)) {
+            (Some(sunrise), Some(sunset)) => now < sunrise || now >= sunset,
+            // without a location, or in a polar day or night
+            _ => time.hour() < 7 || time.hour() >= 19,
+        };
+        let temperature = day.temperature(time);
+        let fan = (temperature >= WARM).then(|| (1 + ((temperature - WARM) / 2.0) as u8).min(3));
+        let tv = (day.tv.0..day.tv.1).contains(&time).then_some(day.channel);
+        Wanted { light, fan, tv }
+    }
+}
+
+/// Moves the residents of the homes through their days as the simulated
+/// time goes by.
+#[derive(Debug)]
+pub struct Residents {
+    topics: TopicScheme,
+    seed: Option<u64>,
+    households: Vec<Household>,
+}
+
+impl Residents {
+    pub fn new(topics: TopicScheme, seed: Option<u64>) -> Self {
+        Self {
+            topics,
+            seed,
+            households: vec![],
+        }
+    }
+
+    /// Add residents to `home`, e.g. `home/0`, living in the time zone and
+    /// daylight of `location`.
+    pub fn with_home(mut self, home: impl Into<String>, location: Location) -> Self {
+        self.households.push(Household {
+            home: home.into(),
+            location,
+            day: None,
+            wanted: None,
+        });
+        self
+    }
+
+    /// What the residents of `home` want at `now`.
+    pub fn wanted(&mut self, home: &str, now: DateTime<Utc>) -> Option<Wanted> {
+        let household = self.households.iter_mut().find(|h| h.home == home)?;
+        Some(household.wanted_at(self.seed, now))
+    }
+
+    /// The commands of the residents whose wishes changed since the last
+    /// tick. On the first tick, every device is put in the state its
+    /// residents want.
+    pub fn tick(&mut self, now: DateTime<Utc>) -> Vec<Message> {
+        let codec = self.topics.codec();
+        let mut messages = vec![];
+        for household in &mut self.households {
+            let wanted = household.wanted_at(self.seed, now);
+            let previous = household.wanted.replace(wanted);
+            for (kind, command) in wanted.commands(previous.as_ref()) {
+                let Ok(payload) = codec.encode_command(&command) else {
+                    continue;
+                };
+                let topic = self.topics.command_topic(kind, &household.home);
+                messages.push(Message::new(topic, payload).with_content_type(codec.content_type()));
+            }
+        }
+        messages
+    }
+}
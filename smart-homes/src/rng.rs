@@ -0,0 +1,28 @@
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, SeedableRng};
+use std::sync::Arc;
+
+/// Random number generator of a device, shared with its status publisher.
+pub type DeviceRng = Arc<Mutex<StdRng>>;
+
+/// Create the random number generator of the device identified by `key`.
+///
+/// Without a `seed` the generator is seeded from the OS. With a seed, every
+/// device gets its own stream of numbers that only depends on the seed and
+/// the key, so a run can be replayed regardless of the order in which the
+/// devices draw their numbers.
+pub fn device_rng(seed: Option<u64>, key: &str) -> DeviceRng {
+    let rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed ^ fnv1a(key.as_bytes())),
+        None => StdRng::from_entropy(),
+    };
+    Arc::new(Mutex::new(rng))
+}
+
+// not using std's DefaultHasher because its output may change between Rust
+// releases, which would break replaying old seeds.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,386 @@
+//! Automation rules: when something happens to a device and the conditions
+//! hold, send commands to devices or publish notifications.
+//!
+//! Devices are named by their kind and id, e.g. `tv/home/0`, as in the fault
+//! configuration.
+
+use crate::{
+    bulb::BulbCommand,
+    error::Error,
+    fan::FanCommand,
+    layout::{DeviceTopic, TopicScheme, KINDS},
+    schema::Availability,
+    transport::Message,
+    tv::TVCommand,
+    DeviceStatus,
+};
+use chrono::{DateTime, NaiveTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// The rules, as read from a rules file or the admin topic.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RuleSet {
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    /// The name the executions are logged and notified with.
+    pub name: String,
+    pub trigger: Trigger,
+    /// What must hold for the actions to run, when the rule is triggered.
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+    pub actions: Vec<Action>,
+}
+
+/// What gets a rule evaluated.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "on", rename_all = "snake_case")]
+pub enum Trigger {
+    /// A status of `device` differs from the previous one in `field`, or in
+    /// any field but the timestamp and the voltage, and `field` is now `to`,
+    /// if given.
+    StateChange {
+        device: String,
+        field: Option<String>,
+        to: Option<Value>,
+    },
+    /// `device` becomes available or unavailable, or only `is_available` if
+    /// given.
+    Availability {
+        device: String,
+        is_available: Option<bool>,
+    },
+    /// The simulated clock reaches this time of day, in UTC.
+    Time { at: NaiveTime },
+    /// The condition starts to hold, e.g. the voltage of a fan goes above
+    /// 240.
+    Threshold(Condition),
+}
+
+/// A test of the current value of a field of a device. Every test given must
+/// pass.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Condition {
+    pub device: String,
+    /// A field of the status, e.g. `voltage`, or `available`.
+    pub field: String,
+    pub equals: Option<Value>,
+    pub above: Option<f64>,
+    pub below: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "do", rename_all = "snake_case")]
+pub enum Action {
+    /// Send `command` to `device`, e.g. `{"cmd": "color", "args": [255, 0, 0]}`.
+    Command { device: String, command: Value },
+    /// Publish `message` on the notification topic.
+    Notify { message: String },
+}
+
+/// Split a device name into its kind and id.
+fn parse_device(device: &str) -> Result<(&'static str, &str), Error> {
+    device
+        .split_once('/')
+        .and_then(|(kind, id)| Some((*KINDS.iter().find(|k| **k == kind)?, id)))
+        .ok_or_else(|| Error::InvalidRule(format!("{device} is not a device, e.g. bulb/home/0")))
+}
+
+/// Check `command` against the commands of the devices of `kind`, telling
+/// what is wrong with it.
+pub(crate) fn check_command(kind: &str, command: &Value) -> Result<(), String> {
+    let res = match kind {
+        "bulb" => serde_json::from_value::<BulbCommand>(command.clone()).map(drop),
+        "fan" => serde_json::from_value::<FanCommand>(command.clone()).map(drop),
+        _ => serde_json::from_value::<TVCommand>(command.clone()).map(drop),
+    };
+    res.map_err(|err| format!("{command} is not a {kind} command: {err}"))
+}
+
+impl RuleSet {
+    /// Check the devices and commands of the rules, so that a broken rule is
+    /// rejected instead of failing when it is triggered.
+    pub fn validate(&self) -> Result<(), Error> {
+        for rule in &self.rules {
+            let mut conditions: Vec<_> = rule.conditions.iter().collect();
+            match &rule.trigger {
+                Trigger::StateChange { device, .. } | Trigger::Availability { device, .. } => {
+                    parse_device(device)?;
+                }
+                Trigger::Time { .. } => {}
+                Trigger::Threshold(condition) => conditions.push(condition),
+            }
+            for condition in conditions {
+                parse_device(&condition.device)?;
+                if condition.equals.is_none()
+                    && condition.above.is_none()
+                    && condition.below.is_none()
+                {
+                    return Err(Error::InvalidRule(format!(
+                        "condition on {} of {} needs equals, above or below",
+                        condition.field, condition.device
+                    )));
+                }
+            }
+            for action in &rule.actions {
+                if let Action::Command { device, command } = action {
+                    let (kind, _) = parse_device(device)?;
+                    check_command(kind, command).map_err(Error::InvalidRule)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A rule whose conditions held when it was triggered, with the messages
+/// its actions publish.
+#[derive(Debug)]
+pub struct Firing {
+    pub rule: String,
+    pub messages: Vec<Message>,
+}
+
+/// Evaluates the rules against the messages of the devices.
+#[derive(Debug)]
+pub struct RuleEngine {
+    topics: TopicScheme,
+    rules: Vec<Rule>,
+    /// The last status of every device, by name.
+    statuses: HashMap<String, DeviceStatus>,
+    available: HashMap<String, bool>,
+    last_tick: Option<DateTime<Utc>>,
+}
+
+impl RuleEngine {
+    pub fn new(topics: TopicScheme, rules: RuleSet) -> Result<Self, Error> {
+        rules.validate()?;
+        Ok(Self {
+            topics,
+            rules: rules.rules,
+            statuses: HashMap::new(),
+            available: HashMap::new(),
+            last_tick: None,
+        })
+    }
+
+    /// Replace the rules, keeping what is known of the devices.
+    pub fn set_rules(&mut self, rules: RuleSet) -> Result<(), Error> {
+        rules.validate()?;
+        self.rules = rules.rules;
+        Ok(())
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Take into account a message of a device received at `now`, and fire
+    /// the rules it triggers.
+    pub fn handle(&mut self, msg: &Message, now: DateTime<Utc>) -> Vec<Firing> {
+        let Some((kind, id, what)) = self.topics.parse_topic(msg.topic()) else {
+            return vec![];
+        };
+        let device = format!("{kind}/{id}");
+        // the thresholds fire when their condition starts to hold
+        let held: Vec<_> = self
+            .rules
+            .iter()
+            .map(|rule| match &rule.trigger {
+                Trigger::Threshold(condition) => self.holds(condition),
+                _ => false,
+            })
+            .collect();
+
+        let mut triggered = vec![false; self.rules.len()];
+        match what {
+            DeviceTopic::Status => {
+                let codec = self.topics.codec().or_content_type(msg.content_type());
+                let Ok(status) = codec.decode_status(msg.payload()) else {
+                    return vec![];
+                };
+                let previous = self.statuses.insert(device.clone(), status.clone());
+                for (rule, triggered) in self.rules.iter().zip(&mut triggered) {
+                    if let Trigger::StateChange {
+                        device: name,
+                        field,
+                        to,
+                    } = &rule.trigger
+                    {
+                        *triggered = *name == device
+                            && previous.as_ref().is_some_and(|previous| {
+                                changed(previous, &status, field.as_deref())
+                            })
+                            && to.as_ref().is_none_or(|to| {
+                                field
+                                    .as_deref()
+                                    .and_then(|field| field_value(&status, field))
+                                    .is_some_and(|value| values_equal(&value, to))
+                            });
+                    }
+                }
+            }
+            DeviceTopic::Available => {
+                let Ok(availability) = serde_json::from_slice::<Availability>(msg.payload()) else {
+                    return vec![];
+                };
+                let is_available = availability.is_available;
+                let previous = self.available.insert(device.clone(), is_available);
+                for (rule, triggered) in self.rules.iter().zip(&mut triggered) {
+                    if let Trigger::Availability {
+                        device: name,
+                        is_available: wanted,
+                    } = &rule.trigger
+                    {
+                        // the first availability is the retained one, not a change
+                        *triggered = *name == device
+                            && previous.is_some_and(|previous| previous != is_available)
+                            && wanted.is_none_or(|wanted| wanted == is_available);
+                    }
+                }
+            }
+            DeviceTopic::Command => return vec![],
+        }
+
+        for ((rule, triggered), held) in self.rules.iter().zip(&mut triggered).zip(held) {
+            if let Trigger::Threshold(condition) = &rule.trigger {
+                *triggered = condition.device == device && !held && self.holds(condition);
+            }
+        }
+        self.fire(&triggered, now)
+    }
+
+    /// Fire the rules whose time of day was reached since the last tick.
+    pub fn tick(&mut self, now: DateTime<Utc>) -> Vec<Firing> {
+        let Some(last_tick) = self.last_tick.replace(now) else {
+            return vec![];
+        };
+        let triggered: Vec<_> = self
+            .rules
+            .iter()
+            .map(|rule| {
+                let Trigger::Time { at } = rule.trigger else {
+                    return false;
+                };
+                let today = now.date_naive().and_time(at).and_utc();
+                let latest = if today <= now {
+                    today
+                } else {
+                    today - TimeDelta::days(1)
+                };
+                latest > last_tick
+            })
+            .collect();
+        self.fire(&triggered, now)
+    }
+
+    fn holds(&self, condition: &Condition) -> bool {
+        let value = if condition.field == "available" {
+            self.available
+                .get(&condition.device)
+                .map(|a| Value::Bool(*a))
+        } else {
+            self.statuses
+                .get(&condition.device)
+                .and_then(|status| field_value(status, &condition.field))
+        };
+        let Some(value) = value else {
+            return false;
+        };
+        condition
+            .equals
+            .as_ref()
+            .is_none_or(|equals| values_equal(&value, equals))
+            && condition
+                .above
+                .is_none_or(|above| value.as_f64().is_some_and(|v| v > above))
+            && condition
+                .below
+                .is_none_or(|below| value.as_f64().is_some_and(|v| v < below))
+    }
+
+    /// Run the actions of the `triggered` rules whose conditions hold.
+    fn fire(&self, triggered: &[bool], now: DateTime<Utc>) -> Vec<Firing> {
+        self.rules
+            .iter()
+            .zip(triggered)
+            .filter(|(rule, triggered)| {
+                **triggered && rule.conditions.iter().all(|c| self.holds(c))
+            })
+            .map(|(rule, _)| Firing {
+                rule: rule.name.clone(),
+                messages: rule
+                    .actions
+                    .iter()
+                    .filter_map(|action| self.action_message(rule, action, now))
+                    .collect(),
+            })
+            .collect()
+    }
+
+    fn action_message(&self, rule: &Rule, action: &Action, now: DateTime<Utc>) -> Option<Message> {
+        match action {
+            Action::Command { device, command } => {
+                // checked when the rules were set
+                let (kind, id) = parse_device(device).ok()?;
+                let codec = self.topics.codec();
+                let payload = codec.encode_command(command).ok()?;
+                let msg = Message::new(self.topics.command_topic(kind, id), payload)
+                    .with_content_type(codec.content_type());
+                Some(msg)
+            }
+            Action::Notify { message } => {
+                let payload = json!({
+                    "rule": rule.name,
+                    "message": message,
+                    "timestamp": now.timestamp(),
+                });
+                Some(Message::new(
+                    self.topics.topic("sim/rules/notifications"),
+                    payload.to_string(),
+                ))
+            }
+        }
+    }
+}
+
+/// The value of `field` in the status, e.g. `voltage`.
+fn field_value(status: &DeviceStatus, field: &str) -> Option<Value> {
+    let mut status = serde_json::to_value(status).ok()?;
+    Some(status.get_mut("status")?.get_mut(field)?.take())
+}
+
+/// The fields of a status that vary with every report, rather than with the
+/// state of the device.
+const TELEMETRY: [&str; 2] = ["timestamp", "voltage"];
+
+/// Whether `field`, or any field but the telemetry, differs.
+fn changed(previous: &DeviceStatus, status: &DeviceStatus, field: Option<&str>) -> bool {
+    match field {
+        Some(field) => field_value(previous, field) != field_value(status, field),
+        None => {
+            let fields = |status: &DeviceStatus| {
+                let mut value = serde_json::to_value(status).ok()?;
+                let fields = value.get_mut("status")?.as_object_mut()?;
+                for field in TELEMETRY {
+                    fields.remove(field);
+                }
+                Some(value)
+            };
+            fields(previous) != fields(status)
+        }
+    }
+}
+
+/// Compare numbers by value, so that `220` equals `220.0`.
+fn values_equal(value: &Value, other: &Value) -> bool {
+    match (value.as_f64(), other.as_f64()) {
+        (Some(value), Some(other)) => value == other,
+        _ => value == other,
+    }
+}
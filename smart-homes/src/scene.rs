@@ -0,0 +1,541 @@
+//! Scenes: a state of the devices of a home, saved under a name and restored
+//! with a single request.
+//!
+//! Scenes are kept as retained messages on `sim/scenes/{name}`, so that
+//! every client of the broker shares them. Publishing `{"home": "home/0"}`
+//! on `sim/scenes/{name}/activate` sends the devices of `home/0` the
+//! commands of the scene, and the outcome for every device is published on
+//! `sim/scenes/{name}/report` once they all reported their new state, or
+//! gave up. Publishing it on `sim/scenes/{name}/capture` saves the current
+//! state of the home as the scene instead.
+
+use crate::{
+    bulb::BulbCommand,
+    error::Error,
+    fan::FanCommand,
+    layout::{DeviceTopic, TopicScheme, KINDS},
+    schema::Availability,
+    transport::Message,
+    tv::TVCommand,
+    DeviceStatus,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// How long the devices have to report the state of a scene, three status
+/// intervals at the normal speed of the simulation.
+pub const SCENE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The state of the devices of a home. Devices left out are not touched,
+/// and neither are the fields left out.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Scene {
+    pub bulb: Option<BulbScene>,
+    pub fan: Option<FanScene>,
+    pub tv: Option<TVScene>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BulbScene {
+    pub is_on: bool,
+    pub color: Option<(u8, u8, u8)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FanScene {
+    pub is_on: bool,
+    /// Only set when the fan is on, since fans ignore it otherwise.
+    pub speed: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TVScene {
+    pub is_on: bool,
+    pub channel: Option<u16>,
+    /// 0 mutes the tv.
+    pub volume: Option<u8>,
+}
+
+impl Scene {
+    /// The scene the devices of a home are in, given their `statuses`.
+    pub fn capture<'a>(statuses: impl IntoIterator<Item = &'a DeviceStatus>) -> Self {
+        let mut scene = Self::default();
+        for status in statuses {
+            match status {
+                DeviceStatus::Bulb(s) => {
+                    scene.bulb = Some(BulbScene {
+                        is_on: s.is_on,
+                        color: Some(s.color),
+                    })
+                }
+                DeviceStatus::Fan(s) => {
+                    scene.fan = Some(FanScene {
+                        is_on: s.is_on,
+                        speed: s.is_on.then_some(s.speed),
+                    })
+                }
+                DeviceStatus::TV(s) => {
+                    scene.tv = Some(TVScene {
+                        is_on: s.is_on,
+                        channel: Some(s.channel),
+                        volume: Some(s.volume),
+                    })
+                }
+            }
+        }
+        scene
+    }
+
+    /// Whether the scene sets the devices of `kind`.
+    pub fn covers(&self, kind: &str) -> bool {
+        match kind {
+            "bulb" => self.bulb.is_some(),
+            "fan" => self.fan.is_some(),
+            "tv" => self.tv.is_some(),
+            _ => false,
+        }
+    }
+
+    /// Whether the device reporting `status` is in the state of the scene.
+    pub fn is_applied(&self, status: &DeviceStatus) -> bool {
+        match status {
+            DeviceStatus::Bulb(s) => self.bulb.as_ref().is_none_or(|scene| {
+                s.is_on == scene.is_on && scene.color.is_none_or(|color| color == s.color)
+            }),
+            DeviceStatus::Fan(s) => self.fan.as_ref().is_none_or(|scene| {
+                s.is_on == scene.is_on
+                    && (!scene.is_on || scene.speed.is_none_or(|speed| speed == s.speed))
+            }),
+            DeviceStatus::TV(s) => self.tv.as_ref().is_none_or(|scene| {
+                s.is_on == scene.is_on
+                    && scene.channel.is_none_or(|channel| channel == s.channel)
+                    && scene.volume.is_none_or(|volume| volume == s.volume)
+            }),
+        }
+    }
+
+    /// The commands, in order, that bring the device reporting `status` to
+    /// the state of the scene.
+    pub fn commands(&self, status: &DeviceStatus) -> Vec<Value> {
+        fn to_values<C: Serialize>(commands: Vec<C>) -> Vec<Value> {
+            commands
+                .iter()
+                .map(|command| serde_json::to_value(command).expect("commands are serializable"))
+                .collect()
+        }
+
+        match status {
+            DeviceStatus::Bulb(s) => {
+                let Some(scene) = &self.bulb else {
+                    return vec![];
+                };
+                let mut commands = vec![];
+                if s.is_on != scene.is_on {
+                    commands.push(if scene.is_on {
+                        BulbCommand::On
+                    } else {
+                        BulbCommand::Off
+                    });
+                }
+                if let Some(color) = scene.color.filter(|color| *color != s.color) {
+                    commands.push(BulbCommand::Color(color));
+                }
+                to_values(commands)
+            }
+            DeviceStatus::Fan(s) => {
+                let Some(scene) = &self.fan else {
+                    return vec![];
+                };
+                let mut commands = vec![];
+                if s.is_on != scene.is_on {
+                    commands.push(if scene.is_on {
+                        FanCommand::On
+                    } else {
+                        FanCommand::Off
+                    });
+                }
+                // the fan must be on for the speed to stick
+                if let Some(speed) = scene.speed.filter(|speed| scene.is_on && *speed != s.speed) {
+                    commands.push(FanCommand::Speed(speed));
+                }
+                to_values(commands)
+            }
+            DeviceStatus::TV(s) => {
+                let Some(scene) = &self.tv else {
+                    return vec![];
+                };
+                let mut commands = vec![];
+                if s.is_on != scene.is_on {
+                    commands.push(if scene.is_on {
+                        TVCommand::On
+                    } else {
+                        TVCommand::Off
+                    });
+                }
+                if let Some(channel) = scene.channel.filter(|channel| *channel != s.channel) {
+                    commands.push(TVCommand::Channel(channel));
+                }
+                if let Some(volume) = scene.volume.filter(|volume| *volume != s.volume) {
+                    commands.push(TVCommand::Volume(volume));
+                }
+                to_values(commands)
+            }
+        }
+    }
+}
+
+/// What a topic of a scene carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SceneTopic {
+    /// The retained [`Scene`].
+    Definition,
+    /// A [`SceneRequest`] to activate the scene.
+    Activate,
+    /// A [`SceneRequest`] to save the state of a home as the scene.
+    Capture,
+    /// The [`SceneReport`] of an activation.
+    Report,
+}
+
+/// Check that `name` can be used in a topic.
+pub fn check_scene_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.contains(['/', '+', '#']) {
+        return Err(Error::InvalidScene(format!(
+            "{name:?} must not be empty nor contain /, + or #"
+        )));
+    }
+    Ok(())
+}
+
+/// The topic of the scene `name` that carries `what`.
+pub fn scene_topic(topics: &TopicScheme, name: &str, what: SceneTopic) -> String {
+    let suffix = match what {
+        SceneTopic::Definition => "",
+        SceneTopic::Activate => "/activate",
+        SceneTopic::Capture => "/capture",
+        SceneTopic::Report => "/report",
+    };
+    topics.topic(&format!("sim/scenes/{name}{suffix}"))
+}
+
+/// The filters matching the definitions and the requests of every scene.
+pub fn scene_filters(topics: &TopicScheme) -> Vec<String> {
+    [
+        SceneTopic::Definition,
+        SceneTopic::Activate,
+        SceneTopic::Capture,
+    ]
+    .into_iter()
+    .map(|what| scene_topic(topics, "+", what))
+    .collect()
+}
+
+fn parse_scene_topic<'a>(topics: &TopicScheme, topic: &'a str) -> Option<(&'a str, SceneTopic)> {
+    let rest = topics.strip_prefix(topic)?.strip_prefix("sim/scenes/")?;
+    Some(match rest.split_once('/') {
+        None => (rest, SceneTopic::Definition),
+        Some((name, "activate")) => (name, SceneTopic::Activate),
+        Some((name, "capture")) => (name, SceneTopic::Capture),
+        Some((name, "report")) => (name, SceneTopic::Report),
+        Some(_) => return None,
+    })
+}
+
+/// A request to activate or capture a scene for a home.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SceneRequest {
+    /// The id of the home, e.g. `home/0`.
+    pub home: String,
+    /// Copied into the report, to tell it from the reports of other
+    /// requests.
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The device reported the state of the scene after the commands.
+    Applied,
+    /// The device was in the state of the scene already.
+    Unchanged,
+    /// The device did not report the state of the scene in time, e.g. it
+    /// ignored a command.
+    TimedOut,
+    /// The device is unavailable, or never reported its status.
+    Unavailable,
+    /// Still waiting for the device. Never found in a report.
+    Pending,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceReport {
+    /// The kind and id of the device, e.g. `bulb/home/0`.
+    pub device: String,
+    /// How many commands were sent.
+    pub commands: usize,
+    pub outcome: Outcome,
+}
+
+/// How the activation of a scene went.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SceneReport {
+    pub scene: String,
+    pub home: String,
+    pub request_id: Option<String>,
+    /// Whether every device is in the state of the scene.
+    pub success: bool,
+    /// Why the scene could not be activated at all.
+    pub error: Option<String>,
+    pub devices: Vec<DeviceReport>,
+}
+
+#[derive(Debug)]
+struct Activation {
+    report: SceneReport,
+    scene: Scene,
+    deadline: Instant,
+}
+
+/// Keeps the scenes and the state of the devices, and activates the scenes
+/// on request.
+#[derive(Debug)]
+pub struct SceneManager {
+    topics: TopicScheme,
+    timeout: Duration,
+    scenes: HashMap<String, Scene>,
+    /// The last status of every device, by kind and id.
+    statuses: HashMap<(&'static str, String), DeviceStatus>,
+    available: HashMap<(&'static str, String), bool>,
+    activations: Vec<Activation>,
+}
+
+impl SceneManager {
+    pub fn new(topics: TopicScheme) -> Self {
+        Self {
+            topics,
+            timeout: SCENE_TIMEOUT,
+            scenes: HashMap::new(),
+            statuses: HashMap::new(),
+            available: HashMap::new(),
+            activations: vec![],
+        }
+    }
+
+    /// Give up on the devices that did not report the state of a scene
+    /// after `timeout`.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+
+    pub fn scene(&self, name: &str) -> Option<&Scene> {
+        self.scenes.get(name)
+    }
+
+    /// Take into account a message of a device or a scene received at
+    /// `now`, and get the messages to publish in return.
+    pub fn handle(&mut self, msg: &Message, now: Instant) -> Vec<Message> {
+        if let Some((kind, id, what)) = self.topics.parse_topic(msg.topic()) {
+            self.update_device(kind, id, what, msg);
+            return self.finished(now);
+        }
+        let Some((name, what)) = parse_scene_topic(&self.topics, msg.topic()) else {
+            return vec![];
+        };
+        let name = name.to_string();
+        match what {
+            SceneTopic::Definition if msg.payload().is_empty() => {
+                self.scenes.remove(&name);
+                vec![]
+            }
+            SceneTopic::Definition => {
+                match serde_json::from_slice(msg.payload()) {
+                    Ok(scene) => {
+                        self.scenes.insert(name, scene);
+                    }
+                    Err(err) => warn!(name, ?err, "Invalid scene received"),
+                }
+                vec![]
+            }
+            SceneTopic::Activate | SceneTopic::Capture => {
+                let request = match serde_json::from_slice::<SceneRequest>(msg.payload()) {
+                    Ok(request) => request,
+                    Err(err) => {
+                        warn!(name, ?err, "Invalid scene request received");
+                        return vec![];
+                    }
+                };
+                if what == SceneTopic::Capture {
+                    self.capture(&name, &request.home).into_iter().collect()
+                } else {
+                    let mut messages = self.activate(&name, request, now);
+                    messages.extend(self.finished(now));
+                    messages
+                }
+            }
+            SceneTopic::Report => vec![],
+        }
+    }
+
+    /// Give up on the devices that are late, and get the reports of the
+    /// activations that are over.
+    pub fn tick(&mut self, now: Instant) -> Vec<Message> {
+        self.finished(now)
+    }
+
+    fn update_device(&mut self, kind: &'static str, id: &str, what: DeviceTopic, msg: &Message) {
+        let key = (kind, id.to_string());
+        match what {
+            DeviceTopic::Status => {
+                let codec = self.topics.codec().or_content_type(msg.content_type());
+                let Ok(status) = codec.decode_status(msg.payload()) else {
+                    return;
+                };
+                let device = format!("{kind}/{id}");
+                for activation in &mut self.activations {
+                    if activation.report.home != id || !activation.scene.is_applied(&status) {
+                        continue;
+                    }
+                    for report in &mut activation.report.devices {
+                        if report.device == device && report.outcome == Outcome::Pending {
+                            report.outcome = Outcome::Applied;
+                        }
+                    }
+                }
+                self.statuses.insert(key, status);
+            }
+            DeviceTopic::Available => {
+                if let Ok(availability) = serde_json::from_slice::<Availability>(msg.payload()) {
+                    self.available.insert(key, availability.is_available);
+                }
+            }
+            DeviceTopic::Command => {}
+        }
+    }
+
+    fn capture(&mut self, name: &str, home: &str) -> Option<Message> {
+        let statuses: Vec<_> = KINDS
+            .iter()
+            .filter_map(|kind| self.statuses.get(&(*kind, home.to_string())))
+            .collect();
+        if statuses.is_empty() {
+            warn!(name, home, "Nothing to capture, the home never reported");
+            return None;
+        }
+        let scene = Scene::capture(statuses);
+        info!(name, home, ?scene, "Captured scene");
+        let payload = serde_json::to_vec(&scene).expect("scenes are serializable");
+        self.scenes.insert(name.into(), scene);
+        let topic = scene_topic(&self.topics, name, SceneTopic::Definition);
+        Some(Message::new_retained(topic, payload))
+    }
+
+    fn activate(&mut self, name: &str, request: SceneRequest, now: Instant) -> Vec<Message> {
+        let mut report = SceneReport {
+            scene: name.into(),
+            home: request.home,
+            request_id: request.request_id,
+            success: false,
+            error: None,
+            devices: vec![],
+        };
+        let Some(scene) = self.scenes.get(name).cloned() else {
+            report.error = Some(format!("there is no scene {name}"));
+            self.activations.push(Activation {
+                report,
+                scene: Scene::default(),
+                deadline: now,
+            });
+            return vec![];
+        };
+
+        let codec = self.topics.codec();
+        let mut messages = vec![];
+        for kind in KINDS.into_iter().filter(|kind| scene.covers(kind)) {
+            let key = (kind, report.home.clone());
+            let mut device = DeviceReport {
+                device: format!("{kind}/{}", report.home),
+                commands: 0,
+                outcome: Outcome::Unavailable,
+            };
+            match self.statuses.get(&key) {
+                Some(status) if self.available.get(&key) != Some(&false) => {
+                    let commands = scene.commands(status);
+                    device.commands = commands.len();
+                    device.outcome = if commands.is_empty() {
+                        Outcome::Unchanged
+                    } else {
+                        Outcome::Pending
+                    };
+                    for command in commands {
+                        let Ok(payload) = codec.encode_command(&command) else {
+                            continue;
+                        };
+                        let topic = self.topics.command_topic(kind, &report.home);
+                        messages.push(
+                            Message::new(topic, payload).with_content_type(codec.content_type()),
+                        );
+                    }
+                }
+                _ => {}
+            }
+            report.devices.push(device);
+        }
+        info!(name, home = report.home, "Activating scene");
+        self.activations.push(Activation {
+            report,
+            scene,
+            deadline: now + self.timeout,
+        });
+        messages
+    }
+
+    /// The reports of the activations that have no pending device anymore,
+    /// or ran out of time.
+    fn finished(&mut self, now: Instant) -> Vec<Message> {
+        let mut reports = vec![];
+        self.activations.retain_mut(|activation| {
+            let timed_out = now >= activation.deadline;
+            let report = &mut activation.report;
+            let pending = report
+                .devices
+                .iter()
+                .any(|device| device.outcome == Outcome::Pending);
+            if pending && !timed_out {
+                return true;
+            }
+            for device in &mut report.devices {
+                if device.outcome == Outcome::Pending {
+                    device.outcome = Outcome::TimedOut;
+                }
+            }
+            report.success = report.error.is_none()
+                && report
+                    .devices
+                    .iter()
+                    .all(|device| matches!(device.outcome, Outcome::Applied | Outcome::Unchanged));
+            info!(
+                scene = report.scene,
+                home = report.home,
+                success = report.success,
+                "Scene activated"
+            );
+            reports.push(report.clone());
+            false
+        });
+        reports
+            .into_iter()
+            .map(|report| {
+                let topic = scene_topic(&self.topics, &report.scene, SceneTopic::Report);
+                let payload = serde_json::to_vec(&report).expect("reports are serializable");
+                Message::new(topic, payload)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,380 @@
+//! Schedules: commands and scenes run at the times of a cron expression, or
+//! around sunrise and sunset, in the time zone of each home.
+//!
+//! Times are those of the simulated clock, so schedules run faster when the
+//! time of the simulation is accelerated.
+
+use crate::{
+    error::Error,
+    layout::{TopicScheme, KINDS},
+    rules::check_command,
+    scene::{check_scene_name, scene_topic, SceneRequest, SceneTopic},
+    transport::Message,
+};
+use chrono::{DateTime, Days, NaiveDate, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, fs, path::Path, str::FromStr};
+
+/// Where a home is, for its time zone and the times of the sun.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    /// Degrees, north positive. Needed for sunrise and sunset.
+    pub latitude: Option<f64>,
+    /// Degrees, east positive. Needed for sunrise and sunset.
+    pub longitude: Option<f64>,
+    /// e.g. `Europe/Paris`, UTC by default.
+    #[serde(default = "utc")]
+    pub timezone: Tz,
+}
+
+fn utc() -> Tz {
+    Tz::UTC
+}
+
+/// The schedules, as read from the schedule file or the admin topic.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    /// The location of the homes that have none of their own.
+    pub location: Location,
+    /// The locations by home, e.g. `home/1`.
+    pub homes: HashMap<String, Location>,
+    pub schedules: Vec<Schedule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub name: String,
+    /// The home whose devices are scheduled, and whose time zone and
+    /// location the times are in.
+    pub home: String,
+    pub when: When,
+    pub actions: Vec<ScheduledAction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum When {
+    /// A cron expression, e.g. `30 6 * * Mon-Fri`, with an optional leading
+    /// seconds field.
+    Cron { cron: String },
+    /// Sunrise or sunset, shifted by `offset_minutes`, e.g. -20 for 20
+    /// minutes before, by a day at most.
+    Sun {
+        sun: SunEvent,
+        #[serde(default)]
+        offset_minutes: i64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SunEvent {
+    Sunrise,
+    Sunset,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "do", rename_all = "snake_case")]
+pub enum ScheduledAction {
+    /// Send `command` to the device of the home of kind `device`, e.g.
+    /// `bulb`.
+    Command { device: String, command: Value },
+    /// Activate a scene in the home.
+    Scene { scene: String },
+}
+
+impl ScheduleConfig {
+    /// Read the schedules of `path`. A missing file has no schedules.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        match fs::read(path) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(Error::ScheduleFileError(err)),
+        }
+    }
+
+    /// Write the schedules to `path`, so that they survive a restart.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        // same as the state store: never leave a truncated file
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?).map_err(Error::ScheduleFileError)?;
+        fs::rename(&tmp_path, path).map_err(Error::ScheduleFileError)?;
+        Ok(())
+    }
+
+    pub fn location(&self, home: &str) -> &Location {
+        self.homes.get(home).unwrap_or(&self.location)
+    }
+}
+
+/// Parse a cron expression, adding the seconds field if it is left out.
+fn parse_cron(expression: &str) -> Result<cron::Schedule, Error> {
+    let expression = match expression.split_whitespace().count() {
+        5 => format!("0 {expression}"),
+        _ => expression.to_string(),
+    };
+    cron::Schedule::from_str(&expression)
+        .map_err(|err| Error::InvalidSchedule(format!("{expression}: {err}")))
+}
+
+/// A schedule ready to run.
+#[derive(Debug)]
+struct Compiled {
+    schedule: Schedule,
+    location: Location,
+    cron: Option<cron::Schedule>,
+}
+
+/// The most a sun schedule can be shifted by, in minutes either way.
+const MAX_OFFSET_MINUTES: i64 = 24 * 60;
+
+impl Compiled {
+    fn new(schedule: Schedule, config: &ScheduleConfig) -> Result<Self, Error> {
+        let invalid = |why: String| Error::InvalidSchedule(format!("{}: {why}", schedule.name));
+        // the home goes in the topics of the commands
+        let home = &schedule.home;
+        if home.contains(['+', '#']) || home.split('/').any(str::is_empty) {
+            return Err(invalid(format!("{home} is not a home, e.g. home/0")));
+        }
+        let location = config.location(&schedule.home).clone();
+        let cron = match &schedule.when {
+            When::Cron { cron } => Some(parse_cron(cron)?),
+            When::Sun { .. } if location.latitude.is_none() || location.longitude.is_none() => {
+                return Err(invalid(format!(
+                    "the sun needs the latitude and longitude of {}",
+                    schedule.home
+                )));
+            }
+            When::Sun { offset_minutes, .. }
+                if !(-MAX_OFFSET_MINUTES..=MAX_OFFSET_MINUTES).contains(offset_minutes) =>
+            {
+                return Err(invalid(format!(
+                    "the offset must be within {MAX_OFFSET_MINUTES} minutes of the sun"
+                )));
+            }
+            When::Sun { .. } => None,
+        };
+        for action in &schedule.actions {
+            match action {
+                ScheduledAction::Command { device, command } => {
+                    let kind = KINDS
+                        .iter()
+                        .find(|kind| *kind == device)
+                        .ok_or_else(|| invalid(format!("{device} is not a kind of device")))?;
+                    check_command(kind, command).map_err(invalid)?;
+                }
+                ScheduledAction::Scene { scene } => check_scene_name(scene)?,
+            }
+        }
+        Ok(Self {
+            schedule,
+            location,
+            cron,
+        })
+    }
+
+    /// The first run strictly after `after`.
+    fn next_run(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let tz = self.location.timezone;
+        if let Some(cron) = &self.cron {
+            return cron
+                .after(&after.with_timezone(&tz))
+                .next()
+                .map(|run| run.with_timezone(&Utc));
+        }
+        let When::Sun {
+            sun,
+            offset_minutes,
+        } = self.schedule.when
+        else {
+            return None;
+        };
+        let (latitude, longitude) = (self.location.latitude?, self.location.longitude?);
+        // the day before too, for offsets that cross midnight
+        let first_day = after.with_timezone(&tz).date_naive() - Days::new(1);
+        // give up after a year, e.g. in a polar night
+        (0..370)
+            .filter_map(|i| {
+                let day = first_day + Days::new(i);
+                let time = sun_time(day, latitude, longitude, sun)?;
+                time.checked_add_signed(TimeDelta::try_minutes(offset_minutes)?)
+            })
+            .find(|run| *run > after)
+    }
+}
+
+/// The time of sunrise or sunset on `day` at a location, or `None` when
+/// the sun does not rise or set that day. Computed with the sunrise
+/// equation, which is good to a minute or so.
+pub fn sun_time(
+    day: NaiveDate,
+    latitude: f64,
+    longitude: f64,
+    event: SunEvent,
+) -> Option<DateTime<Utc>> {
+    let epoch = NaiveDate::from_ymd_opt(2000, 1, 1)?;
+    // days since noon on the 1st of January 2000
+    let n = (day - epoch).num_days() as f64 + 0.0008;
+    let mean_noon = n - longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * mean_noon).rem_euclid(360.0);
+    let m = anomaly.to_radians();
+    let center = 1.9148 * m.sin() + 0.02 * (2.0 * m).sin() + 0.0003 * (3.0 * m).sin();
+    let ecliptic = (anomaly + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let transit = 2451545.0 + mean_noon + 0.0053 * m.sin() - 0.0069 * (2.0 * ecliptic).sin();
+    let declination = (ecliptic.sin() * 23.4397f64.to_radians().sin()).asin();
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = ((-0.833f64).to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+    let julian = match event {
+        SunEvent::Sunrise => transit - hour_angle / 360.0,
+        SunEvent::Sunset => transit + hour_angle / 360.0,
+    };
+    let unix = (julian - 2440587.5) * 86400.0;
+    DateTime::from_timestamp(unix.round() as i64, 0)
+}
+
+/// The next time a schedule runs.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NextRun {
+    pub schedule: String,
+    pub home: String,
+    #[serde_as(as = "serde_with::TimestampSeconds<i64>")]
+    pub at: DateTime<Utc>,
+    /// The same time in the time zone of the home, e.g.
+    /// `2024-10-21T19:25:00+02:00`.
+    pub local: String,
+}
+
+/// A schedule that ran, with the messages its actions publish.
+#[derive(Debug)]
+pub struct Run {
+    pub schedule: String,
+    /// How many runs were skipped because the clock went past them at
+    /// once.
+    pub skipped: usize,
+    pub messages: Vec<Message>,
+}
+
+/// Runs the schedules as the simulated time goes by.
+#[derive(Debug)]
+pub struct Scheduler {
+    topics: TopicScheme,
+    config: ScheduleConfig,
+    schedules: Vec<Compiled>,
+    last_tick: Option<DateTime<Utc>>,
+}
+
+impl Scheduler {
+    pub fn new(topics: TopicScheme, config: ScheduleConfig) -> Result<Self, Error> {
+        let mut scheduler = Self {
+            topics,
+            config: ScheduleConfig::default(),
+            schedules: vec![],
+            last_tick: None,
+        };
+        scheduler.set_config(config)?;
+        Ok(scheduler)
+    }
+
+    /// Replace the schedules, checking them first.
+    pub fn set_config(&mut self, config: ScheduleConfig) -> Result<(), Error> {
+        self.schedules = config
+            .schedules
+            .iter()
+            .map(|schedule| Compiled::new(schedule.clone(), &config))
+            .collect::<Result<_, _>>()?;
+        self.config = config;
+        Ok(())
+    }
+
+    pub fn config(&self) -> &ScheduleConfig {
+        &self.config
+    }
+
+    /// The next run of every schedule after `now`, soonest first.
+    pub fn next_runs(&self, now: DateTime<Utc>) -> Vec<NextRun> {
+        let mut runs: Vec<_> = self
+            .schedules
+            .iter()
+            .filter_map(|compiled| {
+                let at = compiled.next_run(now)?;
+                Some(NextRun {
+                    schedule: compiled.schedule.name.clone(),
+                    home: compiled.schedule.home.clone(),
+                    at,
+                    local: compiled
+                        .location
+                        .timezone
+                        .from_utc_datetime(&at.naive_utc())
+                        .to_rfc3339(),
+                })
+            })
+            .collect();
+        runs.sort_by_key(|run| run.at);
+        runs
+    }
+
+    /// Run the schedules that were due since the last tick. A schedule runs
+    /// once per tick at most, however many of its runs the clock went past.
+    pub fn tick(&mut self, now: DateTime<Utc>) -> Vec<Run> {
+        let Some(last_tick) = self.last_tick.replace(now) else {
+            return vec![];
+        };
+        let mut runs = vec![];
+        for compiled in &self.schedules {
+            let Some(mut last_run) = compiled.next_run(last_tick).filter(|run| *run <= now) else {
+                continue;
+            };
+            // counting stops at a thousand, for schedules every second
+            let mut skipped = 0;
+            while skipped < 1000 {
+                match compiled.next_run(last_run) {
+                    Some(run) if run <= now => last_run = run,
+                    _ => break,
+                }
+                skipped += 1;
+            }
+            runs.push(Run {
+                schedule: compiled.schedule.name.clone(),
+                skipped,
+                messages: self.messages(&compiled.schedule),
+            });
+        }
+        runs
+    }
+
+    fn messages(&self, schedule: &Schedule) -> Vec<Message> {
+        let codec = self.topics.codec();
+        schedule
+            .actions
+            .iter()
+            .filter_map(|action| match action {
+                ScheduledAction::Command { device, command } => {
+                    let payload = codec.encode_command(command).ok()?;
+                    let topic = self.topics.command_topic(device, &schedule.home);
+                    Some(Message::new(topic, payload).with_content_type(codec.content_type()))
+                }
+                ScheduledAction::Scene { scene } => {
+                    let request = SceneRequest {
+                        home: schedule.home.clone(),
+                        request_id: Some(format!("schedule {}", schedule.name)),
+                    };
+                    let payload = serde_json::to_vec(&request).ok()?;
+                    let topic = scene_topic(&self.topics, scene, SceneTopic::Activate);
+                    Some(Message::new(topic, payload))
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,69 @@
+//! The versioned wire format of the statuses and commands, and its JSON
+//! Schemas.
+//!
+//! Every status and command carries the `schema_version` it was written
+//! with. Adding an optional field keeps the version, since decoders ignore
+//! the fields they don't know. Removing, renaming or changing the type of a
+//! field bumps it. Decoders accept every version up to [`SCHEMA_VERSION`],
+//! where a missing version is 0, the format from before versioning, and
+//! reject the newer ones.
+
+use crate::{bulb::BulbCommand, error::Error, fan::FanCommand, tv::TVCommand, DeviceStatus};
+use schemars::{schema::RootSchema, schema_for, JsonSchema};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The version of the payloads the simulator publishes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A status or command, with the version of its schema.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Versioned<T> {
+    /// The version of the schema of the payload. Missing from the payloads
+    /// written before versioning, which are version 0.
+    #[serde(default)]
+    pub schema_version: u32,
+    #[serde(flatten)]
+    pub payload: T,
+}
+
+impl<T> Versioned<T> {
+    /// Wrap `payload` with the current schema version.
+    pub fn new(payload: T) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            payload,
+        }
+    }
+
+    /// Unwrap the payload, if this version of the simulator understands it.
+    pub fn into_payload(self) -> Result<T, Error> {
+        check_version(self.schema_version)?;
+        Ok(self.payload)
+    }
+}
+
+/// Check that a payload of `version` can be decoded.
+pub fn check_version(version: u32) -> Result<(), Error> {
+    if version > SCHEMA_VERSION {
+        return Err(Error::UnsupportedSchemaVersion(version));
+    }
+    Ok(())
+}
+
+/// Whether a device is there, published on its availability topic.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub struct Availability {
+    pub is_available: bool,
+}
+
+/// The JSON Schemas of all the payloads, by name.
+pub fn schemas() -> BTreeMap<&'static str, RootSchema> {
+    BTreeMap::from([
+        ("status", schema_for!(Versioned<DeviceStatus>)),
+        ("bulb-command", schema_for!(Versioned<BulbCommand>)),
+        ("fan-command", schema_for!(Versioned<FanCommand>)),
+        ("tv-command", schema_for!(Versioned<TVCommand>)),
+        ("availability", schema_for!(Availability)),
+    ])
+}
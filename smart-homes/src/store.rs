@@ -0,0 +1,102 @@
+use crate::error::Error;
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::Notify;
+use tracing::{debug, error, warn};
+
+/// How long to wait for more changes before writing the store, so that a
+/// burst of commands leads to a single write.
+const FLUSH_DELAY: Duration = Duration::from_millis(500);
+
+/// A JSON file that keeps the state of every device, keyed by device id, so
+/// that the simulated homes survive a restart.
+///
+/// Saving a state only updates the store in memory; a background task writes
+/// the file shortly after, off the async executor.
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    path: Arc<PathBuf>,
+    states: Arc<Mutex<HashMap<String, serde_json::Value>>>,
+    changed: Arc<Notify>,
+}
+
+impl StateStore {
+    /// Open the store at `path`. A missing file is treated as an empty store.
+    ///
+    /// Must be called within a tokio runtime, which runs the task writing
+    /// the store.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let states = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(Error::StoreError(err)),
+        };
+
+        let store = Self {
+            path: Arc::new(path),
+            states: Arc::new(Mutex::new(states)),
+            changed: Default::default(),
+        };
+        tokio::spawn(store.clone().write_changes());
+        Ok(store)
+    }
+
+    /// Get the last saved state of the device, if any.
+    pub fn load<T: DeserializeOwned>(&self, id: &str) -> Option<T> {
+        let value = self.states.lock().get(id)?.clone();
+        match serde_json::from_value(value) {
+            Ok(state) => Some(state),
+            Err(err) => {
+                // the state was probably written by an older version; start
+                // afresh rather than refusing to boot the device.
+                warn!(id, ?err, "Ignoring unreadable saved state");
+                None
+            }
+        }
+    }
+
+    /// Save the state of the device. The store is written to disk shortly
+    /// after.
+    pub fn save<T: Serialize>(&self, id: &str, state: &T) -> Result<(), Error> {
+        let value = serde_json::to_value(state)?;
+        self.states.lock().insert(id.into(), value);
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Write the whole store to disk now.
+    pub async fn flush(&self) -> Result<(), Error> {
+        let data = serde_json::to_vec_pretty(&*self.states.lock())?;
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            // write to a temporary file first so that a crash midway does not
+            // leave a truncated store behind.
+            let tmp_path = path.with_extension("tmp");
+            fs::write(&tmp_path, data).map_err(Error::StoreError)?;
+            fs::rename(&tmp_path, &*path).map_err(Error::StoreError)?;
+            debug!(?path, "Saved device states");
+            Ok::<_, Error>(())
+        })
+        .await?
+    }
+
+    /// Write the store whenever it changes, until the program ends. A failed
+    /// write is retried with the next change.
+    async fn write_changes(self) {
+        loop {
+            self.changed.notified().await;
+            tokio::time::sleep(FLUSH_DELAY).await;
+            if let Err(err) = self.flush().await {
+                error!(path = ?self.path, ?err, "Failed to save the device states");
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use super::{topic_matches, Message, Transport};
+use crate::error::Error;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::warn;
+
+/// How many messages the broker keeps for a client that is busy, before it
+/// drops them.
+const CLIENT_QUEUE: usize = 1024;
+
+#[derive(Debug)]
+struct Client {
+    filters: Vec<String>,
+    will: Message,
+    tx: mpsc::Sender<Message>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    clients: HashMap<String, Client>,
+    retained: HashMap<String, Message>,
+}
+
+/// A broker that lives in the process and hands messages over channels, so
+/// that devices can run without any network or MQTT library.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBroker {
+    state: Arc<Mutex<State>>,
+}
+
+impl MemoryBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A transport that connects to the broker as `client_id`.
+    pub fn transport(&self, client_id: impl Into<String>) -> MemoryTransport {
+        MemoryTransport {
+            broker: self.clone(),
+            client_id: client_id.into(),
+        }
+    }
+
+    /// The ids of the clients that are currently connected.
+    pub fn client_ids(&self) -> Vec<String> {
+        self.state.lock().clients.keys().cloned().collect()
+    }
+
+    /// The filters `client_id` is subscribed to, if it is connected.
+    pub fn filters(&self, client_id: &str) -> Option<Vec<String>> {
+        let state = self.state.lock();
+        state.clients.get(client_id).map(|c| c.filters.clone())
+    }
+
+    /// The message retained on `topic`, if any.
+    pub fn retained(&self, topic: &str) -> Option<Message> {
+        self.state.lock().retained.get(topic).cloned()
+    }
+
+    /// Drop the connection of `client_id` as if the network failed, which
+    /// publishes its last will. Returns whether the client was connected.
+    pub async fn disconnect(&self, client_id: &str) -> bool {
+        let client = self.state.lock().clients.remove(client_id);
+        match client {
+            Some(client) => {
+                self.publish(client.will);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Hand `msg` to every subscriber. Like a real broker, it does not wait
+    /// for a subscriber that is behind, which loses the message instead.
+    fn publish(&self, msg: Message) {
+        let receivers: Vec<_> = {
+            let mut state = self.state.lock();
+            if msg.retained {
+                if msg.payload.is_empty() {
+                    state.retained.remove(&msg.topic);
+                } else {
+                    state.retained.insert(msg.topic.clone(), msg.clone());
+                }
+            }
+            state
+                .clients
+                .iter()
+                .filter(|(_, client)| client.filters.iter().any(|f| topic_matches(f, &msg.topic)))
+                .map(|(client_id, client)| (client_id.clone(), client.tx.clone()))
+                .collect()
+        };
+
+        // like a real broker, only new subscribers see the retained flag.
+        let msg = Message {
+            retained: false,
+            ..msg
+        };
+        for (client_id, tx) in receivers {
+            deliver(&client_id, &tx, msg.clone());
+        }
+    }
+}
+
+/// Hand `msg` to `client_id`, unless it is behind.
+fn deliver(client_id: &str, tx: &mpsc::Sender<Message>, msg: Message) {
+    if let Err(TrySendError::Full(msg)) = tx.try_send(msg) {
+        warn!(
+            client_id,
+            topic = msg.topic,
+            "Dropping message, the client is behind"
+        );
+    }
+}
+
+/// A [`Transport`] to a [`MemoryBroker`].
+#[derive(Debug, Clone)]
+pub struct MemoryTransport {
+    broker: MemoryBroker,
+    client_id: String,
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    async fn connect(&self, will: Message) -> Result<mpsc::Receiver<Message>, Error> {
+        let (tx, rx) = mpsc::channel(CLIENT_QUEUE);
+        let client = Client {
+            filters: vec![],
+            will,
+            tx,
+        };
+        let previous = self
+            .broker
+            .state
+            .lock()
+            .clients
+            .insert(self.client_id.clone(), client);
+        if let Some(previous) = previous {
+            // the new connection takes over, as it would on a real broker
+            self.broker.publish(previous.will);
+        }
+        Ok(rx)
+    }
+
+    async fn publish(&self, msg: Message) -> Result<(), Error> {
+        if !self
+            .broker
+            .state
+            .lock()
+            .clients
+            .contains_key(&self.client_id)
+        {
+            return Err(Error::Disconnected);
+        }
+        self.broker.publish(msg);
+        Ok(())
+    }
+
+    async fn subscribe(&self, filter: &str) -> Result<(), Error> {
+        let (tx, retained): (_, Vec<_>) = {
+            let mut state = self.broker.state.lock();
+            let State { clients, retained } = &mut *state;
+            let client = clients
+                .get_mut(&self.client_id)
+                .ok_or(Error::Disconnected)?;
+            client.filters.push(filter.into());
+            let retained = retained
+                .values()
+                .filter(|msg| topic_matches(filter, &msg.topic))
+                .cloned()
+                .collect();
+            (client.tx.clone(), retained)
+        };
+        for msg in retained {
+            deliver(&self.client_id, &tx, msg);
+        }
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, filter: &str) -> Result<(), Error> {
+        let mut state = self.broker.state.lock();
+        let client = state
+            .clients
+            .get_mut(&self.client_id)
+            .ok_or(Error::Disconnected)?;
+        client.filters.retain(|f| f != filter);
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+//! How the devices talk to the broker.
+//!
+//! Devices only see the [`Transport`] trait, so they can run over paho, over
+//! rumqttc, or entirely in memory without any broker at all.
+
+pub mod memory;
+#[cfg(feature = "paho")]
+pub mod paho;
+#[cfg(feature = "rumqttc")]
+pub mod rumqttc;
+
+use crate::error::Error;
+use async_trait::async_trait;
+use std::borrow::Cow;
+use tokio::sync::mpsc;
+
+/// A message published or received over a [`Transport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    topic: String,
+    payload: Vec<u8>,
+    retained: bool,
+    content_type: Option<String>,
+}
+
+impl Message {
+    pub fn new(topic: impl Into<String>, payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            topic: topic.into(),
+            payload: payload.into(),
+            retained: false,
+            content_type: None,
+        }
+    }
+
+    /// A message the broker keeps and hands to every new subscriber.
+    pub fn new_retained(topic: impl Into<String>, payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            retained: true,
+            ..Self::new(topic, payload)
+        }
+    }
+
+    /// Tell the receivers how the payload is encoded, with the MQTT 5 content
+    /// type property. Transports speaking older versions of MQTT drop it.
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn payload_str(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.payload)
+    }
+
+    pub fn retained(&self) -> bool {
+        self.retained
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+}
+
+/// A client connection to an MQTT broker, or something that behaves like
+/// one. All messages are exchanged with QoS 1.
+#[async_trait]
+pub trait Transport: Send + Sync + 'static {
+    /// Connect to the broker, which publishes `will` if the connection is
+    /// lost. Returns the stream of the messages received on the subscribed
+    /// topics, which ends when the connection is lost.
+    async fn connect(&self, will: Message) -> Result<mpsc::Receiver<Message>, Error>;
+
+    /// Publish a message.
+    async fn publish(&self, msg: Message) -> Result<(), Error>;
+
+    /// Subscribe to a topic filter, wildcards included.
+    async fn subscribe(&self, filter: &str) -> Result<(), Error>;
+
+    /// Unsubscribe from a topic filter subscribed to before.
+    async fn unsubscribe(&self, filter: &str) -> Result<(), Error>;
+}
+
+/// Whether `topic` matches the subscription `filter`, wildcards included.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    // wildcards at the start must not match the $SYS-like topics.
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut levels = topic.split('/');
+    for pattern in filter.split('/') {
+        match (pattern, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (pattern, Some(level)) if pattern == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
@@ -0,0 +1,103 @@
+use super::{Message, Transport};
+use crate::{broker::BrokerConfig, error::Error};
+use async_trait::async_trait;
+use educe::Educe;
+use paho_mqtt::{AsyncClient, MessageBuilder, Properties, PropertyCode, QOS_1};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// A [`Transport`] over the paho MQTT client.
+#[derive(Educe, Clone)]
+#[educe(Debug)]
+pub struct PahoTransport {
+    #[educe(Debug(ignore))]
+    client: AsyncClient,
+    client_id: String,
+    broker: BrokerConfig,
+}
+
+impl PahoTransport {
+    pub fn try_new(
+        client_id: impl Into<String>,
+        broker: impl Into<BrokerConfig>,
+    ) -> Result<Self, Error> {
+        let client_id = client_id.into();
+        let broker = broker.into();
+        Ok(Self {
+            client: AsyncClient::new(broker.create_options(&client_id))?,
+            client_id,
+            broker,
+        })
+    }
+}
+
+impl From<Message> for paho_mqtt::Message {
+    fn from(msg: Message) -> Self {
+        let mut props = Properties::new();
+        if let Some(content_type) = msg.content_type {
+            // only fails if the property does not hold a string
+            let _ = props.push_string(PropertyCode::ContentType, &content_type);
+        }
+        MessageBuilder::new()
+            .topic(msg.topic)
+            .payload(msg.payload)
+            .qos(QOS_1)
+            .retained(msg.retained)
+            .properties(props)
+            .finalize()
+    }
+}
+
+impl From<paho_mqtt::Message> for Message {
+    fn from(msg: paho_mqtt::Message) -> Self {
+        Self {
+            topic: msg.topic().into(),
+            payload: msg.payload().to_vec(),
+            retained: msg.retained(),
+            content_type: msg.properties().get_string(PropertyCode::ContentType),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for PahoTransport {
+    async fn connect(&self, will: Message) -> Result<mpsc::Receiver<Message>, Error> {
+        let connect_opts = self
+            .broker
+            .connect_options(&self.client_id)?
+            .keep_alive_interval(Duration::from_secs(5))
+            .will_message(paho_mqtt::Message::from(will))
+            .finalize();
+
+        // the stream must be set up before connecting, or we might miss the
+        // first messages.
+        let stream = self.client.clone().get_stream(64);
+        self.client.connect(connect_opts).await?;
+
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            // the stream yields `None` when the connection is lost
+            while let Ok(Some(msg)) = stream.recv().await {
+                if tx.send(msg.into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(rx)
+    }
+
+    async fn publish(&self, msg: Message) -> Result<(), Error> {
+        self.client.publish(msg.into()).await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, filter: &str) -> Result<(), Error> {
+        self.client.subscribe(filter, QOS_1).await?;
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, filter: &str) -> Result<(), Error> {
+        self.client.unsubscribe(filter).await?;
+        Ok(())
+    }
+}
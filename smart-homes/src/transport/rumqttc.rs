@@ -0,0 +1,153 @@
+use super::{Message, Transport};
+use crate::{broker::BrokerConfig, codec::Codec, error::Error};
+use async_trait::async_trait;
+use educe::Educe;
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use std::{sync::OnceLock, time::Duration};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+/// A [`Transport`] over the pure Rust rumqttc client, which speaks MQTT
+/// 3.1.1. It supports credentials but not TLS.
+///
+/// MQTT 3.1.1 has no content type, so the messages received have none, and
+/// publishing a payload of another codec than JSON fails rather than leave
+/// the receivers guessing how it is encoded.
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct RumqttcTransport {
+    client_id: String,
+    host: String,
+    port: u16,
+    broker: BrokerConfig,
+    #[educe(Debug(ignore))]
+    client: OnceLock<AsyncClient>,
+}
+
+impl RumqttcTransport {
+    /// Create a transport to `broker`, whose url looks like
+    /// `tcp://localhost:1883`.
+    pub fn try_new(
+        client_id: impl Into<String>,
+        broker: impl Into<BrokerConfig>,
+    ) -> Result<Self, Error> {
+        let broker = broker.into();
+        if broker.tls.is_some() {
+            return Err(Error::Transport(
+                "TLS is only supported by the paho transport".into(),
+            ));
+        }
+        let url = broker.url.as_str();
+        let address = url.split_once("://").map_or(url, |(_, address)| address);
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port
+                    .parse()
+                    .map_err(|_| Error::Transport(format!("invalid port in {url}")))?;
+                (host, port)
+            }
+            None => (address, 1883),
+        };
+        Ok(Self {
+            client_id: client_id.into(),
+            host: host.into(),
+            port,
+            broker,
+            client: OnceLock::new(),
+        })
+    }
+
+    fn client(&self) -> Result<&AsyncClient, Error> {
+        self.client.get().ok_or(Error::Disconnected)
+    }
+}
+
+#[async_trait]
+impl Transport for RumqttcTransport {
+    async fn connect(&self, will: Message) -> Result<mpsc::Receiver<Message>, Error> {
+        let mut opts = MqttOptions::new(&self.client_id, &self.host, self.port);
+        opts.set_keep_alive(Duration::from_secs(5));
+        if let Some(username) = self.broker.username_for(&self.client_id) {
+            let password = self.broker.password_for(&self.client_id);
+            opts.set_credentials(username, password.unwrap_or_default());
+        }
+        opts.set_last_will(LastWill::new(
+            will.topic,
+            will.payload,
+            QoS::AtLeastOnce,
+            will.retained,
+        ));
+        let (client, mut event_loop) = AsyncClient::new(opts, 64);
+        self.client
+            .set(client)
+            .map_err(|_| Error::Transport("already connected".into()))?;
+
+        let (tx, rx) = mpsc::channel(64);
+        let (connected_tx, connected_rx) = oneshot::channel();
+        let client_id = self.client_id.clone();
+        tokio::spawn(async move {
+            let mut connected_tx = Some(connected_tx);
+            loop {
+                match event_loop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        if let Some(connected_tx) = connected_tx.take() {
+                            let _ = connected_tx.send(Ok(()));
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        let msg = Message {
+                            topic: publish.topic,
+                            payload: publish.payload.to_vec(),
+                            retained: publish.retain,
+                            content_type: None,
+                        };
+                        if tx.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        match connected_tx.take() {
+                            Some(connected_tx) => {
+                                let _ = connected_tx.send(Err(Error::Transport(err.to_string())));
+                            }
+                            None => warn!(client_id, ?err, "Connection lost"),
+                        }
+                        break;
+                    }
+                }
+            }
+        });
+
+        connected_rx.await.map_err(|_| Error::Disconnected)??;
+        Ok(rx)
+    }
+
+    async fn publish(&self, msg: Message) -> Result<(), Error> {
+        if let Some(content_type) = msg.content_type() {
+            if content_type != Codec::Json.content_type() {
+                return Err(Error::Transport(format!(
+                    "{content_type} needs the MQTT 5 content type, which only the paho transport has"
+                )));
+            }
+        }
+        self.client()?
+            .publish(msg.topic, QoS::AtLeastOnce, msg.retained, msg.payload)
+            .await
+            .map_err(|err| Error::Transport(err.to_string()))
+    }
+
+    async fn subscribe(&self, filter: &str) -> Result<(), Error> {
+        self.client()?
+            .subscribe(filter, QoS::AtLeastOnce)
+            .await
+            .map_err(|err| Error::Transport(err.to_string()))
+    }
+
+    async fn unsubscribe(&self, filter: &str) -> Result<(), Error> {
+        self.client()?
+            .unsubscribe(filter)
+            .await
+            .map_err(|err| Error::Transport(err.to_string()))
+    }
+}
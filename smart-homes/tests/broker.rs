@@ -0,0 +1,84 @@
+use clap::Parser;
+use paho_mqtt::AsyncClient;
+use smart_homes::{broker::TlsConfig, cli::Cli};
+use std::path::PathBuf;
+use test_broker::Broker;
+
+fn cli(args: &[&str]) -> Cli {
+    Cli::try_parse_from(["smart-homes"].iter().chain(args)).unwrap()
+}
+
+#[test]
+fn tls_options_are_set_from_the_command_line() {
+    let config = cli(&[]).broker.config();
+    assert_eq!(config.tls, None);
+    let options = format!(
+        "{:?}",
+        config.connect_options("sim/rules").unwrap().finalize()
+    );
+    assert!(options.contains("ssl: None"), "{options}");
+
+    let config = cli(&[
+        "-b",
+        "ssl://broker:8883",
+        "--ca-file",
+        "ca.pem",
+        "--cert-file",
+        "client.pem",
+        "--key-file",
+        "client.key",
+        "--alpn",
+        "mqtt",
+    ])
+    .broker
+    .config();
+    assert_eq!(
+        config.tls,
+        Some(TlsConfig {
+            ca_file: Some(PathBuf::from("ca.pem")),
+            cert_file: Some(PathBuf::from("client.pem")),
+            key_file: Some(PathBuf::from("client.key")),
+            alpn: vec!["mqtt".into()],
+        })
+    );
+    // paho has no getters for its SSL options, but shows them
+    let options = format!(
+        "{:?}",
+        config.connect_options("sim/rules").unwrap().finalize()
+    );
+    for file in ["ca.pem", "client.pem", "client.key"] {
+        assert!(options.contains(file), "{file} missing from {options}");
+    }
+
+    // a CA alone is enough to turn TLS on
+    let config = cli(&["--ca-file", "ca.pem"]).broker.config();
+    assert!(config.tls.is_some());
+}
+
+#[tokio::test]
+async fn credentials_are_sent_for_each_client_id() {
+    let broker = Broker::start().await.unwrap();
+    let url = broker.url();
+    let config = cli(&[
+        "-b",
+        &url,
+        "--username",
+        "device-{client_id}",
+        "--password",
+        "secret-{client_id}",
+    ])
+    .broker
+    .config();
+
+    for client_id in ["sim/rules", "tenant/a/sim/watcher"] {
+        let client = AsyncClient::new(config.create_options(client_id)).unwrap();
+        let options = config.connect_options(client_id).unwrap().finalize();
+        client.connect(options).await.unwrap();
+        let credentials = broker.credentials(client_id).unwrap();
+        assert_eq!(credentials.username, Some(format!("device-{client_id}")));
+        assert_eq!(
+            credentials.password,
+            Some(format!("secret-{client_id}").into_bytes())
+        );
+    }
+}
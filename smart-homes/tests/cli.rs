@@ -0,0 +1,31 @@
+use clap::Parser;
+use smart_homes::cli::Cli;
+
+#[test]
+fn load_rates_are_bounded() {
+    let parse = |rate: &str| Cli::try_parse_from(["smart-homes", "load", "--ramp-rate", rate]);
+    assert!(parse("1000").is_ok());
+    for rate in ["0", "-1", "1e10", "inf", "nan"] {
+        assert!(parse(rate).is_err(), "{rate} was accepted");
+    }
+}
+
+#[test]
+fn replay_speed_is_bounded() {
+    let parse =
+        |speed: &str| Cli::try_parse_from(["smart-homes", "replay", "x.jsonl", "--speed", speed]);
+    assert!(parse("0.001").is_ok());
+    for speed in ["0", "1e-20", "inf"] {
+        assert!(parse(speed).is_err(), "{speed} was accepted");
+    }
+}
+
+#[test]
+fn time_scale_is_bounded() {
+    let parse = |scale: &str| Cli::try_parse_from(["smart-homes", "--time-scale", scale]);
+    assert!(parse("0.001").is_ok());
+    assert!(parse("100000").is_ok());
+    for scale in ["0", "1e-300", "1e300", "inf", "nan"] {
+        assert!(parse(scale).is_err(), "{scale} was accepted");
+    }
+}
@@ -0,0 +1,46 @@
+//! Statuses of the devices of `home/0`, as the rules and scenes see them.
+
+// every test uses only some of them
+#![allow(dead_code)]
+
+use chrono::Utc;
+use smart_homes::{
+    bulb::BulbStatus, codec::Codec, fan::FanStatus, transport::Message, tv::TVStatus, DeviceStatus,
+};
+
+pub fn status(status: DeviceStatus) -> Message {
+    let topic = format!("{}/{}/status", status.kind(), status.id());
+    Message::new_retained(topic, Codec::Json.encode_status(&status).unwrap())
+}
+
+pub fn bulb(is_on: bool, color: (u8, u8, u8)) -> Message {
+    status(DeviceStatus::Bulb(BulbStatus {
+        id: "home/0".into(),
+        is_on,
+        speed: 0,
+        voltage: 220.0,
+        color,
+        timestamp: Utc::now(),
+    }))
+}
+
+pub fn fan(voltage: f32) -> Message {
+    status(DeviceStatus::Fan(FanStatus {
+        id: "home/0".into(),
+        is_on: true,
+        speed: 2,
+        voltage,
+        timestamp: Utc::now(),
+    }))
+}
+
+pub fn tv(is_on: bool, channel: u16) -> Message {
+    status(DeviceStatus::TV(TVStatus {
+        id: "home/0".into(),
+        is_on,
+        channel,
+        volume: 10,
+        is_muted: false,
+        timestamp: Utc::now(),
+    }))
+}
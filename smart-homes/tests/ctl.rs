@@ -0,0 +1,79 @@
+use chrono::Utc;
+use serde_json::Value;
+use smart_homes::{bulb::Bulb, clock::Clock};
+use std::{process::Command, time::Duration};
+use test_broker::Broker;
+use tokio::time::{sleep, Instant};
+
+/// Run the control client against `broker` and get what it printed.
+fn ctl(broker: &Broker, args: &[&str]) -> Result<String, String> {
+    let output = Command::new(env!("CARGO_BIN_EXE_smart-homes-ctl"))
+        .args(["-b", &broker.url()])
+        .args(args)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    if output.status.success() {
+        Ok(stdout)
+    } else {
+        Err(String::from_utf8(output.stderr).unwrap())
+    }
+}
+
+/// Wait for the retained status of the bulb to satisfy `pred`, moving the
+/// clock along so that it keeps publishing.
+async fn wait_for_status(broker: &Broker, clock: &Clock, pred: impl Fn(&Value) -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if let Some(msg) = broker.retained("bulb/home/0/status") {
+            if pred(&serde_json::from_slice(&msg.payload).unwrap()) {
+                return;
+            }
+        }
+        clock.advance(Duration::from_secs(5));
+        sleep(Duration::from_millis(50)).await;
+    }
+    panic!("the status of the bulb never matched");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ctl_controls_and_shows_devices() {
+    let broker = Broker::start().await.unwrap();
+    let clock = Clock::manual(Utc::now());
+    let mut bulb = Bulb::try_new("home/0", broker.url())
+        .unwrap()
+        .with_clock(clock.clone());
+    tokio::spawn(async move { bulb.handle_incoming().await });
+    wait_for_status(&broker, &clock, |_| true).await;
+
+    ctl(&broker, &["bulb", "home/0", "on"]).unwrap();
+    ctl(&broker, &["bulb", "home/0", "color", "255", "0", "0"]).unwrap();
+    wait_for_status(&broker, &clock, |v| {
+        v["status"]["is_on"] == true && v["status"]["color"] == serde_json::json!([255, 0, 0])
+    })
+    .await;
+
+    let status = ctl(&broker, &["status", "bulb", "home/0", "-o", "json"]).unwrap();
+    let status: Value = serde_json::from_str(&status).unwrap();
+    assert_eq!(status["type"], "bulb");
+    assert_eq!(status["status"]["is_on"], true);
+
+    let list = ctl(&broker, &["list", "--wait", "0.5"]).unwrap();
+    let mut lines = list.lines();
+    assert!(lines.next().unwrap().starts_with("KIND"));
+    let row: Vec<_> = lines.next().unwrap().split_whitespace().collect();
+    assert_eq!(row[..4], ["bulb", "home/0", "on", "color=255,0,0"]);
+
+    // commands are checked against the commands of the device
+    let err = ctl(&broker, &["bulb", "home/0", "speed", "3"]).unwrap_err();
+    assert!(err.contains("is not a bulb command"), "{err}");
+}
+
+#[tokio::test]
+async fn list_waits_are_bounded() {
+    let broker = Broker::start().await.unwrap();
+    for wait in ["-1", "nan", "1e30"] {
+        let err = ctl(&broker, &["list", &format!("--wait={wait}")]).unwrap_err();
+        assert!(err.contains("wait must be"), "{wait}: {err}");
+    }
+}
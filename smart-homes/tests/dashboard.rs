@@ -0,0 +1,85 @@
+use chrono::Utc;
+use ratatui::{backend::TestBackend, crossterm::event::KeyCode, Terminal};
+use serde_json::{json, Value};
+use smart_homes::{
+    codec::Codec,
+    dashboard::{Action, Dashboard},
+    fan::FanStatus,
+    layout::TopicScheme,
+    transport::Message,
+    DeviceStatus,
+};
+use std::time::{Duration, Instant};
+
+fn fan_status(id: &str, speed: u8) -> Message {
+    let status = DeviceStatus::Fan(FanStatus {
+        id: id.into(),
+        is_on: true,
+        speed,
+        voltage: 220.0,
+        timestamp: Utc::now(),
+    });
+    let payload = Codec::Json.encode_status(&status).unwrap();
+    Message::new_retained(format!("fan/{id}/status"), payload)
+}
+
+fn available(kind: &str, id: &str) -> Message {
+    let payload = json!({"is_available": true}).to_string();
+    Message::new_retained(format!("{kind}/{id}/available"), payload)
+}
+
+#[test]
+fn dashboard_shows_homes_and_commands_the_selected_device() {
+    let mut dashboard = Dashboard::new(TopicScheme::default());
+    let start = Instant::now();
+    for id in ["home/10", "home/2"] {
+        dashboard.update(&available("fan", id), start);
+        dashboard.update(&fan_status(id, 3), start);
+    }
+    // commands and other topics are ignored
+    dashboard.update(&Message::new("fan/home/2/command", "{}"), start);
+    dashboard.update(&Message::new("homie/fan-home-2/$state", "ready"), start);
+
+    assert_eq!(dashboard.homes(), ["home/2", "home/10"]);
+    let fan = dashboard.device("fan", "home/10").unwrap();
+    assert_eq!(fan.available, Some(true));
+    assert!(fan.status.is_some());
+
+    let mut terminal = Terminal::new(TestBackend::new(100, 8)).unwrap();
+    terminal
+        .draw(|frame| dashboard.draw(frame, start + Duration::from_secs(7)))
+        .unwrap();
+    let screen: String = terminal
+        .backend()
+        .buffer()
+        .content()
+        .iter()
+        .map(|cell| cell.symbol())
+        .collect();
+    assert!(
+        screen.contains("2 homes, 2/2 devices available"),
+        "{screen}"
+    );
+    assert!(screen.contains("220V speed 3 7s ago"), "{screen}");
+
+    // select the fan of home/10 and speed it up
+    for key in [KeyCode::Down, KeyCode::Right] {
+        assert!(dashboard.handle_key(key).is_none());
+    }
+    assert_eq!(dashboard.selected(), Some(("fan", "home/10")));
+    let Some(Action::Send(msg)) = dashboard.handle_key(KeyCode::Char('+')) else {
+        panic!("no command was sent");
+    };
+    assert_eq!(msg.topic(), "fan/home/10/command");
+    let cmd: Value = serde_json::from_slice(msg.payload()).unwrap();
+    assert_eq!(cmd["cmd"], "speed");
+    assert_eq!(cmd["args"], 4);
+
+    // the bulbs have not reported anything, so there is nothing to command
+    assert!(dashboard.handle_key(KeyCode::Left).is_none());
+    assert!(dashboard.handle_key(KeyCode::Char('o')).is_none());
+    assert!(matches!(
+        dashboard.handle_key(KeyCode::Char('q')),
+        Some(Action::Quit)
+    ));
+}
@@ -0,0 +1,227 @@
+use chrono::{DateTime, Utc};
+use paho_mqtt::{AsyncClient, AsyncReceiver, Message, QOS_1};
+use serde_json::{json, Value};
+use smart_homes::{
+    broker::BrokerConfig, bulb::Bulb, clock::Clock, connection::Connection, fan::Fan, home::Home,
+    tv::TV,
+};
+use std::time::Duration;
+use test_broker::Broker;
+use tokio::time::{timeout, Instant};
+
+/// A client that watches what the devices publish.
+struct Observer {
+    client: AsyncClient,
+    stream: AsyncReceiver<Option<Message>>,
+}
+
+impl Observer {
+    async fn new(broker: &Broker, topic: &str) -> Self {
+        let mut client = AsyncClient::new(broker.url()).unwrap();
+        let stream = client.get_stream(64);
+        client.connect(None).await.unwrap();
+        client.subscribe(topic, QOS_1).await.unwrap();
+        Self { client, stream }
+    }
+
+    async fn send(&self, topic: &str, payload: Value) {
+        self.client
+            .publish(Message::new(topic, payload.to_string(), QOS_1))
+            .await
+            .unwrap();
+    }
+
+    /// Wait for a message on `topic` whose payload satisfies `pred`, moving
+    /// the clock along so that the devices keep publishing their statuses.
+    async fn wait_for(&self, clock: &Clock, topic: &str, pred: impl Fn(&Value) -> bool) -> Value {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            match timeout(Duration::from_millis(50), self.stream.recv()).await {
+                Ok(Ok(Some(msg))) if msg.topic() == topic => {
+                    let payload = serde_json::from_slice(msg.payload()).unwrap();
+                    if pred(&payload) {
+                        return payload;
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => clock.advance(Duration::from_secs(5)),
+            }
+        }
+        panic!("nothing matching was published on {topic}");
+    }
+}
+
+#[tokio::test]
+async fn bulb_reports_its_availability_and_status() {
+    let broker = Broker::start().await.unwrap();
+    let observer = Observer::new(&broker, "bulb/home/0/#").await;
+    let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let clock = Clock::manual(start);
+
+    let mut bulb = Bulb::try_new("home/0", broker.url())
+        .unwrap()
+        .with_clock(clock.clone());
+    tokio::spawn(async move { bulb.handle_incoming().await });
+
+    observer
+        .wait_for(&clock, "bulb/home/0/available", |v| {
+            v["is_available"] == true
+        })
+        .await;
+    let status = observer
+        .wait_for(&clock, "bulb/home/0/status", |_| true)
+        .await;
+    assert_eq!(status["type"], "bulb");
+    assert_eq!(status["status"]["id"], "home/0");
+    assert_eq!(status["status"]["is_on"], false);
+    // the status must be timestamped by the simulated clock
+    let timestamp = status["status"]["timestamp"].as_i64().unwrap();
+    assert!((start.timestamp()..=clock.now().timestamp()).contains(&timestamp));
+}
+
+#[tokio::test]
+async fn bulb_follows_commands() {
+    let broker = Broker::start().await.unwrap();
+    let observer = Observer::new(&broker, "bulb/home/0/status").await;
+    let clock = Clock::manual(Utc::now());
+
+    let mut bulb = Bulb::try_new("home/0", broker.url())
+        .unwrap()
+        .with_clock(clock.clone());
+    tokio::spawn(async move { bulb.handle_incoming().await });
+    observer
+        .wait_for(&clock, "bulb/home/0/status", |_| true)
+        .await;
+
+    observer
+        .send("bulb/home/0/command", json!({"cmd": "on"}))
+        .await;
+    observer
+        .send(
+            "bulb/home/0/command",
+            json!({"cmd": "color", "args": [255, 0, 0]}),
+        )
+        .await;
+    observer
+        .wait_for(&clock, "bulb/home/0/status", |v| {
+            v["status"]["is_on"] == true && v["status"]["color"] == json!([255, 0, 0])
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn last_will_marks_device_unavailable() {
+    let broker = Broker::start().await.unwrap();
+    let observer = Observer::new(&broker, "fan/home/0/available").await;
+    let clock = Clock::manual(Utc::now());
+
+    let mut fan = Fan::try_new("home/0", broker.url())
+        .unwrap()
+        .with_clock(clock.clone());
+    tokio::spawn(async move { fan.handle_incoming().await });
+    observer
+        .wait_for(&clock, "fan/home/0/available", |v| {
+            v["is_available"] == true
+        })
+        .await;
+
+    assert!(broker.disconnect("fan/home/0"));
+    observer
+        .wait_for(&clock, "fan/home/0/available", |v| {
+            v["is_available"] == false
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn home_shares_one_connection() {
+    let broker = Broker::start().await.unwrap();
+    let observer = Observer::new(&broker, "#").await;
+    let clock = Clock::manual(Utc::now());
+
+    let supervisor = Connection::try_new_shared("supervisor", broker.url()).unwrap();
+    let conn = Connection::try_new_shared("home/1", broker.url())
+        .unwrap()
+        .with_supervisor(supervisor);
+    let home = Home::new(
+        "home-1",
+        Bulb::new("home/1", conn.clone()).with_clock(clock.clone()),
+        Fan::new("home/1", conn.clone()).with_clock(clock.clone()),
+        TV::new("home/1", conn).with_clock(clock.clone()),
+    );
+    tokio::spawn(home.handle_incoming());
+
+    for kind in ["bulb", "fan", "tv"] {
+        observer
+            .wait_for(&clock, &format!("{kind}/home/1/available"), |v| {
+                v["is_available"] == true
+            })
+            .await;
+    }
+    let mut clients = broker.client_ids();
+    clients.sort();
+    assert!(clients.contains(&"home/1".to_string()));
+    // the home, its supervisor and the observer
+    assert_eq!(clients.len(), 3, "{clients:?}");
+
+    // every command must reach its own device only
+    observer
+        .send("tv/home/1/command", json!({"cmd": "on"}))
+        .await;
+    observer
+        .send("tv/home/1/command", json!({"cmd": "channel", "args": 5}))
+        .await;
+    observer
+        .wait_for(&clock, "tv/home/1/status", |v| {
+            v["status"]["is_on"] == true && v["status"]["channel"] == 5
+        })
+        .await;
+    let bulb = observer
+        .wait_for(&clock, "bulb/home/1/status", |_| true)
+        .await;
+    assert_eq!(bulb["status"]["is_on"], false);
+
+    // losing the shared connection is reported on the home's topic, and on
+    // those of its devices by the supervisor
+    assert!(broker.disconnect("home/1"));
+    observer
+        .wait_for(&clock, "home/1/available", |v| v["is_available"] == false)
+        .await;
+    let unavailable = async {
+        for kind in ["bulb", "fan", "tv"] {
+            let topic = format!("{kind}/home/1/available");
+            while broker.retained(&topic).map(|msg| msg.payload)
+                != Some(br#"{"is_available":false}"#.to_vec())
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    };
+    timeout(Duration::from_secs(5), unavailable).await.unwrap();
+}
+
+#[tokio::test]
+async fn devices_log_in_with_their_own_credentials() {
+    let broker = Broker::start().await.unwrap();
+    let observer = Observer::new(&broker, "bulb/home/2/available").await;
+    let clock = Clock::manual(Utc::now());
+
+    let config = BrokerConfig {
+        username: Some("sim-{client_id}".into()),
+        password: Some("secret".into()),
+        ..BrokerConfig::new(broker.url())
+    };
+    let mut bulb = Bulb::try_new("home/2", &config)
+        .unwrap()
+        .with_clock(clock.clone());
+    tokio::spawn(async move { bulb.handle_incoming().await });
+    observer
+        .wait_for(&clock, "bulb/home/2/available", |v| {
+            v["is_available"] == true
+        })
+        .await;
+
+    let credentials = broker.credentials("bulb/home/2").unwrap();
+    assert_eq!(credentials.username.as_deref(), Some("sim-bulb/home/2"));
+    assert_eq!(credentials.password.as_deref(), Some(&b"secret"[..]));
+}
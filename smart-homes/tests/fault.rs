@@ -0,0 +1,30 @@
+use serde_json::json;
+use smart_homes::fault::{FaultConfig, Faults};
+
+#[test]
+fn fault_parameters_are_bounded() {
+    let mut config = FaultConfig::default();
+    for spec in [
+        "all:delay_ms=-1",
+        "all:offline_secs=nan",
+        "all:skew_secs=1e30",
+        "all:drop_status=inf",
+        "bulb:stuck=1.5",
+    ] {
+        assert!(config.add(spec).is_err(), "{spec} was accepted");
+    }
+    config.add("all:skew_secs=3600").unwrap();
+    config.add("bulb:offline_secs=30").unwrap();
+    assert_eq!(config.all.unwrap().skew_secs, 3600);
+    assert_eq!(config.kinds["bulb"].offline_secs, 30);
+
+    for faults in [
+        json!({"skew_secs": u64::MAX}),
+        json!({"delay_ms": -1}),
+        json!({"malformed": 2.0}),
+    ] {
+        assert!(serde_json::from_value::<Faults>(faults).is_err());
+    }
+    let faults: Faults = serde_json::from_value(json!({"clock_skew": 0.5})).unwrap();
+    assert_eq!(faults.clock_skew, 0.5);
+}
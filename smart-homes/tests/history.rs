@@ -0,0 +1,199 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use serde_json::json;
+use smart_homes::{
+    codec::Codec,
+    error::Error,
+    history::{aggregate, Downsampling, Event, HistoryPolicy, HistoryStore, Record},
+    layout::TopicScheme,
+    transport::Message,
+    tv::TVStatus,
+    DeviceStatus,
+};
+use std::{fs, path::PathBuf};
+
+/// An empty directory of its own for every test.
+fn history_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("smart-homes-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn tv(is_on: bool, channel: u16, timestamp: DateTime<Utc>) -> DeviceStatus {
+    DeviceStatus::TV(TVStatus {
+        id: "home/0".into(),
+        is_on,
+        channel,
+        volume: 10,
+        is_muted: false,
+        timestamp,
+    })
+}
+
+fn tv_status(is_on: bool, timestamp: DateTime<Utc>) -> Message {
+    let status = tv(is_on, 1, timestamp);
+    let payload = Codec::Json.encode_status(&status).unwrap();
+    Message::new_retained("tv/home/0/status", payload)
+}
+
+#[test]
+fn history_tells_when_the_tv_was_turned_on() {
+    let dir = history_dir("history-query");
+    let history = HistoryStore::open(&dir, HistoryPolicy::default()).unwrap();
+    let topics = TopicScheme::default();
+    let yesterday = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let today = yesterday + TimeDelta::days(1);
+
+    history
+        .record(&topics, &tv_status(false, yesterday), today)
+        .unwrap();
+    let turned_on = yesterday + TimeDelta::hours(2);
+    history
+        .record(&topics, &tv_status(true, turned_on), today)
+        .unwrap();
+    let available = Message::new_retained(
+        "tv/home/0/available",
+        json!({"is_available": false}).to_string(),
+    );
+    history.record(&topics, &available, today).unwrap();
+    // commands and other devices are not the history of the tv
+    let command = Message::new("tv/home/0/command", json!({"cmd": "on"}).to_string());
+    history.record(&topics, &command, today).unwrap();
+
+    let records = history
+        .query("tv", "home/0", yesterday, yesterday + TimeDelta::hours(23))
+        .unwrap();
+    let on: Vec<_> = records
+        .iter()
+        .filter(|r| matches!(&r.event, Event::Status(s) if s.is_on()))
+        .map(|r| r.timestamp)
+        .collect();
+    assert_eq!(on, [turned_on]);
+
+    let records = history.query("tv", "home/0", yesterday, today).unwrap();
+    assert_eq!(records.len(), 3);
+    assert!(matches!(records[2].event, Event::Availability(false)));
+    // one file per day
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+}
+
+#[test]
+fn history_is_downsampled_then_dropped() {
+    let dir = history_dir("history-policy");
+    let policy = HistoryPolicy {
+        retention: Some(TimeDelta::days(7)),
+        downsampling: Some(Downsampling {
+            after: TimeDelta::days(1),
+            interval: TimeDelta::hours(1),
+        }),
+    };
+    let history = HistoryStore::open(&dir, policy).unwrap();
+    let topics = TopicScheme::default();
+    let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+
+    // a status every 5 minutes for the last 10 days
+    let start = now - TimeDelta::days(10);
+    let mut timestamp = start;
+    while timestamp < now {
+        history
+            .record(&topics, &tv_status(true, timestamp), now)
+            .unwrap();
+        timestamp += TimeDelta::minutes(5);
+    }
+    history.apply_policy(now).unwrap();
+
+    let records = history.query("tv", "home/0", start, now).unwrap();
+    let oldest = records.first().unwrap().timestamp;
+    assert!(oldest >= now - TimeDelta::days(7));
+    let day = |from: DateTime<Utc>| {
+        records
+            .iter()
+            .filter(|r| (from..from + TimeDelta::hours(12)).contains(&r.timestamp))
+            .count()
+    };
+    // an hour apart three days ago, five minutes apart in the last day
+    assert_eq!(day(now - TimeDelta::days(3)), 12);
+    assert_eq!(day(now - TimeDelta::hours(12)), 144);
+
+    // applying the policy again changes nothing
+    history.apply_policy(now).unwrap();
+    let again = history.query("tv", "home/0", start, now).unwrap();
+    assert_eq!(again.len(), records.len());
+}
+
+#[test]
+fn history_is_aggregated_in_buckets() {
+    let from = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let at = |minutes| from + TimeDelta::minutes(minutes);
+    let record = |minutes, event| Record {
+        timestamp: at(minutes),
+        kind: "tv".into(),
+        id: "home/0".into(),
+        event,
+    };
+    let records = [
+        // out of order, as with a skewed clock
+        record(30, Event::Status(tv(false, 2, at(30)))),
+        record(15, Event::Status(tv(true, 1, at(15)))),
+        record(45, Event::Status(tv(true, 2, at(45)))),
+        // unavailable for the whole second hour
+        record(60, Event::Availability(false)),
+        record(130, Event::Status(tv(true, 7, at(130)))),
+    ];
+
+    let buckets = aggregate(&records, from, at(180), TimeDelta::hours(1));
+    assert_eq!(buckets.len(), 3);
+    let [first, second, third] = &buckets[..] else {
+        unreachable!()
+    };
+    assert_eq!((first.start, first.end), (from, at(60)));
+    assert_eq!(first.statuses, 3);
+    // on from 15 to 30 and from 45 to 60, out of 45 known minutes
+    assert_eq!(first.on_percent.map(f64::round), Some(67.0));
+    assert_eq!(first.channel_changes, Some(1));
+    assert_eq!(first.min_voltage, None);
+
+    assert_eq!(second.statuses, 0);
+    assert_eq!(second.on_percent, None);
+    assert_eq!(second.channel_changes, Some(0));
+
+    assert_eq!(third.on_percent, Some(100.0));
+    assert_eq!(third.channel_changes, Some(1));
+}
+
+#[test]
+fn policies_longer_than_the_history_expire_nothing() {
+    let dir = history_dir("history-long-policy");
+    let policy = HistoryPolicy {
+        retention: Some(TimeDelta::days(99_999_999)),
+        downsampling: Some(Downsampling {
+            after: TimeDelta::days(99_999_999),
+            interval: TimeDelta::hours(1),
+        }),
+    };
+    let history = HistoryStore::open(&dir, policy).unwrap();
+    let topics = TopicScheme::default();
+    let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    history.record(&topics, &tv_status(true, now), now).unwrap();
+
+    history.apply_policy(now).unwrap();
+    let records = history.query("tv", "home/0", now, now).unwrap();
+    assert_eq!(records.len(), 1);
+}
+
+#[test]
+fn buckets_end_at_the_end_of_time() {
+    let to = DateTime::<Utc>::MAX_UTC;
+    let from = to - TimeDelta::days(3);
+    let buckets = aggregate(&[], from, to, TimeDelta::days(2));
+    assert_eq!(buckets.len(), 2);
+    assert_eq!(buckets[1].end, to);
+}
+
+#[test]
+fn history_failures_are_history_errors() {
+    // a file where the directory of the history should be
+    let path = history_dir("history-not-a-dir");
+    fs::write(&path, "").unwrap();
+    let err = HistoryStore::open(path.join("history"), HistoryPolicy::default()).unwrap_err();
+    assert!(matches!(err, Error::HistoryError(_)), "{err}");
+}
@@ -0,0 +1,121 @@
+use rand::{rngs::StdRng, SeedableRng};
+use serde_json::Value;
+use smart_homes::{
+    bulb::BulbCommand,
+    load::{Errors, LoadTracker, Percentiles},
+};
+use std::{
+    process::Command,
+    time::{Duration, Instant},
+};
+use test_broker::Broker;
+
+fn color(command: BulbCommand) -> (u8, u8, u8) {
+    match command {
+        BulbCommand::Color(color) => color,
+        command => panic!("{command:?} is not a color"),
+    }
+}
+
+#[test]
+fn tracker_matches_statuses_to_commands() {
+    let start = Instant::now();
+    let ms = |n| start + Duration::from_millis(n);
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut tracker = LoadTracker::new(6, Duration::from_secs(1), start);
+    assert!(tracker.send(&mut rng, start).is_none());
+
+    for id in ["home/0", "home/1"] {
+        for kind in ["bulb", "fan", "tv"] {
+            tracker.availability(kind, id, true, ms(100));
+        }
+    }
+    let (first, command) = tracker.send(&mut rng, ms(200)).unwrap();
+    let first_color = color(command);
+    let (second, command) = tracker.send(&mut rng, ms(300)).unwrap();
+    assert_ne!(first, second);
+    assert_ne!(first_color, color(command));
+    // both bulbs have a command under way
+    assert!(tracker.send(&mut rng, ms(300)).is_none());
+
+    // a status from before the command does not answer it
+    assert_eq!(tracker.status(&first, (255, 255, 255), ms(400)), None);
+    assert_eq!(
+        tracker.status(&first, first_color, ms(450)),
+        Some(Duration::from_millis(250))
+    );
+    // the other bulb goes away with its command
+    tracker.availability("bulb", &second, false, ms(500));
+    assert_eq!(tracker.pending(), 0);
+
+    let (again, command) = tracker.send(&mut rng, ms(600)).unwrap();
+    assert_eq!(again, first);
+    tracker.send_failed(&again);
+    let (again, _) = tracker.send(&mut rng, ms(700)).unwrap();
+    tracker.expire(ms(1500));
+    assert_eq!(tracker.pending(), 1);
+    tracker.expire(ms(1800));
+    assert_eq!(tracker.pending(), 0);
+    assert_eq!(tracker.status(&again, color(command), ms(1900)), None);
+
+    let report = tracker.report(ms(2000));
+    assert_eq!((report.devices, report.available), (6, 5));
+    assert_eq!(report.ramp_up_secs, Some(0.1));
+    assert_eq!((report.commands_sent, report.answered), (4, 1));
+    assert_eq!(report.statuses, 3);
+    assert_eq!(report.commands_per_sec, 2.0);
+    assert_eq!(report.latency_ms.unwrap().max, 250.0);
+    assert_eq!(
+        report.errors,
+        Errors {
+            send: 1,
+            timeouts: 1,
+            lost: 1,
+            skipped: 2,
+            device_failures: 0,
+        }
+    );
+}
+
+#[test]
+fn percentiles_are_by_nearest_rank() {
+    assert_eq!(Percentiles::of(&[]), None);
+    let latencies: Vec<_> = (1..=200).rev().map(Duration::from_millis).collect();
+    let percentiles = Percentiles::of(&latencies).unwrap();
+    assert_eq!(
+        percentiles,
+        Percentiles {
+            min: 1.0,
+            mean: 100.5,
+            p50: 100.0,
+            p90: 180.0,
+            p99: 198.0,
+            max: 200.0,
+        }
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn load_test_reports_round_trips() {
+    let broker = Broker::start().await.unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_smart-homes"))
+        .args(["-b", &broker.url(), "-n", "4"])
+        .args(["--time-scale", "50", "--seed", "1"])
+        .args(["load", "--ramp-rate", "20", "--command-rate", "20"])
+        .args(["--duration", "3s", "--timeout", "2s", "-o", "json"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["devices"], 12);
+    assert_eq!(report["available"], 12);
+    assert!(report["ramp_up_secs"].as_f64().unwrap() < 3.0);
+    let sent = report["commands_sent"].as_u64().unwrap();
+    assert!(sent > 10, "{report}");
+    assert_eq!(report["answered"], sent);
+    // a status every 100ms
+    assert!(report["latency_ms"]["max"].as_f64().unwrap() < 1000.0);
+    assert_eq!(report["errors"]["timeouts"], 0);
+    assert_eq!(report["errors"]["device_failures"], 0);
+}
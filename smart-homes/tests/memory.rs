@@ -0,0 +1,462 @@
+use chrono::Utc;
+use serde_json::{json, Value};
+use smart_homes::{
+    bulb::Bulb,
+    clock::Clock,
+    codec::Codec,
+    connection::Connection,
+    error::Error,
+    fan::Fan,
+    home::Home,
+    layout::{TopicLayout, TopicScheme},
+    transport::{
+        memory::{MemoryBroker, MemoryTransport},
+        Message, Transport,
+    },
+    tv::{TVCommand, TV},
+    DeviceStatus,
+};
+use std::time::Duration;
+use tokio::{
+    sync::mpsc,
+    time::{timeout, Instant},
+};
+
+/// Wait for a message on `topic` whose payload satisfies `pred`, moving the
+/// clock along so that the devices keep publishing their statuses.
+async fn wait_for(
+    stream: &mut mpsc::Receiver<Message>,
+    clock: &Clock,
+    topic: &str,
+    pred: impl Fn(&Value) -> bool,
+) -> Value {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        match timeout(Duration::from_millis(50), stream.recv()).await {
+            Ok(Some(msg)) if msg.topic() == topic => {
+                let payload = serde_json::from_slice(msg.payload()).unwrap();
+                if pred(&payload) {
+                    return payload;
+                }
+            }
+            Ok(_) => {}
+            Err(_) => clock.advance(Duration::from_secs(5)),
+        }
+    }
+    panic!("nothing matching was published on {topic}");
+}
+
+async fn observer(broker: &MemoryBroker) -> (MemoryTransport, mpsc::Receiver<Message>) {
+    let observer = broker.transport("observer");
+    let stream = observer
+        .connect(Message::new("observer/gone", ""))
+        .await
+        .unwrap();
+    observer.subscribe("#").await.unwrap();
+    (observer, stream)
+}
+
+#[tokio::test]
+async fn devices_run_without_a_broker() {
+    let broker = MemoryBroker::new();
+    let (observer, mut stream) = observer(&broker).await;
+    let clock = Clock::manual(Utc::now());
+
+    // the bulb has a connection of its own, the fan and the tv share one
+    let bulb_conn = Connection::new(
+        "bulb/home/0",
+        "bulb/home/0/available",
+        broker.transport("bulb/home/0"),
+    );
+    let shared_conn = Connection::new_shared("home/0", broker.transport("home/0"));
+    let home = Home::new(
+        "home-0",
+        Bulb::new("home/0", bulb_conn).with_clock(clock.clone()),
+        Fan::new("home/0", shared_conn.clone()).with_clock(clock.clone()),
+        TV::new("home/0", shared_conn).with_clock(clock.clone()),
+    );
+    tokio::spawn(home.handle_incoming());
+
+    wait_for(&mut stream, &clock, "fan/home/0/status", |_| true).await;
+    for cmd in [json!({"cmd": "on"}), json!({"cmd": "speed", "args": 3})] {
+        observer
+            .publish(Message::new("fan/home/0/command", cmd.to_string()))
+            .await
+            .unwrap();
+    }
+    wait_for(&mut stream, &clock, "fan/home/0/status", |v| {
+        v["status"]["speed"] == 3
+    })
+    .await;
+
+    let status = broker.retained("fan/home/0/status").unwrap();
+    assert!(status.retained());
+    let available = broker.retained("bulb/home/0/available").unwrap();
+    assert_eq!(available.payload_str(), r#"{"is_available":true}"#);
+}
+
+#[tokio::test]
+async fn memory_broker_publishes_last_will() {
+    let broker = MemoryBroker::new();
+    let (_observer, mut stream) = observer(&broker).await;
+    let clock = Clock::manual(Utc::now());
+
+    let conn = Connection::new(
+        "tv/home/0",
+        "tv/home/0/available",
+        broker.transport("tv/home/0"),
+    );
+    let mut tv = TV::new("home/0", conn).with_clock(clock.clone());
+    let handle = tokio::spawn(async move { tv.handle_incoming().await });
+    wait_for(&mut stream, &clock, "tv/home/0/available", |v| {
+        v["is_available"] == true
+    })
+    .await;
+
+    assert!(broker.disconnect("tv/home/0").await);
+    wait_for(&mut stream, &clock, "tv/home/0/available", |v| {
+        v["is_available"] == false
+    })
+    .await;
+    // the device notices that it lost its connection
+    let res = timeout(Duration::from_secs(5), handle)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(res.is_err());
+}
+
+#[tokio::test]
+async fn slow_receivers_do_not_hold_up_a_shared_connection() {
+    let broker = MemoryBroker::new();
+    let sender = broker.transport("sender");
+    sender
+        .connect(Message::new("sender/gone", ""))
+        .await
+        .unwrap();
+
+    let conn = Connection::new_shared("home/0", broker.transport("home/0"));
+    conn.connect().await.unwrap();
+    // nobody reads the commands of the bulb
+    let _bulb = conn.subscribe("bulb/home/0/command").await.unwrap();
+    let mut fan = conn.subscribe("fan/home/0/command").await.unwrap();
+
+    for _ in 0..100 {
+        sender
+            .publish(Message::new("bulb/home/0/command", "{}"))
+            .await
+            .unwrap();
+    }
+    sender
+        .publish(Message::new("fan/home/0/command", "{}"))
+        .await
+        .unwrap();
+    let msg = timeout(Duration::from_secs(5), fan.recv()).await.unwrap();
+    assert_eq!(msg.unwrap().topic(), "fan/home/0/command");
+}
+
+#[tokio::test]
+async fn clients_that_are_behind_do_not_hold_up_the_broker() {
+    let broker = MemoryBroker::new();
+    let connect = |client_id: &str| {
+        let transport = broker.transport(client_id);
+        let will = Message::new(format!("{client_id}/gone"), "");
+        async move {
+            let incoming = transport.connect(will).await.unwrap();
+            (transport, incoming)
+        }
+    };
+    let (sender, _) = connect("sender").await;
+    // the idle client never reads what it subscribed to
+    let (idle, _idle_incoming) = connect("idle").await;
+    idle.subscribe("#").await.unwrap();
+    let (reader, mut incoming) = connect("reader").await;
+    reader.subscribe("done").await.unwrap();
+
+    let publish_all = async {
+        for _ in 0..2000 {
+            sender.publish(Message::new("noise", "{}")).await.unwrap();
+        }
+        sender.publish(Message::new("done", "{}")).await.unwrap();
+    };
+    timeout(Duration::from_secs(5), publish_all).await.unwrap();
+    let msg = timeout(Duration::from_secs(5), incoming.recv())
+        .await
+        .unwrap();
+    assert_eq!(msg.unwrap().topic(), "done");
+}
+
+#[tokio::test]
+async fn messages_go_to_the_most_specific_subscription() {
+    let broker = MemoryBroker::new();
+    let sender = broker.transport("sender");
+    sender
+        .connect(Message::new("sender/gone", ""))
+        .await
+        .unwrap();
+
+    let conn = Connection::new_shared("simulator", broker.transport("simulator"));
+    conn.connect().await.unwrap();
+    let mut first = conn.subscribe("homie/+/bulb/+/set").await.unwrap();
+    let mut second = conn.subscribe("homie/bulb-home-0/#").await.unwrap();
+    let mut exact = conn
+        .subscribe("homie/bulb-home-0/bulb/power/set")
+        .await
+        .unwrap();
+    let duplicate = conn.subscribe("homie/bulb-home-0/#").await;
+    assert!(matches!(duplicate, Err(Error::DuplicateSubscription(_))));
+
+    for topic in [
+        "homie/bulb-home-0/bulb/power/set",
+        "homie/bulb-home-0/bulb/color/set",
+        "homie/bulb-home-0/$state",
+    ] {
+        sender.publish(Message::new(topic, "")).await.unwrap();
+    }
+    for (stream, topic) in [
+        (&mut exact, "homie/bulb-home-0/bulb/power/set"),
+        (&mut first, "homie/bulb-home-0/bulb/color/set"),
+        (&mut second, "homie/bulb-home-0/$state"),
+    ] {
+        let msg = timeout(Duration::from_secs(5), stream.recv()).await;
+        assert_eq!(msg.unwrap().unwrap().topic(), topic);
+    }
+}
+
+#[tokio::test]
+async fn failed_subscriptions_can_be_retried() {
+    let broker = MemoryBroker::new();
+    let conn = Connection::new_shared("simulator", broker.transport("simulator"));
+    let failed = conn.subscribe("bulb/+/status").await;
+    assert!(matches!(failed, Err(Error::Disconnected)));
+
+    conn.connect().await.unwrap();
+    conn.subscribe("bulb/+/status").await.unwrap();
+}
+
+#[tokio::test]
+async fn devices_announce_themselves_to_home_assistant() {
+    let broker = MemoryBroker::new();
+    let (_observer, mut stream) = observer(&broker).await;
+    let clock = Clock::manual(Utc::now());
+
+    let conn = Connection::new_shared("home/0", broker.transport("home/0"));
+    let home = Home::new(
+        "home-0",
+        Bulb::new("home/0", conn.clone()).with_discovery("ha"),
+        Fan::new("home/0", conn.clone()).with_discovery("ha"),
+        TV::new("home/0", conn).with_discovery("ha"),
+    );
+    tokio::spawn(home.handle_incoming());
+    wait_for(&mut stream, &clock, "ha/switch/tv_home_0/config", |_| true).await;
+
+    let light = broker.retained("ha/light/bulb_home_0/config").unwrap();
+    let light: Value = serde_json::from_slice(light.payload()).unwrap();
+    assert_eq!(light["state_topic"], "bulb/home/0/status");
+    assert_eq!(light["command_topic"], "bulb/home/0/command");
+    // a shared connection makes the devices depend on its availability too
+    assert_eq!(light["availability"][1]["topic"], "home/0/available");
+
+    let fan = broker.retained("ha/fan/fan_home_0/config").unwrap();
+    let fan: Value = serde_json::from_slice(fan.payload()).unwrap();
+    assert_eq!(fan["unique_id"], "smart_homes_fan_home_0");
+    let sensor = broker.retained("ha/sensor/fan_home_0_voltage/config");
+    assert!(sensor.is_some());
+    let volume = broker.retained("ha/number/tv_home_0_volume/config");
+    assert!(volume.is_some());
+}
+
+#[tokio::test]
+async fn devices_follow_the_homie_convention() {
+    let broker = MemoryBroker::new();
+    let (observer, mut stream) = observer(&broker).await;
+    let clock = Clock::manual(Utc::now());
+
+    let conn = Connection::new(
+        "bulb/home/0",
+        "bulb/home/0/available",
+        broker.transport("bulb/home/0"),
+    );
+    let mut bulb = Bulb::new("home/0", conn)
+        .with_clock(clock.clone())
+        .with_topics(TopicScheme::new(TopicLayout::Homie));
+    tokio::spawn(async move { bulb.handle_incoming().await });
+
+    wait_for_raw(&mut stream, &clock, "homie/bulb-home-0/$state", "ready").await;
+    let settable = broker.retained("homie/bulb-home-0/bulb/color/$settable");
+    assert_eq!(settable.unwrap().payload_str(), "true");
+    let datatype = broker.retained("homie/bulb-home-0/bulb/voltage/$datatype");
+    assert_eq!(datatype.unwrap().payload_str(), "float");
+
+    for (prop, value) in [("power", "true"), ("color", "255,0,0")] {
+        observer
+            .publish(Message::new(
+                format!("homie/bulb-home-0/bulb/{prop}/set"),
+                value,
+            ))
+            .await
+            .unwrap();
+    }
+    wait_for_raw(
+        &mut stream,
+        &clock,
+        "homie/bulb-home-0/bulb/color",
+        "255,0,0",
+    )
+    .await;
+    let power = broker.retained("homie/bulb-home-0/bulb/power").unwrap();
+    assert_eq!(power.payload_str(), "true");
+
+    // the last will says the device is lost
+    assert!(broker.disconnect("bulb/home/0").await);
+    let state = broker.retained("homie/bulb-home-0/$state").unwrap();
+    assert_eq!(state.payload_str(), "lost");
+}
+
+#[test]
+fn homie_tvs_are_muted_through_their_volume() {
+    let topics = TopicScheme::new(TopicLayout::Homie);
+    let settable = |prop: &str| {
+        let topic = format!("homie/tv-home-0/tv/{prop}/$settable");
+        let msgs = topics.announce("tv", "home/0");
+        msgs.into_iter()
+            .find(|msg| msg.topic() == topic)
+            .map(|msg| msg.payload_str().into_owned())
+    };
+    assert_eq!(settable("muted").as_deref(), Some("false"));
+    assert_eq!(settable("volume").as_deref(), Some("true"));
+
+    let set = |prop: &str, value: &str| {
+        let msg = Message::new(format!("homie/tv-home-0/tv/{prop}/set"), value);
+        topics.parse_command::<TVCommand>("tv", &msg)
+    };
+    assert!(set("muted", "true").is_none());
+    assert!(set("muted", "false").is_none());
+    assert!(matches!(set("volume", "0"), Some(TVCommand::Volume(0))));
+}
+
+#[tokio::test]
+async fn tenants_share_a_broker() {
+    let broker = MemoryBroker::new();
+    let (observer, mut stream) = observer(&broker).await;
+    let clock = Clock::manual(Utc::now());
+
+    // two simulators with the same devices, under different prefixes
+    for tenant in ["a", "b"] {
+        let topics = TopicScheme::default().with_prefix(format!("tenant/{tenant}"));
+        // the connection shared by the whole simulator, as in the binary
+        let client_id = topics.client_id("simulator");
+        let conn = Connection::new_shared(&client_id, broker.transport(&client_id))
+            .with_will(topics.connection_will("simulator"));
+        let mut tv = TV::new("home/0", conn)
+            .with_clock(clock.clone())
+            .with_topics(topics);
+        tokio::spawn(async move { tv.handle_incoming().await });
+    }
+    wait_for(&mut stream, &clock, "tenant/b/tv/home/0/status", |_| true).await;
+
+    observer
+        .publish(Message::new(
+            "tenant/a/tv/home/0/command",
+            json!({"cmd": "on"}).to_string(),
+        ))
+        .await
+        .unwrap();
+    wait_for(&mut stream, &clock, "tenant/a/tv/home/0/status", |v| {
+        v["status"]["is_on"] == true
+    })
+    .await;
+    let other = broker.retained("tenant/b/tv/home/0/status").unwrap();
+    let other: Value = serde_json::from_slice(other.payload()).unwrap();
+    assert_eq!(other["status"]["is_on"], false);
+    assert!(broker.retained("tenant/a/simulator/available").is_some());
+    let client_ids = broker.client_ids();
+    assert!(client_ids.contains(&"tenant/a/simulator".to_string()));
+    assert!(client_ids.contains(&"tenant/b/simulator".to_string()));
+    assert!(broker.retained("tv/home/0/status").is_none());
+}
+
+#[tokio::test]
+async fn devices_speak_binary_codecs() {
+    let broker = MemoryBroker::new();
+    let (observer, mut stream) = observer(&broker).await;
+    let clock = Clock::manual(Utc::now());
+
+    for (codec, id) in [(Codec::Cbor, "home/0"), (Codec::Protobuf, "home/1")] {
+        let client_id = format!("tv/{id}");
+        let conn = Connection::new(
+            &client_id,
+            format!("{client_id}/available"),
+            broker.transport(&client_id),
+        );
+        let mut tv = TV::new(id, conn)
+            .with_clock(clock.clone())
+            .with_topics(TopicScheme::default().with_codec(codec));
+        tokio::spawn(async move { tv.handle_incoming().await });
+    }
+
+    // the devices are listening once they are available
+    let ready = async {
+        while ["tv/home/0/available", "tv/home/1/available"]
+            .iter()
+            .any(|topic| broker.retained(topic).is_none())
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    timeout(Duration::from_secs(5), ready).await.unwrap();
+
+    // commands are decoded by their content type, whatever the codec of
+    // the device
+    for (codec, id) in [(Codec::MessagePack, "home/0"), (Codec::Protobuf, "home/1")] {
+        let cmd = codec.encode_command(&TVCommand::Channel(42)).unwrap();
+        let msg =
+            Message::new(format!("tv/{id}/command"), cmd).with_content_type(codec.content_type());
+        observer.publish(msg).await.unwrap();
+    }
+
+    for (codec, id) in [(Codec::Cbor, "home/0"), (Codec::Protobuf, "home/1")] {
+        let topic = format!("tv/{id}/status");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let status = loop {
+            assert!(
+                Instant::now() < deadline,
+                "channel 42 was not published on {topic}"
+            );
+            match timeout(Duration::from_millis(50), stream.recv()).await {
+                Ok(Some(msg)) if msg.topic() == topic => {
+                    assert_eq!(msg.content_type(), Some(codec.content_type()));
+                    match codec.decode_status(msg.payload()).unwrap() {
+                        DeviceStatus::TV(status) if status.channel == 42 => break status,
+                        _ => {}
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => clock.advance(Duration::from_secs(5)),
+            }
+        };
+        assert_eq!(status.id, id);
+    }
+
+    // availability stays JSON
+    let available = broker.retained("tv/home/1/available").unwrap();
+    assert_eq!(available.payload_str(), r#"{"is_available":true}"#);
+}
+
+/// Wait for `value` to be published on `topic`, moving the clock along.
+async fn wait_for_raw(
+    stream: &mut mpsc::Receiver<Message>,
+    clock: &Clock,
+    topic: &str,
+    value: &str,
+) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        match timeout(Duration::from_millis(50), stream.recv()).await {
+            Ok(Some(msg)) if msg.topic() == topic && msg.payload_str() == value => return,
+            Ok(_) => {}
+            Err(_) => clock.advance(Duration::from_secs(5)),
+        }
+    }
+    panic!("{value} was not published on {topic}");
+}
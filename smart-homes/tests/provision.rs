@@ -0,0 +1,288 @@
+use chrono::Utc;
+use serde_json::{json, Value};
+use smart_homes::{
+    clock::Clock,
+    connection::Connection,
+    error::Error,
+    fault::{FaultConfig, FaultInjector, Faults},
+    layout::TopicScheme,
+    provision::{AdminCommand, Device, DeviceInfo, Provisioner},
+    transport::{
+        memory::{MemoryBroker, MemoryTransport},
+        Message, Transport,
+    },
+};
+use std::time::Duration;
+use tokio::{
+    sync::mpsc,
+    time::{timeout, Instant},
+};
+
+fn command(command: Value) -> AdminCommand {
+    serde_json::from_value(command).unwrap()
+}
+
+/// Run the devices on `broker`, each with a connection of its own.
+fn provisioner(broker: &MemoryBroker, clock: &Clock, injector: &FaultInjector) -> Provisioner {
+    let (broker, clock, faults) = (broker.clone(), clock.clone(), injector.clone());
+    Provisioner::new(
+        TopicScheme::default(),
+        injector.clone(),
+        move |kind: &str, id: &str| {
+            let name = format!("{kind}/{id}");
+            let will = format!("{name}/available");
+            let conn = Connection::new(&name, &will, broker.transport(&name));
+            Ok(Device::new(kind, id, conn)
+                .with_clock(clock.clone())
+                .with_faults(faults.clone()))
+        },
+    )
+}
+
+/// Count the messages published on `topic` while the clock moves a minute
+/// along, leaving out those that were under way already.
+async fn published(stream: &mut mpsc::Receiver<Message>, clock: &Clock, topic: &str) -> usize {
+    while let Ok(Some(_)) = timeout(Duration::from_millis(50), stream.recv()).await {}
+    let mut count = 0;
+    for _ in 0..12 {
+        clock.advance(Duration::from_secs(5));
+        while let Ok(Some(msg)) = timeout(Duration::from_millis(20), stream.recv()).await {
+            count += usize::from(msg.topic() == topic);
+        }
+    }
+    count
+}
+
+/// Wait for a message on `topic` whose payload satisfies `pred`.
+async fn wait_for(
+    stream: &mut mpsc::Receiver<Message>,
+    clock: &Clock,
+    topic: &str,
+    pred: impl Fn(&Value) -> bool,
+) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        match timeout(Duration::from_millis(50), stream.recv()).await {
+            Ok(Some(msg)) if msg.topic() == topic => {
+                if pred(&serde_json::from_slice(msg.payload()).unwrap()) {
+                    return;
+                }
+            }
+            Ok(_) => {}
+            Err(_) => clock.advance(Duration::from_secs(5)),
+        }
+    }
+    panic!("nothing matching was published on {topic}");
+}
+
+async fn observer(broker: &MemoryBroker) -> (MemoryTransport, mpsc::Receiver<Message>) {
+    let observer = broker.transport("observer");
+    let stream = observer
+        .connect(Message::new("observer/gone", ""))
+        .await
+        .unwrap();
+    observer.subscribe("#").await.unwrap();
+    (observer, stream)
+}
+
+#[test]
+fn admin_commands_target_devices_or_homes() {
+    let home = command(json!({"cmd": "pause", "target": "home/3"}));
+    assert_eq!(
+        home.devices().unwrap(),
+        [
+            ("bulb", "home/3".to_string()),
+            ("fan", "home/3".into()),
+            ("tv", "home/3".into()),
+        ]
+    );
+    let faults = command(json!({
+        "cmd": "configure",
+        "target": "fan/home/3",
+        "faults": {"drop_status": 0.5}
+    }));
+    assert_eq!(faults.devices().unwrap(), [("fan", "home/3".to_string())]);
+    let AdminCommand::Configure { faults, .. } = faults else {
+        panic!("{faults:?} is not a configure command");
+    };
+    assert_eq!(faults.unwrap().drop_status, 0.5);
+
+    for target in ["", "bulb", "home/+", "fan/home/#", "home//3"] {
+        let remove = AdminCommand::Remove {
+            target: target.into(),
+        };
+        assert!(remove.devices().is_err(), "{target} is a valid target");
+    }
+}
+
+#[tokio::test]
+async fn devices_are_added_paused_and_removed() {
+    let broker = MemoryBroker::new();
+    let (observer, mut stream) = observer(&broker).await;
+    let clock = Clock::manual(Utc::now());
+    let injector = FaultInjector::new(FaultConfig::default());
+    let mut provisioner = provisioner(&broker, &clock, &injector);
+
+    provisioner.add_home("home/0", true).await.unwrap();
+    wait_for(&mut stream, &clock, "fan/home/0/status", |_| true).await;
+    let paused = provisioner
+        .handle(&command(json!({"cmd": "pause", "target": "fan/home/0"})))
+        .await
+        .unwrap();
+    assert_eq!(paused[0].topic(), "fan/home/0/available");
+    assert_eq!(paused[0].payload_str(), r#"{"is_available":false}"#);
+    let inventory: Vec<DeviceInfo> = serde_json::from_slice(paused[1].payload()).unwrap();
+    assert_eq!(
+        inventory,
+        [
+            DeviceInfo {
+                device: "bulb/home/0".into(),
+                paused: false,
+            },
+            DeviceInfo {
+                device: "fan/home/0".into(),
+                paused: true,
+            },
+            DeviceInfo {
+                device: "tv/home/0".into(),
+                paused: false,
+            },
+        ]
+    );
+
+    // a paused fan ignores its commands and publishes nothing
+    let on = Message::new("fan/home/0/command", json!({"cmd": "on"}).to_string());
+    observer.publish(on.clone()).await.unwrap();
+    assert_eq!(published(&mut stream, &clock, "fan/home/0/status").await, 0);
+    provisioner
+        .handle(&command(json!({"cmd": "resume", "target": "home/0"})))
+        .await
+        .unwrap();
+    observer.publish(on).await.unwrap();
+    wait_for(&mut stream, &clock, "fan/home/0/status", |v| {
+        v["status"]["is_on"] == true
+    })
+    .await;
+
+    let removed = provisioner
+        .handle(&command(json!({"cmd": "remove", "target": "home/0"})))
+        .await
+        .unwrap();
+    assert_eq!(removed.len(), 4);
+    assert!(provisioner.devices().is_empty());
+    assert_eq!(
+        published(&mut stream, &clock, "bulb/home/0/status").await,
+        0
+    );
+    // nothing is left to remove
+    let again = command(json!({"cmd": "remove", "target": "home/0"}));
+    assert!(provisioner.handle(&again).await.is_err());
+}
+
+#[tokio::test]
+async fn devices_are_configured_and_reported_when_they_fail() {
+    let broker = MemoryBroker::new();
+    let (_observer, mut stream) = observer(&broker).await;
+    let clock = Clock::manual(Utc::now());
+    let injector = FaultInjector::new(FaultConfig::default());
+    let mut provisioner = provisioner(&broker, &clock, &injector);
+
+    let add = command(json!({"cmd": "add", "target": "bulb/home/1"}));
+    provisioner.handle(&add).await.unwrap();
+    // adding a running device again changes nothing
+    provisioner.handle(&add).await.unwrap();
+    assert_eq!(provisioner.devices().len(), 1);
+    wait_for(&mut stream, &clock, "bulb/home/1/status", |_| true).await;
+
+    let drop_all = Faults {
+        drop_status: 1.0,
+        ..Default::default()
+    };
+    let configure = AdminCommand::Configure {
+        target: "bulb/home/1".into(),
+        faults: Some(drop_all),
+    };
+    provisioner.handle(&configure).await.unwrap();
+    assert_eq!(
+        published(&mut stream, &clock, "bulb/home/1/status").await,
+        0
+    );
+    let reset = AdminCommand::Configure {
+        target: "bulb/home/1".into(),
+        faults: None,
+    };
+    provisioner.handle(&reset).await.unwrap();
+    assert!(published(&mut stream, &clock, "bulb/home/1/status").await > 0);
+
+    assert!(broker.disconnect("bulb/home/1").await);
+    let (device, res) = timeout(Duration::from_secs(5), provisioner.join_next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(device, "bulb/home/1");
+    assert!(res.is_err());
+    assert!(provisioner.devices().is_empty());
+}
+
+#[tokio::test]
+async fn removed_devices_leave_their_shared_connection() {
+    let broker = MemoryBroker::new();
+    let (observer, mut stream) = observer(&broker).await;
+    let clock = Clock::manual(Utc::now());
+    let injector = FaultInjector::new(FaultConfig::default());
+    let conn = Connection::new_shared("home/0", broker.transport("home/0"));
+    let mut provisioner = Provisioner::new(TopicScheme::default(), injector, {
+        let clock = clock.clone();
+        move |kind: &str, id: &str| Ok(Device::new(kind, id, conn.clone()).with_clock(clock.clone()))
+    });
+
+    provisioner.add_home("home/0", true).await.unwrap();
+    wait_for(&mut stream, &clock, "tv/home/0/status", |_| true).await;
+    let remove = command(json!({"cmd": "remove", "target": "tv/home/0"}));
+    provisioner.handle(&remove).await.unwrap();
+    let mut filters = broker.filters("home/0").unwrap();
+    filters.sort();
+    assert_eq!(filters, ["bulb/home/0/command", "fan/home/0/command"]);
+
+    // the tv comes back with a single subscription, and takes commands again
+    let add = command(json!({"cmd": "add", "target": "tv/home/0"}));
+    provisioner.handle(&add).await.unwrap();
+    wait_for(&mut stream, &clock, "tv/home/0/available", |v| {
+        v["is_available"] == true
+    })
+    .await;
+    assert_eq!(broker.filters("home/0").unwrap().len(), 3);
+    let on = Message::new("tv/home/0/command", json!({"cmd": "on"}).to_string());
+    observer.publish(on).await.unwrap();
+    wait_for(&mut stream, &clock, "tv/home/0/status", |v| {
+        v["status"]["is_on"] == true
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn homes_that_fail_to_start_are_not_half_added() {
+    let broker = MemoryBroker::new();
+    let injector = FaultInjector::new(FaultConfig::default());
+    let mut provisioner = Provisioner::new(TopicScheme::default(), injector, {
+        let broker = broker.clone();
+        move |kind: &str, id: &str| {
+            if kind == "tv" {
+                return Err(Error::Transport("no tvs here".into()));
+            }
+            let name = format!("{kind}/{id}");
+            let will = format!("{name}/available");
+            Ok(Device::new(
+                kind,
+                id,
+                Connection::new(&name, &will, broker.transport(&name)),
+            ))
+        }
+    });
+
+    let add = command(json!({"cmd": "add", "target": "home/2"}));
+    assert!(provisioner.handle(&add).await.is_err());
+    assert!(provisioner.devices().is_empty());
+    assert!(provisioner.add_home("home/3", false).await.is_err());
+    assert!(provisioner.devices().is_empty());
+}
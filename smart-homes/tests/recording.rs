@@ -0,0 +1,147 @@
+use paho_mqtt::{AsyncClient, Message as PahoMessage, QOS_1};
+use serde_json::{json, Value};
+use smart_homes::{
+    recording::{self, Payload, Recorder},
+    transport::Message,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+use test_broker::Broker;
+use tokio::time::{sleep, Instant};
+
+fn recording_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("smart-homes-{name}-{}.jsonl", std::process::id()))
+}
+
+/// Kills the simulator when the test is done with it, passed or not.
+struct Simulator(Child);
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Wait for the recording to have a message on `topic`.
+async fn wait_for_recorded(path: &Path, topic: &str) -> recording::RecordedMessage {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if let Ok(messages) = recording::load(path) {
+            if let Some(msg) = messages.into_iter().find(|msg| msg.topic == topic) {
+                return msg;
+            }
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    panic!("nothing was recorded on {topic}");
+}
+
+#[test]
+fn recordings_keep_payloads_and_timing() {
+    let path = recording_path("recording-round-trip");
+    let mut recorder = Recorder::create(&path).unwrap();
+    let status = Message::new_retained("bulb/home/0/status", r#"{"type":"bulb"}"#)
+        .with_content_type("application/json");
+    let command = Message::new("fan/home/0/command", vec![0xa1, 0x63, 0xff])
+        .with_content_type("application/cbor");
+    recorder.record(&status).unwrap();
+    recorder.record(&command).unwrap();
+
+    let text = fs::read_to_string(&path).unwrap();
+    assert!(text
+        .lines()
+        .nth(1)
+        .unwrap()
+        .contains(r#""payload_base64":"oWP/""#));
+    let messages = recording::load(&path).unwrap();
+    assert_eq!(
+        messages.iter().map(|msg| msg.message()).collect::<Vec<_>>(),
+        [status, command]
+    );
+    assert_eq!(
+        messages[0].payload,
+        Payload::Text(r#"{"type":"bulb"}"#.into())
+    );
+
+    // a line cut short or too late is skipped, and edited recordings are
+    // put back in order
+    let lines = [
+        r#"{"offset_ms":2000,"topic":"b","payload":"2"}"#,
+        r#"{"offset_ms":1000,"topic":"a","retained":true,"payload":"1"}"#,
+        r#"{"offset_ms":3000,"topic":"#,
+        // too late to replay, edited by hand
+        r#"{"offset_ms":18446744073709551615,"topic":"c","payload":"3"}"#,
+    ];
+    fs::write(&path, lines.join("\n")).unwrap();
+    let messages = recording::load(&path).unwrap();
+    assert_eq!(
+        messages.iter().map(|msg| &*msg.topic).collect::<Vec<_>>(),
+        ["a", "b"]
+    );
+    assert!(messages[0].retained && !messages[1].retained);
+    assert_eq!(messages[1].due(4.0), Duration::from_millis(500));
+    let _ = fs::remove_file(&path);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn recorded_traffic_is_replayed() {
+    let path = recording_path("recording-replay");
+    let broker = Broker::start().await.unwrap();
+    let simulator = Simulator(
+        Command::new(env!("CARGO_BIN_EXE_smart-homes"))
+            .args(["-b", &broker.url(), "-n", "1", "--seed", "1"])
+            .args(["--time-scale", "10", "--record"])
+            .arg(&path)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    let available = wait_for_recorded(&path, "bulb/home/0/available").await;
+    assert!(available.retained);
+    let client = AsyncClient::new(broker.url()).unwrap();
+    client.connect(None).await.unwrap();
+    let command = json!({"cmd": "color", "args": [0, 0, 255]}).to_string();
+    client
+        .publish(PahoMessage::new("bulb/home/0/command", command, QOS_1))
+        .await
+        .unwrap();
+    let recorded = wait_for_recorded(&path, "bulb/home/0/command").await;
+    assert!(!recorded.retained);
+    assert!(recorded.offset_ms > available.offset_ms);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let status = loop {
+        let status = recording::load(&path)
+            .unwrap()
+            .into_iter()
+            .filter(|msg| msg.topic == "bulb/home/0/status")
+            .filter_map(|msg| serde_json::from_slice::<Value>(msg.message().payload()).ok())
+            .find(|status| status["status"]["color"] == json!([0, 0, 255]));
+        if let Some(status) = status {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "the new color was not recorded");
+        sleep(Duration::from_millis(100)).await;
+    };
+    drop(simulator);
+
+    // a fresh broker gets the statuses back, but the commands are not kept
+    let local = Broker::start().await.unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_smart-homes"))
+        .args(["-b", &local.url(), "replay", "--speed", "100"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let replayed = local.retained("bulb/home/0/status").unwrap();
+    let replayed: Value = serde_json::from_slice(&replayed.payload).unwrap();
+    assert_eq!(replayed["status"]["color"], status["status"]["color"]);
+    assert!(local.retained("bulb/home/0/available").is_some());
+    assert!(local.retained("bulb/home/0/command").is_none());
+    let _ = fs::remove_file(&path);
+}
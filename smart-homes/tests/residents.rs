@@ -0,0 +1,124 @@
+use chrono::{DateTime, Days, NaiveDate, NaiveTime, TimeDelta, Utc};
+use serde_json::{json, Value};
+use smart_homes::{
+    layout::TopicScheme,
+    residents::{Day, Residents},
+    schedule::Location,
+};
+
+fn paris() -> Location {
+    Location {
+        latitude: Some(48.8566),
+        longitude: Some(2.3522),
+        timezone: "Europe/Paris".parse().unwrap(),
+    }
+}
+
+#[test]
+fn days_are_drawn_from_the_seed() {
+    let first = NaiveDate::from_ymd_opt(2024, 6, 1).unwrap();
+    for i in 0..60 {
+        let date = first + Days::new(i);
+        let day = Day::draw(Some(7), "home/0", date);
+        assert_eq!(day, Day::draw(Some(7), "home/0", date));
+        assert!(day.wake_up < day.tv.0 && day.tv.0 < day.tv.1 && day.tv.1 < day.bedtime);
+        if let Some((leave, back)) = day.away {
+            assert!(day.wake_up < leave && leave < back && back < day.tv.0);
+        }
+    }
+    let date = first + Days::new(2);
+    assert_ne!(
+        Day::draw(Some(7), "home/0", date),
+        Day::draw(Some(7), "home/1", date)
+    );
+}
+
+#[test]
+fn residents_use_the_devices_through_the_day() {
+    let topics = TopicScheme::default();
+    let mut residents = Residents::new(topics.clone(), Some(7)).with_home("home/0", paris());
+    // midnight in Paris, on a Wednesday
+    let start: DateTime<Utc> = "2024-06-18T22:00:00Z".parse().unwrap();
+    let day = Day::draw(
+        Some(7),
+        "home/0",
+        NaiveDate::from_ymd_opt(2024, 6, 19).unwrap(),
+    );
+
+    // everybody is asleep: everything gets turned off
+    let messages = residents.tick(start);
+    let topics_sent: Vec<_> = messages.iter().map(|msg| msg.topic()).collect();
+    assert_eq!(
+        topics_sent,
+        [
+            "bulb/home/0/command",
+            "fan/home/0/command",
+            "tv/home/0/command"
+        ]
+    );
+
+    let mut tv = vec![];
+    let mut bulb = vec![];
+    for minute in 1..=24 * 60 {
+        let now = start + TimeDelta::minutes(minute);
+        for msg in residents.tick(now) {
+            let command: Value = topics.parse_command("tv", &msg).unwrap();
+            let local = (now + TimeDelta::hours(2)).time();
+            match msg.topic() {
+                "tv/home/0/command" => tv.push((local, command)),
+                "bulb/home/0/command" => bulb.push((local, command)),
+                _ => {}
+            }
+        }
+    }
+    assert_eq!(
+        tv,
+        [
+            (day.tv.0, json!({"cmd": "on"})),
+            (day.tv.0, json!({"cmd": "channel", "args": day.channel})),
+            (day.tv.1, json!({"cmd": "off"})),
+        ]
+    );
+    // on until sunrise, and from sunset to bedtime
+    let sunset = NaiveTime::from_hms_opt(21, 58, 0).unwrap();
+    assert_eq!(bulb.last().unwrap(), &(day.bedtime, json!({"cmd": "off"})));
+    let (on, _) = bulb[bulb.len() - 2];
+    assert!((on - sunset).num_minutes().abs() <= 2);
+
+    // the wishes only depend on the time, not on how often they are asked
+    let mut again = Residents::new(topics, Some(7)).with_home("home/0", paris());
+    let evening = start + TimeDelta::hours(23);
+    assert_eq!(
+        again.wanted("home/0", evening),
+        residents.wanted("home/0", evening)
+    );
+}
+
+#[test]
+fn residents_turn_the_fan_on_when_it_is_warm() {
+    let mut residents =
+        Residents::new(TopicScheme::default(), Some(7)).with_home("home/0", Location::default());
+    let afternoon = NaiveTime::from_hms_opt(15, 0, 0).unwrap();
+    let first = NaiveDate::from_ymd_opt(2024, 6, 1).unwrap();
+    let mut checked = 0;
+    for i in 0..60 {
+        let date = first + Days::new(i);
+        let day = Day::draw(Some(7), "home/0", date);
+        if !day.is_up(afternoon) {
+            continue;
+        }
+        let wanted = residents
+            .wanted("home/0", date.and_time(afternoon).and_utc())
+            .unwrap();
+        let speed = match day.peak_temperature {
+            t if t >= 30.0 => Some(3),
+            t if t >= 28.0 => Some(2),
+            t if t >= 26.0 => Some(1),
+            _ => None,
+        };
+        assert_eq!(wanted.fan, speed, "{day:?}");
+        assert!(!wanted.light);
+        checked += 1;
+    }
+    assert!(checked > 5);
+}
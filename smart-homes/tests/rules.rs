@@ -0,0 +1,146 @@
+mod common;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use common::{fan, tv};
+use serde_json::{json, Value};
+use smart_homes::{
+    layout::TopicScheme,
+    rules::{RuleEngine, RuleSet},
+    transport::Message,
+};
+
+fn rules(rules: Value) -> RuleSet {
+    serde_json::from_value(json!({ "rules": rules })).unwrap()
+}
+
+#[test]
+fn rules_fire_on_changes_when_their_conditions_hold() {
+    let rules = rules(json!([
+        {
+            "name": "movie lights",
+            "trigger": { "on": "state_change", "device": "tv/home/0", "field": "is_on", "to": true },
+            "conditions": [{ "device": "fan/home/0", "field": "voltage", "below": 240 }],
+            "actions": [
+                { "do": "command", "device": "bulb/home/0", "command": { "cmd": "color", "args": [255, 0, 0] } },
+                { "do": "notify", "message": "enjoy the movie" }
+            ]
+        },
+        {
+            "name": "overvoltage",
+            "trigger": { "on": "threshold", "device": "fan/home/0", "field": "voltage", "above": 240 },
+            "actions": [{ "do": "command", "device": "fan/home/0", "command": { "cmd": "off" } }]
+        },
+        {
+            "name": "tv lost",
+            "trigger": { "on": "availability", "device": "tv/home/0", "is_available": false },
+            "actions": [{ "do": "notify", "message": "the tv is gone" }]
+        }
+    ]));
+    let mut engine = RuleEngine::new(TopicScheme::default(), rules).unwrap();
+    let now = Utc::now();
+
+    assert!(engine.handle(&fan(220.0), now).is_empty());
+    // the first status is not a change
+    assert!(engine.handle(&tv(false, 1), now).is_empty());
+    assert!(engine.handle(&tv(false, 2), now).is_empty());
+
+    let firings = engine.handle(&tv(true, 2), now);
+    assert_eq!(firings.len(), 1);
+    assert_eq!(firings[0].rule, "movie lights");
+    let [command, notification] = &firings[0].messages[..] else {
+        panic!("{:?}", firings[0].messages);
+    };
+    assert_eq!(command.topic(), "bulb/home/0/command");
+    let command: Value = serde_json::from_slice(command.payload()).unwrap();
+    assert_eq!(command["cmd"], "color");
+    assert_eq!(notification.topic(), "sim/rules/notifications");
+
+    // the threshold fires once when crossed, not on every status above it
+    assert_eq!(engine.handle(&fan(245.0), now)[0].rule, "overvoltage");
+    assert!(engine.handle(&fan(246.0), now).is_empty());
+    // and the condition of the first rule no longer holds
+    assert!(engine.handle(&tv(false, 2), now).is_empty());
+    assert!(engine.handle(&tv(true, 2), now).is_empty());
+
+    let available = |is_available| {
+        let payload = json!({ "is_available": is_available }).to_string();
+        Message::new_retained("tv/home/0/available", payload)
+    };
+    assert!(engine.handle(&available(true), now).is_empty());
+    assert_eq!(engine.handle(&available(false), now)[0].rule, "tv lost");
+}
+
+#[test]
+fn voltage_jitter_is_not_a_state_change() {
+    let rules = rules(json!([
+        {
+            "name": "fan changed",
+            "trigger": { "on": "state_change", "device": "fan/home/0" },
+            "actions": [{ "do": "notify", "message": "the fan changed" }]
+        },
+        {
+            "name": "voltage changed",
+            "trigger": { "on": "state_change", "device": "fan/home/0", "field": "voltage" },
+            "actions": [{ "do": "notify", "message": "the voltage changed" }]
+        }
+    ]));
+    let mut engine = RuleEngine::new(TopicScheme::default(), rules).unwrap();
+    let now = Utc::now();
+
+    assert!(engine.handle(&fan(220.0), now).is_empty());
+    let firings = engine.handle(&fan(221.5), now);
+    assert_eq!(firings.len(), 1);
+    assert_eq!(firings[0].rule, "voltage changed");
+}
+
+#[test]
+fn rules_fire_at_a_time_of_day() {
+    let rules = rules(json!([{
+        "name": "night",
+        "trigger": { "on": "time", "at": "22:00" },
+        "actions": [{ "do": "command", "device": "tv/home/3", "command": { "cmd": "off" } }]
+    }]));
+    let mut engine = RuleEngine::new(TopicScheme::default().with_prefix("site"), rules).unwrap();
+    let evening: DateTime<Utc> = "2024-10-21T21:59:30Z".parse().unwrap();
+
+    assert!(engine.tick(evening).is_empty());
+    assert!(engine.tick(evening + TimeDelta::seconds(20)).is_empty());
+    let firings = engine.tick(evening + TimeDelta::seconds(40));
+    assert_eq!(firings[0].messages[0].topic(), "site/tv/home/3/command");
+    assert!(engine.tick(evening + TimeDelta::seconds(60)).is_empty());
+    // a whole day later, at once
+    assert_eq!(
+        engine
+            .tick(evening + TimeDelta::days(1) + TimeDelta::minutes(1))
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn invalid_rules_are_rejected() {
+    let mut engine = RuleEngine::new(TopicScheme::default(), RuleSet::default()).unwrap();
+    for (rule, error) in [
+        (
+            json!({"on": "state_change", "device": "oven/home/0"}),
+            "oven/home/0 is not a device",
+        ),
+        (
+            json!({"on": "threshold", "device": "fan/home/0", "field": "voltage"}),
+            "needs equals, above or below",
+        ),
+    ] {
+        let rules = rules(json!([{ "name": "broken", "trigger": rule, "actions": [] }]));
+        let err = engine.set_rules(rules).unwrap_err().to_string();
+        assert!(err.contains(error), "{err}");
+    }
+
+    let rules = rules(json!([{
+        "name": "broken",
+        "trigger": { "on": "time", "at": "07:00" },
+        "actions": [{ "do": "command", "device": "bulb/home/0", "command": { "cmd": "speed", "args": 3 } }]
+    }]));
+    let err = engine.set_rules(rules).unwrap_err().to_string();
+    assert!(err.contains("is not a bulb command"), "{err}");
+    assert!(engine.rules().is_empty());
+}
@@ -0,0 +1,29 @@
+use smart_homes::{
+    codec::Codec,
+    transport::{rumqttc::RumqttcTransport, Message, Transport},
+};
+use std::time::Duration;
+use test_broker::Broker;
+use tokio::time::sleep;
+
+#[tokio::test]
+async fn only_json_is_published_without_content_types() {
+    let broker = Broker::start().await.unwrap();
+    let transport = RumqttcTransport::try_new("rumqttc", broker.url()).unwrap();
+    let will = Message::new_retained("rumqttc/available", r#"{"is_available":false}"#);
+    let _incoming = transport.connect(will).await.unwrap();
+
+    let status = |codec: Codec| {
+        Message::new_retained(format!("{codec:?}/status"), "{}")
+            .with_content_type(codec.content_type())
+    };
+    assert!(transport.publish(status(Codec::Cbor)).await.is_err());
+    transport.publish(status(Codec::Json)).await.unwrap();
+    for _ in 0..100 {
+        if broker.retained("Json/status").is_some() {
+            return;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    panic!("the JSON status was not published");
+}
@@ -0,0 +1,98 @@
+mod common;
+
+use common::{bulb, tv};
+use serde_json::{json, Value};
+use smart_homes::{
+    layout::TopicScheme,
+    scene::{Outcome, Scene, SceneManager, SceneReport},
+    transport::Message,
+};
+use std::time::{Duration, Instant};
+
+fn request(name: &str, what: &str) -> Message {
+    let payload = json!({"home": "home/0", "request_id": "42"}).to_string();
+    Message::new(format!("sim/scenes/{name}/{what}"), payload)
+}
+
+fn report_of(messages: &[Message]) -> SceneReport {
+    let msg = messages
+        .iter()
+        .find(|msg| msg.topic().ends_with("/report"))
+        .expect("no report");
+    serde_json::from_slice(msg.payload()).unwrap()
+}
+
+#[test]
+fn scenes_are_activated_and_reported_per_device() {
+    let mut manager = SceneManager::new(TopicScheme::default());
+    let now = Instant::now();
+    let movie_night = json!({
+        "bulb": {"is_on": true, "color": [255, 191, 0]},
+        "fan": {"is_on": true, "speed": 2},
+        "tv": {"is_on": true, "channel": 5}
+    });
+    let definition = Message::new_retained("sim/scenes/movie night", movie_night.to_string());
+    assert!(manager.handle(&definition, now).is_empty());
+    assert!(manager
+        .handle(&bulb(false, (255, 255, 255)), now)
+        .is_empty());
+    assert!(manager.handle(&tv(true, 5), now).is_empty());
+
+    let messages = manager.handle(&request("movie night", "activate"), now);
+    let commands: Vec<(&str, Value)> = messages
+        .iter()
+        .map(|msg| (msg.topic(), serde_json::from_slice(msg.payload()).unwrap()))
+        .collect();
+    assert_eq!(commands.len(), 2, "{commands:?}");
+    assert_eq!(commands[0].0, "bulb/home/0/command");
+    assert_eq!(commands[0].1["cmd"], "on");
+    assert_eq!(commands[1].1["cmd"], "color");
+
+    // nothing is reported until the bulb has the color of the scene
+    assert!(manager.handle(&bulb(true, (255, 255, 255)), now).is_empty());
+    let report = report_of(&manager.handle(&bulb(true, (255, 191, 0)), now));
+    assert_eq!(report.request_id.as_deref(), Some("42"));
+    let outcomes: Vec<_> = report
+        .devices
+        .iter()
+        .map(|device| (device.device.as_str(), device.outcome))
+        .collect();
+    assert_eq!(
+        outcomes,
+        [
+            ("bulb/home/0", Outcome::Applied),
+            ("fan/home/0", Outcome::Unavailable),
+            ("tv/home/0", Outcome::Unchanged),
+        ]
+    );
+    assert!(!report.success);
+}
+
+#[test]
+fn scenes_are_captured_and_time_out() {
+    let mut manager =
+        SceneManager::new(TopicScheme::default()).with_timeout(Duration::from_secs(5));
+    let now = Instant::now();
+    manager.handle(&bulb(true, (0, 0, 255)), now);
+    manager.handle(&tv(false, 3), now);
+
+    let messages = manager.handle(&request("evening", "capture"), now);
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].topic(), "sim/scenes/evening");
+    assert!(messages[0].retained());
+    let scene: Scene = serde_json::from_slice(messages[0].payload()).unwrap();
+    assert_eq!(manager.scene("evening"), Some(&scene));
+    assert!(scene.fan.is_none());
+
+    // the bulb ignores the command, as if stuck
+    manager.handle(&bulb(false, (0, 0, 255)), now);
+    let messages = manager.handle(&request("evening", "activate"), now);
+    assert_eq!(messages.len(), 1);
+    assert!(manager.tick(now + Duration::from_secs(4)).is_empty());
+    let report = report_of(&manager.tick(now + Duration::from_secs(5)));
+    assert_eq!(report.devices[0].outcome, Outcome::TimedOut);
+    assert_eq!(report.devices[0].commands, 1);
+
+    let report = report_of(&manager.handle(&request("nope", "activate"), now));
+    assert!(report.error.unwrap().contains("no scene nope"));
+}
@@ -0,0 +1,139 @@
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use serde_json::{json, Value};
+use smart_homes::{
+    layout::TopicScheme,
+    schedule::{sun_time, ScheduleConfig, Scheduler, SunEvent},
+};
+
+fn config(value: Value) -> ScheduleConfig {
+    serde_json::from_value(value).unwrap()
+}
+
+fn utc(s: &str) -> DateTime<Utc> {
+    s.parse().unwrap()
+}
+
+#[test]
+fn sun_times_are_within_minutes() {
+    let solstice = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+    // Paris, 05:47 and 21:58 local time
+    let sunrise = sun_time(solstice, 48.8566, 2.3522, SunEvent::Sunrise).unwrap();
+    let sunset = sun_time(solstice, 48.8566, 2.3522, SunEvent::Sunset).unwrap();
+    assert!((sunrise - utc("2024-06-21T03:47:00Z")).num_minutes().abs() <= 2);
+    assert!((sunset - utc("2024-06-21T19:58:00Z")).num_minutes().abs() <= 2);
+    // the midnight sun of Tromsø
+    assert_eq!(sun_time(solstice, 69.65, 18.96, SunEvent::Sunset), None);
+}
+
+#[test]
+fn schedules_run_in_the_time_zone_of_their_home() {
+    let config = config(json!({
+        "location": {"latitude": 48.8566, "longitude": 2.3522, "timezone": "Europe/Paris"},
+        "homes": {"home/1": {"timezone": "America/New_York"}},
+        "schedules": [
+            {
+                "name": "wake up",
+                "home": "home/1",
+                "when": {"cron": "30 6 * * Mon-Fri"},
+                "actions": [{"do": "scene", "scene": "morning"}]
+            },
+            {
+                "name": "porch light",
+                "home": "home/0",
+                "when": {"sun": "sunset", "offset_minutes": -20},
+                "actions": [{"do": "command", "device": "bulb", "command": {"cmd": "on"}}]
+            }
+        ]
+    }));
+    let mut scheduler = Scheduler::new(TopicScheme::default(), config).unwrap();
+    // a Saturday
+    let now = utc("2024-06-22T12:00:00Z");
+
+    let runs = scheduler.next_runs(now);
+    assert_eq!(runs[0].schedule, "porch light");
+    assert!(
+        (runs[0].at - utc("2024-06-22T19:38:00Z"))
+            .num_minutes()
+            .abs()
+            <= 2
+    );
+    assert!(runs[0].local.ends_with("+02:00"), "{}", runs[0].local);
+    assert_eq!(runs[1].schedule, "wake up");
+    assert_eq!(runs[1].local, "2024-06-24T06:30:00-04:00");
+
+    assert!(scheduler.tick(now).is_empty());
+    let runs = scheduler.tick(now + TimeDelta::hours(12));
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0].schedule, "porch light");
+    assert_eq!(runs[0].messages[0].topic(), "bulb/home/0/command");
+
+    // the clock jumps a week ahead at once: every schedule runs once
+    let runs = scheduler.tick(now + TimeDelta::days(7));
+    assert_eq!(runs.len(), 2);
+    let wake_up = runs.iter().find(|run| run.schedule == "wake up").unwrap();
+    assert_eq!(wake_up.skipped, 4);
+    assert_eq!(wake_up.messages[0].topic(), "sim/scenes/morning/activate");
+}
+
+#[test]
+fn schedules_are_checked_and_saved() {
+    let invalid = [
+        (
+            json!({"cron": "every day"}),
+            json!([{"do": "scene", "scene": "x"}]),
+        ),
+        (
+            json!({"sun": "sunrise"}),
+            json!([{"do": "scene", "scene": "x"}]),
+        ),
+        (
+            json!({"cron": "0 7 * * *"}),
+            json!([{"do": "command", "device": "tv", "command": {"cmd": "color"}}]),
+        ),
+    ];
+    for (when, actions) in invalid {
+        let config = config(json!({"schedules": [
+            {"name": "broken", "home": "home/0", "when": when, "actions": actions}
+        ]}));
+        assert!(Scheduler::new(TopicScheme::default(), config).is_err());
+    }
+    // homes that do not make a topic
+    for home in ["home/+", "#", "home//0", ""] {
+        let config = config(json!({"schedules": [{
+            "name": "nowhere",
+            "home": home,
+            "when": {"cron": "0 7 * * *"},
+            "actions": [{"do": "scene", "scene": "x"}]
+        }]}));
+        assert!(Scheduler::new(TopicScheme::default(), config).is_err());
+    }
+    // shifted by more than a day
+    for offset_minutes in [1441, -1441, i64::MIN] {
+        let config = config(json!({
+            "location": {"latitude": 48.8566, "longitude": 2.3522},
+            "schedules": [{
+                "name": "late",
+                "home": "home/0",
+                "when": {"sun": "sunset", "offset_minutes": offset_minutes},
+                "actions": [{"do": "scene", "scene": "x"}]
+            }]
+        }));
+        assert!(Scheduler::new(TopicScheme::default(), config).is_err());
+    }
+
+    let path =
+        std::env::temp_dir().join(format!("smart-homes-schedules-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(
+        ScheduleConfig::load(&path).unwrap(),
+        ScheduleConfig::default()
+    );
+    let config = config(json!({"schedules": [{
+        "name": "night",
+        "home": "home/0",
+        "when": {"cron": "0 0 22 * * *"},
+        "actions": [{"do": "command", "device": "tv", "command": {"cmd": "off"}}]
+    }]}));
+    config.save(&path).unwrap();
+    assert_eq!(ScheduleConfig::load(&path).unwrap(), config);
+}
@@ -0,0 +1,86 @@
+use chrono::Utc;
+use serde_json::{json, Value};
+use smart_homes::{
+    codec::Codec,
+    fan::{FanCommand, FanStatus},
+    schema::{schemas, SCHEMA_VERSION},
+    DeviceStatus,
+};
+
+fn fan_status() -> DeviceStatus {
+    DeviceStatus::Fan(FanStatus {
+        id: "home/0".into(),
+        is_on: true,
+        speed: 3,
+        voltage: 220.0,
+        timestamp: Utc::now(),
+    })
+}
+
+#[test]
+fn payloads_carry_their_schema_version() {
+    let status = Codec::Json.encode_status(&fan_status()).unwrap();
+    let status: Value = serde_json::from_slice(&status).unwrap();
+    assert_eq!(status["schema_version"], SCHEMA_VERSION);
+    assert_eq!(status["type"], "fan");
+    assert_eq!(status["status"]["speed"], 3);
+
+    let cmd = Codec::Json.encode_command(&FanCommand::Speed(2)).unwrap();
+    let cmd: Value = serde_json::from_slice(&cmd).unwrap();
+    assert_eq!(
+        cmd,
+        json!({"schema_version": SCHEMA_VERSION, "cmd": "speed", "args": 2})
+    );
+
+    for codec in [Codec::Cbor, Codec::MessagePack, Codec::Protobuf] {
+        let status = codec.encode_status(&fan_status()).unwrap();
+        let DeviceStatus::Fan(status) = codec.decode_status(&status).unwrap() else {
+            panic!("{codec:?} did not decode a fan status");
+        };
+        assert_eq!(status.speed, 3);
+    }
+}
+
+#[test]
+fn older_versions_are_decoded_and_newer_ones_rejected() {
+    // payloads from before versioning have no schema_version
+    let legacy = json!({"cmd": "speed", "args": 2}).to_string();
+    let cmd: FanCommand = Codec::Json.decode_command(legacy.as_bytes()).unwrap();
+    assert!(matches!(cmd, FanCommand::Speed(2)));
+
+    let mut status = serde_json::to_value(fan_status()).unwrap();
+    assert!(Codec::Json
+        .decode_status(status.to_string().as_bytes())
+        .is_ok());
+
+    status["schema_version"] = json!(SCHEMA_VERSION + 1);
+    assert!(Codec::Json
+        .decode_status(status.to_string().as_bytes())
+        .is_err());
+    let future = json!({"schema_version": SCHEMA_VERSION + 1, "cmd": "on"}).to_string();
+    assert!(Codec::Json
+        .decode_command::<FanCommand>(future.as_bytes())
+        .is_err());
+}
+
+#[test]
+fn schemas_describe_every_payload() {
+    let schemas = schemas();
+    let names: Vec<_> = schemas.keys().copied().collect();
+    assert_eq!(
+        names,
+        [
+            "availability",
+            "bulb-command",
+            "fan-command",
+            "status",
+            "tv-command"
+        ]
+    );
+
+    let status = serde_json::to_value(&schemas["status"]).unwrap();
+    assert_eq!(status["properties"]["schema_version"]["type"], "integer");
+    assert!(status["definitions"]["TVStatus"]["properties"]["channel"].is_object());
+    let availability = serde_json::to_value(&schemas["availability"]).unwrap();
+    assert_eq!(availability["required"], json!(["is_available"]));
+}
@@ -0,0 +1,59 @@
+use paho_mqtt::{AsyncClient, Message, QOS_1};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    process::{Command, Stdio},
+    time::Duration,
+};
+use test_broker::Broker;
+use tokio::time::{sleep, Instant};
+
+/// Wait for the retained status of the bulb to satisfy `pred`.
+async fn wait_for_status(broker: &Broker, pred: impl Fn(&Value) -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if let Some(msg) = broker.retained("bulb/home/0/status") {
+            if pred(&serde_json::from_slice(&msg.payload).unwrap()) {
+                return;
+            }
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    panic!("the bulb did not publish the expected status");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn device_states_are_saved_on_ctrl_c() {
+    let path =
+        std::env::temp_dir().join(format!("smart-homes-shutdown-{}.json", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let broker = Broker::start().await.unwrap();
+    let mut simulator = Command::new(env!("CARGO_BIN_EXE_smart-homes"))
+        .args(["-b", &broker.url(), "-n", "1", "--state-file"])
+        .arg(&path)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    wait_for_status(&broker, |_| true).await;
+
+    let client = AsyncClient::new(broker.url()).unwrap();
+    client.connect(None).await.unwrap();
+    let command = json!({"cmd": "color", "args": [0, 0, 255]}).to_string();
+    client
+        .publish(Message::new("bulb/home/0/command", command, QOS_1))
+        .await
+        .unwrap();
+    wait_for_status(&broker, |v| v["status"]["color"] == json!([0, 0, 255])).await;
+
+    // sooner than the store is written in the background
+    let interrupted = Command::new("kill")
+        .args(["-INT", &simulator.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(interrupted.success());
+    assert!(simulator.wait().unwrap().success());
+
+    let states: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    assert_eq!(states["bulb/home/0"]["color"], json!([0, 0, 255]));
+    let _ = fs::remove_file(&path);
+}
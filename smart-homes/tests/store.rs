@@ -0,0 +1,40 @@
+use smart_homes::store::StateStore;
+use std::time::Duration;
+
+#[tokio::test]
+async fn states_survive_a_reopen() {
+    let path = std::env::temp_dir().join(format!("smart-homes-states-{}.json", std::process::id()));
+    let store = StateStore::open(&path).unwrap();
+    assert_eq!(store.load::<u8>("fan/home/0"), None);
+
+    store.save("fan/home/0", &3u8).unwrap();
+    store.save("fan/home/0", &4u8).unwrap();
+    // not written yet, but already loaded from memory
+    assert_eq!(store.load::<u8>("fan/home/0"), Some(4));
+    store.flush().await.unwrap();
+
+    let reopened = StateStore::open(&path).unwrap();
+    assert_eq!(reopened.load::<u8>("fan/home/0"), Some(4));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn changes_are_written_in_the_background() {
+    let path =
+        std::env::temp_dir().join(format!("smart-homes-states-bg-{}.json", std::process::id()));
+    let store = StateStore::open(&path).unwrap();
+    store.save("tv/home/0", &7u8).unwrap();
+    assert!(!path.exists());
+
+    for _ in 0..50 {
+        if path.exists() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(
+        StateStore::open(&path).unwrap().load::<u8>("tv/home/0"),
+        Some(7)
+    );
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,218 @@
+//! Just enough of the MQTT 3.1.1 and 5 wire format to talk to paho.
+
+use std::io::{self, ErrorKind};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const CONNECT: u8 = 1;
+pub const CONNACK: u8 = 2;
+pub const PUBLISH: u8 = 3;
+pub const PUBACK: u8 = 4;
+pub const PUBREC: u8 = 5;
+pub const PUBREL: u8 = 6;
+pub const PUBCOMP: u8 = 7;
+pub const SUBSCRIBE: u8 = 8;
+pub const SUBACK: u8 = 9;
+pub const UNSUBSCRIBE: u8 = 10;
+pub const UNSUBACK: u8 = 11;
+pub const PINGREQ: u8 = 12;
+pub const PINGRESP: u8 = 13;
+pub const DISCONNECT: u8 = 14;
+
+/// Protocol level of MQTT 5 in the CONNECT packet.
+pub const V5: u8 = 5;
+
+/// A packet as it comes off the wire, before its body is parsed.
+#[derive(Debug)]
+pub struct RawPacket {
+    pub kind: u8,
+    pub flags: u8,
+    pub body: Vec<u8>,
+}
+
+pub async fn read_packet(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<RawPacket> {
+    let first = reader.read_u8().await?;
+    let mut len = 0usize;
+    for i in 0..4 {
+        let byte = reader.read_u8().await?;
+        len |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).await?;
+            return Ok(RawPacket {
+                kind: first >> 4,
+                flags: first & 0x0f,
+                body,
+            });
+        }
+    }
+    Err(invalid("malformed remaining length"))
+}
+
+pub fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads the fields of a packet body.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(invalid("packet too short"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.buf)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub fn varint(&mut self) -> io::Result<usize> {
+        let mut value = 0usize;
+        for i in 0..4 {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as usize) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("malformed variable byte integer"))
+    }
+
+    pub fn binary(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+
+    pub fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.binary()?.to_vec()).map_err(|_| invalid("invalid UTF-8 string"))
+    }
+
+    /// Read the properties of an MQTT 5 packet, keeping only those that
+    /// travel with an application message.
+    pub fn properties(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.varint()?;
+        let mut props = Reader::new(self.bytes(len)?);
+        let mut kept = Vec::new();
+        while !props.is_empty() {
+            let start = props.buf;
+            let id = props.varint()?;
+            match id {
+                // byte
+                0x01 | 0x17 | 0x19 | 0x24 | 0x25 | 0x28 | 0x29 | 0x2a => {
+                    props.u8()?;
+                }
+                // two byte integer
+                0x13 | 0x21 | 0x22 | 0x23 => {
+                    props.u16()?;
+                }
+                // four byte integer
+                0x02 | 0x11 | 0x18 | 0x27 => {
+                    props.bytes(4)?;
+                }
+                // variable byte integer
+                0x0b => {
+                    props.varint()?;
+                }
+                // string or binary data
+                0x03 | 0x08 | 0x09 | 0x12 | 0x15 | 0x16 | 0x1a | 0x1c | 0x1f => {
+                    props.binary()?;
+                }
+                // string pair
+                0x26 => {
+                    props.binary()?;
+                    props.binary()?;
+                }
+                _ => return Err(invalid("unknown property")),
+            }
+            // payload format, content type, response topic, correlation data
+            // and user properties are forwarded to the subscribers.
+            if matches!(id, 0x01 | 0x03 | 0x08 | 0x09 | 0x26) {
+                kept.extend_from_slice(&start[..start.len() - props.buf.len()]);
+            }
+        }
+        Ok(kept)
+    }
+}
+
+/// Builds the body of a packet.
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn u8(mut self, v: u8) -> Self {
+        self.buf.push(v);
+        self
+    }
+
+    pub fn u16(mut self, v: u16) -> Self {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn binary(self, v: &[u8]) -> Self {
+        let mut this = self.u16(v.len() as u16);
+        this.buf.extend_from_slice(v);
+        this
+    }
+
+    pub fn raw(mut self, v: &[u8]) -> Self {
+        self.buf.extend_from_slice(v);
+        self
+    }
+
+    pub fn varint(mut self, mut v: usize) -> Self {
+        loop {
+            let mut byte = (v & 0x7f) as u8;
+            v >>= 7;
+            if v > 0 {
+                byte |= 0x80;
+            }
+            self.buf.push(byte);
+            if v == 0 {
+                return self;
+            }
+        }
+    }
+
+    /// Properties of an MQTT 5 packet, already encoded.
+    pub fn properties(self, props: &[u8]) -> Self {
+        self.varint(props.len()).raw(props)
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// Wrap the body in the fixed header of a packet.
+    pub fn finish(self, kind: u8, flags: u8) -> Vec<u8> {
+        Writer::default()
+            .u8(kind << 4 | flags)
+            .varint(self.buf.len())
+            .raw(&self.buf)
+            .buf
+    }
+}
@@ -0,0 +1,486 @@
+//! A small MQTT broker that runs inside the test process, so that the
+//! simulator and the HTTP API can be tested without an external broker.
+//!
+//! It speaks MQTT 3.1.1 and 5 well enough for paho: retained messages,
+//! wildcard subscriptions, last wills and the retain as published option
+//! are supported. Messages are always
+//! delivered with QoS 0 and sessions are never persisted.
+
+mod codec;
+
+use codec::*;
+use smart_homes::transport::topic_matches;
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Notify},
+    task::{JoinHandle, JoinSet},
+    time::timeout,
+};
+use tracing::{debug, warn};
+
+/// A message kept by the broker, either retained or as a last will.
+#[derive(Debug, Clone)]
+pub struct StoredMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    /// MQTT 5 properties that travel with the message, already encoded.
+    properties: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Client {
+    conn_id: u64,
+    version: u8,
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    subscriptions: Vec<Subscription>,
+    kill: Arc<Notify>,
+    credentials: Credentials,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Subscription {
+    filter: String,
+    /// Whether live messages keep their retain flag, rather than only the
+    /// retained messages sent on subscribing.
+    retain_as_published: bool,
+}
+
+/// The username and password a client connected with.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    clients: HashMap<String, Client>,
+    retained: HashMap<String, StoredMessage>,
+}
+
+impl State {
+    fn publish(&mut self, msg: StoredMessage, retain: bool) {
+        debug!(topic = msg.topic, "publish");
+        for client in self.clients.values() {
+            let mut matching = client
+                .subscriptions
+                .iter()
+                .filter(|sub| topic_matches(&sub.filter, &msg.topic))
+                .peekable();
+            if matching.peek().is_some() {
+                let retain = retain && matching.any(|sub| sub.retain_as_published);
+                let _ = client.tx.send(encode_publish(&msg, client.version, retain));
+            }
+        }
+
+        if retain {
+            if msg.payload.is_empty() {
+                self.retained.remove(&msg.topic);
+            } else {
+                self.retained.insert(msg.topic.clone(), msg);
+            }
+        }
+    }
+}
+
+/// An MQTT broker listening on an ephemeral port of the loopback interface.
+/// It stops when dropped.
+#[derive(Debug)]
+pub struct Broker {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    task: JoinHandle<()>,
+}
+
+impl Broker {
+    pub async fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let task = tokio::spawn({
+            let state = state.clone();
+            async move {
+                // the connections live in the join set, so that they are all
+                // dropped together with the broker.
+                let mut connections = JoinSet::new();
+                let conn_ids = AtomicU64::new(0);
+                while let Ok((stream, _)) = listener.accept().await {
+                    let conn_id = conn_ids.fetch_add(1, Ordering::Relaxed);
+                    connections.spawn(serve(stream, conn_id, state.clone()));
+                    // reap the connections that are already closed
+                    while connections.try_join_next().is_some() {}
+                }
+            }
+        });
+
+        Ok(Self { addr, state, task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The URL to give to paho to connect to this broker.
+    pub fn url(&self) -> String {
+        format!("tcp://{}", self.addr)
+    }
+
+    /// The ids of the clients currently connected.
+    pub fn client_ids(&self) -> Vec<String> {
+        self.state.lock().unwrap().clients.keys().cloned().collect()
+    }
+
+    /// The message retained on `topic`, if any.
+    pub fn retained(&self, topic: &str) -> Option<StoredMessage> {
+        self.state.lock().unwrap().retained.get(topic).cloned()
+    }
+
+    /// The credentials `client_id` connected with, if it is connected.
+    pub fn credentials(&self, client_id: &str) -> Option<Credentials> {
+        let state = self.state.lock().unwrap();
+        state.clients.get(client_id).map(|c| c.credentials.clone())
+    }
+
+    /// Drop the connection of a client as if the network failed, so that its
+    /// last will is published. Returns whether the client was connected.
+    pub fn disconnect(&self, client_id: &str) -> bool {
+        match self.state.lock().unwrap().clients.get(client_id) {
+            Some(client) => {
+                client.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct Connect {
+    version: u8,
+    client_id: String,
+    keep_alive: u16,
+    will: Option<(StoredMessage, bool)>,
+    credentials: Credentials,
+}
+
+fn parse_connect(packet: &RawPacket, conn_id: u64) -> io::Result<Connect> {
+    if packet.kind != CONNECT {
+        return Err(invalid("expected CONNECT"));
+    }
+    let mut r = Reader::new(&packet.body);
+    let _protocol = r.string()?;
+    let version = r.u8()?;
+    let flags = r.u8()?;
+    let keep_alive = r.u16()?;
+    if version == V5 {
+        r.properties()?;
+    }
+
+    let mut client_id = r.string()?;
+    if client_id.is_empty() {
+        client_id = format!("anonymous-{conn_id}");
+    }
+
+    let will = if flags & 0x04 != 0 {
+        let properties = if version == V5 {
+            r.properties()?
+        } else {
+            vec![]
+        };
+        let topic = r.string()?;
+        let payload = r.binary()?.to_vec();
+        let retain = flags & 0x20 != 0;
+        Some((
+            StoredMessage {
+                topic,
+                payload,
+                properties,
+            },
+            retain,
+        ))
+    } else {
+        None
+    };
+    // username and password are accepted whatever they are.
+    let credentials = Credentials {
+        username: (flags & 0x80 != 0).then(|| r.string()).transpose()?,
+        password: (flags & 0x40 != 0)
+            .then(|| r.binary().map(<[u8]>::to_vec))
+            .transpose()?,
+    };
+
+    Ok(Connect {
+        version,
+        client_id,
+        keep_alive,
+        will,
+        credentials,
+    })
+}
+
+fn encode_publish(msg: &StoredMessage, version: u8, retain: bool) -> Vec<u8> {
+    let mut w = Writer::default().binary(msg.topic.as_bytes());
+    if version == V5 {
+        w = w.properties(&msg.properties);
+    }
+    w.raw(&msg.payload).finish(PUBLISH, retain as u8)
+}
+
+async fn serve(stream: TcpStream, conn_id: u64, state: Arc<Mutex<State>>) {
+    if let Err(err) = serve_inner(stream, conn_id, &state).await {
+        debug!(?err, conn_id, "connection closed");
+    }
+}
+
+async fn serve_inner(stream: TcpStream, conn_id: u64, state: &Mutex<State>) -> io::Result<()> {
+    let _ = stream.set_nodelay(true);
+    let (mut reader, mut writer) = stream.into_split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    let writer_task = tokio::spawn(async move {
+        while let Some(buf) = rx.recv().await {
+            if writer.write_all(&buf).await.is_err() {
+                break;
+            }
+        }
+    });
+    // stop writing once the connection is done with, whatever the reason.
+    let _writer_guard = AbortOnDrop(writer_task);
+
+    let packet = timeout(Duration::from_secs(10), read_packet(&mut reader))
+        .await
+        .map_err(|_| invalid("no CONNECT received"))??;
+    let connect = parse_connect(&packet, conn_id)?;
+    let version = connect.version;
+    let client_id = connect.client_id.clone();
+
+    let mut connack = Writer::default().u8(0).u8(0);
+    if version == V5 {
+        // tell the client which id it got, in case it did not pick one
+        let props = Writer::default()
+            .u8(0x12)
+            .binary(client_id.as_bytes())
+            .into_bytes();
+        connack = connack.properties(&props);
+    }
+    let _ = tx.send(connack.finish(CONNACK, 0));
+
+    let kill = Arc::new(Notify::new());
+    {
+        let mut state = state.lock().unwrap();
+        if let Some(old) = state.clients.get(&client_id) {
+            // another connection took over the client id
+            old.kill.notify_one();
+        }
+        state.clients.insert(
+            client_id.clone(),
+            Client {
+                conn_id,
+                version,
+                tx: tx.clone(),
+                subscriptions: vec![],
+                kill: kill.clone(),
+                credentials: connect.credentials.clone(),
+            },
+        );
+    }
+    debug!(client_id, version, "client connected");
+
+    // clients that go quiet for 1.5 times their keep alive are gone.
+    let idle_limit = match connect.keep_alive {
+        0 => Duration::MAX,
+        secs => Duration::from_millis(secs as u64 * 1500),
+    };
+
+    let mut will = connect.will;
+    let res = loop {
+        let packet = tokio::select! {
+            packet = timeout(idle_limit, read_packet(&mut reader)) => packet,
+            _ = kill.notified() => break Ok(()),
+        };
+        let packet = match packet {
+            Ok(Ok(packet)) => packet,
+            Ok(Err(err)) => break Err(err),
+            Err(_) => break Err(invalid("keep alive expired")),
+        };
+
+        match handle_packet(&packet, version, &client_id, &tx, state) {
+            Ok(Some(send_will)) => {
+                if !send_will {
+                    will = None;
+                }
+                break Ok(());
+            }
+            Ok(None) => {}
+            Err(err) => break Err(err),
+        }
+    };
+
+    let mut state = state.lock().unwrap();
+    if state
+        .clients
+        .get(&client_id)
+        .is_some_and(|c| c.conn_id == conn_id)
+    {
+        state.clients.remove(&client_id);
+    }
+    if let Some((will, retain)) = will {
+        debug!(client_id, topic = will.topic, "publishing last will");
+        state.publish(will, retain);
+    }
+    res
+}
+
+/// Handle a packet from a connected client. Returns whether the last will
+/// should be published if the client asked to disconnect.
+fn handle_packet(
+    packet: &RawPacket,
+    version: u8,
+    client_id: &str,
+    tx: &mpsc::UnboundedSender<Vec<u8>>,
+    state: &Mutex<State>,
+) -> io::Result<Option<bool>> {
+    let mut r = Reader::new(&packet.body);
+    match packet.kind {
+        PUBLISH => {
+            let qos = (packet.flags >> 1) & 0x03;
+            let retain = packet.flags & 0x01 != 0;
+            let topic = r.string()?;
+            let packet_id = if qos > 0 { Some(r.u16()?) } else { None };
+            let properties = if version == V5 {
+                r.properties()?
+            } else {
+                vec![]
+            };
+            let payload = r.rest().to_vec();
+
+            state.lock().unwrap().publish(
+                StoredMessage {
+                    topic,
+                    payload,
+                    properties,
+                },
+                retain,
+            );
+
+            match (qos, packet_id) {
+                (1, Some(id)) => {
+                    let _ = tx.send(Writer::default().u16(id).finish(PUBACK, 0));
+                }
+                (2, Some(id)) => {
+                    let _ = tx.send(Writer::default().u16(id).finish(PUBREC, 0));
+                }
+                _ => {}
+            }
+        }
+        PUBREL => {
+            let id = r.u16()?;
+            let _ = tx.send(Writer::default().u16(id).finish(PUBCOMP, 0));
+        }
+        SUBSCRIBE => {
+            let packet_id = r.u16()?;
+            if version == V5 {
+                r.properties()?;
+            }
+            let mut filters = vec![];
+            while !r.is_empty() {
+                let filter = r.string()?;
+                let options = r.u8()?;
+                filters.push(Subscription {
+                    filter,
+                    retain_as_published: version == V5 && options & 0x08 != 0,
+                });
+            }
+
+            let mut suback = Writer::default().u16(packet_id);
+            if version == V5 {
+                suback = suback.properties(&[]);
+            }
+            for _ in &filters {
+                // everything is delivered with QoS 0
+                suback = suback.u8(0);
+            }
+
+            let mut state = state.lock().unwrap();
+            let _ = tx.send(suback.finish(SUBACK, 0));
+            for sub in &filters {
+                for msg in state.retained.values() {
+                    if topic_matches(&sub.filter, &msg.topic) {
+                        let _ = tx.send(encode_publish(msg, version, true));
+                    }
+                }
+            }
+            if let Some(client) = state.clients.get_mut(client_id) {
+                for sub in filters {
+                    // subscribing again replaces the options
+                    client.subscriptions.retain(|s| s.filter != sub.filter);
+                    client.subscriptions.push(sub);
+                }
+            }
+        }
+        UNSUBSCRIBE => {
+            let packet_id = r.u16()?;
+            if version == V5 {
+                r.properties()?;
+            }
+            let mut filters = vec![];
+            while !r.is_empty() {
+                filters.push(r.string()?);
+            }
+
+            let mut unsuback = Writer::default().u16(packet_id);
+            if version == V5 {
+                unsuback = unsuback.properties(&[]);
+                for _ in &filters {
+                    unsuback = unsuback.u8(0);
+                }
+            }
+
+            let mut state = state.lock().unwrap();
+            if let Some(client) = state.clients.get_mut(client_id) {
+                client
+                    .subscriptions
+                    .retain(|sub| !filters.contains(&sub.filter));
+            }
+            let _ = tx.send(unsuback.finish(UNSUBACK, 0));
+        }
+        PINGREQ => {
+            let _ = tx.send(Writer::default().finish(PINGRESP, 0));
+        }
+        DISCONNECT => {
+            // 0x04 is "disconnect with will message"
+            let send_will = version == V5 && !r.is_empty() && r.u8()? == 0x04;
+            return Ok(Some(send_will));
+        }
+        PUBACK | PUBREC | PUBCOMP => {
+            // nothing is ever sent with QoS > 0, so there is nothing to
+            // acknowledge.
+        }
+        kind => warn!(kind, client_id, "unsupported packet"),
+    }
+    Ok(None)
+}
+
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}